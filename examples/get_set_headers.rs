@@ -2,6 +2,7 @@ extern crate ferrum;
 
 use ferrum::*;
 use ferrum::header::{ContentType, UserAgent};
+use ferrum::request::{ForwardedInfo, TrustedProxies};
 
 struct DefaultContentType;
 
@@ -22,18 +23,22 @@ fn info(request: &mut Request) -> FerrumResult<Response> {
         Some(user_agent) => format!("User Agent: {}\n", user_agent),
         None => "No User Agent\n".to_string(),
     };
-    // Get a non-standard header using the raw header
-    let x_forwarded_for = match request.headers.get_raw("X-Forwarded-For") {
-        Some(proxies) => format!("Proxies: {}\n", std::str::from_utf8(&proxies[0]).unwrap()),
-        None => "No proxy\n".to_string(),
+    // Get the proxies resolved by the `TrustedProxies` middleware from the
+    // non-standard `X-Forwarded-For` or the `Forwarded` header
+    let proxies = match request.extensions.get::<ForwardedInfo>() {
+        Some(info) if !info.proxies.is_empty() => format!("Proxies: {:?}\n", info.proxies),
+        _ => "No proxy\n".to_string(),
     };
-    let body = format!("{}{}\n", user_agent, x_forwarded_for);
+    let client = format!("Client: {:?}\n", request.client_ip());
+    let body = format!("{}{}{}\n", user_agent, proxies, client);
 
     Ok(Response::new().with_body(body))
 }
 
 fn main() {
     let mut chain = Chain::new(info);
+    // Only believe the forwarding headers set by a proxy on the local host
+    chain.link_before(TrustedProxies::new(&["127.0.0.1", "::1"]).unwrap());
     chain.link_after(DefaultContentType);
     Ferrum::new(chain).http(("localhost", 3000)).unwrap();
 }
@@ -3,8 +3,13 @@ extern crate ferrum;
 use ferrum::*;
 
 fn main() {
-    Ferrum::new(move |_: &mut Request | {
-        Ok(Response::new_redirect("http://rust-lang.org"))
+    Ferrum::new(move |request: &mut Request| {
+        if request.uri.path() == "/rust" {
+            return Ok(Response::new_redirect("http://rust-lang.org"));
+        }
+
+        // Build an absolute location from the URL of the request
+        let location = request.url_for("/rust").map_err(|err| FerrumError::new(err, None))?;
+        Ok(Response::new_redirect(location.to_string()))
     }).http("localhost:3000").unwrap();
 }
-
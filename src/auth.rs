@@ -0,0 +1,438 @@
+//! HTTP authentication with the `Basic` and `Bearer` schemes.
+//!
+//! `Authentication` is a `BeforeMiddleware` parsing the `Authorization`
+//! header of requests into `Credentials`, checked by a `Verifier`. The
+//! `Principal` of the valid credentials is stored in the extensions of the
+//! request. The other requests are rejected with `401 Unauthorized` and the
+//! `WWW-Authenticate` challenges of the accepted schemes:
+//!
+//! ```rust
+//! use ferrum::*;
+//! use ferrum::auth::{constant_time_eq, Authentication, Credentials, Principal};
+//!
+//! fn hello(request: &mut Request) -> FerrumResult<Response> {
+//!     let name = request.extensions.get::<Principal>().map(|principal| principal.name.clone());
+//!     Ok(Response::new().with_content(format!("Hello {}!", name.unwrap_or_default()), mime::TEXT_PLAIN))
+//! }
+//!
+//! let verifier = |credentials: &Credentials| match *credentials {
+//!     Credentials::Basic { ref username, ref password }
+//!         if username == "admin" && constant_time_eq(password.as_bytes(), b"secret") => {
+//!         Some(Principal::new("admin").with_role("admin"))
+//!     },
+//!     Credentials::Bearer(ref token) if constant_time_eq(token.as_bytes(), b"api-token") => Some(Principal::new("api")),
+//!     _ => None
+//! };
+//!
+//! let mut chain = Chain::new(hello);
+//! chain.link_before(Authentication::new(verifier).with_realm("admin").with_public_path("/health"));
+//! ```
+//!
+//! Secrets should be compared with `constant_time_eq`, which doesn't leak
+//! how much of them a guess got right through its timing.
+//!
+//! In optional mode, the requests without `Authorization` go through
+//! anonymously, while invalid credentials are still rejected. Public paths
+//! are matched against the percent decoded `Request::uri_path_segments`,
+//! and never include paths with `.` or `..` segments.
+
+use std::error::Error;
+use std::fmt;
+use std::sync::Arc;
+
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use hyper::Uri;
+
+use {Request, Response, FerrumResult, FerrumError, StatusCode};
+use middleware::BeforeMiddleware;
+use request::UriPathSegments;
+use typemap::Key;
+
+/// Whether `a` and `b` are equal, in a time which only depends on their
+/// lengths.
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+/// The credentials of the `Authorization` header of a request.
+#[derive(Clone, PartialEq, Eq)]
+pub enum Credentials {
+    /// The user and password of the `Basic` scheme.
+    Basic {
+        username: String,
+        password: String,
+    },
+    /// The token of the `Bearer` scheme.
+    Bearer(String),
+}
+
+// Keep the secrets out of the logs.
+impl fmt::Debug for Credentials {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Credentials::Basic { ref username, .. } => write!(formatter, "Basic {{ username: {:?}, .. }}", username),
+            Credentials::Bearer(_) => formatter.write_str("Bearer(..)"),
+        }
+    }
+}
+
+impl Credentials {
+    /// Parse the value of an `Authorization` header, returning `None` for
+    /// the other schemes and malformed credentials.
+    ///
+    /// ```rust
+    /// use ferrum::auth::Credentials;
+    ///
+    /// assert_eq!(Credentials::parse("Basic QWxhZGRpbjpvcGVuIHNlc2FtZQ=="), Some(Credentials::Basic {
+    ///     username: "Aladdin".to_string(),
+    ///     password: "open sesame".to_string(),
+    /// }));
+    /// assert_eq!(Credentials::parse("bearer mF_9.B5f-4.1JqM"), Some(Credentials::Bearer("mF_9.B5f-4.1JqM".to_string())));
+    /// ```
+    pub fn parse(value: &str) -> Option<Credentials> {
+        let value = value.trim();
+        let (scheme, rest) = value.split_at(value.find(' ').unwrap_or(value.len()));
+        let rest = rest.trim();
+        if scheme.eq_ignore_ascii_case("Basic") {
+            let decoded = String::from_utf8(BASE64.decode(rest).ok()?).ok()?;
+            let colon = decoded.find(':')?;
+            Some(Credentials::Basic {
+                username: decoded[..colon].to_string(),
+                password: decoded[colon + 1..].to_string(),
+            })
+        } else if scheme.eq_ignore_ascii_case("Bearer") {
+            let token68 = !rest.is_empty() && rest.trim_end_matches('=').bytes()
+                .all(|c| c.is_ascii_alphanumeric() || b"-._~+/".contains(&c));
+            if token68 { Some(Credentials::Bearer(rest.to_string())) } else { None }
+        } else {
+            None
+        }
+    }
+
+    fn scheme(&self) -> Scheme {
+        match *self {
+            Credentials::Basic { .. } => Scheme::Basic,
+            Credentials::Bearer(_) => Scheme::Bearer,
+        }
+    }
+}
+
+/// The authenticated client of a request, in its extensions.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct Principal {
+    /// The name of the user or service.
+    pub name: String,
+    /// The roles granted to the client, as given by the `Verifier`.
+    pub roles: Vec<String>,
+}
+
+impl Principal {
+    /// Create a `Principal` without roles.
+    pub fn new<S: Into<String>>(name: S) -> Principal {
+        Principal {
+            name: name.into(),
+            roles: Vec::new(),
+        }
+    }
+
+    /// Add a role.
+    pub fn with_role<S: Into<String>>(mut self, role: S) -> Self {
+        self.roles.push(role.into());
+        self
+    }
+
+    /// Whether the client has `role`.
+    pub fn has_role(&self, role: &str) -> bool {
+        self.roles.iter().any(|other| other == role)
+    }
+}
+
+impl Key for Principal {
+    type Value = Principal;
+}
+
+/// The check of the credentials of requests.
+///
+/// `Verifier` is implemented for the functions and closures taking the
+/// `Credentials`.
+pub trait Verifier: Send + Sync + 'static {
+    /// The `Principal` of valid `credentials`, or `None`.
+    fn verify(&self, credentials: &Credentials) -> Option<Principal>;
+}
+
+impl<F> Verifier for F
+    where F: Send + Sync + 'static + Fn(&Credentials) -> Option<Principal>
+{
+    fn verify(&self, credentials: &Credentials) -> Option<Principal> {
+        (*self)(credentials)
+    }
+}
+
+/// The error of a request which failed to authenticate, answered with
+/// `401 Unauthorized`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AuthError {
+    /// The request has no `Authorization` header.
+    MissingCredentials,
+    /// The `Authorization` header is malformed, or of a scheme which isn't
+    /// accepted.
+    MalformedCredentials,
+    /// The `Verifier` rejected the credentials.
+    InvalidCredentials,
+}
+
+impl fmt::Display for AuthError {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str(match *self {
+            AuthError::MissingCredentials => "No credentials in the request",
+            AuthError::MalformedCredentials => "Malformed or unsupported credentials",
+            AuthError::InvalidCredentials => "Invalid credentials",
+        })
+    }
+}
+
+impl Error for AuthError {}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Scheme {
+    Basic,
+    Bearer,
+}
+
+type SkipFn = Arc<dyn Fn(&Request) -> bool + Send + Sync>;
+
+/// A `BeforeMiddleware` authenticating requests, see the `auth` module.
+#[derive(Clone)]
+pub struct Authentication {
+    verifier: Arc<dyn Verifier>,
+    realm: String,
+    schemes: Vec<Scheme>,
+    optional: bool,
+    public_paths: Vec<Vec<String>>,
+    skip: Option<SkipFn>,
+}
+
+impl Authentication {
+    /// Authenticate the requests with `verifier`, accepting both schemes.
+    pub fn new<V: Verifier>(verifier: V) -> Authentication {
+        Authentication {
+            verifier: Arc::new(verifier),
+            realm: "ferrum".to_string(),
+            schemes: vec![Scheme::Basic, Scheme::Bearer],
+            optional: false,
+            public_paths: Vec::new(),
+            skip: None,
+        }
+    }
+
+    /// Set the realm of the challenges, shown by browsers asking for a
+    /// password. The default is `ferrum`.
+    pub fn with_realm<S: Into<String>>(mut self, realm: S) -> Self {
+        self.realm = realm.into();
+        self
+    }
+
+    /// Accept the `Basic` scheme. The default is `true`.
+    pub fn with_basic(self, accept: bool) -> Self {
+        self.with_scheme(Scheme::Basic, accept)
+    }
+
+    /// Accept the `Bearer` scheme. The default is `true`.
+    pub fn with_bearer(self, accept: bool) -> Self {
+        self.with_scheme(Scheme::Bearer, accept)
+    }
+
+    fn with_scheme(mut self, scheme: Scheme, accept: bool) -> Self {
+        self.schemes.retain(|&other| other != scheme);
+        if accept {
+            self.schemes.push(scheme);
+            self.schemes.sort_by_key(|&scheme| scheme as u8);
+        }
+        self
+    }
+
+    /// Let the requests without credentials through, without `Principal`.
+    /// The default is `false`.
+    pub fn with_optional(mut self, optional: bool) -> Self {
+        self.optional = optional;
+        self
+    }
+
+    /// Don't authenticate the requests under `prefix`, matched by whole
+    /// segments, e.g. `/health` or `/assets`. The prefix is percent decoded
+    /// like `Request::uri_path_segments`.
+    pub fn with_public_path(mut self, prefix: &str) -> Self {
+        let uri = format!("/{}", prefix.trim_start_matches('/')).parse::<Uri>();
+        let segments = uri.map(|uri| uri.decoded_path_segments()).unwrap_or_default();
+        self.public_paths.push(segments.into_iter().filter(|segment| !segment.is_empty()).collect());
+        self
+    }
+
+    /// Don't authenticate the requests for which `skip` returns `true`.
+    pub fn with_skip<F>(mut self, skip: F) -> Self
+        where F: Fn(&Request) -> bool + Send + Sync + 'static
+    {
+        self.skip = Some(Arc::new(skip));
+        self
+    }
+
+    fn is_public(&self, request: &Request) -> bool {
+        // The handlers don't resolve dot segments either, so a path with
+        // some, even encoded, could climb out of a public prefix.
+        let segments = &request.uri_path_segments;
+        if segments.iter().any(|segment| segment == "." || segment == "..") {
+            return false;
+        }
+        let segments = segments.iter().filter(|segment| !segment.is_empty()).collect::<Vec<_>>();
+        let public = self.public_paths.iter().any(|prefix| {
+            prefix.len() <= segments.len() && prefix.iter().zip(&segments).all(|(a, b)| a == *b)
+        });
+        public || self.skip.as_ref().map(|skip| skip(request)).unwrap_or(false)
+    }
+
+    fn authenticate(&self, request: &Request) -> Result<Option<Principal>, (AuthError, Option<Scheme>)> {
+        let value = match request.headers.get_raw("Authorization").and_then(|raw| raw.one()) {
+            Some(value) => String::from_utf8_lossy(value).into_owned(),
+            None if self.optional => return Ok(None),
+            None => return Err((AuthError::MissingCredentials, None))
+        };
+        let credentials = match Credentials::parse(&value) {
+            Some(ref credentials) if self.schemes.contains(&credentials.scheme()) => credentials.clone(),
+            _ => return Err((AuthError::MalformedCredentials, None))
+        };
+        match self.verifier.verify(&credentials) {
+            Some(principal) => Ok(Some(principal)),
+            None => Err((AuthError::InvalidCredentials, Some(credentials.scheme())))
+        }
+    }
+
+    fn unauthorized(&self, error: AuthError, scheme: Option<Scheme>) -> FerrumError {
+        let mut response = Response::new().with_status(StatusCode::Unauthorized);
+        let realm = self.realm.replace('\\', "\\\\").replace('"', "\\\"");
+        for &challenge in &self.schemes {
+            let value = match challenge {
+                Scheme::Basic => format!("Basic realm=\"{}\", charset=\"UTF-8\"", realm),
+                // The error of a rejected token, as of RFC 6750.
+                Scheme::Bearer if scheme == Some(Scheme::Bearer) => format!("Bearer realm=\"{}\", error=\"invalid_token\"", realm),
+                Scheme::Bearer => format!("Bearer realm=\"{}\"", realm),
+            };
+            response.headers.append_raw("WWW-Authenticate", value);
+        }
+        FerrumError::new(error, Some(response))
+    }
+}
+
+impl BeforeMiddleware for Authentication {
+    fn before(&self, request: &mut Request) -> FerrumResult<()> {
+        if self.is_public(request) {
+            return Ok(());
+        }
+        match self.authenticate(request) {
+            Ok(Some(principal)) => {
+                request.extensions.insert::<Principal>(principal);
+                Ok(())
+            },
+            Ok(None) => Ok(()),
+            Err((error, scheme)) => Err(self.unauthorized(error, scheme))
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn verifier(credentials: &Credentials) -> Option<Principal> {
+        match *credentials {
+            Credentials::Basic { ref username, ref password } if username == "user" && password == "pass:word" => {
+                Some(Principal::new("user").with_role("admin"))
+            },
+            Credentials::Bearer(ref token) if token == "abc.def" => Some(Principal::new("service")),
+            _ => None
+        }
+    }
+
+    fn request(path: &str, authorization: Option<&str>) -> Request {
+        let mut request = Request::stub();
+        request.uri = path.parse().unwrap();
+        request.uri_path_segments = request.uri.decoded_path_segments();
+        if let Some(authorization) = authorization {
+            request.headers.set_raw("Authorization", authorization.to_string());
+        }
+        request
+    }
+
+    fn challenges(auth: &Authentication, request: &mut Request) -> (String, Vec<String>) {
+        let error = auth.before(request).unwrap_err();
+        let response = error.response.unwrap();
+        assert_eq!(response.status, StatusCode::Unauthorized);
+        let challenges = response.headers.get_raw("WWW-Authenticate").unwrap().iter()
+            .map(|line| String::from_utf8(line.to_vec()).unwrap())
+            .collect();
+        (error.error.to_string(), challenges)
+    }
+
+    #[test]
+    fn test_authenticate() {
+        let auth = Authentication::new(verifier);
+
+        let mut basic = request("/", Some(&format!("Basic {}", BASE64.encode("user:pass:word"))));
+        auth.before(&mut basic).unwrap();
+        let principal = basic.extensions.get::<Principal>().unwrap();
+        assert_eq!(principal.name, "user");
+        assert!(principal.has_role("admin"));
+
+        let mut bearer = request("/", Some("Bearer abc.def"));
+        auth.before(&mut bearer).unwrap();
+        assert_eq!(bearer.extensions.get::<Principal>().unwrap().name, "service");
+
+        assert_eq!(challenges(&auth, &mut request("/", None)), ("No credentials in the request".to_string(), vec![
+            "Basic realm=\"ferrum\", charset=\"UTF-8\"".to_string(),
+            "Bearer realm=\"ferrum\"".to_string(),
+        ]));
+        assert_eq!(challenges(&auth, &mut request("/", Some("Bearer wrong"))).1[1],
+            "Bearer realm=\"ferrum\", error=\"invalid_token\"");
+        assert_eq!(challenges(&auth, &mut request("/", Some("Basic !!!"))).0, "Malformed or unsupported credentials");
+        assert_eq!(challenges(&auth, &mut request("/", Some("Digest username=\"user\""))).0, "Malformed or unsupported credentials");
+    }
+
+    #[test]
+    fn test_schemes_and_realm() {
+        let auth = Authentication::new(verifier).with_basic(false).with_realm("the \"api\"");
+        assert_eq!(challenges(&auth, &mut request("/", Some(&format!("Basic {}", BASE64.encode("user:pass:word"))))),
+            ("Malformed or unsupported credentials".to_string(), vec!["Bearer realm=\"the \\\"api\\\"\"".to_string()]));
+    }
+
+    #[test]
+    fn test_constant_time_eq() {
+        assert!(constant_time_eq(b"secret", b"secret"));
+        assert!(!constant_time_eq(b"secret", b"secreT"));
+        assert!(!constant_time_eq(b"secret", b"secret!"));
+        assert!(constant_time_eq(b"", b""));
+    }
+
+    #[test]
+    fn test_opt_out() {
+        let auth = Authentication::new(verifier)
+            .with_public_path("/health/")
+            .with_skip(|request: &Request| request.uri.path() == "/robots.txt");
+
+        assert!(auth.before(&mut request("/health", None)).is_ok());
+        assert!(auth.before(&mut request("/health/ready", Some("Bearer wrong"))).is_ok());
+        assert!(auth.before(&mut request("/robots.txt", None)).is_ok());
+        assert!(auth.before(&mut request("/healthz", None)).is_err());
+        assert!(auth.before(&mut request("/%68ealth/ready", None)).is_ok());
+
+        // Dot segments don't climb out of a public prefix.
+        assert!(auth.before(&mut request("/health/../admin", None)).is_err());
+        assert!(auth.before(&mut request("/health/%2e%2E/admin", None)).is_err());
+        assert!(auth.before(&mut request("/health/./ready", None)).is_err());
+
+        let optional = Authentication::new(verifier).with_optional(true);
+        let mut anonymous = request("/", None);
+        optional.before(&mut anonymous).unwrap();
+        assert!(anonymous.extensions.get::<Principal>().is_none());
+        assert_eq!(challenges(&optional, &mut request("/", Some("Bearer wrong"))).0, "Invalid credentials");
+    }
+}
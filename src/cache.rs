@@ -0,0 +1,532 @@
+//! An in-memory cache of whole responses.
+//!
+//! `ResponseCache` is an `AroundMiddleware` which stores successful buffered
+//! responses to `GET` and `HEAD` requests and replays them while they are fresh.
+//!
+//! Entries are keyed on the request method, the URL, host included, and the values
+//! of the request headers listed in the response's `Vary` header. Responses marked
+//! `no-store`, `no-cache` or `private` are never stored. The responses to requests
+//! with `Authorization` are only stored, and replayed to such requests, when marked
+//! `public`, `s-maxage` or `must-revalidate`. The freshness lifetime is taken from
+//! the `s-maxage` or `max-age` directive, or else the `Expires` header, falling
+//! back to the configured `ttl`.
+//! The least recently used entries are evicted once the cache holds more than
+//! `capacity` bytes of bodies.
+//!
+//! `ResponseCache` is cheaply clonable, keep a clone around to read the stats:
+//!
+//! ```rust
+//! use std::time::Duration;
+//! use ferrum::*;
+//! use ferrum::cache::ResponseCache;
+//!
+//! # fn hello(_: &mut Request) -> FerrumResult<Response> {
+//! #     Ok(Response::new().with_content("Hello world!", mime::TEXT_PLAIN))
+//! # }
+//! let cache = ResponseCache::new(16 * 1024 * 1024, Duration::from_secs(60));
+//! let mut chain = Chain::new(hello);
+//! chain.link_around(cache.clone());
+//!
+//! println!("Cache hits: {}", cache.stats().hits);
+//! ```
+
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+
+use hyper::header::{CacheControl, CacheDirective, Expires, Pragma, SetCookie, Vary};
+
+use {Request, Response, FerrumResult, Headers, Method, StatusCode};
+use middleware::{AroundMiddleware, Handler};
+
+/// Counters describing the activity of a `ResponseCache`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    /// Requests answered from the cache.
+    pub hits: u64,
+    /// Cacheable requests which had to be handled.
+    pub misses: u64,
+    /// Entries removed to make room for new ones.
+    pub evictions: u64,
+    /// Number of entries currently stored.
+    pub entries: usize,
+    /// Total size of the stored bodies, in bytes.
+    pub size: usize,
+}
+
+struct Entry {
+    // The method and URI of the entry, without the headers it varies on.
+    primary: String,
+    status: StatusCode,
+    headers: Headers,
+    body: Vec<u8>,
+    stored: Instant,
+    expires: Instant,
+    tick: u64,
+}
+
+#[derive(Default)]
+struct Store {
+    // Request headers each resource varies on, by method and URI, along with
+    // the number of entries stored for it.
+    vary: HashMap<String, (Vec<String>, usize)>,
+    entries: HashMap<String, Entry>,
+    // Entry keys by the tick of their last use, oldest first.
+    recency: BTreeMap<u64, String>,
+    tick: u64,
+    stats: CacheStats,
+}
+
+impl Store {
+    fn vary(&self, primary: &str) -> &[String] {
+        self.vary.get(primary).map(|(names, _)| &names[..]).unwrap_or(&[])
+    }
+
+    fn key(primary: &str, names: &[String], request: &Request) -> String {
+        let mut key = primary.to_string();
+        for name in names {
+            key.push('\n');
+            key.push_str(name);
+            key.push(':');
+            if let Some(values) = request.headers.get_raw(name) {
+                // Header values can't hold a NUL, which keeps them apart.
+                for value in values {
+                    key.push('\0');
+                    key.push_str(&String::from_utf8_lossy(value));
+                }
+            }
+        }
+        key
+    }
+
+    fn touch(&mut self, key: &str) {
+        self.tick += 1;
+        let tick = self.tick;
+        if let Some(entry) = self.entries.get_mut(key) {
+            self.recency.remove(&entry.tick);
+            entry.tick = tick;
+            self.recency.insert(tick, key.to_string());
+        }
+    }
+
+    fn remove(&mut self, key: &str) -> Option<Entry> {
+        let entry = self.entries.remove(key)?;
+        self.recency.remove(&entry.tick);
+        let last = match self.vary.get_mut(&entry.primary) {
+            Some(&mut (_, ref mut count)) => {
+                *count -= 1;
+                *count == 0
+            },
+            None => false
+        };
+        if last {
+            self.vary.remove(&entry.primary);
+        }
+        self.stats.size -= entry.body.len();
+        self.stats.entries -= 1;
+        Some(entry)
+    }
+
+    fn insert(&mut self, key: String, names: Vec<String>, entry: Entry, capacity: usize) {
+        self.remove(&key);
+        // Counted before evicting, so the other variants can't take it along.
+        let vary = self.vary.entry(entry.primary.clone()).or_insert((vec![], 0));
+        vary.0 = names;
+        vary.1 += 1;
+        while self.stats.size + entry.body.len() > capacity {
+            let oldest = match self.recency.values().next() {
+                Some(oldest) => oldest.clone(),
+                None => break
+            };
+            self.remove(&oldest);
+            self.stats.evictions += 1;
+        }
+
+        self.stats.size += entry.body.len();
+        self.stats.entries += 1;
+        self.entries.insert(key.clone(), entry);
+        self.touch(&key);
+    }
+}
+
+struct Inner {
+    capacity: usize,
+    ttl: Duration,
+    store: Mutex<Store>,
+}
+
+/// An `AroundMiddleware` caching whole responses in memory.
+#[derive(Clone)]
+pub struct ResponseCache {
+    inner: Arc<Inner>,
+}
+
+impl ResponseCache {
+    /// Create a cache holding at most `capacity` bytes of response bodies, and
+    /// keeping responses without explicit freshness lifetime for `ttl`.
+    pub fn new(capacity: usize, ttl: Duration) -> ResponseCache {
+        ResponseCache {
+            inner: Arc::new(Inner {
+                capacity,
+                ttl,
+                store: Mutex::new(Store::default()),
+            })
+        }
+    }
+
+    /// Get a snapshot of the cache counters.
+    pub fn stats(&self) -> CacheStats {
+        self.inner.store.lock().unwrap().stats
+    }
+
+    /// Remove all the stored responses.
+    pub fn clear(&self) {
+        let mut store = self.inner.store.lock().unwrap();
+        let stats = store.stats;
+        *store = Store::default();
+        store.stats = CacheStats { entries: 0, size: 0, ..stats };
+    }
+}
+
+impl AroundMiddleware for ResponseCache {
+    fn around(self, handler: Box<dyn Handler>) -> Box<dyn Handler> {
+        Box::new(ResponseCacheHandler {
+            cache: self.inner,
+            handler
+        })
+    }
+}
+
+struct ResponseCacheHandler {
+    cache: Arc<Inner>,
+    handler: Box<dyn Handler>,
+}
+
+impl ResponseCacheHandler {
+    fn lookup(&self, primary: &str, request: &Request) -> Option<Response> {
+        let mut store = self.cache.store.lock().unwrap();
+        let key = Store::key(primary, store.vary(primary), request);
+        let now = Instant::now();
+
+        let (fresh, shared) = match store.entries.get(&key) {
+            Some(entry) => (entry.expires > now, shared(&entry.headers)),
+            None => return None
+        };
+        if !fresh {
+            store.remove(&key);
+            return None;
+        }
+        // Authenticated requests only get the responses allowed to be shared.
+        if request.headers.get_raw("Authorization").is_some() && !shared {
+            return None;
+        }
+
+        store.touch(&key);
+        store.stats.hits += 1;
+        let entry = &store.entries[&key];
+        let mut response = Response::new()
+            .with_status(entry.status)
+            .with_headers(entry.headers.clone())
+            .with_body(entry.body.clone());
+        response.headers.set_raw("Age", (now - entry.stored).as_secs().to_string());
+        Some(response)
+    }
+
+    fn store(&self, primary: String, request: &Request, response: &mut Response) {
+        let ttl = match freshness(response) {
+            Some(ttl) => ttl,
+            None => self.cache.ttl
+        };
+        if ttl == Duration::from_secs(0) {
+            return;
+        }
+
+        let body = match response.buffer_content() {
+            Ok(Some(content)) => content.0,
+            Ok(None) => vec![],
+            Err(_) => return
+        };
+        if body.len() > self.cache.capacity {
+            return;
+        }
+
+        let names = match response.headers.get::<Vary>() {
+            Some(Vary::Items(names)) => names.iter().map(|name| name.to_lowercase()).collect(),
+            Some(Vary::Any) => return,
+            None => vec![]
+        };
+
+        let now = Instant::now();
+        let mut store = self.cache.store.lock().unwrap();
+        let key = Store::key(&primary, &names, request);
+        let entry = Entry {
+            primary,
+            status: response.status,
+            headers: response.headers.clone(),
+            body,
+            stored: now,
+            expires: now + ttl,
+            tick: 0,
+        };
+        store.insert(key, names, entry, self.cache.capacity);
+    }
+}
+
+impl Handler for ResponseCacheHandler {
+    fn handle(&self, request: &mut Request) -> FerrumResult<Response> {
+        let cacheable = request.method == Method::Get || request.method == Method::Head;
+        let directives = request.headers.get::<CacheControl>().map(|cc| cc.0.clone()).unwrap_or_default();
+        if !cacheable || directives.contains(&CacheDirective::NoStore) {
+            return self.handler.handle(request);
+        }
+
+        // Server-side URIs have no host, the URL tells the virtual hosts apart.
+        let url = request.url().map(|url| url.to_string()).unwrap_or_else(|_| request.uri.to_string());
+        let primary = format!("{} {}", request.method, url);
+        let revalidate = directives.contains(&CacheDirective::NoCache)
+            || request.headers.get::<Pragma>() == Some(&Pragma::NoCache);
+        if !revalidate {
+            if let Some(response) = self.lookup(&primary, request) {
+                return Ok(response);
+            }
+        }
+        self.cache.store.lock().unwrap().stats.misses += 1;
+
+        let mut response = self.handler.handle(request)?;
+        let authorized = request.headers.get_raw("Authorization").is_some();
+        if storable(&response) && (!authorized || shared(&response.headers)) {
+            self.store(primary, request, &mut response);
+        }
+        Ok(response)
+    }
+}
+
+fn storable(response: &Response) -> bool {
+    if response.status != StatusCode::Ok || !response.is_buffered() || response.headers.has::<SetCookie>() {
+        return false;
+    }
+    match response.headers.get::<CacheControl>() {
+        Some(cache_control) => !cache_control.iter().any(|directive| {
+            matches!(*directive, CacheDirective::NoStore | CacheDirective::NoCache | CacheDirective::Private)
+        }),
+        None => true
+    }
+}
+
+// Whether the response to an authenticated request may be stored and shared,
+// as of RFC 7234 section 3.2.
+fn shared(headers: &Headers) -> bool {
+    match headers.get::<CacheControl>() {
+        Some(cache_control) => cache_control.iter().any(|directive| {
+            matches!(*directive, CacheDirective::Public | CacheDirective::SMaxAge(_) | CacheDirective::MustRevalidate)
+        }),
+        None => false
+    }
+}
+
+fn freshness(response: &Response) -> Option<Duration> {
+    if let Some(cache_control) = response.headers.get::<CacheControl>() {
+        let s_max_age = cache_control.iter().filter_map(|directive| match *directive {
+            CacheDirective::SMaxAge(age) => Some(age),
+            _ => None
+        }).next();
+        let max_age = cache_control.iter().filter_map(|directive| match *directive {
+            CacheDirective::MaxAge(age) => Some(age),
+            _ => None
+        }).next();
+        if let Some(age) = s_max_age.or(max_age) {
+            return Some(Duration::from_secs(u64::from(age)));
+        }
+    }
+    response.headers.get_raw("Expires")?;
+    // An invalid date, such as `0`, means already expired.
+    let expires = response.headers.get::<Expires>()
+        .map(|&Expires(date)| SystemTime::from(date))
+        .unwrap_or(SystemTime::UNIX_EPOCH);
+    Some(expires.duration_since(SystemTime::now()).unwrap_or_default())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::str::FromStr;
+    use hyper::Uri;
+    use response::CachePolicy;
+    use mime;
+
+    fn counting_handler(cache: &ResponseCache, policy: CachePolicy) -> (Box<dyn Handler>, Arc<AtomicUsize>) {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let counter = calls.clone();
+        let handler = move |request: &mut Request| {
+            counter.fetch_add(1, Ordering::SeqCst);
+            Ok(Response::new()
+                .with_content(request.uri.path().to_string(), mime::TEXT_PLAIN)
+                .with_cache_policy(policy.clone()))
+        };
+        (cache.clone().around(Box::new(handler)), calls)
+    }
+
+    fn request(path: &str) -> Request {
+        let mut request = Request::stub();
+        request.uri = Uri::from_str(&format!("http://www.rust-lang.org{}", path)).unwrap();
+        request
+    }
+
+    #[test]
+    fn test_cache_hit() {
+        let cache = ResponseCache::new(1024, Duration::from_secs(60));
+        let (handler, calls) = counting_handler(&cache, CachePolicy::new().public());
+
+        handler.handle(&mut request("/a")).unwrap();
+        let mut response = handler.handle(&mut request("/a")).unwrap();
+        handler.handle(&mut request("/b")).unwrap();
+
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+        assert_eq!(*response.buffer_content().unwrap().unwrap(), b"/a".to_vec());
+        assert_eq!(cache.stats(), CacheStats { hits: 1, misses: 2, evictions: 0, entries: 2, size: 4 });
+    }
+
+    #[test]
+    fn test_cache_respects_no_store_and_private() {
+        let cache = ResponseCache::new(1024, Duration::from_secs(60));
+        let (handler, calls) = counting_handler(&cache, CachePolicy::new().private());
+        handler.handle(&mut request("/a")).unwrap();
+        handler.handle(&mut request("/a")).unwrap();
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+
+        let (handler, calls) = counting_handler(&cache, CachePolicy::new().no_store());
+        handler.handle(&mut request("/a")).unwrap();
+        handler.handle(&mut request("/a")).unwrap();
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+        assert_eq!(cache.stats().entries, 0);
+    }
+
+    #[test]
+    fn test_cache_expiration() {
+        let cache = ResponseCache::new(1024, Duration::from_secs(60));
+        let (handler, calls) = counting_handler(&cache, CachePolicy::new().max_age(Duration::from_secs(0)));
+        handler.handle(&mut request("/a")).unwrap();
+        handler.handle(&mut request("/a")).unwrap();
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn test_cache_vary() {
+        let cache = ResponseCache::new(1024, Duration::from_secs(60));
+        let (handler, calls) = counting_handler(&cache, CachePolicy::new().vary("Accept-Language"));
+
+        let mut english = request("/a");
+        english.headers.set_raw("Accept-Language", "en");
+        let mut french = request("/a");
+        french.headers.set_raw("Accept-Language", "fr");
+
+        handler.handle(&mut english).unwrap();
+        handler.handle(&mut french).unwrap();
+        handler.handle(&mut english).unwrap();
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+        assert_eq!(cache.stats().hits, 1);
+
+        // Values split differently across header lines are different variants.
+        let names = vec!["accept-language".to_string()];
+        let mut split = request("/a");
+        split.headers.set_raw("Accept-Language", vec![b"a".to_vec(), b"bc".to_vec()]);
+        let mut other = request("/a");
+        other.headers.set_raw("Accept-Language", vec![b"ab".to_vec(), b"c".to_vec()]);
+        assert_ne!(Store::key("GET /a", &names, &split), Store::key("GET /a", &names, &other));
+
+        // The headers a resource varies on go with its last variant.
+        cache.inner.store.lock().unwrap().remove(&Store::key("GET http://www.rust-lang.org/a", &names, &english));
+        assert_eq!(cache.inner.store.lock().unwrap().vary.len(), 1);
+        cache.inner.store.lock().unwrap().remove(&Store::key("GET http://www.rust-lang.org/a", &names, &french));
+        assert!(cache.inner.store.lock().unwrap().vary.is_empty());
+    }
+
+    #[test]
+    fn test_cache_authorization() {
+        let cache = ResponseCache::new(1024, Duration::from_secs(60));
+        let handler = |policy: CachePolicy| cache.clone().around(Box::new(move |request: &mut Request| {
+            let user = request.headers.get_raw("Authorization").map(|raw| raw.one().unwrap().to_vec()).unwrap_or_default();
+            Ok(Response::new().with_content(user, mime::TEXT_PLAIN).with_cache_policy(policy.clone()))
+        }));
+        let authorized = |path: &str, credentials: &str| {
+            let mut request = request(path);
+            request.headers.set_raw("Authorization", credentials.to_string());
+            request
+        };
+        let content = |mut response: Response| response.buffer_content().unwrap().unwrap().0;
+
+        let private = handler(CachePolicy::new().max_age(Duration::from_secs(60)));
+        assert_eq!(content(private.handle(&mut authorized("/a", "Basic YWxpY2U6MQ==")).unwrap()), b"Basic YWxpY2U6MQ==");
+        assert_eq!(content(private.handle(&mut authorized("/a", "Basic Ym9iOjI=")).unwrap()), b"Basic Ym9iOjI=");
+        assert_eq!(content(private.handle(&mut request("/a")).unwrap()), b"");
+        assert_eq!(cache.stats().hits, 0);
+
+        // The anonymous response isn't replayed to authenticated requests either.
+        assert_eq!(content(private.handle(&mut authorized("/a", "Basic Ym9iOjI=")).unwrap()), b"Basic Ym9iOjI=");
+        assert_eq!(cache.stats().hits, 0);
+
+        let public = handler(CachePolicy::new().public());
+        public.handle(&mut authorized("/b", "Basic YWxpY2U6MQ==")).unwrap();
+        assert_eq!(content(public.handle(&mut authorized("/b", "Basic Ym9iOjI=")).unwrap()), b"Basic YWxpY2U6MQ==");
+        assert_eq!(cache.stats().hits, 1);
+    }
+
+    #[test]
+    fn test_cache_key_host() {
+        let cache = ResponseCache::new(1024, Duration::from_secs(60));
+        let (handler, calls) = counting_handler(&cache, CachePolicy::new());
+        let on_host = |host: &str| {
+            let mut request = request("/");
+            request.uri = Uri::from_str("/").unwrap();
+            request.headers.set_raw("Host", host.to_string());
+            request
+        };
+
+        handler.handle(&mut on_host("a.example.com")).unwrap();
+        handler.handle(&mut on_host("b.example.com")).unwrap();
+        handler.handle(&mut on_host("a.example.com")).unwrap();
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn test_cache_expires() {
+        let cache = ResponseCache::new(1024, Duration::from_secs(60));
+        let handler = |expires: &'static str| cache.clone().around(Box::new(move |_: &mut Request| {
+            let mut response = Response::new().with_content("expiring", mime::TEXT_PLAIN);
+            response.headers.set_raw("Expires", expires);
+            Ok(response)
+        }));
+
+        let expired = handler("Thu, 01 Jan 1970 00:00:00 GMT");
+        expired.handle(&mut request("/a")).unwrap();
+        let invalid = handler("0");
+        invalid.handle(&mut request("/b")).unwrap();
+        assert_eq!(cache.stats().entries, 0);
+
+        let future = handler("Fri, 01 Jan 2100 00:00:00 GMT");
+        future.handle(&mut request("/c")).unwrap();
+        future.handle(&mut request("/c")).unwrap();
+        assert_eq!(cache.stats().hits, 1);
+    }
+
+    #[test]
+    fn test_cache_lru_eviction() {
+        let cache = ResponseCache::new(4, Duration::from_secs(60));
+        let (handler, calls) = counting_handler(&cache, CachePolicy::new());
+
+        handler.handle(&mut request("/a")).unwrap();
+        handler.handle(&mut request("/b")).unwrap();
+        handler.handle(&mut request("/a")).unwrap();
+        handler.handle(&mut request("/c")).unwrap();
+        assert_eq!(calls.load(Ordering::SeqCst), 3);
+
+        // "/b" was the least recently used entry
+        handler.handle(&mut request("/a")).unwrap();
+        handler.handle(&mut request("/b")).unwrap();
+        assert_eq!(calls.load(Ordering::SeqCst), 4);
+        assert_eq!(cache.stats().evictions, 2);
+        let entries = cache.stats().entries;
+        assert_eq!(cache.inner.store.lock().unwrap().vary.len(), entries);
+    }
+}
@@ -0,0 +1,301 @@
+//! Conditional requests support (RFC 7232).
+//!
+//! `ConditionalGet` is an `AfterMiddleware` which tags buffered responses with an
+//! `ETag` and answers `304 Not Modified` or `412 Precondition Failed` according to
+//! the `If-*` headers of the `Request`.
+//!
+//! The middleware runs after the `Handler`, so it is only able to answer requests
+//! with safe methods. Handlers of unsafe methods (e.g. `PUT`) should call
+//! `evaluate` with the current validators of the resource *before* applying any
+//! change, in order to protect against lost updates:
+//!
+//! ```rust
+//! use ferrum::*;
+//! use ferrum::header::EntityTag;
+//! use ferrum::conditional::{evaluate, Precondition};
+//!
+//! fn update(request: &mut Request) -> FerrumResult<Response> {
+//!     let current = EntityTag::strong("v1".to_string());
+//!     if let Precondition::Failed = evaluate(request, Some(&current), None) {
+//!         return Ok(Response::new().with_status(StatusCode::PreconditionFailed));
+//!     }
+//!     // Apply the update...
+//!     Ok(Response::new().with_status(StatusCode::NoContent))
+//! }
+//! ```
+
+use std::time::SystemTime;
+
+use hyper::header::{ContentLength, ContentType, EntityTag, ETag, IfMatch, IfModifiedSince, IfNoneMatch,
+                    IfUnmodifiedSince, LastModified};
+use sha1_smol::Sha1;
+
+use {Request, Response, FerrumResult, FerrumError, Method, StatusCode};
+use middleware::AfterMiddleware;
+use error::HyperResult;
+
+/// The kind of `ETag` generated by `ConditionalGet` for responses without one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ETagMode {
+    /// Generate strong entity tags, usable for range requests and `If-Match`.
+    Strong,
+    /// Generate weak entity tags, only usable for cache validation.
+    Weak,
+    /// Never generate entity tags, only use the ones set by the `Handler`.
+    Disabled,
+}
+
+/// The outcome of evaluating the preconditions of a `Request`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Precondition {
+    /// All preconditions hold (or there are none), the request should proceed.
+    Passed,
+    /// The client's cached representation is current, answer `304 Not Modified`.
+    NotModified,
+    /// A precondition does not hold, answer `412 Precondition Failed`.
+    Failed,
+}
+
+/// Evaluate the `If-Match`, `If-Unmodified-Since`, `If-None-Match` and
+/// `If-Modified-Since` headers of the `request` against the current validators
+/// of the selected representation, in the order defined by RFC 7232, section 6.
+///
+/// An `etag` of `None` stands for no current representation, which fails
+/// `If-Match: *`, so that a `PUT` meant to update can't create the resource.
+pub fn evaluate(request: &Request, etag: Option<&EntityTag>, last_modified: Option<SystemTime>) -> Precondition {
+    let safe = request.method == Method::Get || request.method == Method::Head;
+
+    if let Some(if_match) = request.headers.get::<IfMatch>() {
+        let matched = match *if_match {
+            IfMatch::Any => etag.is_some(),
+            IfMatch::Items(ref tags) => match etag {
+                Some(etag) => tags.iter().any(|tag| tag.strong_eq(etag)),
+                None => false
+            }
+        };
+        if !matched {
+            return Precondition::Failed;
+        }
+    } else if let Some(&IfUnmodifiedSince(date)) = request.headers.get::<IfUnmodifiedSince>() {
+        if let Some(last_modified) = last_modified {
+            if truncate(last_modified) > SystemTime::from(date) {
+                return Precondition::Failed;
+            }
+        }
+    }
+
+    if let Some(if_none_match) = request.headers.get::<IfNoneMatch>() {
+        let matched = match *if_none_match {
+            IfNoneMatch::Any => true,
+            IfNoneMatch::Items(ref tags) => match etag {
+                Some(etag) => tags.iter().any(|tag| tag.weak_eq(etag)),
+                None => false
+            }
+        };
+        if matched {
+            return if safe { Precondition::NotModified } else { Precondition::Failed };
+        }
+    } else if let Some(&IfModifiedSince(date)) = request.headers.get::<IfModifiedSince>() {
+        if let Some(last_modified) = last_modified {
+            if safe && truncate(last_modified) <= SystemTime::from(date) {
+                return Precondition::NotModified;
+            }
+        }
+    }
+
+    Precondition::Passed
+}
+
+/// Compute an entity tag for the given representation bytes.
+///
+/// The tag is the length and the SHA-1 digest of the bytes, so it is the same
+/// across processes and releases.
+pub fn entity_tag(content: &[u8], weak: bool) -> EntityTag {
+    EntityTag::new(weak, format!("{:x}-{}", content.len(), Sha1::from(content).digest()))
+}
+
+// HTTP dates have a one second resolution.
+fn truncate(time: SystemTime) -> SystemTime {
+    SystemTime::from(::hyper::header::HttpDate::from(time))
+}
+
+/// An `AfterMiddleware` answering conditional `GET` and `HEAD` requests.
+///
+/// Successful buffered responses without an `ETag` get one computed from their
+/// body, according to `mode`. Responses with an `ETag` or `Last-Modified` set by
+/// the `Handler` are validated as is.
+#[derive(Debug, Clone)]
+pub struct ConditionalGet {
+    /// How to generate entity tags for responses without one.
+    ///
+    /// The default is `ETagMode::Strong`.
+    pub mode: ETagMode,
+}
+
+impl ConditionalGet {
+    /// Create a `ConditionalGet` generating strong entity tags.
+    pub fn new() -> ConditionalGet {
+        ConditionalGet {
+            mode: ETagMode::Strong
+        }
+    }
+
+    /// Create a `ConditionalGet` generating weak entity tags.
+    pub fn weak() -> ConditionalGet {
+        ConditionalGet {
+            mode: ETagMode::Weak
+        }
+    }
+
+    fn tag(&self, response: &mut Response) -> HyperResult<()> {
+        let weak = match self.mode {
+            ETagMode::Strong => false,
+            ETagMode::Weak => true,
+            ETagMode::Disabled => return Ok(())
+        };
+        if response.headers.has::<ETag>() || !response.is_buffered() {
+            return Ok(());
+        }
+
+        if let Some(content) = response.buffer_content()? {
+            response.headers.set(ETag(entity_tag(&content, weak)));
+        }
+        Ok(())
+    }
+}
+
+impl Default for ConditionalGet {
+    fn default() -> ConditionalGet {
+        ConditionalGet::new()
+    }
+}
+
+impl AfterMiddleware for ConditionalGet {
+    fn after(&self, request: &mut Request, mut response: Response) -> FerrumResult<Response> {
+        let safe = request.method == Method::Get || request.method == Method::Head;
+        if !safe || !response.status.is_success() {
+            return Ok(response);
+        }
+
+        self.tag(&mut response).map_err(|err| FerrumError::new(err, None))?;
+
+        let last_modified = response.headers.get::<LastModified>()
+            .map(|&LastModified(date)| SystemTime::from(date));
+        let precondition = evaluate(request, response.headers.get::<ETag>().map(|etag| &etag.0), last_modified);
+
+        match precondition {
+            Precondition::Passed => {},
+            Precondition::NotModified => {
+                response.status = StatusCode::NotModified;
+                response.body = None;
+                response.headers.remove::<ContentLength>();
+                response.headers.remove::<ContentType>();
+            },
+            Precondition::Failed => {
+                response.status = StatusCode::PreconditionFailed;
+                response.body = None;
+                response.headers.set(ContentLength(0));
+                response.headers.remove::<ContentType>();
+            }
+        }
+        Ok(response)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::time::Duration;
+    use hyper::header::HttpDate;
+    use mime;
+
+    fn content_response() -> Response {
+        Response::new().with_content("Hello world!", mime::TEXT_PLAIN)
+    }
+
+    #[test]
+    fn test_etag_is_generated() {
+        let mut request = Request::stub();
+        let response = ConditionalGet::new().after(&mut request, content_response()).unwrap();
+
+        let etag = &response.headers.get::<ETag>().unwrap().0;
+        assert!(!etag.weak);
+        assert_eq!(etag, &entity_tag(b"Hello world!", false));
+        assert_eq!(etag.tag(), "c-d3486ae9136e7856bc42212385ea797094475802");
+        assert_eq!(response.status, StatusCode::Ok);
+        assert!(response.body.is_some());
+
+        let response = ConditionalGet::weak().after(&mut request, content_response()).unwrap();
+        assert!(response.headers.get::<ETag>().unwrap().0.weak);
+    }
+
+    #[test]
+    fn test_if_none_match() {
+        let mut request = Request::stub();
+        request.headers.set(IfNoneMatch::Items(vec![entity_tag(b"Hello world!", true)]));
+        let response = ConditionalGet::new().after(&mut request, content_response()).unwrap();
+
+        assert_eq!(response.status, StatusCode::NotModified);
+        assert!(response.body.is_none());
+        assert!(response.headers.has::<ETag>());
+
+        request.headers.set(IfNoneMatch::Items(vec![EntityTag::strong("other".to_string())]));
+        let response = ConditionalGet::new().after(&mut request, content_response()).unwrap();
+        assert_eq!(response.status, StatusCode::Ok);
+    }
+
+    #[test]
+    fn test_if_modified_since() {
+        let modified = SystemTime::now() - Duration::from_secs(3600);
+        let mut request = Request::stub();
+        request.headers.set(IfModifiedSince(HttpDate::from(SystemTime::now())));
+        let handler_response = || content_response().with_header(LastModified(HttpDate::from(modified)));
+
+        let response = ConditionalGet::new().after(&mut request, handler_response()).unwrap();
+        assert_eq!(response.status, StatusCode::NotModified);
+
+        request.headers.set(IfModifiedSince(HttpDate::from(modified - Duration::from_secs(60))));
+        let response = ConditionalGet::new().after(&mut request, handler_response()).unwrap();
+        assert_eq!(response.status, StatusCode::Ok);
+    }
+
+    #[test]
+    fn test_if_match() {
+        let mut request = Request::stub();
+        request.headers.set(IfMatch::Items(vec![EntityTag::strong("other".to_string())]));
+        let response = ConditionalGet::new().after(&mut request, content_response()).unwrap();
+        assert_eq!(response.status, StatusCode::PreconditionFailed);
+
+        let current = EntityTag::strong("v1".to_string());
+        request.method = Method::Put;
+        request.headers.set(IfMatch::Items(vec![EntityTag::weak("v1".to_string())]));
+        assert_eq!(evaluate(&request, Some(&current), None), Precondition::Failed);
+
+        request.headers.set(IfMatch::Items(vec![current.clone()]));
+        assert_eq!(evaluate(&request, Some(&current), None), Precondition::Passed);
+
+        request.headers.set(IfMatch::Any);
+        assert_eq!(evaluate(&request, Some(&current), None), Precondition::Passed);
+        assert_eq!(evaluate(&request, None, None), Precondition::Failed);
+    }
+
+    #[test]
+    fn test_if_unmodified_since() {
+        let mut request = Request::stub();
+        request.method = Method::Put;
+        let modified = SystemTime::now();
+        request.headers.set(IfUnmodifiedSince(HttpDate::from(modified - Duration::from_secs(60))));
+        assert_eq!(evaluate(&request, None, Some(modified)), Precondition::Failed);
+
+        request.headers.set(IfUnmodifiedSince(HttpDate::from(modified)));
+        assert_eq!(evaluate(&request, None, Some(modified)), Precondition::Passed);
+    }
+
+    #[test]
+    fn test_if_none_match_unsafe_method() {
+        let mut request = Request::stub();
+        request.method = Method::Put;
+        request.headers.set(IfNoneMatch::Any);
+        assert_eq!(evaluate(&request, Some(&EntityTag::strong("v1".to_string())), None), Precondition::Failed);
+    }
+}
@@ -0,0 +1,318 @@
+//! Accepting and serving the connections of a `Ferrum` server.
+
+use std::io::Error;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+
+use futures::{future, task, Async, Future, Poll, Stream};
+use futures::future::Shared;
+use futures::sync::oneshot;
+use futures::task::Task;
+use hyper::{Chunk, Error as HyperError, HttpVersion};
+use hyper::server::Http;
+use tokio_io::io::shutdown;
+use tokio_core::net::TcpListener;
+use tokio_core::reactor::{Handle, Timeout};
+use tokio_io::{AsyncRead, AsyncWrite};
+#[cfg(unix)]
+use tokio_uds::UnixListener;
+
+use http2::{self, Http2Settings, Preface};
+use limits::{self, ConnectionLimits, LimitedIo};
+use listener::{Listener, Transport};
+use middleware::Handler;
+use rewind::Rewind;
+use service::InitialService;
+use websocket;
+#[cfg(feature = "tls")]
+use http2::ALPN_H2;
+#[cfg(feature = "tls")]
+use request::Scheme;
+#[cfg(feature = "tls")]
+use tls::TlsStream;
+#[cfg(unix)]
+use unix;
+
+pub type Service = InitialService<Box<dyn Handler>>;
+
+type Connection = Box<dyn Future<Item = (), Error = ()>>;
+
+/// A future accepting the connections of a listener.
+///
+/// Failing to accept or to set up a connection is logged and skips that
+/// connection, the future does not resolve while the listener is open.
+pub type Connections = Box<dyn Future<Item = (), Error = Error>>;
+
+/// Resolves once the server stopped accepting connections and waits for the
+/// open ones to finish.
+pub type Draining = Shared<oneshot::Receiver<()>>;
+
+/// What the connections of all the endpoints of a server share.
+#[derive(Clone)]
+pub struct ConnectionContext {
+    pub handle: Handle,
+    pub protocol: Http<Chunk>,
+    pub http2: Option<Http2Settings>,
+    pub limits: ConnectionLimits,
+    pub active: Arc<ActiveConnections>,
+    pub draining: Draining,
+}
+
+impl ConnectionContext {
+    fn serve_http1<S>(&self, io: S, mut service: Service) -> Connection
+        where S: AsyncRead + AsyncWrite + 'static
+    {
+        let slot = Arc::new(Mutex::new(None));
+        service.upgrade = Some(slot.clone());
+        let io = LimitedIo::new(io, self.limits.clone(), &self.handle);
+        let mut connection = Some(self.protocol.serve_connection(io, service));
+        let served = future::poll_fn(move || {
+            if connection.as_mut().expect("polled after completion").poll_without_shutdown()?.is_not_ready() {
+                return Ok(Async::NotReady);
+            }
+            Ok(Async::Ready(connection.take().expect("polled after completion").into_parts()))
+        });
+
+        let handle = self.handle.clone();
+        Box::new(served.map_err(|_: HyperError| ()).and_then(move |parts| -> Connection {
+            // A WebSocket upgrade continues on the same transport, starting
+            // with what the client sent after the request.
+            match slot.lock().unwrap().take() {
+                Some(upgrade) => websocket::serve(Rewind::new(parts.io, parts.read_buf.to_vec()), upgrade, &handle),
+                None => Box::new(shutdown(parts.io).map(|_| ()).map_err(|_| ()))
+            }
+        }))
+    }
+
+    fn serve_http2<S>(&self, io: S, service: Service, version: HttpVersion) -> Connection
+        where S: AsyncRead + AsyncWrite + 'static
+    {
+        let settings = self.http2.clone().unwrap_or_default();
+        http2::serve(io, service, version, &settings, &self.limits, self.draining.clone(), &self.handle)
+    }
+
+    fn spawn(&self, connection: Connection) {
+        let active = ActiveConnection::new(&self.active);
+        self.handle.spawn(connection.then(move |_| {
+            drop(active);
+            Ok(())
+        }));
+    }
+}
+
+/// The open connections of a server, shared by all its endpoints.
+#[derive(Default)]
+pub struct ActiveConnections {
+    count: AtomicUsize,
+    // The listeners waiting for a connection to close.
+    waiting: Mutex<Vec<Task>>,
+}
+
+impl ActiveConnections {
+    pub fn count(&self) -> usize {
+        self.count.load(Ordering::SeqCst)
+    }
+
+    fn release(&self) {
+        self.count.fetch_sub(1, Ordering::SeqCst);
+        for task in self.waiting.lock().unwrap().drain(..) {
+            task.notify();
+        }
+    }
+}
+
+// Counts a connection as active until dropped.
+struct ActiveConnection(Arc<ActiveConnections>);
+
+impl ActiveConnection {
+    fn new(active: &Arc<ActiveConnections>) -> ActiveConnection {
+        active.count.fetch_add(1, Ordering::SeqCst);
+        ActiveConnection(active.clone())
+    }
+}
+
+impl Drop for ActiveConnection {
+    fn drop(&mut self) {
+        self.0.release();
+    }
+}
+
+// Stops accepting connections while the server has `max` open connections,
+// leaving the new ones in the backlog of the listener.
+struct AcceptLimit<I> {
+    incoming: I,
+    active: Arc<ActiveConnections>,
+    max: usize,
+}
+
+impl<I: Stream> Stream for AcceptLimit<I> {
+    type Item = I::Item;
+    type Error = I::Error;
+
+    fn poll(&mut self) -> Poll<Option<I::Item>, I::Error> {
+        if self.active.count() >= self.max {
+            self.active.waiting.lock().unwrap().push(task::current());
+            // A connection may have closed in between.
+            if self.active.count() >= self.max {
+                return Ok(Async::NotReady);
+            }
+        }
+        self.incoming.poll()
+    }
+}
+
+// How long to pause accepting after an error, which is likely to repeat
+// right away, like running out of file descriptors.
+const ACCEPT_ERROR_PAUSE: Duration = Duration::from_millis(10);
+
+// Logs and skips the errors of accepting connections, pausing for a while
+// after each one.
+struct SkipErrors<I> {
+    incoming: I,
+    handle: Handle,
+    pause: Option<Timeout>,
+}
+
+impl<I: Stream<Error = Error>> Stream for SkipErrors<I> {
+    type Item = I::Item;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Option<I::Item>, Error> {
+        loop {
+            if let Some(mut pause) = self.pause.take() {
+                if pause.poll()?.is_not_ready() {
+                    self.pause = Some(pause);
+                    return Ok(Async::NotReady);
+                }
+            }
+            match self.incoming.poll() {
+                Err(err) => {
+                    warn!("Failed to accept a connection: {}", err);
+                    self.pause = Some(Timeout::new(ACCEPT_ERROR_PAUSE, &self.handle)?);
+                },
+                result => return result
+            }
+        }
+    }
+}
+
+/// Accept the connections of `listener` on the event loop of the server.
+pub fn accept(
+    context: &ConnectionContext,
+    listener: Listener,
+    transport: Transport,
+    mut service: Service
+) -> Result<Connections, Error> {
+    let handle = &context.handle;
+    match listener {
+        Listener::Tcp(listener) => {
+            let local_addr = listener.local_addr()?;
+            let listener = TcpListener::from_listener(listener, &local_addr, handle)?;
+            service.local_addr = Some(local_addr);
+
+            let incoming = listener.incoming().map(move |(stream, remote_addr)| {
+                let mut service = service.clone();
+                service.remote_addr = Some(remote_addr);
+                (stream, service)
+            });
+            connections(context, incoming, transport)
+        },
+        #[cfg(unix)]
+        Listener::Unix(listener) => {
+            let listener = UnixListener::from_std(listener, handle.new_tokio_handle())?;
+
+            let incoming = listener.incoming().map(move |stream| {
+                let mut service = service.clone();
+                service.peer_credentials = unix::peer_credentials(&stream).ok();
+                (stream, service)
+            });
+            connections(context, incoming, transport)
+        }
+    }
+}
+
+// Serve each connection of `incoming` with its own service.
+fn connections<I, S>(context: &ConnectionContext, incoming: I, transport: Transport) -> Result<Connections, Error>
+    where I: Stream<Item = (S, Service), Error = Error> + 'static,
+          S: AsyncRead + AsyncWrite + 'static
+{
+    let context = context.clone();
+    let incoming = AcceptLimit {
+        incoming: SkipErrors { incoming, handle: context.handle.clone(), pause: None },
+        active: context.active.clone(),
+        max: context.limits.max_connections.unwrap_or(usize::MAX),
+    };
+    match transport {
+        Transport::Plain => Ok(Box::new(incoming.for_each(move |(stream, service)| {
+            let connection = match context.http2 {
+                // Clients with prior knowledge start with the HTTP/2 preface.
+                Some(_) => {
+                    let preface = Preface::new(stream);
+                    let preface = limits::with_deadline(preface, context.limits.header_read_timeout, &context.handle);
+                    let context = context.clone();
+                    Box::new(preface.and_then(move |(io, is_http2)| {
+                        if is_http2 {
+                            context.serve_http2(io, service, HttpVersion::H2c)
+                        } else {
+                            context.serve_http1(io, service)
+                        }
+                    }))
+                },
+                None => context.serve_http1(stream, service)
+            };
+            context.spawn(connection);
+            Ok(())
+        }))),
+        #[cfg(feature = "tls")]
+        Transport::Tls(config) => {
+            let mut protocols = config.alpn_protocols().to_vec();
+            match context.http2 {
+                Some(_) if !protocols.iter().any(|protocol| protocol == ALPN_H2) => protocols.insert(0, ALPN_H2.to_vec()),
+                Some(_) => {},
+                None => protocols.retain(|protocol| protocol != ALPN_H2)
+            }
+            let config = config.with_alpn_protocols(protocols).server_config()?;
+
+            Ok(Box::new(incoming.for_each(move |(stream, mut service)| {
+                service.scheme = Scheme::Https;
+                let stream = match TlsStream::new(stream, config.clone()) {
+                    Ok(stream) => stream,
+                    Err(err) => {
+                        warn!("Failed to set up a TLS connection: {}", err);
+                        return Ok(());
+                    }
+                };
+                let handshake = stream.handshake();
+                let handshake = limits::with_deadline(handshake, context.limits.header_read_timeout, &context.handle);
+                let serving = context.clone();
+                let connection = handshake.and_then(move |stream| {
+                    if stream.session().alpn_protocol() == Some(ALPN_H2) {
+                        serving.serve_http2(stream, service, HttpVersion::H2)
+                    } else {
+                        serving.serve_http1(stream, service)
+                    }
+                });
+                context.spawn(Box::new(connection));
+                Ok(())
+            })))
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::ErrorKind;
+
+    use futures::stream;
+    use tokio_core::reactor::Core;
+
+    #[test]
+    fn test_skip_accept_errors() {
+        let mut core = Core::new().unwrap();
+        let incoming = stream::iter_result(vec![Ok(1), Err(Error::from(ErrorKind::Other)), Err(Error::from(ErrorKind::Other)), Ok(2)]);
+        let incoming = SkipErrors { incoming, handle: core.handle(), pause: None };
+        assert_eq!(core.run(incoming.collect()).unwrap(), vec![1, 2]);
+    }
+}
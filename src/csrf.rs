@@ -0,0 +1,437 @@
+//! Protection against cross-site request forgery.
+//!
+//! `Csrf` is a `BeforeMiddleware` implementing the double-submit cookie
+//! pattern. Each client gets a random token in a cookie, which is also the
+//! `CsrfToken` of its requests, for the handlers to embed in their forms and
+//! pages. The requests with an unsafe method, `POST`, `PUT`, `PATCH` or
+//! `DELETE`, must submit the token of their cookie, in a header or in a field
+//! of their urlencoded form, which other sites can't read. Their `Origin`, or
+//! else their `Referer`, must also be the origin of the request, as given by
+//! `Request::url`, or a trusted origin. Failing requests are rejected with
+//! `403 Forbidden`, and forms larger than the maximum form size with
+//! `413 Payload Too Large`.
+//!
+//! `CsrfCookie` is the `AfterMiddleware` sending the cookie of new tokens:
+//!
+//! ```rust
+//! use ferrum::*;
+//! use ferrum::csrf::{Csrf, CsrfToken};
+//!
+//! fn form(request: &mut Request) -> FerrumResult<Response> {
+//!     let token = request.extensions.get::<CsrfToken>().cloned().unwrap_or_default();
+//!     let html = format!("<form method=\"post\"><input type=\"hidden\" name=\"csrf_token\" value=\"{}\"></form>", token);
+//!     Ok(Response::new().with_content(html, mime::TEXT_HTML))
+//! }
+//!
+//! let mut chain = Chain::new(form);
+//! chain.link(Csrf::new().with_trusted_origin("https://admin.example.com").both());
+//! ```
+
+use std::error::Error;
+use std::fmt;
+
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD as BASE64;
+use futures::Stream;
+use hyper::Body;
+use hyper::header::{ContentLength, ContentType, Cookie, SetCookie};
+use url::{form_urlencoded, Url};
+
+use {mime, Request, Response, FerrumResult, FerrumError, Method, StatusCode};
+use auth::constant_time_eq;
+use middleware::{BeforeMiddleware, AfterMiddleware};
+use random;
+use request::Scheme;
+use typemap::Key;
+
+// The length of the tokens, before encoding.
+const TOKEN_LEN: usize = 32;
+
+// The largest form read for its token by default.
+const MAX_FORM_SIZE: usize = 64 << 10;
+
+/// The CSRF token of a request, in its extensions.
+pub struct CsrfToken;
+
+impl Key for CsrfToken {
+    type Value = String;
+}
+
+// The `Set-Cookie` of a new token.
+struct IssuedCookie;
+
+impl Key for IssuedCookie {
+    type Value = String;
+}
+
+/// The reason a request was rejected by `Csrf`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CsrfError {
+    /// The request has no token cookie.
+    MissingCookie,
+    /// The request submitted no token.
+    MissingToken,
+    /// The submitted token is not the one of the cookie.
+    InvalidToken,
+    /// The request comes from another origin, given by its `Origin` or
+    /// `Referer`.
+    CrossOrigin(String),
+    /// The form submitting the token is larger than the maximum form size.
+    FormTooLarge,
+}
+
+impl fmt::Display for CsrfError {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            CsrfError::MissingCookie => formatter.write_str("No CSRF cookie in the request"),
+            CsrfError::MissingToken => formatter.write_str("No CSRF token in the request"),
+            CsrfError::InvalidToken => formatter.write_str("Invalid CSRF token"),
+            CsrfError::CrossOrigin(ref origin) => write!(formatter, "Cross-origin request from {}", origin),
+            CsrfError::FormTooLarge => formatter.write_str("CSRF form too large"),
+        }
+    }
+}
+
+impl Error for CsrfError {}
+
+/// A `BeforeMiddleware` protecting against cross-site request forgery, see
+/// the `csrf` module.
+#[derive(Debug, Clone)]
+pub struct Csrf {
+    cookie_name: String,
+    header_name: String,
+    field_name: String,
+    trusted_origins: Vec<String>,
+    max_form_size: usize,
+}
+
+impl Csrf {
+    /// Create a `Csrf` with the `csrf_token` cookie and form field, and the
+    /// `X-CSRF-Token` header, reading forms of up to 64KiB.
+    pub fn new() -> Csrf {
+        Csrf {
+            cookie_name: "csrf_token".to_string(),
+            header_name: "X-CSRF-Token".to_string(),
+            field_name: "csrf_token".to_string(),
+            trusted_origins: Vec::new(),
+            max_form_size: MAX_FORM_SIZE,
+        }
+    }
+
+    /// Set the name of the cookie holding the token.
+    pub fn with_cookie_name<S: Into<String>>(mut self, name: S) -> Self {
+        self.cookie_name = name.into();
+        self
+    }
+
+    /// Set the name of the header submitting the token, used by scripts.
+    pub fn with_header_name<S: Into<String>>(mut self, name: S) -> Self {
+        self.header_name = name.into();
+        self
+    }
+
+    /// Set the name of the form field submitting the token.
+    pub fn with_field_name<S: Into<String>>(mut self, name: S) -> Self {
+        self.field_name = name.into();
+        self
+    }
+
+    /// Set the maximum size of the forms read for their token, in bytes.
+    pub fn with_max_form_size(mut self, size: usize) -> Self {
+        self.max_form_size = size;
+        self
+    }
+
+    /// Accept the unsafe requests from `origin`, e.g.
+    /// `https://admin.example.com`, besides the ones of the same origin.
+    pub fn with_trusted_origin(mut self, origin: &str) -> Self {
+        if let Ok(url) = Url::parse(origin) {
+            self.trusted_origins.push(url.origin().ascii_serialization());
+        }
+        self
+    }
+
+    /// Get both this `Csrf` and `CsrfCookie`, to link them at once.
+    pub fn both(self) -> (Csrf, CsrfCookie) {
+        (self, CsrfCookie)
+    }
+
+    // The token of the cookie of the request, if well-formed.
+    fn cookie_token(&self, request: &Request) -> Option<String> {
+        let cookie = request.headers.get::<Cookie>()?;
+        let token = cookie.get(&self.cookie_name)?;
+        match BASE64.decode(token) {
+            Ok(ref bytes) if bytes.len() == TOKEN_LEN => Some(token.to_string()),
+            _ => None
+        }
+    }
+
+    // The token submitted in the header, or else in the form.
+    fn submitted_token(&self, request: &mut Request) -> Result<Option<String>, CsrfError> {
+        let header = request.headers.get_raw(&self.header_name)
+            .and_then(|raw| raw.one())
+            .map(|value| String::from_utf8_lossy(value).trim().to_string());
+        if header.is_some() {
+            return Ok(header);
+        }
+
+        let form = match request.headers.get::<ContentType>() {
+            Some(ContentType(mime)) => mime.type_() == mime::APPLICATION && mime.subtype() == mime::WWW_FORM_URLENCODED,
+            None => false
+        };
+        if !form {
+            return Ok(None);
+        }
+        if request.headers.get::<ContentLength>().is_some_and(|length| length.0 > self.max_form_size as u64) {
+            return Err(CsrfError::FormTooLarge);
+        }
+        // The body is read, then put back for the handler.
+        let mut body = Vec::new();
+        for chunk in request.take_body().wait() {
+            let chunk = match chunk {
+                Ok(chunk) => chunk,
+                Err(_) => return Ok(None)
+            };
+            if body.len() + chunk.len() > self.max_form_size {
+                return Err(CsrfError::FormTooLarge);
+            }
+            body.extend_from_slice(&chunk);
+        }
+        let token = form_urlencoded::parse(&body)
+            .find(|(name, _)| *name == self.field_name)
+            .map(|(_, value)| value.into_owned());
+        request.body = Some(Body::from(body));
+        Ok(token)
+    }
+
+    // Check the `Origin`, or else the `Referer`, of an unsafe request.
+    fn check_origin(&self, request: &Request) -> Result<(), CsrfError> {
+        let source = request.headers.get_raw("Origin")
+            .or_else(|| request.headers.get_raw("Referer"))
+            .and_then(|raw| raw.one())
+            .map(|value| String::from_utf8_lossy(value).trim().to_string());
+        let source = match source {
+            Some(source) => source,
+            // Without these headers, the token is the only check.
+            None => return Ok(())
+        };
+
+        let origin = match Url::parse(&source) {
+            Ok(url) => url.origin().ascii_serialization(),
+            Err(_) => return Err(CsrfError::CrossOrigin(source))
+        };
+        let own = request.url().ok().map(|url| url.origin().ascii_serialization());
+        if own.as_ref() == Some(&origin) || self.trusted_origins.contains(&origin) {
+            Ok(())
+        } else {
+            Err(CsrfError::CrossOrigin(origin))
+        }
+    }
+
+    fn check(&self, request: &mut Request, cookie_token: Option<&String>) -> Result<(), CsrfError> {
+        self.check_origin(request)?;
+        let cookie_token = cookie_token.ok_or(CsrfError::MissingCookie)?;
+        let submitted = self.submitted_token(request)?.ok_or(CsrfError::MissingToken)?;
+        if constant_time_eq(submitted.as_bytes(), cookie_token.as_bytes()) {
+            Ok(())
+        } else {
+            Err(CsrfError::InvalidToken)
+        }
+    }
+
+    fn issue(&self, request: &mut Request) -> String {
+        let mut bytes = [0; TOKEN_LEN];
+        random::fill(&mut bytes);
+        let token = BASE64.encode(bytes);
+
+        let https = request.url()
+            .map(|url| url.scheme() == "https")
+            .unwrap_or(request.scheme == Scheme::Https);
+        let mut cookie = format!("{}={}; Path=/; SameSite=Lax; HttpOnly", self.cookie_name, token);
+        if https {
+            cookie.push_str("; Secure");
+        }
+        request.extensions.insert::<IssuedCookie>(cookie);
+        token
+    }
+}
+
+impl Default for Csrf {
+    fn default() -> Csrf {
+        Csrf::new()
+    }
+}
+
+impl BeforeMiddleware for Csrf {
+    fn before(&self, request: &mut Request) -> FerrumResult<()> {
+        let cookie_token = self.cookie_token(request);
+        let token = match cookie_token {
+            Some(ref token) => token.clone(),
+            None => self.issue(request)
+        };
+        request.extensions.insert::<CsrfToken>(token);
+
+        let unsafe_method = matches!(request.method, Method::Post | Method::Put | Method::Patch | Method::Delete);
+        if !unsafe_method {
+            return Ok(());
+        }
+        self.check(request, cookie_token.as_ref()).map_err(|err| {
+            let status = match err {
+                CsrfError::FormTooLarge => StatusCode::PayloadTooLarge,
+                _ => StatusCode::Forbidden
+            };
+            FerrumError::new(err, Some(Response::new().with_status(status)))
+        })
+    }
+}
+
+/// An `AfterMiddleware` sending the cookie of the tokens issued by a `Csrf`,
+/// including with error responses.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CsrfCookie;
+
+impl AfterMiddleware for CsrfCookie {
+    fn after(&self, request: &mut Request, mut response: Response) -> FerrumResult<Response> {
+        if let Some(cookie) = request.extensions.get::<IssuedCookie>() {
+            append_cookie(&mut response, cookie.clone());
+        }
+        Ok(response)
+    }
+
+    fn catch(&self, request: &mut Request, mut error: FerrumError) -> FerrumResult<Response> {
+        if let (Some(cookie), Some(ref mut response)) = (request.extensions.get::<IssuedCookie>(), error.response.as_mut()) {
+            append_cookie(response, cookie.clone());
+        }
+        Err(error)
+    }
+}
+
+fn append_cookie(response: &mut Response, cookie: String) {
+    let mut cookies = response.headers.get::<SetCookie>().cloned().unwrap_or_else(|| SetCookie(Vec::new()));
+    cookies.0.push(cookie);
+    response.headers.set(cookies);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use futures::Future;
+
+    use {Chain, Handler};
+
+    fn chain(csrf: Csrf) -> Chain {
+        let mut chain = Chain::new(|request: &mut Request| {
+            let body = request.take_body().concat2().wait().unwrap().to_vec();
+            let content = format!("{} {}", request.extensions.get::<CsrfToken>().unwrap(), String::from_utf8(body).unwrap());
+            Ok(Response::new().with_content(content, mime::TEXT_PLAIN))
+        });
+        chain.link(csrf.both());
+        chain
+    }
+
+    fn request(method: Method, cookie: Option<&str>) -> Request {
+        let mut request = Request::stub();
+        request.method = method;
+        request.uri = "/form".parse().unwrap();
+        request.headers.set_raw("Host", "example.com");
+        if let Some(cookie) = cookie {
+            request.headers.set_raw("Cookie", format!("theme=dark; csrf_token={}", cookie));
+        }
+        request
+    }
+
+    fn forbidden(chain: &Chain, request: &mut Request) -> String {
+        let error = chain.handle(request).unwrap_err();
+        assert_eq!(error.response.unwrap().status, StatusCode::Forbidden);
+        error.error.to_string()
+    }
+
+    // Get a token along with its cookie.
+    fn token(chain: &Chain) -> String {
+        let response = chain.handle(&mut request(Method::Get, None)).unwrap();
+        let cookie = &response.headers.get::<SetCookie>().unwrap().0[0];
+        assert!(cookie.ends_with("; Path=/; SameSite=Lax; HttpOnly"), "{}", cookie);
+        cookie["csrf_token=".len()..cookie.find(';').unwrap()].to_string()
+    }
+
+    #[test]
+    fn test_issue_token() {
+        let chain = chain(Csrf::new());
+        let token = token(&chain);
+        assert_eq!(BASE64.decode(&token).unwrap().len(), TOKEN_LEN);
+        assert_ne!(token, self::token(&chain));
+
+        // The token of the cookie is kept.
+        let mut response = chain.handle(&mut request(Method::Get, Some(&token))).unwrap();
+        assert!(response.headers.get::<SetCookie>().is_none());
+        let content = response.buffer_content().unwrap().unwrap();
+        assert_eq!(String::from_utf8(content.0).unwrap(), format!("{} ", token));
+
+        // Malformed cookies are replaced.
+        let response = chain.handle(&mut request(Method::Get, Some("forged"))).unwrap();
+        assert!(response.headers.get::<SetCookie>().is_some());
+    }
+
+    #[test]
+    fn test_submitted_token() {
+        let chain = chain(Csrf::new());
+        let token = token(&chain);
+
+        let mut with_header = request(Method::Delete, Some(&token));
+        with_header.headers.set_raw("X-CSRF-Token", token.clone());
+        assert!(chain.handle(&mut with_header).is_ok());
+
+        let mut with_form = request(Method::Post, Some(&token));
+        with_form.headers.set(ContentType::form_url_encoded());
+        let form = format!("name=ferrum&csrf_token={}", token);
+        with_form.body = Some(Body::from(form.clone()));
+        let mut response = chain.handle(&mut with_form).unwrap();
+        // The handler still gets the form.
+        let content = response.buffer_content().unwrap().unwrap();
+        assert_eq!(String::from_utf8(content.0).unwrap(), format!("{} {}", token, form));
+
+        // Forms over the maximum size are not read.
+        let chain = self::chain(Csrf::new().with_max_form_size(16));
+        let mut too_large = request(Method::Post, Some(&token));
+        too_large.headers.set(ContentType::form_url_encoded());
+        too_large.body = Some(Body::from(form));
+        let error = chain.handle(&mut too_large).unwrap_err();
+        assert_eq!(error.response.unwrap().status, StatusCode::PayloadTooLarge);
+        assert_eq!(error.error.to_string(), "CSRF form too large");
+
+        let mut invalid = request(Method::Put, Some(&token));
+        invalid.headers.set_raw("X-CSRF-Token", self::token(&chain));
+        assert_eq!(forbidden(&chain, &mut invalid), "Invalid CSRF token");
+
+        assert_eq!(forbidden(&chain, &mut request(Method::Patch, Some(&token))), "No CSRF token in the request");
+
+        // A rejected client without cookie gets one.
+        let mut without_cookie = request(Method::Post, None);
+        without_cookie.headers.set_raw("X-CSRF-Token", token);
+        let error = chain.handle(&mut without_cookie).unwrap_err();
+        assert_eq!(error.error.to_string(), "No CSRF cookie in the request");
+        assert!(error.response.unwrap().headers.get::<SetCookie>().is_some());
+    }
+
+    #[test]
+    fn test_check_origin() {
+        let chain = chain(Csrf::new().with_trusted_origin("https://admin.example.com/"));
+        let token = token(&chain);
+        let post = |header: &str, value: &str| {
+            let mut request = request(Method::Post, Some(&token));
+            request.headers.set_raw("X-CSRF-Token", token.clone());
+            request.headers.set_raw(header.to_string(), value.to_string());
+            request
+        };
+
+        assert!(chain.handle(&mut post("Origin", "http://example.com")).is_ok());
+        assert!(chain.handle(&mut post("Origin", "https://admin.example.com")).is_ok());
+        assert!(chain.handle(&mut post("Referer", "http://example.com:80/form?page=2")).is_ok());
+
+        assert_eq!(forbidden(&chain, &mut post("Origin", "https://example.com")),
+            "Cross-origin request from https://example.com");
+        assert_eq!(forbidden(&chain, &mut post("Origin", "null")), "Cross-origin request from null");
+        assert_eq!(forbidden(&chain, &mut post("Referer", "http://evil.example/form")),
+            "Cross-origin request from http://evil.example");
+    }
+}
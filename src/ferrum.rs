@@ -1,15 +1,33 @@
 //! Exposes the `Ferrum` type, the main entrance point of the `Ferrum` library.
 
-use std::net::ToSocketAddrs;
-use std::time::Duration;
-use std::io::{Error, ErrorKind};
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::time::{Duration, Instant};
+use std::io::Error;
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
 
 use hyper::Body;
 use hyper::server::{Http, Server as HyperServer};
 
+use futures::{future, Future};
+use futures::future::Either;
+use futures::sync::oneshot;
+use tokio_core::reactor::{Core, Timeout};
+
 use error::HyperResult;
 use service::InitialService;
 use middleware::Handler;
+use connection::{self, ActiveConnections, ConnectionContext};
+use http2::Http2Settings;
+use limits::ConnectionLimits;
+use metrics::Metrics;
+use health::Health;
+use queue::WorkQueue;
+use listener::{Endpoint, Listener};
+#[cfg(feature = "tls")]
+use tls::TlsConfig;
+#[cfg(unix)]
+use unix::UnixSocket;
 
 pub type Server<H> = HyperServer<InitialService<H>, Body>;
 
@@ -34,6 +52,43 @@ pub struct Ferrum<H>
     ///
     /// Defaults to `num_cpus`.
     pub num_threads: usize,
+
+    /// The queue of the requests waiting for one of the `num_threads`
+    /// threads, see the `queue` module.
+    ///
+    /// The default is an unbounded queue.
+    pub work_queue: WorkQueue,
+
+    /// The settings of HTTP/2 connections, `None` to only serve HTTP/1.
+    ///
+    /// HTTP/2 is not available on the hyper `Server` returned by `server`.
+    /// The default is `None`.
+    pub http2: Option<Http2Settings>,
+
+    /// The limits of the connections, protecting the server from slow or
+    /// oversized requests.
+    ///
+    /// The limits don't apply to the hyper `Server` returned by `server`.
+    /// The default is `ConnectionLimits::new()`.
+    pub limits: ConnectionLimits,
+
+    /// The metrics reporting the internals of the server, see the `metrics`
+    /// module.
+    ///
+    /// The default is `None`.
+    pub metrics: Option<Metrics>,
+
+    /// The health endpoints failing readiness once the server starts
+    /// shutting down, see the `health` module. The server keeps accepting
+    /// connections for their `Health::shutdown_delay` then.
+    ///
+    /// The default is `None`.
+    pub health: Option<Health>,
+
+    /// How long a shut down server waits for its open connections to finish.
+    ///
+    /// The default is 30 seconds.
+    pub shutdown_timeout: Duration,
 }
 
 impl<H> Ferrum<H>
@@ -49,6 +104,12 @@ impl<H> Ferrum<H>
             keep_alive: true,
             timeout: Some(Duration::from_secs(30)),
             num_threads: ::num_cpus::get(),
+            work_queue: WorkQueue::new(),
+            http2: None,
+            limits: ConnectionLimits::new(),
+            metrics: None,
+            health: None,
+            shutdown_timeout: Duration::from_secs(30),
         }
     }
 
@@ -60,8 +121,171 @@ impl<H> Ferrum<H>
     pub fn http<A>(self, addr: A) -> HyperResult<()>
         where A: ToSocketAddrs
     {
-        let server = self.server(addr)?;//server.bind(&addr, InitialService::new(self.handler, Some(self.num_threads)))?;
-        server.run()
+        self.serve(vec![Endpoint::http(addr)?])
+    }
+
+    /// Kick off the server process using the HTTPS protocol.
+    ///
+    /// Call this once to begin listening for requests on the server.
+    /// This consumes the Ferrum instance. This method will block
+    /// the current thread executing the HTTPS server.
+    ///
+    /// Available with the `tls` feature.
+    #[cfg(feature = "tls")]
+    pub fn https<A>(self, addr: A, config: TlsConfig) -> HyperResult<()>
+        where A: ToSocketAddrs
+    {
+        self.serve(vec![Endpoint::https(addr, config)?])
+    }
+
+    /// Kick off the server process using the HTTP protocol over a Unix
+    /// domain socket.
+    ///
+    /// The socket can be given as a path or a `UnixSocket` describing its
+    /// permissions. The socket file is removed when the server stops.
+    /// This consumes the Ferrum instance. This method will block
+    /// the current thread executing the HTTP server.
+    #[cfg(unix)]
+    pub fn unix<S>(self, socket: S) -> HyperResult<()>
+        where S: Into<UnixSocket>
+    {
+        self.serve(vec![Endpoint::unix(socket)?])
+    }
+
+    /// Kick off the server process using the HTTP protocol on an already
+    /// bound listener.
+    ///
+    /// This allows serving on a listener inherited from a parent process,
+    /// see the `systemd` module for socket activation. This consumes the
+    /// Ferrum instance. This method will block the current thread executing
+    /// the HTTP server.
+    pub fn listen<L>(self, listener: L) -> HyperResult<()>
+        where L: Into<Listener>
+    {
+        self.serve(vec![Endpoint::listener(listener)])
+    }
+
+    /// Kick off the server process on several endpoints.
+    ///
+    /// All the endpoints share the thread pool of the server. Requests are
+    /// handled by the handler of their endpoint if it has one, by the handler
+    /// of the server otherwise. This consumes the Ferrum instance. This method
+    /// will block the current thread executing the servers.
+    pub fn serve(self, endpoints: Vec<Endpoint>) -> HyperResult<()> {
+        self.run(endpoints, future::empty())
+    }
+
+    /// Start serving `addr` using the HTTP protocol on a background thread.
+    ///
+    /// Binding port 0 picks a free port, which is given by
+    /// `Listening::local_addr`:
+    ///
+    /// ```rust
+    /// use ferrum::*;
+    ///
+    /// let listening = Ferrum::new(|_: &mut Request| Ok(Response::new()))
+    ///     .spawn("127.0.0.1:0").unwrap();
+    /// println!("Listening on {}", listening.local_addr().unwrap());
+    /// listening.shutdown().unwrap();
+    /// ```
+    pub fn spawn<A>(self, addr: A) -> HyperResult<Listening>
+        where A: ToSocketAddrs
+    {
+        self.spawn_endpoints(vec![Endpoint::http(addr)?])
+    }
+
+    /// Start serving several endpoints on a background thread, see `serve`.
+    pub fn spawn_endpoints(self, endpoints: Vec<Endpoint>) -> HyperResult<Listening> {
+        let local_addrs = endpoints.iter()
+            .map(|endpoint| endpoint.local_addr())
+            .collect::<Result<Vec<_>, _>>()?;
+        let (shutdown, signal) = oneshot::channel();
+        let thread = thread::Builder::new()
+            .name("ferrum-server".to_string())
+            .spawn(move || self.run(endpoints, signal.then(|_| Ok(()))))?;
+
+        Ok(Listening {
+            local_addrs,
+            shutdown: Some(shutdown),
+            thread: Some(thread),
+        })
+    }
+
+    // Serve `endpoints` until `shutdown` resolves, then wait for the open
+    // connections to finish.
+    fn run<F>(self, endpoints: Vec<Endpoint>, shutdown: F) -> HyperResult<()>
+        where F: Future<Item = (), Error = ()>
+    {
+        let mut core = Core::new()?;
+        let handle = core.handle();
+
+        let handler: Box<dyn Handler> = Box::new(self.handler);
+        let mut service = InitialService::new(handler, Some(self.num_threads));
+        service.work_queue = self.work_queue.clone();
+        service.metrics = self.metrics.clone();
+        let mut protocol = Http::new();
+        protocol.keep_alive(self.keep_alive);
+        let active = Arc::new(ActiveConnections::default());
+        if let Some(ref metrics) = self.metrics {
+            metrics.observe_server(Some(active.clone()), self.work_queue.stats());
+        }
+
+        let (drain, draining) = oneshot::channel();
+        let context = ConnectionContext {
+            handle: handle.clone(),
+            protocol,
+            http2: self.http2.clone(),
+            limits: self.limits.clone(),
+            active: active.clone(),
+            draining: draining.shared(),
+        };
+
+        let mut servers = Vec::with_capacity(endpoints.len());
+        #[cfg(unix)]
+        let mut socket_files = Vec::new();
+        for endpoint in endpoints {
+            let mut service = service.clone();
+            if let Some(handler) = endpoint.handler {
+                service.handler = Arc::new(handler);
+            }
+            service.listener_tag = endpoint.tag;
+            #[cfg(unix)]
+            socket_files.extend(endpoint.socket_file);
+
+            servers.push(connection::accept(&context, endpoint.listener, endpoint.transport, service)?);
+        }
+
+        // Dropping the listeners once `shutdown` resolves stops accepting connections.
+        let shutdown = shutdown.map_err(|_| Error::other("Shutdown signal failed"));
+        let servers = future::join_all(servers).map(|_| ());
+        let servers = match core.run(shutdown.select2(servers)) {
+            Ok(Either::A((_, servers))) => servers,
+            Ok(Either::B(_)) => return Ok(()),
+            Err(Either::A((err, _))) | Err(Either::B((err, _))) => return Err(err.into())
+        };
+
+        // Fail readiness while still accepting connections for a while.
+        if let Some(ref health) = self.health {
+            health.shut_down();
+            let delay = Timeout::new(health.shutdown_delay(), &handle)?;
+            if let Err((err, _)) = core.run(delay.select(servers)) {
+                return Err(err.into());
+            }
+        } else {
+            drop(servers);
+        }
+
+        // Ask the HTTP/2 clients to stop opening streams.
+        let _ = drain.send(());
+        let deadline = Instant::now() + self.shutdown_timeout;
+        while active.count() > 0 {
+            let now = Instant::now();
+            if now >= deadline {
+                break;
+            }
+            core.turn(Some(deadline - now));
+        }
+        Ok(())
     }
 
     /// Bind the provided `addr` and return a server ready to handle
@@ -69,12 +293,152 @@ impl<H> Ferrum<H>
     pub fn server<A>(self, addr: A) -> HyperResult<Server<H>>
         where A: ToSocketAddrs
     {
-        let addr = addr.to_socket_addrs()?
-            .next()
-            .ok_or(Error::new(ErrorKind::Other, "Empty addrs"))?;
+        let addr = first_addr(addr)?;
+
+        let mut service = InitialService::new(self.handler, Some(self.num_threads));
+        if let Some(ref metrics) = self.metrics {
+            metrics.observe_server(None, self.work_queue.stats());
+        }
+        service.work_queue = self.work_queue;
+        service.metrics = self.metrics;
+        service.local_addr = Some(addr);
 
         let mut server = Http::new();
         server.keep_alive(self.keep_alive);
-        server.bind(&addr, InitialService::new(self.handler, Some(self.num_threads)))
+        server.bind(&addr, service)
+    }
+}
+
+fn first_addr<A: ToSocketAddrs>(addr: A) -> Result<SocketAddr, Error> {
+    addr.to_socket_addrs()?
+        .next()
+        .ok_or_else(|| Error::other("Empty addrs"))
+}
+
+/// A handle on a server running on a background thread, see `Ferrum::spawn`.
+///
+/// Dropping the handle shuts the server down.
+#[derive(Debug)]
+pub struct Listening {
+    local_addrs: Vec<Option<SocketAddr>>,
+    shutdown: Option<oneshot::Sender<()>>,
+    thread: Option<JoinHandle<HyperResult<()>>>,
+}
+
+impl Listening {
+    /// The local address of the first TCP endpoint of the server.
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        self.local_addrs.iter().filter_map(|addr| *addr).next()
+    }
+
+    /// The local addresses of the endpoints of the server, in the order they
+    /// were given. Unix domain sockets have no address.
+    pub fn local_addrs(&self) -> &[Option<SocketAddr>] {
+        &self.local_addrs
+    }
+
+    /// Stop accepting connections and wait for the server to finish the open
+    /// ones, see `Ferrum::shutdown_timeout`.
+    pub fn shutdown(mut self) -> HyperResult<()> {
+        self.stop()
+    }
+
+    /// Block until the server stops, which only happens on errors.
+    pub fn join(mut self) -> HyperResult<()> {
+        self.join_thread()
+    }
+
+    fn stop(&mut self) -> HyperResult<()> {
+        if let Some(shutdown) = self.shutdown.take() {
+            let _ = shutdown.send(());
+        }
+        self.join_thread()
+    }
+
+    fn join_thread(&mut self) -> HyperResult<()> {
+        match self.thread.take() {
+            Some(thread) => thread.join().unwrap_or_else(|_| Err(Error::other("Server thread panicked").into())),
+            None => Ok(())
+        }
+    }
+}
+
+impl Drop for Listening {
+    fn drop(&mut self) {
+        let _ = self.stop();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::{Read, Write};
+    use std::net::TcpStream;
+
+    use {mime, Request, Response};
+
+    fn get(addr: SocketAddr, path: &str) -> String {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        write!(stream, "GET {} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n", path).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    }
+
+    fn handler(request: &mut Request) -> ::FerrumResult<Response> {
+        if request.uri.path() == "/slow" {
+            thread::sleep(Duration::from_millis(200));
+        }
+        Ok(Response::new().with_content(request.uri.path().to_string(), mime::TEXT_PLAIN))
+    }
+
+    #[test]
+    fn test_spawn_on_free_port() {
+        let listening = Ferrum::new(handler).spawn("127.0.0.1:0").unwrap();
+        let addr = listening.local_addr().unwrap();
+        assert!(addr.port() != 0);
+        assert!(get(addr, "/foo").ends_with("/foo"));
+
+        listening.shutdown().unwrap();
+        assert!(TcpStream::connect(addr).is_err());
+    }
+
+    #[test]
+    fn test_shutdown_waits_for_connections() {
+        let listening = Ferrum::new(handler).spawn("127.0.0.1:0").unwrap();
+        let addr = listening.local_addr().unwrap();
+
+        let client = thread::spawn(move || get(addr, "/slow"));
+        thread::sleep(Duration::from_millis(50));
+        listening.shutdown().unwrap();
+
+        let response = client.join().unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK"));
+        assert!(response.ends_with("/slow"));
+    }
+
+    #[test]
+    fn test_shutdown_stops_accepting() {
+        let listening = Ferrum::new(handler).spawn("127.0.0.1:0").unwrap();
+        let addr = listening.local_addr().unwrap();
+
+        // The open connection keeps the server draining.
+        let client = thread::spawn(move || get(addr, "/slow"));
+        thread::sleep(Duration::from_millis(50));
+        let shutdown = thread::spawn(move || listening.shutdown());
+        thread::sleep(Duration::from_millis(50));
+        assert!(TcpStream::connect(addr).is_err());
+
+        assert!(client.join().unwrap().ends_with("/slow"));
+        shutdown.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn test_drop_shuts_down() {
+        let addr = {
+            let listening = Ferrum::new(handler).spawn("127.0.0.1:0").unwrap();
+            listening.local_addr().unwrap()
+        };
+        assert!(TcpStream::connect(addr).is_err());
     }
 }
@@ -0,0 +1,368 @@
+//! Health endpoints for orchestrators: liveness on `/healthz` and readiness
+//! on `/readyz`.
+//!
+//! `Health` runs named checks and reports them as JSON:
+//!
+//! ```rust,no_run
+//! use std::time::Duration;
+//! use ferrum::*;
+//! use ferrum::health::{CheckResult, Health};
+//!
+//! # fn hello(_: &mut Request) -> FerrumResult<Response> {
+//! #     Ok(Response::new().with_content("Hello world!", mime::TEXT_PLAIN))
+//! # }
+//! # fn database_connected() -> bool { true }
+//! let health = Health::new()
+//!     .with_readiness_check("database", || {
+//!         if database_connected() {
+//!             CheckResult::ok()
+//!         } else {
+//!             CheckResult::failed("no connection")
+//!         }
+//!     })
+//!     .with_shutdown_delay(Duration::from_secs(5));
+//!
+//! let mut ferrum = Ferrum::new(hello);
+//! ferrum.health = Some(health.clone());
+//! ferrum.serve(vec![
+//!     Endpoint::http("0.0.0.0:3000").unwrap(),
+//!     Endpoint::http("0.0.0.0:8081").unwrap().with_handler(health.handler()),
+//! ]).unwrap();
+//! ```
+//!
+//! The report is answered with `200 OK` unless a check failed, with
+//! `503 Service Unavailable` then:
+//!
+//! ```text
+//! {"status":"degraded","checks":{"database":{"status":"ok"},"cache":{"status":"degraded","details":"evicting"}}}
+//! ```
+//!
+//! The liveness checks run on both endpoints, the readiness checks only on
+//! `/readyz`. Readiness fails once the graceful shutdown of the server
+//! starts, see `Ferrum::health`.
+
+use std::fmt;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+use hyper::header::{CacheControl, CacheDirective};
+
+use {Request, Response, FerrumResult, StatusCode};
+use middleware::Handler;
+
+/// The status of a check, or of a whole report.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Status {
+    /// Everything works.
+    Ok,
+    /// The service works, with reduced capabilities.
+    Degraded,
+    /// The service doesn't work.
+    Failed,
+}
+
+impl fmt::Display for Status {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str(match *self {
+            Status::Ok => "ok",
+            Status::Degraded => "degraded",
+            Status::Failed => "failed",
+        })
+    }
+}
+
+/// The result of a check.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CheckResult {
+    /// The status of the check.
+    pub status: Status,
+    /// The details of the status, for humans.
+    pub details: Option<String>,
+}
+
+impl CheckResult {
+    /// A passed check.
+    pub fn ok() -> CheckResult {
+        CheckResult { status: Status::Ok, details: None }
+    }
+
+    /// A check passed with reduced capabilities.
+    pub fn degraded<S: Into<String>>(details: S) -> CheckResult {
+        CheckResult { status: Status::Degraded, details: Some(details.into()) }
+    }
+
+    /// A failed check.
+    pub fn failed<S: Into<String>>(details: S) -> CheckResult {
+        CheckResult { status: Status::Failed, details: Some(details.into()) }
+    }
+
+    /// Set the details of the result.
+    pub fn with_details<S: Into<String>>(mut self, details: S) -> Self {
+        self.details = Some(details.into());
+        self
+    }
+}
+
+type CheckFn = Arc<dyn Fn() -> CheckResult + Send + Sync>;
+
+#[derive(Clone)]
+struct NamedCheck {
+    name: String,
+    readiness_only: bool,
+    check: CheckFn,
+}
+
+/// The checks of the health endpoints, see the `health` module.
+///
+/// Clones share the shutdown state.
+#[derive(Clone)]
+pub struct Health {
+    checks: Vec<NamedCheck>,
+    shutdown_delay: Duration,
+    shutting_down: Arc<AtomicBool>,
+}
+
+impl Health {
+    /// Create health endpoints without checks, always passing until the
+    /// server shuts down.
+    pub fn new() -> Health {
+        Health {
+            checks: Vec::new(),
+            shutdown_delay: Duration::from_secs(0),
+            shutting_down: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    /// Add a check of both liveness and readiness. Failing liveness checks
+    /// make orchestrators restart the server, so they should only fail when
+    /// it cannot recover.
+    pub fn with_liveness_check<S, F>(mut self, name: S, check: F) -> Self
+        where S: Into<String>, F: Fn() -> CheckResult + Send + Sync + 'static
+    {
+        self.checks.push(NamedCheck { name: name.into(), readiness_only: false, check: Arc::new(check) });
+        self
+    }
+
+    /// Add a check of readiness, e.g. of the availability of a dependency.
+    pub fn with_readiness_check<S, F>(mut self, name: S, check: F) -> Self
+        where S: Into<String>, F: Fn() -> CheckResult + Send + Sync + 'static
+    {
+        self.checks.push(NamedCheck { name: name.into(), readiness_only: true, check: Arc::new(check) });
+        self
+    }
+
+    /// Set how long a server shutting down keeps accepting connections with
+    /// a failing readiness, for the orchestrator to stop routing requests to
+    /// it. The default is no delay.
+    pub fn with_shutdown_delay(mut self, delay: Duration) -> Self {
+        self.shutdown_delay = delay;
+        self
+    }
+
+    /// Fail readiness from now on, e.g. when the application starts
+    /// shutting down by itself.
+    pub fn shut_down(&self) {
+        self.shutting_down.store(true, Ordering::SeqCst);
+    }
+
+    /// Whether the server is shutting down.
+    pub fn is_shutting_down(&self) -> bool {
+        self.shutting_down.load(Ordering::SeqCst)
+    }
+
+    /// The delay set by `with_shutdown_delay`.
+    pub fn shutdown_delay(&self) -> Duration {
+        self.shutdown_delay
+    }
+
+    /// Get a `Handler` answering `/healthz` and `/readyz`, and `404 Not
+    /// Found` to the other paths.
+    pub fn handler(&self) -> HealthHandler {
+        HealthHandler { health: self.clone(), probe: None }
+    }
+
+    /// Get a `Handler` answering the liveness report on any path.
+    pub fn liveness_handler(&self) -> HealthHandler {
+        HealthHandler { health: self.clone(), probe: Some(Probe::Liveness) }
+    }
+
+    /// Get a `Handler` answering the readiness report on any path.
+    pub fn readiness_handler(&self) -> HealthHandler {
+        HealthHandler { health: self.clone(), probe: Some(Probe::Readiness) }
+    }
+
+    // Run the checks of `probe` into a report.
+    fn report(&self, probe: Probe) -> Report {
+        let mut checks = self.checks.iter()
+            .filter(|check| probe == Probe::Readiness || !check.readiness_only)
+            .map(|check| {
+                let result = panic::catch_unwind(AssertUnwindSafe(|| (check.check)()))
+                    .unwrap_or_else(|_| CheckResult::failed("the check panicked"));
+                (check.name.clone(), result)
+            })
+            .collect::<Vec<_>>();
+        if probe == Probe::Readiness && self.is_shutting_down() {
+            checks.push(("shutdown".to_string(), CheckResult::failed("the server is shutting down")));
+        }
+        Report { checks }
+    }
+}
+
+impl Default for Health {
+    fn default() -> Health {
+        Health::new()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Probe {
+    Liveness,
+    Readiness,
+}
+
+struct Report {
+    checks: Vec<(String, CheckResult)>,
+}
+
+impl Report {
+    fn status(&self) -> Status {
+        self.checks.iter().map(|(_, result)| result.status).max().unwrap_or(Status::Ok)
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        write!(formatter, "{{\"status\":\"{}\",\"checks\":{{", self.status())?;
+        for (index, (name, result)) in self.checks.iter().enumerate() {
+            if index > 0 {
+                formatter.write_str(",")?;
+            }
+            write!(formatter, "\"{}\":{{\"status\":\"{}\"", JsonString(name), result.status)?;
+            if let Some(ref details) = result.details {
+                write!(formatter, ",\"details\":\"{}\"", JsonString(details))?;
+            }
+            formatter.write_str("}")?;
+        }
+        formatter.write_str("}}")
+    }
+}
+
+// The contents of a JSON string.
+struct JsonString<'a>(&'a str);
+
+impl<'a> fmt::Display for JsonString<'a> {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        for c in self.0.chars() {
+            match c {
+                '"' => formatter.write_str("\\\"")?,
+                '\\' => formatter.write_str("\\\\")?,
+                '\n' => formatter.write_str("\\n")?,
+                '\r' => formatter.write_str("\\r")?,
+                '\t' => formatter.write_str("\\t")?,
+                c if (c as u32) < 0x20 => write!(formatter, "\\u{:04x}", c as u32)?,
+                c => write!(formatter, "{}", c)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A `Handler` answering the health reports, see `Health::handler`.
+pub struct HealthHandler {
+    health: Health,
+    probe: Option<Probe>,
+}
+
+impl Handler for HealthHandler {
+    fn handle(&self, request: &mut Request) -> FerrumResult<Response> {
+        let probe = match (self.probe, request.uri.path()) {
+            (Some(probe), _) => probe,
+            (None, "/healthz") => Probe::Liveness,
+            (None, "/readyz") => Probe::Readiness,
+            _ => return Ok(Response::new().with_status(StatusCode::NotFound))
+        };
+
+        let report = self.health.report(probe);
+        let status = match report.status() {
+            Status::Failed => StatusCode::ServiceUnavailable,
+            _ => StatusCode::Ok
+        };
+        Ok(Response::new()
+            .with_content(report.to_string(), ::mime::APPLICATION_JSON)
+            .with_header(CacheControl(vec![CacheDirective::NoStore]))
+            .with_status(status))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::{Read, Write};
+    use std::net::{SocketAddr, TcpStream};
+    use std::thread;
+
+    use Ferrum;
+
+    fn body(handler: &HealthHandler, path: &str) -> (StatusCode, String) {
+        let mut request = Request::stub();
+        request.uri = path.parse().unwrap();
+        let mut response = handler.handle(&mut request).unwrap();
+        let content = response.buffer_content().unwrap().map(|content| content.0).unwrap_or_default();
+        (response.status, String::from_utf8(content).unwrap())
+    }
+
+    #[test]
+    fn test_report() {
+        let health = Health::new()
+            .with_liveness_check("threads", CheckResult::ok)
+            .with_readiness_check("cache", || CheckResult::degraded("evicting \"hot\" keys"))
+            .with_readiness_check("database", || panic!("unreachable"));
+        let handler = health.handler();
+
+        assert_eq!(body(&handler, "/healthz"), (StatusCode::Ok, "{\"status\":\"ok\",\"checks\":{\"threads\":{\"status\":\"ok\"}}}".to_string()));
+        assert_eq!(body(&handler, "/readyz"), (StatusCode::ServiceUnavailable, concat!(
+            "{\"status\":\"failed\",\"checks\":{\"threads\":{\"status\":\"ok\"},",
+            "\"cache\":{\"status\":\"degraded\",\"details\":\"evicting \\\"hot\\\" keys\"},",
+            "\"database\":{\"status\":\"failed\",\"details\":\"the check panicked\"}}}").to_string()));
+        assert_eq!(body(&handler, "/other").0, StatusCode::NotFound);
+    }
+
+    #[test]
+    fn test_degraded_readiness() {
+        let health = Health::new().with_readiness_check("cache", || CheckResult::degraded("slow"));
+        let (status, content) = body(&health.readiness_handler(), "/anything");
+        assert_eq!(status, StatusCode::Ok);
+        assert!(content.starts_with("{\"status\":\"degraded\""), "{}", content);
+    }
+
+    fn get(addr: SocketAddr, path: &str) -> String {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        write!(stream, "GET {} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n", path).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    }
+
+    #[test]
+    fn test_shutdown_readiness() {
+        let health = Health::new().with_shutdown_delay(Duration::from_millis(500));
+        let mut ferrum = Ferrum::new(health.handler());
+        ferrum.health = Some(health.clone());
+        let listening = ferrum.spawn("127.0.0.1:0").unwrap();
+        let addr = listening.local_addr().unwrap();
+
+        assert!(get(addr, "/readyz").starts_with("HTTP/1.1 200 OK"));
+        let shutdown = thread::spawn(move || listening.shutdown().unwrap());
+        thread::sleep(Duration::from_millis(100));
+
+        // The server still accepts connections during the delay.
+        assert!(health.is_shutting_down());
+        let response = get(addr, "/readyz");
+        assert!(response.starts_with("HTTP/1.1 503 Service Unavailable"), "{}", response);
+        assert!(response.contains("\"shutdown\":{\"status\":\"failed\""), "{}", response);
+        assert!(get(addr, "/healthz").starts_with("HTTP/1.1 200 OK"));
+        shutdown.join().unwrap();
+    }
+}
@@ -0,0 +1,624 @@
+//! HTTP/2 support for `Ferrum`.
+//!
+//! HTTP/2 is enabled by setting `Ferrum::http2`. Requests are then handled by
+//! the same `Handler`, with `Request::version` telling the protocol in use:
+//!
+//! ```rust,no_run
+//! use ferrum::*;
+//! use ferrum::http2::Http2Settings;
+//!
+//! let mut ferrum = Ferrum::new(|request: &mut Request| {
+//!     Ok(Response::new().with_content(format!("{}", request.version), mime::TEXT_PLAIN))
+//! });
+//! ferrum.http2 = Some(Http2Settings::new().with_max_concurrent_streams(250));
+//! ferrum.http("localhost:3000").unwrap();
+//! ```
+//!
+//! Plain text listeners serve HTTP/2 to the clients with prior knowledge
+//! (h2c), which start the connection with the HTTP/2 preface, and HTTP/1 to
+//! the others. TLS listeners offer HTTP/2 through ALPN.
+//!
+//! Once the server stops accepting connections, HTTP/2 clients are sent a
+//! `GOAWAY`, the streams they already opened being still served.
+
+use std::cell::{Cell, RefCell};
+use std::io;
+use std::rc::Rc;
+use std::time::{Duration, SystemTime};
+
+use bytes::Bytes;
+use futures::{future, task, Async, Future, Poll, Sink, Stream};
+use futures::task::Task;
+use h2::{Reason, RecvStream, SendStream};
+use h2::server::{Builder, Connection, SendResponse};
+use http;
+use hyper::{self, Body, Chunk, HttpVersion, Method, Uri};
+use hyper::header::{Date, Host};
+use hyper::server::Service;
+use tokio_core::reactor::{Handle, Timeout};
+use tokio_io::{AsyncRead, AsyncWrite};
+
+use connection::Draining;
+use limits::{self, ConnectionLimits};
+use middleware::Handler;
+use request::HyperRequest;
+use response::HyperResponse;
+use rewind::Rewind;
+use service::InitialService;
+
+/// The connection preface sent first by HTTP/2 clients.
+pub const PREFACE: &[u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+
+/// The ALPN protocol identifier of HTTP/2 over TLS.
+pub const ALPN_H2: &[u8] = b"h2";
+
+// Headers specific to HTTP/1 connections, forbidden in HTTP/2.
+const CONNECTION_HEADERS: [&str; 5] = ["connection", "keep-alive", "proxy-connection", "transfer-encoding", "upgrade"];
+
+/// The settings of HTTP/2 connections.
+#[derive(Debug, Clone)]
+pub struct Http2Settings {
+    max_concurrent_streams: Option<u32>,
+    initial_window_size: Option<u32>,
+    initial_connection_window_size: Option<u32>,
+    max_frame_size: Option<u32>,
+    max_header_list_size: Option<u32>,
+}
+
+impl Http2Settings {
+    /// Create the default settings.
+    ///
+    /// A connection is limited to 100 concurrent streams, the other settings
+    /// have the defaults of the HTTP/2 specification.
+    pub fn new() -> Http2Settings {
+        Http2Settings {
+            max_concurrent_streams: Some(100),
+            initial_window_size: None,
+            initial_connection_window_size: None,
+            max_frame_size: None,
+            max_header_list_size: None,
+        }
+    }
+
+    /// Set the maximum number of streams a client can open concurrently on a
+    /// connection.
+    pub fn with_max_concurrent_streams(mut self, max: u32) -> Self {
+        self.max_concurrent_streams = Some(max);
+        self
+    }
+
+    /// Set the flow control window of each stream, in bytes.
+    pub fn with_initial_window_size(mut self, size: u32) -> Self {
+        self.initial_window_size = Some(size);
+        self
+    }
+
+    /// Set the flow control window of each connection, in bytes.
+    pub fn with_initial_connection_window_size(mut self, size: u32) -> Self {
+        self.initial_connection_window_size = Some(size);
+        self
+    }
+
+    /// Set the largest frame payload the server accepts, in bytes.
+    pub fn with_max_frame_size(mut self, size: u32) -> Self {
+        self.max_frame_size = Some(size);
+        self
+    }
+
+    /// Set the largest header list the server accepts, in bytes.
+    pub fn with_max_header_list_size(mut self, size: u32) -> Self {
+        self.max_header_list_size = Some(size);
+        self
+    }
+
+    fn builder(&self) -> Builder {
+        let mut builder = Builder::new();
+        if let Some(max) = self.max_concurrent_streams {
+            builder.max_concurrent_streams(max);
+        }
+        if let Some(size) = self.initial_window_size {
+            builder.initial_window_size(size);
+        }
+        if let Some(size) = self.initial_connection_window_size {
+            builder.initial_connection_window_size(size);
+        }
+        if let Some(size) = self.max_frame_size {
+            builder.max_frame_size(size);
+        }
+        if let Some(size) = self.max_header_list_size {
+            builder.max_header_list_size(size);
+        }
+        builder
+    }
+}
+
+impl Default for Http2Settings {
+    fn default() -> Http2Settings {
+        Http2Settings::new()
+    }
+}
+
+/// A future reading the start of a connection, resolving to the connection
+/// and whether it starts with the HTTP/2 preface.
+pub struct Preface<S> {
+    io: Option<S>,
+    read: Vec<u8>,
+}
+
+impl<S> Preface<S> {
+    pub fn new(io: S) -> Preface<S> {
+        Preface { io: Some(io), read: Vec::with_capacity(PREFACE.len()) }
+    }
+}
+
+impl<S: AsyncRead> Future for Preface<S> {
+    type Item = (Rewind<S>, bool);
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, io::Error> {
+        while self.read.len() < PREFACE.len() && PREFACE.starts_with(&self.read) {
+            let mut buf = [0; 24];
+            let wanted = PREFACE.len() - self.read.len();
+            let io = self.io.as_mut().expect("Preface polled after completion");
+            let len = try_nb!(io.read(&mut buf[..wanted]));
+            if len == 0 {
+                break;
+            }
+            self.read.extend_from_slice(&buf[..len]);
+        }
+
+        let is_http2 = self.read == PREFACE;
+        let io = self.io.take().expect("Preface polled after completion");
+        Ok(Async::Ready((Rewind::new(io, self.read.split_off(0)), is_http2)))
+    }
+}
+
+// Serve an HTTP/2 connection, handling each stream with `service`.
+pub(crate) fn serve<S, H>(
+    io: S,
+    service: InitialService<H>,
+    version: HttpVersion,
+    settings: &Http2Settings,
+    limits: &ConnectionLimits,
+    draining: Draining,
+    handle: &Handle
+) -> Box<dyn Future<Item = (), Error = ()>>
+    where S: AsyncRead + AsyncWrite + 'static,
+          H: Handler
+{
+    let handshake = settings.builder().handshake::<S, Bytes>(io);
+    let handshake = limits::with_deadline(handshake, limits.header_read_timeout, handle);
+    let idle_timeout = limits.keep_alive_timeout;
+    let handle = handle.clone();
+    Box::new(handshake.and_then(move |connection| Serving {
+        connection,
+        service,
+        version,
+        handle,
+        streams: Rc::new(OpenStreams::default()),
+        idle_timeout,
+        idle: None,
+        draining: Some(draining),
+        closing: false,
+    }))
+}
+
+// The number of streams of a connection still being served, waking the
+// connection when the last one completes.
+#[derive(Default)]
+struct OpenStreams {
+    count: Cell<usize>,
+    task: RefCell<Option<Task>>,
+}
+
+// Counts a stream as open until dropped.
+struct OpenStream(Rc<OpenStreams>);
+
+impl OpenStream {
+    fn new(streams: &Rc<OpenStreams>) -> OpenStream {
+        streams.count.set(streams.count.get() + 1);
+        OpenStream(streams.clone())
+    }
+}
+
+impl Drop for OpenStream {
+    fn drop(&mut self) {
+        let count = self.0.count.get() - 1;
+        self.0.count.set(count);
+        if count == 0 {
+            if let Some(task) = self.0.task.borrow_mut().take() {
+                task.notify();
+            }
+        }
+    }
+}
+
+// A future serving the streams of an HTTP/2 connection, sending a `GOAWAY`
+// once the connection has no open streams for the idle timeout or the server
+// is draining, and closing it if the client has not done so after another
+// idle timeout.
+struct Serving<S, H>
+    where H: Handler
+{
+    connection: Connection<S, Bytes>,
+    service: InitialService<H>,
+    version: HttpVersion,
+    handle: Handle,
+    streams: Rc<OpenStreams>,
+    idle_timeout: Option<Duration>,
+    idle: Option<Timeout>,
+    draining: Option<Draining>,
+    closing: bool,
+}
+
+impl<S, H> Serving<S, H>
+    where S: AsyncRead + AsyncWrite + 'static,
+          H: Handler
+{
+    // Send a `GOAWAY`, letting the open streams finish.
+    fn shut_down(&mut self) {
+        if !self.closing {
+            self.connection.graceful_shutdown();
+            self.closing = true;
+            self.idle = None;
+        }
+    }
+
+    // Whether the server started draining its connections.
+    fn poll_draining(&mut self) -> bool {
+        let drained = match self.draining.as_mut().map(|draining| draining.poll()) {
+            Some(Ok(Async::NotReady)) | None => false,
+            // The server is gone if the signal was dropped.
+            Some(Ok(Async::Ready(_))) | Some(Err(_)) => true
+        };
+        if drained {
+            self.draining = None;
+        }
+        drained
+    }
+
+    // Whether the connection has been idle for the idle timeout.
+    fn poll_idle(&mut self) -> bool {
+        if self.streams.count.get() > 0 {
+            *self.streams.task.borrow_mut() = Some(task::current());
+            self.idle = None;
+            return false;
+        }
+        let timeout = match self.idle_timeout {
+            Some(timeout) => timeout,
+            None => return false
+        };
+        if self.idle.is_none() {
+            self.idle = Timeout::new(timeout, &self.handle).ok();
+        }
+        match self.idle.as_mut().map(|idle| idle.poll()) {
+            Some(Ok(Async::NotReady)) => false,
+            // A connection which cannot be timed is treated as idle.
+            _ => true
+        }
+    }
+}
+
+impl<S, H> Future for Serving<S, H>
+    where S: AsyncRead + AsyncWrite + 'static,
+          H: Handler
+{
+    type Item = ();
+    type Error = ();
+
+    fn poll(&mut self) -> Poll<(), ()> {
+        if self.poll_draining() {
+            self.shut_down();
+        }
+        loop {
+            match self.connection.poll().map_err(|_| ())? {
+                Async::Ready(Some((request, respond))) => {
+                    let stream = OpenStream::new(&self.streams);
+                    let serving = serve_stream(&self.service, request, respond, self.version, &self.handle);
+                    self.handle.spawn(serving.then(move |_| {
+                        drop(stream);
+                        Ok(())
+                    }));
+                },
+                Async::Ready(None) => return Ok(Async::Ready(())),
+                Async::NotReady => {
+                    if !self.poll_idle() {
+                        return Ok(Async::NotReady);
+                    }
+                    if self.closing {
+                        return Ok(Async::Ready(()));
+                    }
+                    self.shut_down();
+                }
+            }
+        }
+    }
+}
+
+fn serve_stream<H: Handler>(
+    service: &InitialService<H>,
+    request: http::Request<RecvStream>,
+    mut respond: SendResponse<Bytes>,
+    version: HttpVersion,
+    handle: &Handle
+) -> Box<dyn Future<Item = (), Error = ()>> {
+    let (parts, mut body) = request.into_parts();
+    let mut request = match hyper_request(parts, version) {
+        Some(request) => request,
+        None => {
+            respond.send_reset(Reason::PROTOCOL_ERROR);
+            return Box::new(future::ok(()));
+        }
+    };
+
+    // Forward the request body, releasing the flow control capacity as the
+    // handler consumes it.
+    let (sender, request_body) = Body::pair();
+    request.set_body(request_body);
+    let is_head = *request.method() == Method::Head;
+    let release = body.release_capacity().clone();
+    let forward = body
+        .map_err(|err| hyper::Error::from(io::Error::other(err)))
+        .then(Ok::<_, ()>)
+        .fold(sender, move |sender, chunk| {
+            let mut release = release.clone();
+            let len = chunk.as_ref().map(|bytes| bytes.len()).unwrap_or(0);
+            sender.send(chunk.map(Chunk::from))
+                .map(move |sender| {
+                    let _ = release.release_capacity(len);
+                    sender
+                })
+                .map_err(|_| ())
+        })
+        .map(|_| ());
+    handle.spawn(forward);
+
+    Box::new(service.call(request).then(move |result| -> Box<dyn Future<Item = (), Error = ()>> {
+        match result.ok().and_then(|response| send_response(&mut respond, response, is_head)) {
+            Some(send) => send,
+            None => {
+                respond.send_reset(Reason::INTERNAL_ERROR);
+                Box::new(future::ok(()))
+            }
+        }
+    }))
+}
+
+// Convert the head of an HTTP/2 request.
+fn hyper_request(parts: http::request::Parts, version: HttpVersion) -> Option<HyperRequest> {
+    let method = parts.method.as_str().parse::<Method>().ok()?;
+    let uri = parts.uri.to_string().parse::<Uri>().ok()?;
+    let authority = parts.uri.authority_part().map(|authority| authority.to_string());
+
+    let mut request = HyperRequest::new(method, uri);
+    request.set_version(version);
+    {
+        let headers = request.headers_mut();
+        for (name, value) in &parts.headers {
+            headers.append_raw(name.as_str().to_string(), value.as_bytes().to_vec());
+        }
+        // HTTP/2 carries the host in the `:authority` pseudo header.
+        if !headers.has::<Host>() {
+            if let Some(authority) = authority {
+                headers.set_raw("Host", authority);
+            }
+        }
+    }
+    Some(request)
+}
+
+// Send the head of `response`, returning a future sending its body. The
+// response to a `HEAD` request ends with its head, the body is dropped.
+fn send_response(respond: &mut SendResponse<Bytes>, response: HyperResponse, is_head: bool)
+    -> Option<Box<dyn Future<Item = (), Error = ()>>>
+{
+    let mut head = http::Response::builder();
+    head.status(response.status().as_u16());
+    for header in response.headers().iter() {
+        let name = header.name().to_lowercase();
+        if CONNECTION_HEADERS.contains(&name.as_str()) {
+            continue;
+        }
+        for value in header.raw().iter() {
+            head.header(name.as_str(), value);
+        }
+    }
+    if !response.headers().has::<Date>() {
+        head.header("date", Date(SystemTime::now().into()).to_string().as_str());
+    }
+
+    let head = head.body(()).ok()?;
+    let stream = respond.send_response(head, is_head).ok()?;
+    if is_head {
+        return Some(Box::new(future::ok(())));
+    }
+    Some(Box::new(SendBody { stream, body: response.body(), data: None }))
+}
+
+// Send a response body, respecting the flow control window of the stream.
+struct SendBody {
+    stream: SendStream<Bytes>,
+    body: Body,
+    data: Option<Bytes>,
+}
+
+impl Future for SendBody {
+    type Item = ();
+    type Error = ();
+
+    fn poll(&mut self) -> Poll<(), ()> {
+        loop {
+            if let Some(mut data) = self.data.take() {
+                self.stream.reserve_capacity(data.len());
+                match self.stream.poll_capacity() {
+                    Ok(Async::Ready(Some(capacity))) if capacity > 0 => {
+                        let len = ::std::cmp::min(capacity, data.len());
+                        self.stream.send_data(data.split_to(len), false).map_err(|_| ())?;
+                        if !data.is_empty() {
+                            self.data = Some(data);
+                        }
+                    },
+                    Ok(Async::Ready(Some(_))) | Ok(Async::NotReady) => {
+                        self.data = Some(data);
+                        return Ok(Async::NotReady);
+                    },
+                    Ok(Async::Ready(None)) | Err(_) => return Err(())
+                }
+                continue;
+            }
+
+            match self.body.poll() {
+                Ok(Async::Ready(Some(chunk))) => {
+                    if !chunk.is_empty() {
+                        self.data = Some(Bytes::from(chunk));
+                    }
+                },
+                Ok(Async::Ready(None)) => {
+                    self.stream.send_data(Bytes::new(), true).map_err(|_| ())?;
+                    return Ok(Async::Ready(()));
+                },
+                Ok(Async::NotReady) => return Ok(Async::NotReady),
+                Err(_) => {
+                    self.stream.send_reset(Reason::INTERNAL_ERROR);
+                    return Err(());
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::{Read, Write};
+    use std::net::TcpStream as StdTcpStream;
+
+    use h2::client;
+    use tokio_core::net::TcpStream;
+    use tokio_core::reactor::Core;
+
+    use {mime, Ferrum, Request, Response};
+
+    fn handler(request: &mut Request) -> ::FerrumResult<Response> {
+        if request.uri.path() == "/large" {
+            return Ok(Response::new().with_content(vec![b'x'; 200_000], mime::TEXT_PLAIN));
+        }
+        let body = request.take_body().concat2().wait().unwrap();
+        let host = request.headers.get::<Host>().map(|host| host.to_string());
+        let content = format!("{:?} {:?} {}", request.version, host, String::from_utf8_lossy(&body));
+        Ok(Response::new().with_content(content, mime::TEXT_PLAIN))
+    }
+
+    fn spawn() -> ::Listening {
+        let mut ferrum = Ferrum::new(handler);
+        ferrum.http2 = Some(Http2Settings::new().with_initial_window_size(1024));
+        ferrum.spawn("127.0.0.1:0").unwrap()
+    }
+
+    // Send a request with prior knowledge, returning the status and body.
+    fn request(addr: ::std::net::SocketAddr, method: &str, uri: &str, body: &'static [u8]) -> (u16, Vec<u8>) {
+        let mut core = Core::new().unwrap();
+        let handle = core.handle();
+        let request = http::Request::builder().method(method).uri(uri).body(()).unwrap();
+
+        let response = TcpStream::connect(&addr, &handle)
+            .map_err(h2::Error::from)
+            .and_then(client::handshake)
+            .and_then(move |(mut client, connection)| {
+                handle.spawn(connection.map_err(|_| ()));
+                let (response, mut stream) = client.send_request(request, body.is_empty()).unwrap();
+                if !body.is_empty() {
+                    stream.send_data(Bytes::from_static(body), true).unwrap();
+                }
+                response
+            })
+            .and_then(|response| {
+                let (parts, mut body) = response.into_parts();
+                let mut release = body.release_capacity().clone();
+                body.fold(Vec::new(), move |mut content, chunk| {
+                    let _ = release.release_capacity(chunk.len());
+                    content.extend_from_slice(&chunk);
+                    Ok::<_, h2::Error>(content)
+                }).map(move |content| (parts.status.as_u16(), content))
+            });
+        core.run(response).unwrap()
+    }
+
+    #[test]
+    fn test_prior_knowledge() {
+        let listening = spawn();
+        let addr = listening.local_addr().unwrap();
+
+        let (status, body) = request(addr, "POST", "http://example.com/echo", b"hello");
+        assert_eq!(status, 200);
+        assert_eq!(String::from_utf8(body).unwrap(), "H2c Some(\"example.com\") hello");
+    }
+
+    #[test]
+    fn test_head() {
+        let listening = spawn();
+        let addr = listening.local_addr().unwrap();
+
+        let (status, body) = request(addr, "HEAD", "http://example.com/large", b"");
+        assert_eq!(status, 200);
+        assert!(body.is_empty());
+    }
+
+    #[test]
+    fn test_flow_control() {
+        let listening = spawn();
+        let addr = listening.local_addr().unwrap();
+
+        let (status, body) = request(addr, "GET", "http://example.com/large", b"");
+        assert_eq!(status, 200);
+        assert_eq!(body.len(), 200_000);
+    }
+
+    #[test]
+    fn test_http1_fallback() {
+        let listening = spawn();
+        let mut stream = StdTcpStream::connect(listening.local_addr().unwrap()).unwrap();
+        stream.write_all(b"POST / HTTP/1.1\r\nHost: localhost\r\nContent-Length: 2\r\nConnection: close\r\n\r\nhi").unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+
+        assert!(response.starts_with("HTTP/1.1 200 OK"));
+        assert!(response.ends_with("Http11 Some(\"localhost\") hi"));
+    }
+
+    #[test]
+    fn test_shutdown_goaway() {
+        let listening = spawn();
+        let addr = listening.local_addr().unwrap();
+
+        // The client keeps its connection open after a request.
+        let (responded, response) = ::std::sync::mpsc::channel();
+        let client = ::std::thread::spawn(move || {
+            let mut core = Core::new().unwrap();
+            let handle = core.handle();
+            let connection = TcpStream::connect(&addr, &handle)
+                .map_err(h2::Error::from)
+                .and_then(client::handshake)
+                .and_then(move |(mut client, connection)| {
+                    let request = http::Request::builder().uri("http://example.com/").body(()).unwrap();
+                    let (response, _) = client.send_request(request, true).unwrap();
+                    handle.spawn(response.then(move |response| {
+                        responded.send(response.is_ok()).unwrap();
+                        Ok(())
+                    }));
+                    connection.then(move |result| {
+                        drop(client);
+                        result
+                    })
+                });
+            core.run(connection)
+        });
+        assert!(response.recv().unwrap());
+
+        // The connection is closed right away, without waiting for the shutdown timeout.
+        let start = ::std::time::Instant::now();
+        listening.shutdown().unwrap();
+        assert!(start.elapsed() < Duration::from_secs(5));
+        assert!(client.join().unwrap().is_ok());
+    }
+}
@@ -5,6 +5,28 @@ extern crate unsafe_any as uany;
 extern crate ferrum_plugin as plugin;
 extern crate num_cpus;
 extern crate mime_guess;
+extern crate unicase;
+extern crate tokio_core;
+#[macro_use]
+extern crate tokio_io;
+extern crate bytes;
+extern crate http;
+extern crate h2;
+extern crate httparse;
+extern crate sha1_smol;
+extern crate base64;
+extern crate getrandom;
+extern crate regex;
+#[macro_use]
+extern crate log;
+#[cfg(feature = "tls")]
+extern crate rustls;
+#[cfg(unix)]
+extern crate libc;
+#[cfg(unix)]
+extern crate tokio_uds;
+#[cfg(test)]
+extern crate rcgen;
 pub extern crate mime;
 pub extern crate url;
 
@@ -60,4 +82,71 @@ pub mod response;
 
 pub mod service;
 
+/// Conditional requests handling
+pub mod conditional;
+
+/// In-memory response caching
+pub mod cache;
+
+/// Rate limiting
+pub mod ratelimit;
+
+/// TLS support
+#[cfg(feature = "tls")]
+pub mod tls;
+
+/// Unix domain sockets support
+#[cfg(unix)]
+pub mod unix;
+
+/// Already bound listeners
+pub mod listener;
+pub use listener::{Endpoint, Listener};
+
+/// Systemd socket activation
+#[cfg(unix)]
+pub mod systemd;
+
+/// HTTP/2 support
+pub mod http2;
+
+/// WebSocket support
+pub mod websocket;
+
+/// Connection limits
+pub mod limits;
+
+/// Bounded work queue
+pub mod queue;
+
+/// Prometheus metrics
+pub mod metrics;
+
+/// Distributed tracing
+pub mod trace;
+
+/// Health endpoints
+pub mod health;
+
+/// Prefix dispatch
+pub mod mount;
+
+/// Virtual hosts
+pub mod vhost;
+
+/// Reverse proxy
+pub mod proxy;
+
+/// Security headers
+pub mod security;
+
+/// CSRF protection
+pub mod csrf;
+
+/// HTTP authentication
+pub mod auth;
+
+mod connection;
+mod random;
+mod rewind;
 mod ferrum;
\ No newline at end of file
@@ -0,0 +1,916 @@
+//! Connection limits protecting a `Ferrum` server from slow or oversized
+//! requests.
+//!
+//! The limits are set with `Ferrum::limits`:
+//!
+//! ```rust,no_run
+//! use std::time::Duration;
+//! use ferrum::*;
+//! use ferrum::limits::ConnectionLimits;
+//!
+//! let mut ferrum = Ferrum::new(|_: &mut Request| Ok(Response::new()));
+//! ferrum.limits = ConnectionLimits::new()
+//!     .with_max_connections(Some(10_000))
+//!     .with_header_read_timeout(Some(Duration::from_secs(10)))
+//!     .with_max_body_size(Some(10 << 20));
+//! ferrum.http("localhost:3000").unwrap();
+//! ```
+//!
+//! Requests over a limit are answered without reaching the handler:
+//!
+//! * `408 Request Timeout` when the head or the body of a request is not
+//!   received in time,
+//! * `413 Payload Too Large` when the body is larger than the maximum body size,
+//! * `414 URI Too Long` when the request target is longer than the maximum URI
+//!   length,
+//! * `431 Request Header Fields Too Large` when the head has too many headers or
+//!   is larger than the maximum header size.
+//!
+//! The connection is closed afterwards. The maximum number of connections is
+//! enforced for every protocol. The header read timeout also bounds the TLS
+//! handshake, the detection of the HTTP/2 preface and the HTTP/2 handshake,
+//! and HTTP/2 connections without open streams are sent a `GOAWAY` after the
+//! keep-alive timeout. The other limits apply to HTTP/1 connections, until
+//! they are upgraded; the streams of HTTP/2 connections are limited by
+//! `Http2Settings`.
+
+use std::cmp;
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+use std::mem;
+use std::str;
+use std::time::{Duration, Instant};
+
+use futures::{Future, Poll};
+use futures::future::Either;
+use httparse::{self, Header, Status};
+use hyper::StatusCode;
+use tokio_core::reactor::{Handle, Timeout};
+use tokio_io::{AsyncRead, AsyncWrite};
+
+/// The limits of the connections of a server.
+#[derive(Debug, Clone)]
+pub struct ConnectionLimits {
+    pub(crate) max_connections: Option<usize>,
+    pub(crate) header_read_timeout: Option<Duration>,
+    body_read_timeout: Option<Duration>,
+    pub(crate) keep_alive_timeout: Option<Duration>,
+    max_headers: usize,
+    max_header_size: usize,
+    max_uri_length: usize,
+    max_body_size: Option<u64>,
+}
+
+impl ConnectionLimits {
+    /// Create the default limits.
+    ///
+    /// The number of connections and the size of bodies are unlimited. The
+    /// head of a request must be received within 30 seconds, with at most 100
+    /// headers, 32KiB and an 8KiB URI. The body must not pause for more than
+    /// 30 seconds, and idle connections are closed after 60 seconds.
+    pub fn new() -> ConnectionLimits {
+        ConnectionLimits {
+            max_connections: None,
+            header_read_timeout: Some(Duration::from_secs(30)),
+            body_read_timeout: Some(Duration::from_secs(30)),
+            keep_alive_timeout: Some(Duration::from_secs(60)),
+            max_headers: 100,
+            max_header_size: 32 << 10,
+            max_uri_length: 8 << 10,
+            max_body_size: None,
+        }
+    }
+
+    /// Set the maximum number of open connections.
+    ///
+    /// Once reached, new connections wait in the backlog of the listeners
+    /// until others are closed.
+    pub fn with_max_connections(mut self, max: Option<usize>) -> Self {
+        self.max_connections = max;
+        self
+    }
+
+    /// Set how long a client has to send the whole head of a request, from
+    /// its first byte, or from the start of the connection for the first
+    /// request.
+    pub fn with_header_read_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.header_read_timeout = timeout;
+        self
+    }
+
+    /// Set how long the body of a request may pause while the handler reads it.
+    pub fn with_body_read_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.body_read_timeout = timeout;
+        self
+    }
+
+    /// Set how long a connection may stay idle between two requests.
+    pub fn with_keep_alive_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.keep_alive_timeout = timeout;
+        self
+    }
+
+    /// Set the maximum number of headers of a request.
+    pub fn with_max_headers(mut self, max: usize) -> Self {
+        self.max_headers = max;
+        self
+    }
+
+    /// Set the maximum size of the head of a request, in bytes.
+    pub fn with_max_header_size(mut self, size: usize) -> Self {
+        self.max_header_size = size;
+        self
+    }
+
+    /// Set the maximum length of the request target, in bytes.
+    pub fn with_max_uri_length(mut self, length: usize) -> Self {
+        self.max_uri_length = length;
+        self
+    }
+
+    /// Set the maximum size of the body of a request, in bytes.
+    pub fn with_max_body_size(mut self, size: Option<u64>) -> Self {
+        self.max_body_size = size;
+        self
+    }
+}
+
+impl Default for ConnectionLimits {
+    fn default() -> ConnectionLimits {
+        ConnectionLimits::new()
+    }
+}
+
+// How the end of a message body is found.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Framing {
+    Length(u64),
+    Chunked(Chunked, u64),
+    // The body ends with the connection.
+    Close,
+}
+
+// Where a chunked body is at, the `u64` of `Framing::Chunked` being the size
+// of the chunk being read. The states are those of hyper's decoder, so both
+// agree on where a body ends.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Chunked {
+    Size,
+    SizeLws,
+    Extension,
+    SizeLf,
+    Data,
+    DataCr,
+    DataLf,
+    EndCr,
+    EndLf,
+}
+
+// A body whose framing can't be followed.
+#[derive(Debug, PartialEq, Eq)]
+struct Malformed;
+
+impl Framing {
+    // Consume the bytes of `bytes` which belong to the body.
+    fn consume(&mut self, bytes: &[u8]) -> Result<Consumed, Malformed> {
+        match *self {
+            Framing::Length(ref mut remaining) => {
+                let len = cmp::min(*remaining, bytes.len() as u64);
+                *remaining -= len;
+                Ok(Consumed { len: len as usize, payload: len, end: *remaining == 0 })
+            },
+            Framing::Chunked(ref mut state, ref mut size) => {
+                let mut position = 0;
+                let mut payload = 0;
+                while position < bytes.len() {
+                    if *state == Chunked::Data {
+                        let len = cmp::min(*size, (bytes.len() - position) as u64);
+                        position += len as usize;
+                        payload += len;
+                        *size -= len;
+                        if *size == 0 {
+                            *state = Chunked::DataCr;
+                        }
+                        continue;
+                    }
+
+                    let byte = bytes[position];
+                    position += 1;
+                    *state = match (*state, byte) {
+                        (Chunked::Size, b'\t') | (Chunked::Size, b' ') | (Chunked::SizeLws, b'\t') | (Chunked::SizeLws, b' ') => Chunked::SizeLws,
+                        (Chunked::Size, b';') | (Chunked::SizeLws, b';') => Chunked::Extension,
+                        (Chunked::Size, b'\r') | (Chunked::SizeLws, b'\r') | (Chunked::Extension, b'\r') => Chunked::SizeLf,
+                        (Chunked::Size, byte) => {
+                            let digit = (byte as char).to_digit(16).ok_or(Malformed)?;
+                            *size = size.checked_mul(16).and_then(|size| size.checked_add(u64::from(digit))).ok_or(Malformed)?;
+                            Chunked::Size
+                        },
+                        (Chunked::Extension, _) => Chunked::Extension,
+                        (Chunked::SizeLf, b'\n') if *size == 0 => Chunked::EndCr,
+                        (Chunked::SizeLf, b'\n') => Chunked::Data,
+                        (Chunked::DataCr, b'\r') => Chunked::DataLf,
+                        (Chunked::DataLf, b'\n') => Chunked::Size,
+                        (Chunked::EndCr, b'\r') => Chunked::EndLf,
+                        (Chunked::EndLf, b'\n') => return Ok(Consumed { len: position, payload, end: true }),
+                        _ => return Err(Malformed)
+                    };
+                }
+                Ok(Consumed { len: position, payload, end: false })
+            },
+            Framing::Close => Ok(Consumed { len: bytes.len(), payload: bytes.len() as u64, end: false })
+        }
+    }
+}
+
+// The part of some bytes belonging to a body.
+#[derive(Debug, PartialEq, Eq)]
+struct Consumed {
+    len: usize,
+    // The size of the payload in these bytes, without the chunked encoding.
+    payload: u64,
+    end: bool,
+}
+
+// The framing of a message body given by its headers, `None` when they
+// don't give any. As with hyper, a `Transfer-Encoding` not ending with
+// chunked and conflicting lengths are malformed.
+fn body_framing(headers: &[Header]) -> Result<Option<Framing>, Malformed> {
+    let values = |name: &'static str| headers.iter()
+        .filter(move |header| header.name.eq_ignore_ascii_case(name))
+        .map(|header| str::from_utf8(header.value).map_err(|_| Malformed));
+
+    let mut encodings = Vec::new();
+    for value in values("Transfer-Encoding") {
+        encodings.extend(value?.split(',').map(str::trim).filter(|encoding| !encoding.is_empty()));
+    }
+    if !encodings.is_empty() {
+        return match encodings.last() {
+            Some(last) if last.eq_ignore_ascii_case("chunked") => Ok(Some(Framing::Chunked(Chunked::Size, 0))),
+            _ => Err(Malformed)
+        };
+    }
+
+    let mut length = None;
+    for value in values("Content-Length") {
+        for value in value?.split(',') {
+            let value = value.trim().parse::<u64>().map_err(|_| Malformed)?;
+            if length.map(|length| length != value).unwrap_or(false) {
+                return Err(Malformed);
+            }
+            length = Some(value);
+        }
+    }
+    Ok(length.map(Framing::Length))
+}
+
+// Whether `head` holds the whole head of a message.
+fn is_complete_head(head: &[u8]) -> bool {
+    head.ends_with(b"\r\n\r\n") || head.ends_with(b"\n\n")
+}
+
+#[derive(Debug)]
+enum Reading {
+    Head,
+    Body(Framing),
+    // The connection is upgraded, or left to hyper after a malformed request.
+    Unlimited,
+}
+
+#[derive(Debug)]
+enum Writing {
+    Head,
+    Body(Framing),
+    Unlimited,
+}
+
+// What happens when the deadline of a connection is reached.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Expiry {
+    Close,
+    Reject,
+}
+
+/// Fail `future` if it does not resolve within `timeout`, dropping it along
+/// with the connection it owns.
+pub(crate) fn with_deadline<F>(future: F, timeout: Option<Duration>, handle: &Handle) -> Box<dyn Future<Item = F::Item, Error = ()>>
+    where F: Future + 'static
+{
+    let timeout = match timeout {
+        Some(timeout) => timeout,
+        None => return Box::new(future.map_err(|_| ()))
+    };
+    let timer = match Timeout::new(timeout, handle) {
+        Ok(timer) => timer,
+        Err(_) => return Box::new(::futures::future::err(()))
+    };
+    Box::new(future.select2(timer).then(|result| match result {
+        Ok(Either::A((item, _))) => Ok(item),
+        _ => Err(())
+    }))
+}
+
+/// A transport enforcing `ConnectionLimits` on an HTTP/1 connection.
+///
+/// The head of each request is held back until it is complete and within the
+/// limits, so hyper never sees rejected requests. The responses written by
+/// hyper are followed to tell idle connections from busy ones.
+pub(crate) struct LimitedIo<S> {
+    io: S,
+    limits: ConnectionLimits,
+    handle: Handle,
+    reading: Reading,
+    // The head of the next request, being received.
+    head: Vec<u8>,
+    head_started: Instant,
+    // The bytes received and accepted, not yet read by hyper.
+    received: Vec<u8>,
+    body_size: u64,
+    body_read: Instant,
+    requests: usize,
+    writing: Writing,
+    written_head: Vec<u8>,
+    // Whether each request waiting for its response is a HEAD request.
+    pending: VecDeque<bool>,
+    // Whether anything was written since the head of the last request.
+    responded: bool,
+    idle_since: Instant,
+    timer: Option<(Instant, Timeout)>,
+    // The response rejecting a request, left to write.
+    rejection: Option<Vec<u8>>,
+    closed: bool,
+}
+
+impl<S> LimitedIo<S>
+    where S: AsyncRead + AsyncWrite
+{
+    pub fn new(io: S, limits: ConnectionLimits, handle: &Handle) -> LimitedIo<S> {
+        let now = Instant::now();
+        LimitedIo {
+            io,
+            limits,
+            handle: handle.clone(),
+            reading: Reading::Head,
+            head: Vec::new(),
+            head_started: now,
+            received: Vec::new(),
+            body_size: 0,
+            body_read: now,
+            requests: 0,
+            writing: Writing::Head,
+            written_head: Vec::new(),
+            pending: VecDeque::new(),
+            responded: false,
+            idle_since: now,
+            timer: None,
+            rejection: None,
+            closed: false,
+        }
+    }
+
+    fn is_idle(&self) -> bool {
+        self.pending.is_empty() && matches!(self.writing, Writing::Head) && self.written_head.is_empty()
+    }
+
+    // Answer the current request with `status` and close the connection.
+    fn reject(&mut self, status: StatusCode) {
+        // Only answer when it can't mix with a response of hyper.
+        let can_respond = matches!(self.writing, Writing::Head) && self.written_head.is_empty() && match self.reading {
+            Reading::Head => self.pending.is_empty(),
+            _ => !self.responded
+        };
+        if can_respond {
+            let response = format!("HTTP/1.1 {}\r\nConnection: close\r\nContent-Length: 0\r\n\r\n", status);
+            self.rejection = Some(response.into_bytes());
+        }
+        self.close();
+    }
+
+    fn close(&mut self) {
+        self.closed = true;
+        self.head.clear();
+        self.received.clear();
+        self.timer = None;
+    }
+
+    fn write_rejection(&mut self) -> io::Result<()> {
+        if let Some(ref mut rejection) = self.rejection {
+            while !rejection.is_empty() {
+                let len = self.io.write(rejection)?;
+                if len == 0 {
+                    return Err(io::ErrorKind::WriteZero.into());
+                }
+                rejection.drain(..len);
+            }
+            self.io.flush()?;
+        }
+        Ok(())
+    }
+
+    // The deadline of the connection in its current state.
+    fn deadline(&self) -> Option<(Instant, Expiry)> {
+        match self.reading {
+            Reading::Head if self.head.is_empty() => {
+                if !self.is_idle() {
+                    None
+                } else if self.requests == 0 {
+                    self.limits.header_read_timeout.map(|timeout| (self.idle_since + timeout, Expiry::Close))
+                } else {
+                    self.limits.keep_alive_timeout.map(|timeout| (self.idle_since + timeout, Expiry::Close))
+                }
+            },
+            Reading::Head => self.limits.header_read_timeout.map(|timeout| (self.head_started + timeout, Expiry::Reject)),
+            Reading::Body(_) => self.limits.body_read_timeout.map(|timeout| (self.body_read + timeout, Expiry::Reject)),
+            Reading::Unlimited => None
+        }
+    }
+
+    fn poll_deadline(&mut self) -> io::Result<()> {
+        let (deadline, expiry) = match self.deadline() {
+            Some(deadline) => deadline,
+            None => {
+                self.timer = None;
+                return Ok(());
+            }
+        };
+
+        let expired = if Instant::now() >= deadline {
+            true
+        } else {
+            match self.timer {
+                Some((at, ref mut timeout)) if at == deadline => timeout.poll()?.is_ready(),
+                _ => {
+                    let mut timeout = Timeout::new_at(deadline, &self.handle)?;
+                    let expired = timeout.poll()?.is_ready();
+                    self.timer = Some((deadline, timeout));
+                    expired
+                }
+            }
+        };
+        if expired {
+            match expiry {
+                Expiry::Close => self.close(),
+                Expiry::Reject => self.reject(StatusCode::RequestTimeout)
+            }
+        }
+        Ok(())
+    }
+
+    // Follow the bytes received from the client.
+    fn on_received(&mut self, mut bytes: &[u8]) {
+        while !bytes.is_empty() && !self.closed {
+            match self.reading {
+                Reading::Head => {
+                    if self.head.is_empty() {
+                        // Empty lines before a request are ignored.
+                        while let Some((&(b'\r' | b'\n'), rest)) = bytes.split_first() {
+                            bytes = rest;
+                        }
+                        if bytes.is_empty() {
+                            return;
+                        }
+                        self.head_started = Instant::now();
+                    }
+
+                    let mut position = 0;
+                    while position < bytes.len() && !is_complete_head(&self.head) {
+                        self.head.push(bytes[position]);
+                        position += 1;
+                    }
+                    bytes = &bytes[position..];
+
+                    if is_complete_head(&self.head) {
+                        self.on_request_head();
+                    } else if let Some(status) = self.check_head(&self.head) {
+                        self.reject(status);
+                    }
+                },
+                Reading::Body(ref mut framing) => {
+                    let consumed = match framing.consume(bytes) {
+                        Ok(consumed) => consumed,
+                        Err(Malformed) => return self.reject(StatusCode::BadRequest)
+                    };
+                    self.received.extend_from_slice(&bytes[..consumed.len]);
+                    bytes = &bytes[consumed.len..];
+                    self.body_size += consumed.payload;
+                    self.body_read = Instant::now();
+
+                    if self.limits.max_body_size.map(|max| self.body_size > max).unwrap_or(false) {
+                        self.reject(StatusCode::PayloadTooLarge);
+                    } else if consumed.end {
+                        self.reading = Reading::Head;
+                    }
+                },
+                Reading::Unlimited => {
+                    self.received.extend_from_slice(bytes);
+                    return;
+                }
+            }
+        }
+    }
+
+    // Check the limits of a head, complete or not.
+    fn check_head(&self, head: &[u8]) -> Option<StatusCode> {
+        let request_line = head.split(|&byte| byte == b'\n').next().unwrap_or(head);
+        let uri_length = request_line.split(|&byte| byte == b' ').nth(1).map(|uri| uri.len()).unwrap_or(0);
+        if uri_length > self.limits.max_uri_length {
+            Some(StatusCode::UriTooLong)
+        } else if head.len() > self.limits.max_header_size {
+            Some(StatusCode::RequestHeaderFieldsTooLarge)
+        } else {
+            None
+        }
+    }
+
+    fn on_request_head(&mut self) {
+        let head = mem::take(&mut self.head);
+        if let Some(status) = self.check_head(&head) {
+            return self.reject(status);
+        }
+
+        let mut headers = vec![httparse::EMPTY_HEADER; self.limits.max_headers];
+        let mut request = httparse::Request::new(&mut headers);
+        let reading = match request.parse(&head) {
+            // A request asking for an upgrade stays within the limits until
+            // the upgrade is accepted by a 101 response.
+            Ok(Status::Complete(_)) => match body_framing(request.headers) {
+                Err(Malformed) => return self.reject(StatusCode::BadRequest),
+                Ok(Some(Framing::Length(length))) if self.limits.max_body_size.map(|max| length > max).unwrap_or(false) => {
+                    return self.reject(StatusCode::PayloadTooLarge);
+                },
+                Ok(Some(Framing::Length(0))) | Ok(None) => Reading::Head,
+                Ok(Some(framing)) => Reading::Body(framing)
+            },
+            Err(httparse::Error::TooManyHeaders) => return self.reject(StatusCode::RequestHeaderFieldsTooLarge),
+            // Hyper answers malformed requests.
+            _ => Reading::Unlimited
+        };
+
+        self.pending.push_back(request.method == Some("HEAD"));
+        self.requests += 1;
+        self.responded = false;
+        self.body_size = 0;
+        self.body_read = Instant::now();
+        self.reading = reading;
+        self.received.extend_from_slice(&head);
+    }
+
+    // Follow the bytes written by hyper.
+    fn on_written(&mut self, mut bytes: &[u8]) {
+        self.responded = true;
+        while !bytes.is_empty() {
+            match self.writing {
+                Writing::Head => {
+                    let mut position = 0;
+                    while position < bytes.len() && !is_complete_head(&self.written_head) {
+                        self.written_head.push(bytes[position]);
+                        position += 1;
+                    }
+                    bytes = &bytes[position..];
+                    if is_complete_head(&self.written_head) {
+                        self.on_response_head();
+                    }
+                },
+                Writing::Body(ref mut framing) => match framing.consume(bytes) {
+                    Ok(consumed) => {
+                        bytes = &bytes[consumed.len..];
+                        if consumed.end {
+                            self.on_response_end();
+                        }
+                    },
+                    Err(Malformed) => self.writing = Writing::Unlimited
+                },
+                Writing::Unlimited => return
+            }
+        }
+    }
+
+    fn on_response_head(&mut self) {
+        let head = mem::take(&mut self.written_head);
+        let mut headers = [httparse::EMPTY_HEADER; 128];
+        let mut response = httparse::Response::new(&mut headers);
+        let code = match response.parse(&head) {
+            Ok(Status::Complete(_)) => response.code.unwrap_or(0),
+            _ => {
+                self.writing = Writing::Unlimited;
+                return;
+            }
+        };
+
+        if code == 101 {
+            self.reading = Reading::Unlimited;
+            self.writing = Writing::Unlimited;
+            let head = mem::take(&mut self.head);
+            self.received.extend_from_slice(&head);
+            return;
+        }
+        if code < 200 {
+            // An interim response, the final one follows.
+            return;
+        }
+
+        let is_head = self.pending.pop_front().unwrap_or(false);
+        match body_framing(response.headers) {
+            _ if is_head || code == 204 || code == 304 => self.on_response_end(),
+            Ok(Some(Framing::Length(0))) => self.on_response_end(),
+            Ok(Some(framing)) => self.writing = Writing::Body(framing),
+            // The body of a response may end with the connection.
+            Ok(None) | Err(Malformed) => self.writing = Writing::Body(Framing::Close)
+        }
+    }
+
+    fn on_response_end(&mut self) {
+        self.writing = Writing::Head;
+        if self.pending.is_empty() {
+            self.idle_since = Instant::now();
+        }
+    }
+}
+
+impl<S> Read for LimitedIo<S>
+    where S: AsyncRead + AsyncWrite
+{
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut chunk = [0; 8192];
+        loop {
+            if !self.received.is_empty() {
+                let len = cmp::min(buf.len(), self.received.len());
+                buf[..len].copy_from_slice(&self.received[..len]);
+                self.received.drain(..len);
+                return Ok(len);
+            }
+            if self.closed {
+                self.write_rejection()?;
+                return Ok(0);
+            }
+
+            self.poll_deadline()?;
+            if self.closed {
+                continue;
+            }
+            match self.io.read(&mut chunk)? {
+                0 => return Ok(0),
+                len => self.on_received(&chunk[..len])
+            }
+        }
+    }
+}
+
+impl<S> Write for LimitedIo<S>
+    where S: AsyncRead + AsyncWrite
+{
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.rejection.is_some() {
+            // The connection is closing with a rejection.
+            return Ok(buf.len());
+        }
+        let len = self.io.write(buf)?;
+        self.on_written(&buf[..len]);
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.write_rejection()?;
+        self.io.flush()
+    }
+}
+
+impl<S> AsyncRead for LimitedIo<S>
+    where S: AsyncRead + AsyncWrite
+{}
+
+impl<S> AsyncWrite for LimitedIo<S>
+    where S: AsyncRead + AsyncWrite
+{
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        try_nb!(self.write_rejection());
+        self.io.shutdown()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::net::{SocketAddr, TcpStream};
+    use std::thread;
+
+    use futures::Stream;
+
+    use http2::{Http2Settings, PREFACE};
+    use {mime, Ferrum, Listening, Request, Response};
+
+    #[test]
+    fn test_chunked_framing() {
+        let mut framing = Framing::Chunked(Chunked::Size, 0);
+        assert_eq!(framing.consume(b"5;ext=1\r\nhel"), Ok(Consumed { len: 12, payload: 3, end: false }));
+        assert_eq!(framing.consume(b"lo\r\nA \r\n0123456789\r\n0\r\n"), Ok(Consumed { len: 23, payload: 12, end: false }));
+        assert_eq!(framing.consume(b"\r\nGET / HTTP/1.1"), Ok(Consumed { len: 2, payload: 0, end: true }));
+
+        let mut framing = Framing::Length(4);
+        assert_eq!(framing.consume(b"abcdef"), Ok(Consumed { len: 4, payload: 4, end: true }));
+
+        for malformed in &[&b"5x\r\n"[..], b"5 1\r\n", b"5\r\rhello", b"1\r\nab", b"0\r\nTrailer: x\r\n", b"fffffffffffffffff\r\n"] {
+            assert_eq!(Framing::Chunked(Chunked::Size, 0).consume(malformed), Err(Malformed));
+        }
+    }
+
+    #[test]
+    fn test_body_framing() {
+        let framing = |headers: &[(&'static str, &'static [u8])]| {
+            let headers: Vec<_> = headers.iter().map(|&(name, value)| Header { name, value }).collect();
+            body_framing(&headers)
+        };
+
+        assert_eq!(framing(&[]), Ok(None));
+        assert_eq!(framing(&[("Content-Length", b"3")]), Ok(Some(Framing::Length(3))));
+        assert_eq!(framing(&[("Content-Length", b"3"), ("content-length", b"3, 3")]), Ok(Some(Framing::Length(3))));
+        assert_eq!(framing(&[("Content-Length", b"3"), ("Content-Length", b"4")]), Err(Malformed));
+        assert_eq!(framing(&[("Content-Length", b"3, 4")]), Err(Malformed));
+        assert_eq!(framing(&[("Content-Length", b"-1")]), Err(Malformed));
+        assert_eq!(framing(&[("Transfer-Encoding", b"gzip"), ("Transfer-Encoding", b"chunked")]), Ok(Some(Framing::Chunked(Chunked::Size, 0))));
+        assert_eq!(framing(&[("Transfer-Encoding", b"chunked, gzip")]), Err(Malformed));
+        assert_eq!(framing(&[("Transfer-Encoding", b"identity")]), Err(Malformed));
+    }
+
+    fn spawn(limits: ConnectionLimits) -> Listening {
+        let mut ferrum = Ferrum::new(|request: &mut Request| {
+            // Hyper closes the connections of requests with unread bodies.
+            let _ = request.take_body().concat2().wait();
+            let body = format!("{} {}", request.method, request.uri);
+            Ok(Response::new().with_content(body, mime::TEXT_PLAIN))
+        });
+        ferrum.limits = limits;
+        ferrum.spawn("127.0.0.1:0").unwrap()
+    }
+
+    fn connect(addr: SocketAddr) -> TcpStream {
+        let stream = TcpStream::connect(addr).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        stream
+    }
+
+    fn send(addr: SocketAddr, request: &[u8]) -> String {
+        let mut stream = connect(addr);
+        stream.write_all(request).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    }
+
+    #[test]
+    fn test_head_limits() {
+        let listening = spawn(ConnectionLimits::new().with_max_headers(2).with_max_header_size(256).with_max_uri_length(32));
+        let addr = listening.local_addr().unwrap();
+
+        let response = send(addr, b"GET /ok HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 200 OK"), "{}", response);
+        assert!(response.ends_with("GET /ok"));
+
+        let long_uri = format!("GET /{} HTTP/1.1\r\n", "a".repeat(64));
+        let response = send(addr, long_uri.as_bytes());
+        assert!(response.starts_with("HTTP/1.1 414 URI Too Long"), "{}", response);
+
+        let response = send(addr, b"GET / HTTP/1.1\r\nHost: localhost\r\nA: 1\r\nB: 2\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 431 Request Header Fields Too Large"), "{}", response);
+
+        let large_header = format!("GET / HTTP/1.1\r\nCookie: {}\r\n", "c".repeat(300));
+        let response = send(addr, large_header.as_bytes());
+        assert!(response.starts_with("HTTP/1.1 431 Request Header Fields Too Large"), "{}", response);
+    }
+
+    #[test]
+    fn test_body_limits() {
+        let listening = spawn(ConnectionLimits::new().with_max_body_size(Some(8)));
+        let addr = listening.local_addr().unwrap();
+
+        let response = send(addr, b"POST / HTTP/1.1\r\nHost: localhost\r\nContent-Length: 9\r\n\r\n123456789");
+        assert!(response.starts_with("HTTP/1.1 413 Payload Too Large"), "{}", response);
+
+        let response = send(addr, b"POST / HTTP/1.1\r\nHost: localhost\r\nTransfer-Encoding: chunked\r\n\r\n9\r\n123456789\r\n0\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 413 Payload Too Large"), "{}", response);
+
+        // Keep-alive requests with bodies within the limits.
+        let mut stream = connect(addr);
+        stream.write_all(b"POST /a HTTP/1.1\r\nHost: localhost\r\nTransfer-Encoding: chunked\r\n\r\n3\r\nabc\r\n0\r\n\r\n\
+            POST /b HTTP/1.1\r\nHost: localhost\r\nContent-Length: 3\r\nConnection: close\r\n\r\nabc").unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        assert!(response.contains("POST /a"), "{}", response);
+        assert!(response.ends_with("POST /b"), "{}", response);
+    }
+
+    #[test]
+    fn test_malformed_framing() {
+        let listening = spawn(ConnectionLimits::new());
+        let addr = listening.local_addr().unwrap();
+
+        let response = send(addr, b"POST / HTTP/1.1\r\nHost: localhost\r\nContent-Length: 3\r\nContent-Length: 30\r\n\r\nabc");
+        assert!(response.starts_with("HTTP/1.1 400 Bad Request"), "{}", response);
+
+        let response = send(addr, b"POST / HTTP/1.1\r\nHost: localhost\r\nTransfer-Encoding: identity\r\n\r\nabc");
+        assert!(response.starts_with("HTTP/1.1 400 Bad Request"), "{}", response);
+
+        let response = send(addr, b"POST / HTTP/1.1\r\nHost: localhost\r\nTransfer-Encoding: chunked\r\n\r\n3x\r\nabc\r\n0\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 400 Bad Request"), "{}", response);
+    }
+
+    #[test]
+    fn test_upgrade_limits() {
+        let timeout = Some(Duration::from_millis(100));
+        let listening = spawn(ConnectionLimits::new().with_max_body_size(Some(8)).with_keep_alive_timeout(timeout));
+        let addr = listening.local_addr().unwrap();
+
+        // A request asking for an upgrade which isn't accepted keeps the limits.
+        let mut stream = connect(addr);
+        stream.write_all(b"GET /a HTTP/1.1\r\nHost: localhost\r\nUpgrade: x\r\nConnection: upgrade\r\n\r\n").unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK"), "{}", response);
+        assert!(response.ends_with("GET /a"), "{}", response);
+
+        let mut stream = connect(addr);
+        stream.write_all(b"GET /a HTTP/1.1\r\nHost: localhost\r\nUpgrade: x\r\n\r\n").unwrap();
+        let mut buf = [0; 1024];
+        let mut response = Vec::new();
+        while !response.ends_with(b"GET /a") {
+            let len = stream.read(&mut buf).unwrap();
+            assert!(len > 0);
+            response.extend_from_slice(&buf[..len]);
+        }
+        stream.write_all(b"POST /b HTTP/1.1\r\nHost: localhost\r\nContent-Length: 9\r\n\r\n123456789").unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 413 Payload Too Large\r\nConnection: close\r\nContent-Length: 0\r\n\r\n"), "{}", response);
+    }
+
+    #[test]
+    fn test_timeouts() {
+        let timeout = Some(Duration::from_millis(100));
+        let listening = spawn(ConnectionLimits::new().with_header_read_timeout(timeout).with_keep_alive_timeout(timeout));
+        let addr = listening.local_addr().unwrap();
+
+        // A slow client gets a 408.
+        let response = send(addr, b"GET / HTTP/1.1\r\nHost: loc");
+        assert!(response.starts_with("HTTP/1.1 408 Request Timeout"), "{}", response);
+
+        // An idle connection is closed after its response.
+        let mut stream = connect(addr);
+        stream.write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK"), "{}", response);
+        assert!(response.ends_with("GET /"));
+    }
+
+    #[test]
+    fn test_http2_timeouts() {
+        let timeout = Some(Duration::from_millis(100));
+        let mut ferrum = Ferrum::new(|_: &mut Request| Ok(Response::new()));
+        ferrum.http2 = Some(Http2Settings::new());
+        ferrum.limits = ConnectionLimits::new().with_header_read_timeout(timeout).with_keep_alive_timeout(timeout);
+        let listening = ferrum.spawn("127.0.0.1:0").unwrap();
+        let addr = listening.local_addr().unwrap();
+
+        // A client sending nothing is disconnected before the preface.
+        let mut response = Vec::new();
+        connect(addr).read_to_end(&mut response).unwrap();
+        assert!(response.is_empty());
+
+        // An idle HTTP/2 connection is sent a GOAWAY, then closed.
+        let mut stream = connect(addr);
+        stream.write_all(PREFACE).unwrap();
+        stream.write_all(&[0, 0, 0, 4, 0, 0, 0, 0, 0]).unwrap();
+        let mut frames = Vec::new();
+        stream.read_to_end(&mut frames).unwrap();
+        let mut types = Vec::new();
+        while frames.len() >= 9 {
+            let len = (frames[0] as usize) << 16 | (frames[1] as usize) << 8 | frames[2] as usize;
+            types.push(frames[3]);
+            frames.drain(..cmp::min(9 + len, frames.len()));
+        }
+        assert!(types.contains(&7), "{:?}", types);
+    }
+
+    #[test]
+    fn test_max_connections() {
+        let listening = spawn(ConnectionLimits::new().with_max_connections(Some(1)));
+        let addr = listening.local_addr().unwrap();
+
+        let mut first = connect(addr);
+        first.write_all(b"GET /first HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+        let mut buf = [0; 1024];
+        assert!(first.read(&mut buf).unwrap() > 0);
+
+        // The second connection waits for the first one to close.
+        let mut second = connect(addr);
+        second.set_read_timeout(Some(Duration::from_millis(200))).unwrap();
+        second.write_all(b"GET /second HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n").unwrap();
+        assert!(second.read(&mut buf).is_err());
+
+        drop(first);
+        thread::sleep(Duration::from_millis(50));
+        second.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let mut response = String::new();
+        second.read_to_string(&mut response).unwrap();
+        assert!(response.ends_with("GET /second"), "{}", response);
+    }
+}
@@ -0,0 +1,220 @@
+//! The listeners a `Ferrum` server can serve on.
+//!
+//! A single server can serve several listeners, sharing the same thread pool.
+//! Each `Endpoint` can be tagged, the tag being visible to handlers as
+//! `Request::listener_tag`, and can have its own handler:
+//!
+//! ```rust,no_run
+//! use ferrum::*;
+//!
+//! let app = |_: &mut Request| Ok(Response::new().with_content("Hello world!", mime::TEXT_PLAIN));
+//! let admin = |_: &mut Request| Ok(Response::new().with_content("Admin", mime::TEXT_PLAIN));
+//!
+//! Ferrum::new(app).serve(vec![
+//!     Endpoint::http("0.0.0.0:3000").unwrap().with_tag("public"),
+//!     Endpoint::http("127.0.0.1:3001").unwrap().with_tag("admin").with_handler(admin),
+//! ]).unwrap();
+//! ```
+
+use std::fmt;
+use std::io;
+use std::net::{self, SocketAddr, ToSocketAddrs};
+#[cfg(unix)]
+use std::os::unix::net::UnixListener;
+use middleware::Handler;
+#[cfg(feature = "tls")]
+use tls::TlsConfig;
+#[cfg(unix)]
+use unix::{SocketFile, UnixSocket};
+
+/// A listening socket, bound by the application or inherited from a parent
+/// process, given to `Ferrum::listen` or `Endpoint::listener`.
+pub enum Listener {
+    /// A TCP listener.
+    Tcp(net::TcpListener),
+    /// A Unix domain socket listener.
+    #[cfg(unix)]
+    Unix(UnixListener),
+}
+
+impl Listener {
+    /// The local address of a TCP listener.
+    pub fn local_addr(&self) -> io::Result<Option<SocketAddr>> {
+        match *self {
+            Listener::Tcp(ref listener) => listener.local_addr().map(Some),
+            #[cfg(unix)]
+            Listener::Unix(_) => Ok(None),
+        }
+    }
+}
+
+impl fmt::Debug for Listener {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Listener::Tcp(ref listener) => write!(formatter, "Listener::Tcp({:?})", listener.local_addr().ok()),
+            #[cfg(unix)]
+            Listener::Unix(ref listener) => write!(formatter, "Listener::Unix({:?})", listener.local_addr().ok()),
+        }
+    }
+}
+
+impl From<net::TcpListener> for Listener {
+    fn from(listener: net::TcpListener) -> Listener {
+        Listener::Tcp(listener)
+    }
+}
+
+#[cfg(unix)]
+impl From<UnixListener> for Listener {
+    fn from(listener: UnixListener) -> Listener {
+        Listener::Unix(listener)
+    }
+}
+
+// How the connections of an endpoint are served.
+pub(crate) enum Transport {
+    Plain,
+    #[cfg(feature = "tls")]
+    Tls(TlsConfig),
+}
+
+/// A listener served by `Ferrum::serve`, with its settings.
+pub struct Endpoint {
+    pub(crate) listener: Listener,
+    pub(crate) transport: Transport,
+    pub(crate) tag: Option<String>,
+    pub(crate) handler: Option<Box<dyn Handler>>,
+    #[cfg(unix)]
+    pub(crate) socket_file: Option<SocketFile>,
+}
+
+impl Endpoint {
+    /// Serve an already bound listener using the HTTP protocol.
+    pub fn listener<L>(listener: L) -> Endpoint
+        where L: Into<Listener>
+    {
+        Endpoint {
+            listener: listener.into(),
+            transport: Transport::Plain,
+            tag: None,
+            handler: None,
+            #[cfg(unix)]
+            socket_file: None,
+        }
+    }
+
+    /// Bind `addr` and serve it using the HTTP protocol.
+    pub fn http<A>(addr: A) -> io::Result<Endpoint>
+        where A: ToSocketAddrs
+    {
+        Ok(Endpoint::listener(net::TcpListener::bind(addr)?))
+    }
+
+    /// Bind `addr` and serve it using the HTTPS protocol.
+    ///
+    /// Available with the `tls` feature.
+    #[cfg(feature = "tls")]
+    pub fn https<A>(addr: A, config: TlsConfig) -> io::Result<Endpoint>
+        where A: ToSocketAddrs
+    {
+        // Report configuration errors before serving.
+        config.server_config()?;
+        let mut endpoint = Endpoint::http(addr)?;
+        endpoint.transport = Transport::Tls(config);
+        Ok(endpoint)
+    }
+
+    /// Bind a Unix domain socket and serve it using the HTTP protocol.
+    ///
+    /// The socket file is removed when the server stops.
+    #[cfg(unix)]
+    pub fn unix<S>(socket: S) -> io::Result<Endpoint>
+        where S: Into<UnixSocket>
+    {
+        let socket = socket.into();
+        let mut endpoint = Endpoint::listener(socket.bind()?);
+        endpoint.socket_file = Some(SocketFile(socket.path().to_path_buf()));
+        Ok(endpoint)
+    }
+
+    /// Tag the requests received on this endpoint, see `Request::listener_tag`.
+    pub fn with_tag<S: Into<String>>(mut self, tag: S) -> Self {
+        self.tag = Some(tag.into());
+        self
+    }
+
+    /// Handle the requests received on this endpoint with `handler` instead
+    /// of the handler of the server.
+    pub fn with_handler<H: Handler>(mut self, handler: H) -> Self {
+        self.handler = Some(Box::new(handler));
+        self
+    }
+
+    /// The local address of a TCP endpoint.
+    pub fn local_addr(&self) -> io::Result<Option<SocketAddr>> {
+        self.listener.local_addr()
+    }
+}
+
+impl fmt::Debug for Endpoint {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.debug_struct("Endpoint")
+            .field("listener", &self.listener)
+            .field("tls", &!matches!(self.transport, Transport::Plain))
+            .field("tag", &self.tag)
+            .field("handler", &self.handler.is_some())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::{Read, Write};
+
+    use {mime, Ferrum, Request, Response};
+
+    #[test]
+    fn test_listen_tcp() {
+        let listener = net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let listening = Ferrum::new(|request: &mut Request| {
+            let content = format!("{:?} {:?}", request.local_addr, request.remote_addr.is_some());
+            Ok(Response::new().with_content(content, mime::TEXT_PLAIN))
+        }).spawn_endpoints(vec![Endpoint::listener(listener)]).unwrap();
+
+        // The listener is already bound, so connecting can't race the server start.
+        let response = get(addr);
+
+        assert!(response.starts_with("HTTP/1.1 200 OK"));
+        assert!(response.ends_with(&format!("Some({:?}) true", addr)));
+        listening.shutdown().unwrap();
+    }
+
+    fn get(addr: SocketAddr) -> String {
+        let mut stream = net::TcpStream::connect(addr).unwrap();
+        stream.write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n").unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    }
+
+    #[test]
+    fn test_serve_endpoints() {
+        let public = Endpoint::http("127.0.0.1:0").unwrap().with_tag("public");
+        let admin = Endpoint::http("127.0.0.1:0").unwrap().with_tag("admin")
+            .with_handler(|_: &mut Request| Ok(Response::new().with_content("admin handler", mime::TEXT_PLAIN)));
+        let untagged = Endpoint::http("127.0.0.1:0").unwrap();
+        let addrs = [public.local_addr().unwrap().unwrap(), admin.local_addr().unwrap().unwrap(), untagged.local_addr().unwrap().unwrap()];
+
+        let listening = Ferrum::new(|request: &mut Request| {
+            let content = format!("tag {:?}", request.listener_tag);
+            Ok(Response::new().with_content(content, mime::TEXT_PLAIN))
+        }).spawn_endpoints(vec![public, admin, untagged]).unwrap();
+
+        assert!(get(addrs[0]).ends_with("tag Some(\"public\")"));
+        assert!(get(addrs[1]).ends_with("admin handler"));
+        assert!(get(addrs[2]).ends_with("tag None"));
+        listening.shutdown().unwrap();
+    }
+}
@@ -0,0 +1,455 @@
+//! Request metrics in the Prometheus text exposition format.
+//!
+//! `Metrics` is an `AroundMiddleware` counting the requests, their errors and
+//! their latency, labelled by route, method and status, along with a gauge of
+//! the requests in flight. Methods other than the standard ones are labelled
+//! `OTHER`. `Metrics::handler` renders them, to be served on
+//! `/metrics`:
+//!
+//! ```rust,no_run
+//! use ferrum::*;
+//! use ferrum::metrics::Metrics;
+//!
+//! # fn hello(_: &mut Request) -> FerrumResult<Response> {
+//! #     Ok(Response::new().with_content("Hello world!", mime::TEXT_PLAIN))
+//! # }
+//! let metrics = Metrics::new();
+//! let mut app = Chain::new(hello);
+//! app.link_around(metrics.clone());
+//!
+//! let mut ferrum = Ferrum::new(app);
+//! ferrum.metrics = Some(metrics.clone());
+//! ferrum.serve(vec![
+//!     Endpoint::http("0.0.0.0:3000").unwrap(),
+//!     Endpoint::http("127.0.0.1:9090").unwrap().with_handler(metrics.handler()),
+//! ]).unwrap();
+//! ```
+//!
+//! Setting `Ferrum::metrics` also reports the internals of the server: the
+//! depth of the work queue, the open connections and the `FerrumError`s
+//! turned into responses.
+//!
+//! The route of a request is the `Route` stored in its extensions, e.g. by a
+//! router, see `Metrics::with_route` to compute it otherwise. Labelling by the
+//! raw path would create a series per URL.
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Instant;
+
+use mime::Mime;
+
+use {Method, Request, Response, FerrumResult, StatusCode};
+use connection::ActiveConnections;
+use middleware::{AroundMiddleware, Handler};
+use queue::QueueStats;
+use typemap::Key;
+
+/// The default upper bounds of the latency histogram buckets, in seconds.
+pub const DEFAULT_BUCKETS: [f64; 11] = [0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+
+/// The route of a request, e.g. `/users/:id`, used as the `route` label.
+pub struct Route;
+
+impl Key for Route {
+    type Value = String;
+}
+
+// The route of requests without `Route`.
+const UNMATCHED: &str = "unmatched";
+
+type RouteFn = Arc<dyn Fn(&Request) -> String + Send + Sync>;
+
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+struct Labels {
+    route: String,
+    method: String,
+    status: u16,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+struct InFlightLabels {
+    route: String,
+    method: String,
+}
+
+struct Series {
+    requests: u64,
+    errors: u64,
+    // The number of requests in each bucket, not cumulated.
+    buckets: Vec<u64>,
+    sum: f64,
+}
+
+#[derive(Default)]
+struct Store {
+    series: BTreeMap<Labels, Series>,
+    in_flight: BTreeMap<InFlightLabels, i64>,
+}
+
+// The sources of the server metrics.
+#[derive(Default)]
+struct Server {
+    connections: Option<Arc<ActiveConnections>>,
+    queue: Option<Arc<QueueStats>>,
+}
+
+struct Inner {
+    buckets: Vec<f64>,
+    route: RouteFn,
+    store: Mutex<Store>,
+    server: Mutex<Server>,
+    errors: AtomicU64,
+}
+
+/// An `AroundMiddleware` recording request metrics.
+///
+/// `Metrics` is cheaply clonable, all the clones sharing the same metrics.
+#[derive(Clone)]
+pub struct Metrics {
+    inner: Arc<Inner>,
+}
+
+impl Metrics {
+    /// Create empty metrics, with the `DEFAULT_BUCKETS` latency histogram.
+    pub fn new() -> Metrics {
+        Metrics::build(DEFAULT_BUCKETS.to_vec(), Arc::new(|request: &Request| {
+            request.extensions.get::<Route>().cloned().unwrap_or_else(|| UNMATCHED.to_string())
+        }))
+    }
+
+    /// Use the given upper bounds for the latency histogram buckets, in seconds.
+    ///
+    /// Bounds which are not finite are ignored, the `+Inf` bucket is always
+    /// rendered.
+    pub fn with_buckets(self, mut buckets: Vec<f64>) -> Self {
+        buckets.retain(|bound| bound.is_finite());
+        buckets.sort_by(f64::total_cmp);
+        buckets.dedup();
+        Metrics::build(buckets, self.inner.route.clone())
+    }
+
+    /// Compute the route of requests with a custom function.
+    ///
+    /// The function is called before the request is handled, for the in
+    /// flight gauge, and after.
+    pub fn with_route<F>(self, route: F) -> Self
+        where F: Fn(&Request) -> String + Send + Sync + 'static
+    {
+        let buckets = self.inner.buckets.clone();
+        Metrics::build(buckets, Arc::new(route))
+    }
+
+    /// Get a `Handler` rendering the metrics in the Prometheus text format.
+    pub fn handler(&self) -> MetricsHandler {
+        MetricsHandler { metrics: self.clone() }
+    }
+
+    /// Render the metrics in the Prometheus text exposition format.
+    pub fn render(&self) -> String {
+        let mut output = String::new();
+        self.render_requests(&mut output);
+        self.render_server(&mut output);
+        output
+    }
+
+    fn build(buckets: Vec<f64>, route: RouteFn) -> Metrics {
+        Metrics {
+            inner: Arc::new(Inner {
+                buckets,
+                route,
+                store: Mutex::new(Store::default()),
+                server: Mutex::new(Server::default()),
+                errors: AtomicU64::new(0),
+            })
+        }
+    }
+
+    /// Report the internals of the server, see `Ferrum::metrics`.
+    pub(crate) fn observe_server(&self, connections: Option<Arc<ActiveConnections>>, queue: Arc<QueueStats>) {
+        *self.inner.server.lock().unwrap() = Server { connections, queue: Some(queue) };
+    }
+
+    /// Count a `FerrumError` turned into a response by the server.
+    pub(crate) fn count_error(&self) {
+        self.inner.errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn record(&self, labels: Labels, seconds: f64, error: bool) {
+        let buckets = &self.inner.buckets;
+        let mut store = self.inner.store.lock().unwrap();
+        let series = store.series.entry(labels).or_insert_with(|| Series {
+            requests: 0,
+            errors: 0,
+            buckets: vec![0; buckets.len()],
+            sum: 0.0,
+        });
+        series.requests += 1;
+        if error {
+            series.errors += 1;
+        }
+        if let Some(bucket) = buckets.iter().position(|&bound| seconds <= bound) {
+            series.buckets[bucket] += 1;
+        }
+        series.sum += seconds;
+    }
+
+    fn add_in_flight(&self, labels: &InFlightLabels, delta: i64) {
+        let mut store = self.inner.store.lock().unwrap();
+        *store.in_flight.entry(labels.clone()).or_insert(0) += delta;
+    }
+
+    fn render_requests(&self, output: &mut String) {
+        let store = self.inner.store.lock().unwrap();
+
+        header(output, "ferrum_http_requests_total", "counter", "Number of handled HTTP requests.");
+        for (labels, series) in &store.series {
+            let _ = writeln!(output, "ferrum_http_requests_total{{{}}} {}", labels, series.requests);
+        }
+
+        header(output, "ferrum_http_request_errors_total", "counter", "Number of HTTP requests handled with an error.");
+        for (labels, series) in store.series.iter().filter(|(_, series)| series.errors > 0) {
+            let _ = writeln!(output, "ferrum_http_request_errors_total{{{}}} {}", labels, series.errors);
+        }
+
+        header(output, "ferrum_http_request_duration_seconds", "histogram", "Latency of HTTP requests.");
+        for (labels, series) in &store.series {
+            let mut cumulated = 0;
+            for (bound, count) in self.inner.buckets.iter().zip(&series.buckets) {
+                cumulated += count;
+                let _ = writeln!(output, "ferrum_http_request_duration_seconds_bucket{{{},le=\"{}\"}} {}", labels, bound, cumulated);
+            }
+            let _ = writeln!(output, "ferrum_http_request_duration_seconds_bucket{{{},le=\"+Inf\"}} {}", labels, series.requests);
+            let _ = writeln!(output, "ferrum_http_request_duration_seconds_sum{{{}}} {}", labels, series.sum);
+            let _ = writeln!(output, "ferrum_http_request_duration_seconds_count{{{}}} {}", labels, series.requests);
+        }
+
+        header(output, "ferrum_http_requests_in_flight", "gauge", "Number of HTTP requests being handled.");
+        for (labels, count) in &store.in_flight {
+            let _ = writeln!(output, "ferrum_http_requests_in_flight{{route=\"{}\",method=\"{}\"}} {}",
+                escape(&labels.route), escape(&labels.method), count);
+        }
+    }
+
+    fn render_server(&self, output: &mut String) {
+        let server = self.inner.server.lock().unwrap();
+        if let Some(ref queue) = server.queue {
+            header(output, "ferrum_work_queue_depth", "gauge", "Number of requests waiting for a handler thread.");
+            let _ = writeln!(output, "ferrum_work_queue_depth {}", queue.depth());
+            header(output, "ferrum_work_queue_rejected_total", "counter", "Number of requests rejected by the full work queue.");
+            let _ = writeln!(output, "ferrum_work_queue_rejected_total {}", queue.rejected());
+            header(output, "ferrum_work_queue_expired_total", "counter", "Number of requests which waited too long in the work queue.");
+            let _ = writeln!(output, "ferrum_work_queue_expired_total {}", queue.expired());
+        }
+        if let Some(ref connections) = server.connections {
+            header(output, "ferrum_active_connections", "gauge", "Number of open connections.");
+            let _ = writeln!(output, "ferrum_active_connections {}", connections.count());
+        }
+        header(output, "ferrum_errors_total", "counter", "Number of FerrumErrors turned into responses.");
+        let _ = writeln!(output, "ferrum_errors_total {}", self.inner.errors.load(Ordering::Relaxed));
+    }
+}
+
+impl Default for Metrics {
+    fn default() -> Metrics {
+        Metrics::new()
+    }
+}
+
+impl ::std::fmt::Display for Labels {
+    fn fmt(&self, formatter: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        write!(formatter, "route=\"{}\",method=\"{}\",status=\"{}\"", escape(&self.route), escape(&self.method), self.status)
+    }
+}
+
+fn header(output: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(output, "# HELP {} {}", name, help);
+    let _ = writeln!(output, "# TYPE {} {}", name, kind);
+}
+
+// Escape a label value.
+fn escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+impl AroundMiddleware for Metrics {
+    fn around(self, handler: Box<dyn Handler>) -> Box<dyn Handler> {
+        Box::new(MetricsRecorder {
+            metrics: self,
+            handler
+        })
+    }
+}
+
+struct MetricsRecorder {
+    metrics: Metrics,
+    handler: Box<dyn Handler>,
+}
+
+// Counts a request in flight until dropped, even if its handler panics.
+struct InFlight<'a> {
+    metrics: &'a Metrics,
+    labels: InFlightLabels,
+}
+
+impl<'a> InFlight<'a> {
+    fn new(metrics: &'a Metrics, labels: InFlightLabels) -> InFlight<'a> {
+        metrics.add_in_flight(&labels, 1);
+        InFlight { metrics, labels }
+    }
+}
+
+impl<'a> Drop for InFlight<'a> {
+    fn drop(&mut self) {
+        self.metrics.add_in_flight(&self.labels, -1);
+    }
+}
+
+// The method label of a request, keeping the cardinality of the metrics bounded.
+fn method_label(method: &Method) -> String {
+    match *method {
+        Method::Extension(_) => "OTHER".to_string(),
+        ref method => method.to_string()
+    }
+}
+
+impl Handler for MetricsRecorder {
+    fn handle(&self, request: &mut Request) -> FerrumResult<Response> {
+        let method = method_label(&request.method);
+        let labels = InFlightLabels { route: (self.metrics.inner.route)(request), method: method.clone() };
+        let in_flight = InFlight::new(&self.metrics, labels);
+        let start = Instant::now();
+
+        let result = self.handler.handle(request);
+
+        let elapsed = start.elapsed();
+        let seconds = elapsed.as_secs() as f64 + f64::from(elapsed.subsec_nanos()) / 1e9;
+        let status = match result {
+            Ok(ref response) => response.status,
+            Err(ref error) => error.response.as_ref().map(|response| response.status).unwrap_or(StatusCode::InternalServerError)
+        };
+        let labels = Labels { route: (self.metrics.inner.route)(request), method, status: status.as_u16() };
+        self.metrics.record(labels, seconds, result.is_err());
+        drop(in_flight);
+        result
+    }
+}
+
+/// A `Handler` rendering `Metrics` in the Prometheus text format.
+pub struct MetricsHandler {
+    metrics: Metrics,
+}
+
+impl Handler for MetricsHandler {
+    fn handle(&self, _: &mut Request) -> FerrumResult<Response> {
+        let mime: Mime = "text/plain; version=0.0.4; charset=utf-8".parse().unwrap();
+        Ok(Response::new().with_content(self.metrics.render(), mime))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::{Read, Write as IoWrite};
+    use std::net::TcpStream;
+
+    use {mime, Chain, Ferrum, FerrumError};
+    use queue::WorkQueue;
+
+    #[derive(Debug)]
+    struct Failure;
+
+    impl ::std::fmt::Display for Failure {
+        fn fmt(&self, formatter: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+            formatter.write_str("Failure")
+        }
+    }
+
+    impl ::std::error::Error for Failure {}
+
+    #[test]
+    fn test_record_requests() {
+        let metrics = Metrics::new().with_buckets(vec![1.0, 0.1]);
+        let mut chain = Chain::new(|request: &mut Request| {
+            request.extensions.insert::<Route>("/users/:id".to_string());
+            if request.uri.path() == "/fail" {
+                let response = Response::new().with_status(StatusCode::BadRequest);
+                return Err(FerrumError::new(Failure, Some(response)));
+            }
+            Ok(Response::new().with_content("user", mime::TEXT_PLAIN))
+        });
+        chain.link_around(metrics.clone());
+
+        let mut request = Request::stub();
+        chain.handle(&mut request).unwrap();
+        chain.handle(&mut request).unwrap();
+        request.uri = "/fail".parse().unwrap();
+        assert!(chain.handle(&mut request).is_err());
+
+        let output = metrics.render();
+        let ok = "route=\"/users/:id\",method=\"GET\",status=\"200\"";
+        let failed = "route=\"/users/:id\",method=\"GET\",status=\"400\"";
+        assert!(output.contains(&format!("ferrum_http_requests_total{{{}}} 2\n", ok)), "{}", output);
+        assert!(output.contains(&format!("ferrum_http_requests_total{{{}}} 1\n", failed)), "{}", output);
+        assert!(output.contains(&format!("ferrum_http_request_errors_total{{{}}} 1\n", failed)), "{}", output);
+        assert!(!output.contains(&format!("ferrum_http_request_errors_total{{{}}}", ok)), "{}", output);
+        assert!(output.contains(&format!("ferrum_http_request_duration_seconds_bucket{{{},le=\"0.1\"}} 2\n", ok)), "{}", output);
+        assert!(output.contains(&format!("ferrum_http_request_duration_seconds_bucket{{{},le=\"+Inf\"}} 2\n", ok)), "{}", output);
+        assert!(output.contains(&format!("ferrum_http_request_duration_seconds_count{{{}}} 2\n", ok)), "{}", output);
+        // The route is only known once handled.
+        assert!(output.contains("ferrum_http_requests_in_flight{route=\"unmatched\",method=\"GET\"} 0\n"), "{}", output);
+    }
+
+    #[test]
+    fn test_labels_and_buckets() {
+        let metrics = Metrics::new().with_buckets(vec![0.5, f64::NAN, f64::INFINITY, 0.1]);
+        assert_eq!(metrics.inner.buckets, [0.1, 0.5]);
+
+        let mut chain = Chain::new(|request: &mut Request| -> FerrumResult<Response> {
+            if request.uri.path() == "/panic" {
+                panic!("handler failure");
+            }
+            Ok(Response::new())
+        });
+        chain.link_around(metrics.clone());
+
+        let mut request = Request::stub();
+        request.method = Method::Extension("BREW".to_string());
+        chain.handle(&mut request).unwrap();
+        request.method = Method::Get;
+        request.uri = "/panic".parse().unwrap();
+        let panicked = ::std::panic::catch_unwind(::std::panic::AssertUnwindSafe(|| chain.handle(&mut request)));
+        assert!(panicked.is_err());
+
+        let output = metrics.render();
+        assert!(output.contains("ferrum_http_requests_total{route=\"unmatched\",method=\"OTHER\",status=\"200\"} 1\n"), "{}", output);
+        assert!(!output.contains("BREW"), "{}", output);
+        assert!(output.contains("ferrum_http_requests_in_flight{route=\"unmatched\",method=\"GET\"} 0\n"), "{}", output);
+    }
+
+    #[test]
+    fn test_escape_labels() {
+        assert_eq!(escape("a\"b\\c\nd"), "a\\\"b\\\\c\\nd");
+    }
+
+    #[test]
+    fn test_server_metrics() {
+        let metrics = Metrics::new();
+        let mut ferrum = Ferrum::new(metrics.handler());
+        ferrum.metrics = Some(metrics.clone());
+        ferrum.work_queue = WorkQueue::new().with_max_depth(Some(10));
+        let listening = ferrum.spawn("127.0.0.1:0").unwrap();
+
+        let mut stream = TcpStream::connect(listening.local_addr().unwrap()).unwrap();
+        stream.write_all(b"GET /metrics HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n").unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+
+        assert!(response.contains("Content-Type: text/plain; version=0.0.4; charset=utf-8"), "{}", response);
+        assert!(response.contains("ferrum_active_connections 1\n"), "{}", response);
+        assert!(response.contains("ferrum_work_queue_depth 0\n"), "{}", response);
+        assert!(response.contains("ferrum_errors_total 0\n"), "{}", response);
+    }
+}
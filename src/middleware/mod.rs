@@ -124,8 +124,13 @@
 //! `Response` should be run during both the normal and error flow by
 //! implementing the `catch` method to also do the necessary action.
 
+use std::any::type_name;
+use std::fmt;
 use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
 use {Request, Response, FerrumResult, FerrumError};
+use trace::CurrentSpan;
+use typemap::Key;
 
 /// `Handler`s are responsible for handling requests by creating Responses from Requests.
 pub trait Handler: Send + Sync + 'static {
@@ -202,12 +207,26 @@ pub trait AroundMiddleware {
 /// This is a canonical implementation of Ferrum's middleware system,
 /// but Ferrum's infrastructure is flexible enough to allow alternate
 /// systems.
+///
+/// The links of a `Chain` have names, their type names unless given with
+/// the `*_named` methods, listed by `describe`. The links of a `Chain` handling
+/// a request traced by `trace::Tracing` are recorded as child spans of its
+/// `CurrentSpan`, and a profiling `Chain` records them in the `ChainProfile`
+/// of the request.
 pub struct Chain {
-    befores: Vec<Box<BeforeMiddleware>>,
-    afters: Vec<Box<AfterMiddleware>>,
+    befores: Vec<Link<dyn BeforeMiddleware>>,
+    afters: Vec<Link<dyn AfterMiddleware>>,
 
     // Internal invariant: this is always Some
-    handler: Option<Box<Handler>>
+    handler: Option<Box<dyn Handler>>,
+    handler_name: String,
+    profiling: bool
+}
+
+// A middleware of a `Chain` along with its name.
+struct Link<M: ?Sized> {
+    name: String,
+    middleware: Box<M>
 }
 
 impl Chain {
@@ -216,7 +235,9 @@ impl Chain {
         Chain {
             befores: vec![],
             afters: vec![],
-            handler: Some(Box::new(handler) as Box<Handler>)
+            handler: Some(Box::new(handler) as Box<dyn Handler>),
+            handler_name: type_name::<H>().to_string(),
+            profiling: false
         }
     }
 
@@ -228,9 +249,19 @@ impl Chain {
         where A: AfterMiddleware, B: BeforeMiddleware
     {
         let (before, after) = link;
-        self.befores.push(Box::new(before) as Box<BeforeMiddleware>);
-        self.afters.push(Box::new(after) as Box<AfterMiddleware>);
-        self
+        self.link_before(before);
+        self.link_after(after)
+    }
+
+    /// Link both a before and after middleware to the chain at once, under
+    /// the given name.
+    pub fn link_named<S, B, A>(&mut self, name: S, link: (B, A)) -> &mut Chain
+        where S: Into<String>, A: AfterMiddleware, B: BeforeMiddleware
+    {
+        let name = name.into();
+        let (before, after) = link;
+        self.link_before_named(name.clone(), before);
+        self.link_after_named(name, after)
     }
 
     /// Link a `BeforeMiddleware` to the `Chain`, after all previously linked
@@ -238,7 +269,14 @@ impl Chain {
     pub fn link_before<B>(&mut self, before: B) -> &mut Chain
         where B: BeforeMiddleware
     {
-        self.befores.push(Box::new(before) as Box<BeforeMiddleware>);
+        self.link_before_named(type_name::<B>(), before)
+    }
+
+    /// Link a `BeforeMiddleware` to the `Chain` under the given name.
+    pub fn link_before_named<S, B>(&mut self, name: S, before: B) -> &mut Chain
+        where S: Into<String>, B: BeforeMiddleware
+    {
+        self.befores.push(Link { name: name.into(), middleware: Box::new(before) as Box<dyn BeforeMiddleware> });
         self
     }
 
@@ -247,7 +285,14 @@ impl Chain {
     pub fn link_after<A>(&mut self, after: A) -> &mut Chain
         where A: AfterMiddleware
     {
-        self.afters.push(Box::new(after) as Box<AfterMiddleware>);
+        self.link_after_named(type_name::<A>(), after)
+    }
+
+    /// Link a `AfterMiddleware` to the `Chain` under the given name.
+    pub fn link_after_named<S, A>(&mut self, name: S, after: A) -> &mut Chain
+        where S: Into<String>, A: AfterMiddleware
+    {
+        self.afters.push(Link { name: name.into(), middleware: Box::new(after) as Box<dyn AfterMiddleware> });
         self
     }
 
@@ -264,12 +309,175 @@ impl Chain {
     /// Apply an `AroundMiddleware` to the `Handler` in this `Chain`.
     pub fn link_around<A>(&mut self, around: A) -> &mut Chain
         where A: AroundMiddleware
+    {
+        self.link_around_named(type_name::<A>(), around)
+    }
+
+    /// Apply an `AroundMiddleware` to the `Handler` in this `Chain`, the
+    /// handler being then named `name(handler)`.
+    pub fn link_around_named<S, A>(&mut self, name: S, around: A) -> &mut Chain
+        where S: Into<String>, A: AroundMiddleware
     {
         let mut handler = self.handler.take().unwrap();
         handler = around.around(handler);
         self.handler = Some(handler);
+        self.handler_name = format!("{}({})", name.into(), self.handler_name);
         self
     }
+
+    /// Record the duration of each link in the `ChainProfile` of the requests.
+    ///
+    /// Profiling is disabled by default.
+    pub fn profile(&mut self, enabled: bool) -> &mut Chain {
+        self.profiling = enabled;
+        self
+    }
+
+    /// List the links of the `Chain`, in the order they handle requests.
+    ///
+    /// ```rust
+    /// use ferrum::*;
+    ///
+    /// # fn hello(_: &mut Request) -> FerrumResult<Response> { Ok(Response::new()) }
+    /// # fn check(_: &mut Request) -> FerrumResult<()> { Ok(()) }
+    /// # fn log(_: &mut Request, response: Response) -> FerrumResult<Response> { Ok(response) }
+    /// let mut chain = Chain::new(hello);
+    /// chain.link_before_named("auth", check);
+    /// chain.link_after_named("log", log);
+    ///
+    /// // The handler is named after its type, as given by `std::any::type_name`,
+    /// // e.g. `handler: my_app::hello` for a function at the root of `my_app`.
+    /// let description = chain.describe();
+    /// let lines = description.lines().collect::<Vec<_>>();
+    /// assert_eq!(lines[0], "before 0: auth");
+    /// assert!(lines[1].starts_with("handler: ") && lines[1].ends_with("::hello"));
+    /// assert_eq!(lines[2], "after 0: log");
+    /// ```
+    pub fn describe(&self) -> String {
+        let mut description = String::new();
+        for (index, before) in self.befores.iter().enumerate() {
+            description.push_str(&format!("before {}: {}\n", index, before.name));
+        }
+        description.push_str(&format!("handler: {}\n", self.handler_name));
+        for (index, after) in self.afters.iter().enumerate() {
+            description.push_str(&format!("after {}: {}\n", index, after.name));
+        }
+        description
+    }
+
+    // Start timing a link, when the request is traced or profiled.
+    fn timer(&self, req: &Request) -> LinkTimer {
+        let start = if self.profiling || req.extensions.contains::<CurrentSpan>() {
+            Some((SystemTime::now(), Instant::now()))
+        } else {
+            None
+        };
+        LinkTimer { start, profiling: self.profiling }
+    }
+}
+
+/// The kind of a link of a `Chain`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LinkKind {
+    /// A `BeforeMiddleware`.
+    Before,
+    /// The `Handler`.
+    Handler,
+    /// An `AfterMiddleware`.
+    After
+}
+
+/// The method of a link called by a `Chain`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Phase {
+    /// `BeforeMiddleware::before`.
+    Before,
+    /// `Handler::handle`.
+    Handle,
+    /// `AfterMiddleware::after`.
+    After,
+    /// `BeforeMiddleware::catch` or `AfterMiddleware::catch`.
+    Catch
+}
+
+impl fmt::Display for Phase {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str(match *self {
+            Phase::Before => "before",
+            Phase::Handle => "handle",
+            Phase::After => "after",
+            Phase::Catch => "catch"
+        })
+    }
+}
+
+/// A call of a link by a profiling `Chain`.
+#[derive(Debug, Clone)]
+pub struct LinkProfile {
+    /// The kind of the link.
+    pub kind: LinkKind,
+    /// The position of the link among the links of its kind.
+    pub index: usize,
+    /// The name of the link.
+    pub name: String,
+    /// The method called.
+    pub phase: Phase,
+    /// How long the call took.
+    pub duration: Duration,
+    /// Whether the call returned an error.
+    pub error: bool
+}
+
+/// The calls of the links of profiling `Chain`s handling a request, in its
+/// extensions.
+///
+/// ```rust
+/// use ferrum::*;
+/// use ferrum::middleware::ChainProfile;
+///
+/// # fn hello(_: &mut Request) -> FerrumResult<Response> { Ok(Response::new()) }
+/// let mut chain = Chain::new(hello);
+/// chain.link_after_named("log", |request: &mut Request, response: Response| {
+///     for call in request.extensions.get::<ChainProfile>().into_iter().flatten() {
+///         println!("{} {} took {:?}", call.phase, call.name, call.duration);
+///     }
+///     Ok(response)
+/// });
+/// chain.profile(true);
+/// ```
+pub struct ChainProfile;
+
+impl Key for ChainProfile {
+    type Value = Vec<LinkProfile>;
+}
+
+// Times a call of a link of a chain.
+struct LinkTimer {
+    start: Option<(SystemTime, Instant)>,
+    profiling: bool
+}
+
+impl LinkTimer {
+    // Record the call as a child span of traced requests, and in the
+    // profile of profiled ones.
+    fn finish(self, req: &mut Request, kind: LinkKind, index: usize, name: &str, phase: Phase, error: bool) {
+        let (start, started) = match self.start {
+            Some(start) => start,
+            None => return
+        };
+        let duration = started.elapsed();
+        if let Some(span) = req.extensions.get_mut::<CurrentSpan>() {
+            span.record_child(format!("{} {}", phase, name), start, duration, error);
+        }
+        if self.profiling {
+            let call = LinkProfile { kind, index, name: name.to_string(), phase, duration, error };
+            if let Some(calls) = req.extensions.get_mut::<ChainProfile>() {
+                calls.push(call);
+                return;
+            }
+            req.extensions.insert::<ChainProfile>(vec![call]);
+        }
+    }
 }
 
 impl Handler for Chain {
@@ -296,7 +504,10 @@ impl Chain {
         }
 
         for (i, before) in self.befores[index..].iter().enumerate() {
-            err = match before.catch(req, err) {
+            let timer = self.timer(req);
+            let result = before.middleware.catch(req, err);
+            timer.finish(req, LinkKind::Before, index + i, &before.name, Phase::Catch, result.is_err());
+            err = match result {
                 Err(err) => err,
                 Ok(()) => return self.continue_from_before(req, index + i + 1)
             };
@@ -325,7 +536,10 @@ impl Chain {
         if index == self.afters.len() { return Err(err) }
 
         for (i, after) in self.afters[index..].iter().enumerate() {
-            err = match after.catch(req, err) {
+            let timer = self.timer(req);
+            let result = after.middleware.catch(req, err);
+            timer.finish(req, LinkKind::After, index + i, &after.name, Phase::Catch, result.is_err());
+            err = match result {
                 Err(err) => err,
                 Ok(res) => return self.continue_from_after(req, index + i + 1, res)
             }
@@ -345,7 +559,10 @@ impl Chain {
         }
 
         for (i, before) in self.befores[index..].iter().enumerate() {
-            match before.before(req) {
+            let timer = self.timer(req);
+            let result = before.middleware.before(req);
+            timer.finish(req, LinkKind::Before, index + i, &before.name, Phase::Before, result.is_err());
+            match result {
                 Ok(()) => {},
                 Err(err) => return self.fail_from_before(req, index + i + 1, err)
             }
@@ -357,8 +574,11 @@ impl Chain {
 
     // Enter the normal flow at the handler.
     fn continue_from_handler(&self, req: &mut Request) -> FerrumResult<Response> {
+        let timer = self.timer(req);
         // unwrap is safe because it's always Some
-        match self.handler.as_ref().unwrap().handle(req) {
+        let result = self.handler.as_ref().unwrap().handle(req);
+        timer.finish(req, LinkKind::Handler, 0, &self.handler_name, Phase::Handle, result.is_err());
+        match result {
             Ok(res) => self.continue_from_after(req, 0, res),
             Err(err) => self.fail_from_handler(req, err)
         }
@@ -374,7 +594,10 @@ impl Chain {
         }
 
         for (i, after) in self.afters[index..].iter().enumerate() {
-            res = match after.after(req, res) {
+            let timer = self.timer(req);
+            let result = after.middleware.after(req, res);
+            timer.finish(req, LinkKind::After, index + i, &after.name, Phase::After, result.is_err());
+            res = match result {
                 Ok(r) => r,
                 Err(err) => return self.fail_from_after(req, index + i + 1, err)
             }
@@ -190,23 +190,18 @@ fn to_chain(counters: &ChainLike<Twice<Arc<AtomicBool>>>,
     let (befores, handler, afters) = chain;
     let (ref beforec, ref handlerc, ref afterc) = *counters;
 
-    let befores = befores.into_iter().zip(beforec.iter())
-        .map(into_middleware)
-        .map(|m| Box::new(m) as Box<BeforeMiddleware>)
-        .collect::<Vec<_>>();
-
     let handler = into_middleware((handler, handlerc));
+    let mut chain = Chain::new(handler);
 
-    let afters = afters.into_iter().zip(afterc.iter())
-        .map(into_middleware)
-        .map(|m| Box::new(m) as Box<AfterMiddleware>)
-        .collect::<Vec<_>>();
+    for before in befores.into_iter().zip(beforec.iter()).map(into_middleware) {
+        chain.link_before(before);
+    }
 
-    Chain {
-        befores: befores,
-        handler: Some(Box::new(handler) as Box<Handler>),
-        afters: afters
+    for after in afters.into_iter().zip(afterc.iter()).map(into_middleware) {
+        chain.link_after(after);
     }
+
+    chain
 }
 
 fn into_middleware(input: (Kind, &Twice<Arc<AtomicBool>>)) -> Middleware {
@@ -245,3 +240,49 @@ fn test_chain(chain: ChainLike<Kind>, expected: ChainLike<Kind>) {
     // Yay! Actually do the test!
     assert_eq!(outchain, expected);
 }
+
+#[test] fn test_chain_describe() {
+    let mut chain = Chain::new(|_: &mut Request| Ok(response()));
+    chain.link_before_named("auth", |_: &mut Request| Ok(()));
+    chain.link_named("log", (|_: &mut Request| Ok(()), |_: &mut Request, res: Response| Ok(res)));
+    chain.link_around_named("cache", |handler: Box<dyn Handler>| handler);
+
+    let description = chain.describe();
+    let lines = description.lines().collect::<Vec<_>>();
+    assert_eq!(lines.len(), 4);
+    assert_eq!(&lines[..2], &["before 0: auth", "before 1: log"]);
+    assert!(lines[2].starts_with("handler: cache(ferrum::middleware::test::"), "{}", lines[2]);
+    assert_eq!(lines[3], "after 0: log");
+}
+
+#[test] fn test_chain_profile() {
+    let chain = |profiling| {
+        let mut chain = Chain::new(|_: &mut Request| Ok(response()));
+        chain.link_before_named("fail", |_: &mut Request| Err(error()));
+        chain.link_before_named("recover", Middleware {
+            normal: sharedbool(false),
+            error: sharedbool(false),
+            mode: Fine
+        });
+        chain.link_after_named("log", |_: &mut Request, res: Response| Ok(res));
+        chain.profile(profiling);
+        chain
+    };
+
+    // Profiling is opt-in.
+    let mut req = request();
+    chain(false).handle(&mut req).unwrap();
+    assert!(req.extensions.get::<ChainProfile>().is_none());
+
+    let mut req = request();
+    chain(true).handle(&mut req).unwrap();
+    let calls = req.extensions.get::<ChainProfile>().unwrap().iter()
+        .map(|call| (call.kind, call.index, call.name.as_str(), call.phase, call.error))
+        .collect::<Vec<_>>();
+    assert_eq!(calls, vec![
+        (LinkKind::Before, 0, "fail", Phase::Before, true),
+        (LinkKind::Before, 1, "recover", Phase::Catch, false),
+        (LinkKind::Handler, 0, calls[2].2, Phase::Handle, false),
+        (LinkKind::After, 0, "log", Phase::After, false),
+    ]);
+}
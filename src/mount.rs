@@ -0,0 +1,270 @@
+//! Dispatch of requests to sub-handlers by path prefix.
+//!
+//! `Mount` hands each request to the handler mounted on the longest prefix of
+//! its path, with the prefix stripped from `Request::uri` and
+//! `Request::uri_path_segments`:
+//!
+//! ```rust,no_run
+//! use ferrum::*;
+//! use ferrum::mount::{Mount, MountedPrefix};
+//!
+//! fn users(request: &mut Request) -> FerrumResult<Response> {
+//!     // `/api/users/42` is received as `/42`.
+//!     let prefix = request.extensions.get::<MountedPrefix>().cloned().unwrap_or_default();
+//!     let content = format!("user {} of {}", request.uri.path(), prefix);
+//!     Ok(Response::new().with_content(content, mime::TEXT_PLAIN))
+//! }
+//! # fn api(_: &mut Request) -> FerrumResult<Response> { Ok(Response::new()) }
+//! # fn assets(_: &mut Request) -> FerrumResult<Response> { Ok(Response::new()) }
+//!
+//! let mut mount = Mount::new();
+//! mount.mount("/api", api)
+//!     .mount("/api/users", users)
+//!     .mount("/", assets);
+//! Ferrum::new(mount).http("localhost:3000").unwrap();
+//! ```
+//!
+//! Prefixes match whole segments, `/api` doesn't match `/apiary`. The URI
+//! received by the server stays available as the `OriginalUri`, which
+//! `Request::url` uses, and the stripped prefixes as the `MountedPrefix`.
+//! Both are restored once the mounted handler returns.
+
+use std::error::Error;
+use std::fmt;
+
+use hyper::Uri;
+
+use {Request, Response, FerrumResult, FerrumError, StatusCode};
+use middleware::Handler;
+use request::UriPathSegments;
+use typemap::Key;
+
+/// The URI of a request as received by the server, before any `Mount`
+/// stripped a prefix from it.
+pub struct OriginalUri;
+
+impl Key for OriginalUri {
+    type Value = Uri;
+}
+
+/// The prefixes stripped from the path of a request by the `Mount`s it went
+/// through, e.g. `/api/v1`.
+pub struct MountedPrefix;
+
+impl Key for MountedPrefix {
+    type Value = String;
+}
+
+/// The error of a request matching no prefix of a `Mount`, answered with
+/// `404 Not Found`.
+#[derive(Debug)]
+pub struct NoMatch;
+
+impl fmt::Display for NoMatch {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("No mounted handler matches the path")
+    }
+}
+
+impl Error for NoMatch {}
+
+struct Mounted {
+    segments: Vec<String>,
+    handler: Box<dyn Handler>,
+}
+
+/// A `Handler` dispatching requests by path prefix, see the `mount` module.
+pub struct Mount {
+    // Sorted by decreasing prefix length.
+    mounts: Vec<Mounted>,
+}
+
+impl Mount {
+    /// Create a `Mount` without handlers.
+    pub fn new() -> Mount {
+        Mount { mounts: Vec::new() }
+    }
+
+    /// Mount `handler` on `prefix`, replacing the handler already mounted on
+    /// the same prefix. The prefix is percent decoded like
+    /// `Request::uri_path_segments`, and `/` matches every path.
+    pub fn mount<H: Handler>(&mut self, prefix: &str, handler: H) -> &mut Mount {
+        let segments = prefix.split('/')
+            .filter(|segment| !segment.is_empty())
+            .map(decode)
+            .collect::<Vec<_>>();
+        self.mounts.retain(|mounted| mounted.segments != segments);
+        let index = self.mounts.iter()
+            .position(|mounted| mounted.segments.len() < segments.len())
+            .unwrap_or(self.mounts.len());
+        self.mounts.insert(index, Mounted { segments, handler: Box::new(handler) });
+        self
+    }
+
+    fn find(&self, path_segments: &[String]) -> Option<&Mounted> {
+        self.mounts.iter().find(|mounted| {
+            // The empty last segment of a trailing slash is no segment.
+            let path_segments = match path_segments.split_last() {
+                Some((last, rest)) if last.is_empty() => rest,
+                _ => path_segments
+            };
+            path_segments.starts_with(&mounted.segments)
+        })
+    }
+}
+
+impl Default for Mount {
+    fn default() -> Mount {
+        Mount::new()
+    }
+}
+
+fn decode(segment: &str) -> String {
+    let uri = format!("/{}", segment).parse::<Uri>();
+    uri.ok()
+        .and_then(|uri| uri.decoded_path_segments().pop())
+        .unwrap_or_else(|| segment.to_string())
+}
+
+// The URI without the first `len` segments of its path.
+fn strip_uri(uri: &Uri, len: usize) -> Uri {
+    let path = uri.path_segments().skip(len).collect::<Vec<_>>().join("/");
+    let mut stripped = String::new();
+    if let (Some(scheme), Some(authority)) = (uri.scheme(), uri.authority()) {
+        stripped.push_str(&format!("{}://{}", scheme, authority));
+    }
+    stripped.push('/');
+    stripped.push_str(&path);
+    if let Some(query) = uri.query() {
+        stripped.push('?');
+        stripped.push_str(query);
+    }
+    // The parts of a valid URI make a valid URI.
+    stripped.parse().unwrap_or_else(|_| uri.clone())
+}
+
+impl Handler for Mount {
+    fn handle(&self, request: &mut Request) -> FerrumResult<Response> {
+        let mounted = match self.find(&request.uri_path_segments) {
+            Some(mounted) => mounted,
+            None => return Err(FerrumError::new(NoMatch, Some(Response::new().with_status(StatusCode::NotFound))))
+        };
+        let len = mounted.segments.len();
+
+        let original_uri = request.uri.clone();
+        let original_segments = request.uri_path_segments.clone();
+        let previous_prefix = request.extensions.get::<MountedPrefix>().cloned();
+        let outermost = !request.extensions.contains::<OriginalUri>();
+
+        let prefix = original_uri.path_segments().take(len).fold(previous_prefix.clone().unwrap_or_default(), |prefix, segment| {
+            format!("{}/{}", prefix, segment)
+        });
+        request.uri = strip_uri(&original_uri, len);
+        request.uri_path_segments.drain(..len.min(original_segments.len()));
+        if request.uri_path_segments.is_empty() {
+            request.uri_path_segments.push(String::new());
+        }
+        if outermost {
+            request.extensions.insert::<OriginalUri>(original_uri.clone());
+        }
+        request.extensions.insert::<MountedPrefix>(prefix);
+
+        let result = mounted.handler.handle(request);
+
+        request.uri = original_uri;
+        request.uri_path_segments = original_segments;
+        if outermost {
+            request.extensions.remove::<OriginalUri>();
+        }
+        match previous_prefix {
+            Some(prefix) => { request.extensions.insert::<MountedPrefix>(prefix); },
+            None => { request.extensions.remove::<MountedPrefix>(); }
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use mime;
+
+    fn echo(name: &'static str) -> impl Handler {
+        move |request: &mut Request| {
+            let content = format!("{} {} {:?} {:?} {:?}", name, request.uri, request.uri_path_segments,
+                request.extensions.get::<MountedPrefix>(), request.extensions.get::<OriginalUri>().map(Uri::to_string));
+            Ok(Response::new().with_content(content, mime::TEXT_PLAIN))
+        }
+    }
+
+    fn handle(handler: &dyn Handler, uri: &str) -> FerrumResult<String> {
+        let mut request = Request::stub();
+        request.uri = uri.parse().unwrap();
+        request.uri_path_segments = request.uri.decoded_path_segments();
+        let mut response = handler.handle(&mut request)?;
+
+        // The request is restored for the middleware after the mount.
+        assert_eq!(request.uri.to_string(), uri);
+        assert!(request.extensions.get::<OriginalUri>().is_none());
+        assert!(request.extensions.get::<MountedPrefix>().is_none());
+
+        let content = response.buffer_content().unwrap().unwrap();
+        Ok(String::from_utf8(content.0).unwrap())
+    }
+
+    #[test]
+    fn test_longest_prefix() {
+        let mut mount = Mount::new();
+        mount.mount("/api", echo("api"))
+            .mount("/api/users/", echo("users"))
+            .mount("/", echo("root"));
+
+        assert_eq!(handle(&mount, "/api/users/42?full=1").unwrap(),
+            "users /42?full=1 [\"42\"] Some(\"/api/users\") Some(\"/api/users/42?full=1\")");
+        assert_eq!(handle(&mount, "/api/users").unwrap(),
+            "users / [\"\"] Some(\"/api/users\") Some(\"/api/users\")");
+        assert_eq!(handle(&mount, "/api/usersx/").unwrap(),
+            "api /usersx/ [\"usersx\", \"\"] Some(\"/api\") Some(\"/api/usersx/\")");
+        assert_eq!(handle(&mount, "/apiary").unwrap(),
+            "root /apiary [\"apiary\"] Some(\"\") Some(\"/apiary\")");
+    }
+
+    #[test]
+    fn test_encoded_prefix() {
+        let mut mount = Mount::new();
+        mount.mount("/caf%C3%A9", echo("cafe"));
+
+        assert_eq!(handle(&mount, "http://example.com/caf%C3%A9/cr%C3%A8me").unwrap(),
+            "cafe http://example.com/cr%C3%A8me [\"crème\"] Some(\"/caf%C3%A9\") Some(\"http://example.com/caf%C3%A9/cr%C3%A8me\")");
+    }
+
+    #[test]
+    fn test_nested_mounts() {
+        let mut inner = Mount::new();
+        inner.mount("/v1", echo("v1"));
+        let mut outer = Mount::new();
+        outer.mount("/api", inner);
+
+        assert_eq!(handle(&outer, "/api/v1/users").unwrap(),
+            "v1 /users [\"users\"] Some(\"/api/v1\") Some(\"/api/v1/users\")");
+
+        let error = handle(&outer, "/api/v2").unwrap_err();
+        assert_eq!(error.response.unwrap().status, StatusCode::NotFound);
+    }
+
+    #[test]
+    fn test_url_of_mounted_request() {
+        let mut mount = Mount::new();
+        mount.mount("/app", |request: &mut Request| {
+            let url = request.url().unwrap();
+            Ok(Response::new().with_content(url.to_string(), mime::TEXT_PLAIN))
+        });
+        let mut request = Request::stub();
+        request.uri = "/app/page".parse().unwrap();
+        request.uri_path_segments = request.uri.decoded_path_segments();
+        request.headers.set_raw("Host", "example.com");
+        let mut response = mount.handle(&mut request).unwrap();
+        assert_eq!(response.buffer_content().unwrap().unwrap().0, b"http://example.com/app/page");
+    }
+}
@@ -0,0 +1,653 @@
+//! Reverse proxying of requests to upstream HTTP servers.
+//!
+//! `Proxy` is a `Handler` forwarding requests to a pool of upstream servers,
+//! picked in turn among the healthy ones:
+//!
+//! ```rust,no_run
+//! use std::time::Duration;
+//! use ferrum::*;
+//! use ferrum::proxy::{HealthCheck, Proxy, UpstreamPool};
+//!
+//! let pool = UpstreamPool::new(&["http://10.0.0.1:8080", "http://10.0.0.2:8080"]).unwrap()
+//!     .with_health_check(Some(HealthCheck::new("/healthz").with_interval(Duration::from_secs(5))));
+//! let proxy = Proxy::new(pool).unwrap()
+//!     .with_timeout(Duration::from_secs(10));
+//! Ferrum::new(proxy).http("0.0.0.0:80").unwrap();
+//! ```
+//!
+//! The hop-by-hop headers are removed from requests and responses, and the
+//! requests get the `X-Forwarded-For`, `X-Forwarded-Proto`,
+//! `X-Forwarded-Host` and `Forwarded` headers. The bodies are streamed in
+//! both directions. The requests traced by `trace::Tracing` carry their
+//! `traceparent` to the upstream servers.
+//!
+//! Failing to reach an upstream server is answered with `502 Bad Gateway`,
+//! and marks the server as down for `UpstreamPool::with_fail_timeout`. An
+//! upstream server not answering in time is answered with
+//! `504 Gateway Timeout`. The requests are not retried on other servers, as
+//! their body may already be consumed.
+
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use futures::{Future, Stream};
+use futures::future::Either;
+use futures::sync::{mpsc, oneshot};
+use hyper::{self, Client, Method, Uri};
+use hyper::client::HttpConnector;
+use hyper::header::{ContentLength, Headers, Host, TransferEncoding};
+use tokio_core::reactor::{Core, Handle, Interval, Timeout};
+
+use {Request, Response, FerrumResult, FerrumError, StatusCode};
+use request::HyperRequest;
+use response::HyperResponse;
+use middleware::Handler;
+use trace::CurrentSpan;
+
+/// The default time allowed to upstream servers to answer.
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
+
+// The headers of a single connection, not forwarded.
+const HOP_BY_HOP: [&str; 8] = [
+    "Connection",
+    "Keep-Alive",
+    "Proxy-Authenticate",
+    "Proxy-Authorization",
+    "Proxy-Connection",
+    "TE",
+    "Trailer",
+    "Upgrade",
+];
+
+/// The active health check of the servers of an `UpstreamPool`.
+#[derive(Debug, Clone)]
+pub struct HealthCheck {
+    path: String,
+    interval: Duration,
+    timeout: Duration,
+}
+
+impl HealthCheck {
+    /// Check the servers with a `GET` of `path`, healthy servers answering
+    /// with a `2xx` status.
+    pub fn new<S: Into<String>>(path: S) -> HealthCheck {
+        HealthCheck {
+            path: path.into(),
+            interval: Duration::from_secs(10),
+            timeout: Duration::from_secs(2),
+        }
+    }
+
+    /// Set the interval between checks. The default is 10 seconds.
+    pub fn with_interval(mut self, interval: Duration) -> Self {
+        self.interval = interval;
+        self
+    }
+
+    /// Set the time allowed to servers to answer the check. The default is
+    /// 2 seconds.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+}
+
+#[derive(Debug, Default)]
+struct UpstreamHealth {
+    // Until when the server is down after failing a request.
+    down_until: Option<Instant>,
+    failed_check: bool,
+}
+
+#[derive(Debug)]
+struct Upstream {
+    uri: Uri,
+    health: Mutex<UpstreamHealth>,
+}
+
+impl Upstream {
+    fn is_up(&self) -> bool {
+        let health = self.health.lock().unwrap();
+        !health.failed_check && health.down_until.map(|until| Instant::now() >= until).unwrap_or(true)
+    }
+
+    // The URI of `path_and_query` on the server, under the path of its URI.
+    fn target(&self, path_and_query: &str) -> Result<Uri, hyper::error::UriError> {
+        let base = self.uri.path().trim_end_matches('/');
+        format!("{}://{}{}{}", self.uri.scheme().unwrap_or("http"), self.uri.authority().unwrap_or(""), base, path_and_query).parse()
+    }
+}
+
+/// The upstream servers of a `Proxy`.
+#[derive(Debug)]
+pub struct UpstreamPool {
+    upstreams: Vec<Upstream>,
+    next: AtomicUsize,
+    health_check: Option<HealthCheck>,
+    fail_timeout: Duration,
+}
+
+impl UpstreamPool {
+    /// Create a pool of the servers at the given `http` URLs. The path of a
+    /// URL, if any, prefixes the paths of the forwarded requests.
+    pub fn new<S: AsRef<str>>(urls: &[S]) -> io::Result<UpstreamPool> {
+        let upstreams = urls.iter().map(|url| {
+            let uri = url.as_ref().parse::<Uri>()
+                .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+            if uri.scheme() != Some("http") || uri.authority().is_none() {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("Not an http URL: {}", uri)));
+            }
+            Ok(Upstream { uri, health: Mutex::new(UpstreamHealth::default()) })
+        }).collect::<io::Result<Vec<_>>>()?;
+        if upstreams.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "No upstream server"));
+        }
+
+        Ok(UpstreamPool {
+            upstreams,
+            next: AtomicUsize::new(0),
+            health_check: None,
+            fail_timeout: Duration::from_secs(10),
+        })
+    }
+
+    /// Check the health of the servers periodically, skipping the failing
+    /// ones until they pass a check. The default is `None`.
+    pub fn with_health_check(mut self, check: Option<HealthCheck>) -> Self {
+        self.health_check = check;
+        self
+    }
+
+    /// Set how long a server which could not be reached is skipped. The
+    /// default is 10 seconds.
+    pub fn with_fail_timeout(mut self, timeout: Duration) -> Self {
+        self.fail_timeout = timeout;
+        self
+    }
+
+    /// The number of servers considered healthy.
+    pub fn healthy(&self) -> usize {
+        self.upstreams.iter().filter(|upstream| upstream.is_up()).count()
+    }
+
+    // The index of the next healthy server, in turn.
+    fn pick(&self) -> Option<usize> {
+        let start = self.next.fetch_add(1, Ordering::Relaxed);
+        let len = self.upstreams.len();
+        (0..len)
+            .map(|offset| (start + offset) % len)
+            .find(|&index| self.upstreams[index].is_up())
+    }
+
+    fn failed(&self, upstream: &Upstream) {
+        upstream.health.lock().unwrap().down_until = Some(Instant::now() + self.fail_timeout);
+    }
+
+    fn checked(&self, upstream: &Upstream, healthy: bool) {
+        let mut health = upstream.health.lock().unwrap();
+        health.failed_check = !healthy;
+        if healthy {
+            health.down_until = None;
+        }
+    }
+}
+
+/// The error of a request which could not be proxied.
+#[derive(Debug)]
+pub enum ProxyError {
+    /// No upstream server is healthy.
+    NoUpstream,
+    /// The upstream server could not be reached, or answered with an invalid
+    /// response.
+    Upstream(hyper::Error),
+    /// The upstream server didn't answer in time.
+    Timeout,
+    /// The proxy could not forward the request.
+    Internal(io::Error),
+}
+
+impl fmt::Display for ProxyError {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ProxyError::NoUpstream => formatter.write_str("No healthy upstream server"),
+            ProxyError::Upstream(ref err) => write!(formatter, "Upstream server failed: {}", err),
+            ProxyError::Timeout => formatter.write_str("Upstream server timed out"),
+            ProxyError::Internal(ref err) => write!(formatter, "Proxy failed: {}", err),
+        }
+    }
+}
+
+impl Error for ProxyError {}
+
+impl From<ProxyError> for FerrumError {
+    fn from(err: ProxyError) -> FerrumError {
+        let status = match err {
+            ProxyError::Timeout => StatusCode::GatewayTimeout,
+            _ => StatusCode::BadGateway
+        };
+        FerrumError::new(err, Some(Response::new().with_status(status)))
+    }
+}
+
+// A request for the client thread.
+struct Job {
+    upstream: usize,
+    request: HyperRequest,
+    timeout: Duration,
+    reply: oneshot::Sender<Result<HyperResponse, ProxyError>>,
+}
+
+/// A `Handler` forwarding requests to upstream servers, see the `proxy`
+/// module.
+pub struct Proxy {
+    pool: Arc<UpstreamPool>,
+    jobs: Mutex<mpsc::UnboundedSender<Job>>,
+    timeout: Duration,
+    preserve_host: bool,
+}
+
+impl Proxy {
+    /// Forward requests to the servers of `pool`, from a dedicated client
+    /// thread, stopped with the `Proxy`.
+    pub fn new(pool: UpstreamPool) -> io::Result<Proxy> {
+        let pool = Arc::new(pool);
+        let (jobs, receiver) = mpsc::unbounded();
+        let client_pool = pool.clone();
+        thread::Builder::new()
+            .name("ferrum-proxy".to_string())
+            .spawn(move || run_client(client_pool, receiver))?;
+
+        Ok(Proxy {
+            pool,
+            jobs: Mutex::new(jobs),
+            timeout: DEFAULT_TIMEOUT,
+            preserve_host: false,
+        })
+    }
+
+    /// Set the time allowed to upstream servers to answer with the head of
+    /// their response. The default is `DEFAULT_TIMEOUT`.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Forward the `Host` header of the requests instead of the host of the
+    /// upstream server. The default is `false`.
+    pub fn with_preserve_host(mut self, preserve: bool) -> Self {
+        self.preserve_host = preserve;
+        self
+    }
+
+    /// The upstream servers.
+    pub fn pool(&self) -> &UpstreamPool {
+        &self.pool
+    }
+
+    // The headers forwarded to the upstream server.
+    fn forwarded_headers(&self, request: &Request, upstream: &Upstream) -> Headers {
+        let mut headers = request.headers.clone();
+        remove_hop_by_hop(&mut headers);
+
+        let original_host = raw_value(&request.headers, "Host");
+        if !self.preserve_host || original_host.is_none() {
+            let authority = upstream.uri.authority().unwrap_or("");
+            headers.set_raw("Host", authority.to_string());
+        }
+
+        let client = request.remote_addr.map(|addr| addr.ip());
+        let proto = request.scheme.as_str();
+        if let Some(ip) = client {
+            let forwarded_for = match raw_value(&request.headers, "X-Forwarded-For") {
+                Some(previous) => format!("{}, {}", previous, ip),
+                None => ip.to_string()
+            };
+            headers.set_raw("X-Forwarded-For", forwarded_for);
+        }
+        headers.set_raw("X-Forwarded-Proto", proto);
+        if let Some(ref host) = original_host {
+            headers.set_raw("X-Forwarded-Host", host.clone());
+        }
+
+        let mut element = Vec::new();
+        if let Some(ip) = client {
+            element.push(format!("for={}", forwarded_node(ip)));
+        }
+        element.push(format!("proto={}", proto));
+        if let Some(ref host) = original_host {
+            element.push(format!("host={}", forwarded_value(host)));
+        }
+        let forwarded = match raw_value(&request.headers, "Forwarded") {
+            Some(previous) => format!("{}, {}", previous, element.join(";")),
+            None => element.join(";")
+        };
+        headers.set_raw("Forwarded", forwarded);
+
+        if let Some(span) = request.extensions.get::<CurrentSpan>() {
+            headers.set(span.traceparent());
+            if let Some(state) = span.trace_state() {
+                headers.set(state);
+            }
+        }
+        headers
+    }
+}
+
+impl Handler for Proxy {
+    fn handle(&self, request: &mut Request) -> FerrumResult<Response> {
+        let index = match self.pool.pick() {
+            Some(index) => index,
+            None => return Err(ProxyError::NoUpstream.into())
+        };
+        let upstream = &self.pool.upstreams[index];
+
+        let path_and_query = match request.uri.query() {
+            Some(query) => format!("{}?{}", request.uri.path(), query),
+            None => request.uri.path().to_string()
+        };
+        let uri = upstream.target(&path_and_query)
+            .map_err(|err| ProxyError::Internal(io::Error::new(io::ErrorKind::InvalidInput, err)))?;
+        let mut outgoing = HyperRequest::new(request.method.clone(), uri);
+        *outgoing.headers_mut() = self.forwarded_headers(request, upstream);
+        let has_body = request.headers.has::<ContentLength>() || request.headers.has::<TransferEncoding>();
+        if has_body {
+            outgoing.set_body(request.take_body());
+        }
+
+        let (reply, response) = oneshot::channel();
+        let job = Job { upstream: index, request: outgoing, timeout: self.timeout, reply };
+        if self.jobs.lock().unwrap().unbounded_send(job).is_err() {
+            return Err(ProxyError::Internal(io::Error::other("The proxy client stopped")).into());
+        }
+        let response = match response.wait() {
+            Ok(response) => response?,
+            Err(_) => return Err(ProxyError::Internal(io::Error::other("The proxy client stopped")).into())
+        };
+
+        let mut response = Response::from(response);
+        remove_hop_by_hop(&mut response.headers);
+        Ok(response)
+    }
+}
+
+// Send the requests of `jobs`, and check the health of the servers.
+fn run_client(pool: Arc<UpstreamPool>, jobs: mpsc::UnboundedReceiver<Job>) {
+    let mut core = match Core::new() {
+        Ok(core) => core,
+        Err(_) => return
+    };
+    let handle = core.handle();
+    let client = Client::configure().set_host(false).build(&handle);
+
+    if let Some(ref check) = pool.health_check {
+        for index in 0..pool.upstreams.len() {
+            spawn_health_check(&handle, &client, pool.clone(), index, check.clone());
+        }
+    }
+
+    let _ = core.run(jobs.for_each(|job| {
+        let pool = pool.clone();
+        let (index, reply) = (job.upstream, job.reply);
+        let response = with_timeout(&handle, client.request(job.request), job.timeout);
+        handle.spawn(response.then(move |result| {
+            if let Err(ProxyError::Upstream(hyper::Error::Io(_))) = result {
+                pool.failed(&pool.upstreams[index]);
+            }
+            let _ = reply.send(result);
+            Ok(())
+        }));
+        Ok(())
+    }));
+}
+
+fn with_timeout<F>(handle: &Handle, response: F, timeout: Duration) -> Box<dyn Future<Item = HyperResponse, Error = ProxyError>>
+    where F: Future<Item = HyperResponse, Error = hyper::Error> + 'static
+{
+    let timeout = match Timeout::new(timeout, handle) {
+        Ok(timeout) => timeout,
+        Err(err) => return Box::new(::futures::future::err(ProxyError::Internal(err)))
+    };
+    Box::new(response.select2(timeout).then(|result| match result {
+        Ok(Either::A((response, _))) => Ok(response),
+        Ok(Either::B(_)) => Err(ProxyError::Timeout),
+        Err(Either::A((err, _))) => Err(ProxyError::Upstream(err)),
+        Err(Either::B((err, _))) => Err(ProxyError::Internal(err)),
+    }))
+}
+
+fn spawn_health_check(handle: &Handle, client: &Client<HttpConnector>, pool: Arc<UpstreamPool>, index: usize, check: HealthCheck) {
+    let interval = match Interval::new(check.interval, handle) {
+        Ok(interval) => interval,
+        Err(_) => return
+    };
+    let (handle, client) = (handle.clone(), client.clone());
+    handle.clone().spawn(interval.map_err(|_| ()).for_each(move |_| {
+        let pool = pool.clone();
+        let upstream = &pool.upstreams[index];
+        let uri = match upstream.target(&check.path) {
+            Ok(uri) => uri,
+            Err(_) => return Ok(())
+        };
+        let mut request = HyperRequest::new(Method::Get, uri);
+        if let (Some(host), port) = (upstream.uri.host(), upstream.uri.port()) {
+            request.headers_mut().set(Host::new(host.to_string(), port));
+        }
+        let response = with_timeout(&handle, client.request(request), check.timeout);
+        handle.spawn(response.then(move |result| {
+            let healthy = result.map(|response| response.status().is_success()).unwrap_or(false);
+            pool.checked(&pool.upstreams[index], healthy);
+            Ok(())
+        }));
+        Ok(())
+    }));
+}
+
+fn raw_value(headers: &Headers, name: &str) -> Option<String> {
+    headers.get_raw(name).map(|raw| {
+        raw.iter()
+            .map(|line| String::from_utf8_lossy(line).trim().to_string())
+            .collect::<Vec<_>>()
+            .join(", ")
+    })
+}
+
+// Remove the hop-by-hop headers, including the ones listed by `Connection`.
+fn remove_hop_by_hop(headers: &mut Headers) {
+    if let Some(connection) = raw_value(headers, "Connection") {
+        for name in connection.split(',').map(str::trim).filter(|name| !name.is_empty()) {
+            headers.remove_raw(name);
+        }
+    }
+    for name in HOP_BY_HOP.iter() {
+        headers.remove_raw(name);
+    }
+    headers.remove::<TransferEncoding>();
+}
+
+fn forwarded_node(ip: IpAddr) -> String {
+    match ip {
+        IpAddr::V4(ip) => ip.to_string(),
+        IpAddr::V6(ip) => format!("\"[{}]\"", ip),
+    }
+}
+
+// A `Forwarded` value, quoted unless it is a token.
+fn forwarded_value(value: &str) -> String {
+    let token = !value.is_empty() && value.bytes().all(|c| c.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&c));
+    if token {
+        value.to_string()
+    } else {
+        format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::{Read, Write};
+    use std::net::{SocketAddr, TcpListener, TcpStream};
+
+    use futures::sync::mpsc as channel;
+
+    use {mime, Ferrum, Listening};
+    use response::{Event, EventStream};
+
+    // An upstream server answering with its name, the headers and the body it received.
+    fn echo_upstream(name: &'static str) -> Listening {
+        Ferrum::new(move |request: &mut Request| {
+            if request.uri.path() == "/healthz" {
+                let status = if name == "sick" { StatusCode::ServiceUnavailable } else { StatusCode::Ok };
+                return Ok(Response::new().with_status(status));
+            }
+            let body = request.take_body().concat2().wait().map(|chunk| chunk.to_vec()).unwrap_or_default();
+            let content = format!("{} {}\n{}\n{}", name, request.uri, request.headers, String::from_utf8(body).unwrap());
+            Ok(Response::new().with_content(content, mime::TEXT_PLAIN).with_header(hyper::header::Connection::close()))
+        }).spawn("127.0.0.1:0").unwrap()
+    }
+
+    fn url(listening: &Listening) -> String {
+        format!("http://{}", listening.local_addr().unwrap())
+    }
+
+    fn send(addr: SocketAddr, request: &str) -> String {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.write_all(request.as_bytes()).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    }
+
+    fn get(addr: SocketAddr, path: &str) -> String {
+        send(addr, &format!("GET {} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n", path))
+    }
+
+    #[test]
+    fn test_forward_request() {
+        let upstream = echo_upstream("echo");
+        let pool = UpstreamPool::new(&[format!("{}/base/", url(&upstream))]).unwrap();
+        let proxy = Ferrum::new(Proxy::new(pool).unwrap()).spawn("127.0.0.1:0").unwrap();
+
+        let response = send(proxy.local_addr().unwrap(), concat!(
+            "POST /submit?x=1 HTTP/1.1\r\n",
+            "Host: example.com\r\n",
+            "Connection: close, X-Secret\r\n",
+            "X-Secret: 1\r\n",
+            "Keep-Alive: timeout=5\r\n",
+            "X-Forwarded-For: 203.0.113.7\r\n",
+            "Transfer-Encoding: chunked\r\n",
+            "\r\n",
+            "5\r\nhello\r\n0\r\n\r\n",
+        ));
+        assert!(response.starts_with("HTTP/1.1 200 OK"), "{}", response);
+        // The hop-by-hop headers of the response are not forwarded either.
+        assert!(!response.contains("Connection: close\r\n\r\n"), "{}", response);
+
+        let body = &response[response.find("\r\n\r\n").unwrap() + 4..];
+        assert!(body.starts_with("echo /base/submit?x=1\n"), "{}", body);
+        assert!(body.ends_with("\nhello"), "{}", body);
+        let upstream_addr = upstream.local_addr().unwrap();
+        assert!(body.contains(&format!("Host: {}\r\n", upstream_addr)), "{}", body);
+        assert!(body.contains("X-Forwarded-For: 203.0.113.7, 127.0.0.1\r\n"), "{}", body);
+        assert!(body.contains("X-Forwarded-Proto: http\r\n"), "{}", body);
+        assert!(body.contains("X-Forwarded-Host: example.com\r\n"), "{}", body);
+        assert!(body.contains("Forwarded: for=127.0.0.1;proto=http;host=example.com\r\n"), "{}", body);
+        assert!(!body.contains("X-Secret"), "{}", body);
+        assert!(!body.contains("Keep-Alive"), "{}", body);
+    }
+
+    #[test]
+    fn test_upstream_failures() {
+        // A port nobody listens on.
+        let closed = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let pool = UpstreamPool::new(&[format!("http://{}", closed)]).unwrap();
+        let proxy = Proxy::new(pool).unwrap();
+        let response = Ferrum::new(proxy).spawn("127.0.0.1:0").unwrap();
+        assert!(get(response.local_addr().unwrap(), "/").starts_with("HTTP/1.1 502 Bad Gateway"));
+        // The server is then skipped.
+        assert!(get(response.local_addr().unwrap(), "/").starts_with("HTTP/1.1 502 Bad Gateway"));
+
+        let slow = Ferrum::new(|_: &mut Request| {
+            thread::sleep(Duration::from_millis(500));
+            Ok(Response::new())
+        }).spawn("127.0.0.1:0").unwrap();
+        let pool = UpstreamPool::new(&[url(&slow)]).unwrap();
+        let proxy = Proxy::new(pool).unwrap().with_timeout(Duration::from_millis(100));
+        let listening = Ferrum::new(proxy).spawn("127.0.0.1:0").unwrap();
+        assert!(get(listening.local_addr().unwrap(), "/").starts_with("HTTP/1.1 504 Gateway Timeout"));
+    }
+
+    #[test]
+    fn test_passive_health() {
+        let closed = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let pool = UpstreamPool::new(&[format!("http://{}", closed)]).unwrap();
+        let proxy = Proxy::new(pool).unwrap();
+        assert_eq!(proxy.pool().healthy(), 1);
+        let mut request = Request::stub();
+        request.uri = "/".parse().unwrap();
+        let error = proxy.handle(&mut request).unwrap_err();
+        assert_eq!(error.response.unwrap().status, StatusCode::BadGateway);
+        assert_eq!(proxy.pool().healthy(), 0);
+
+        let error = proxy.handle(&mut request).unwrap_err();
+        assert_eq!(error.error.to_string(), "No healthy upstream server");
+    }
+
+    #[test]
+    fn test_round_robin_health_check() {
+        let (a, b) = (echo_upstream("a"), echo_upstream("sick"));
+        let pool = UpstreamPool::new(&[url(&a), url(&b)]).unwrap()
+            .with_health_check(Some(HealthCheck::new("/healthz").with_interval(Duration::from_millis(200))));
+        let listening = Ferrum::new(Proxy::new(pool).unwrap()).spawn("127.0.0.1:0").unwrap();
+        let addr = listening.local_addr().unwrap();
+
+        let name = |response: String| response[response.find("\r\n\r\n").unwrap() + 4..].split(' ').next().unwrap().to_string();
+        let first = name(get(addr, "/"));
+        let second = name(get(addr, "/"));
+        let mut names = vec![first, second];
+        names.sort();
+        assert_eq!(names, vec!["a", "sick"]);
+
+        // The failing server is skipped once checked.
+        thread::sleep(Duration::from_millis(400));
+        for _ in 0..3 {
+            assert_eq!(name(get(addr, "/")), "a");
+        }
+    }
+
+    #[test]
+    fn test_stream_response() {
+        let (sender, events) = channel::unbounded();
+        let events = Mutex::new(Some(events));
+        let upstream = Ferrum::new(move |_: &mut Request| {
+            let events = events.lock().unwrap().take().unwrap();
+            Ok(Response::new_event_stream(EventStream::new(events).with_keep_alive(None)))
+        }).spawn("127.0.0.1:0").unwrap();
+        let pool = UpstreamPool::new(&[url(&upstream)]).unwrap();
+        let listening = Ferrum::new(Proxy::new(pool).unwrap()).spawn("127.0.0.1:0").unwrap();
+
+        let mut stream = TcpStream::connect(listening.local_addr().unwrap()).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        stream.write_all(b"GET /events HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+        sender.unbounded_send(Event::new("first")).unwrap();
+
+        // The first event arrives while the upstream response goes on.
+        let mut received = Vec::new();
+        let mut buf = [0; 1024];
+        while !String::from_utf8_lossy(&received).contains("data: first") {
+            let len = stream.read(&mut buf).unwrap();
+            assert!(len > 0);
+            received.extend_from_slice(&buf[..len]);
+        }
+        assert!(String::from_utf8_lossy(&received).contains("Content-Type: text/event-stream"));
+
+        // End the responses before stopping the servers.
+        drop(sender);
+        drop(stream);
+    }
+}
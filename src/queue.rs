@@ -0,0 +1,243 @@
+//! The bounded queue of requests waiting for a handler thread.
+//!
+//! Requests wait in the queue of the thread pool of the server until one of
+//! its `Ferrum::num_threads` threads handles them. Bounding the queue sheds
+//! the excess load with `503 Service Unavailable` responses, instead of
+//! letting the latency grow without bound:
+//!
+//! ```rust,no_run
+//! use std::time::Duration;
+//! use ferrum::*;
+//! use ferrum::queue::WorkQueue;
+//!
+//! let work_queue = WorkQueue::new()
+//!     .with_max_depth(Some(1000))
+//!     .with_max_wait(Some(Duration::from_secs(2)));
+//! let stats = work_queue.stats();
+//!
+//! let mut ferrum = Ferrum::new(move |_: &mut Request| {
+//!     let content = format!("{} requests waiting", stats.depth());
+//!     Ok(Response::new().with_content(content, mime::TEXT_PLAIN))
+//! });
+//! ferrum.work_queue = work_queue;
+//! ferrum.http("localhost:3000").unwrap();
+//! ```
+
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+use hyper::header::{ContentLength, RetryAfter};
+
+use {Response, StatusCode};
+
+/// The settings of the work queue of a server, along with its statistics.
+///
+/// Clones share the same statistics.
+#[derive(Debug, Clone)]
+pub struct WorkQueue {
+    max_depth: Option<usize>,
+    max_wait: Option<Duration>,
+    retry_after: Duration,
+    stats: Arc<QueueStats>,
+}
+
+impl WorkQueue {
+    /// Create an unbounded queue, the requests waiting as long as needed.
+    pub fn new() -> WorkQueue {
+        WorkQueue {
+            max_depth: None,
+            max_wait: None,
+            retry_after: Duration::from_secs(1),
+            stats: Arc::new(QueueStats::default()),
+        }
+    }
+
+    /// Set the maximum number of requests waiting for a thread. The requests
+    /// arriving while the queue is full are rejected immediately.
+    pub fn with_max_depth(mut self, max: Option<usize>) -> Self {
+        self.max_depth = max;
+        self
+    }
+
+    /// Set how long a request may wait for a thread. The requests which
+    /// waited longer are rejected without reaching the handler.
+    pub fn with_max_wait(mut self, max: Option<Duration>) -> Self {
+        self.max_wait = max;
+        self
+    }
+
+    /// Set the `Retry-After` delay of the rejections. The default is 1 second.
+    pub fn with_retry_after(mut self, delay: Duration) -> Self {
+        self.retry_after = delay;
+        self
+    }
+
+    /// The statistics of the queue.
+    pub fn stats(&self) -> Arc<QueueStats> {
+        self.stats.clone()
+    }
+
+    // Add a request to the queue, or `None` when the queue is full.
+    pub(crate) fn push(&self) -> Option<Queued> {
+        let depth = self.stats.depth.fetch_add(1, Ordering::SeqCst);
+        let queued = Queued { stats: self.stats.clone(), since: Instant::now() };
+        if self.max_depth.map(|max| depth >= max).unwrap_or(false) {
+            self.stats.rejected.fetch_add(1, Ordering::SeqCst);
+            return None;
+        }
+        Some(queued)
+    }
+
+    // Whether a request which waited `waited` should still be handled.
+    pub(crate) fn accepts(&self, waited: Duration) -> bool {
+        if self.max_wait.map(|max| waited > max).unwrap_or(false) {
+            self.stats.expired.fetch_add(1, Ordering::SeqCst);
+            return false;
+        }
+        true
+    }
+
+    /// The response rejecting the requests shed by the queue.
+    pub(crate) fn rejection(&self) -> Response {
+        Response::new()
+            .with_status(StatusCode::ServiceUnavailable)
+            .with_header(RetryAfter::Delay(self.retry_after))
+            .with_header(ContentLength(0))
+    }
+}
+
+impl Default for WorkQueue {
+    fn default() -> WorkQueue {
+        WorkQueue::new()
+    }
+}
+
+/// The statistics of a `WorkQueue`.
+#[derive(Debug, Default)]
+pub struct QueueStats {
+    depth: AtomicUsize,
+    rejected: AtomicUsize,
+    expired: AtomicUsize,
+}
+
+impl QueueStats {
+    /// The number of requests waiting for a thread.
+    pub fn depth(&self) -> usize {
+        self.depth.load(Ordering::SeqCst)
+    }
+
+    /// The number of requests rejected because the queue was full.
+    pub fn rejected(&self) -> usize {
+        self.rejected.load(Ordering::SeqCst)
+    }
+
+    /// The number of requests rejected because they waited too long.
+    pub fn expired(&self) -> usize {
+        self.expired.load(Ordering::SeqCst)
+    }
+}
+
+/// A request in the queue, leaving it when dropped.
+pub(crate) struct Queued {
+    stats: Arc<QueueStats>,
+    since: Instant,
+}
+
+impl Queued {
+    /// Leave the queue, returning how long the request waited.
+    pub fn leave(self) -> Duration {
+        self.since.elapsed()
+    }
+}
+
+impl Drop for Queued {
+    fn drop(&mut self) {
+        self.stats.depth.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::{Read, Write};
+    use std::net::{SocketAddr, TcpStream};
+    use std::thread;
+
+    use {mime, Ferrum, Request};
+
+    fn get(addr: SocketAddr) -> String {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n").unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    }
+
+    #[test]
+    fn test_queue_depth() {
+        let queue = WorkQueue::new().with_max_depth(Some(1));
+        let first = queue.push().unwrap();
+        assert_eq!(queue.stats().depth(), 1);
+        assert!(queue.push().is_none());
+        assert_eq!(queue.stats().rejected(), 1);
+        assert_eq!(queue.stats().depth(), 1);
+
+        first.leave();
+        assert_eq!(queue.stats().depth(), 0);
+        assert!(queue.push().is_some());
+    }
+
+    #[test]
+    fn test_shed_load() {
+        let queue = WorkQueue::new().with_max_depth(Some(1)).with_retry_after(Duration::from_secs(5));
+        let stats = queue.stats();
+        let mut ferrum = Ferrum::new(|_: &mut Request| {
+            thread::sleep(Duration::from_millis(300));
+            Ok(Response::new().with_content("done", mime::TEXT_PLAIN))
+        });
+        ferrum.num_threads = 1;
+        ferrum.work_queue = queue;
+        let listening = ferrum.spawn("127.0.0.1:0").unwrap();
+        let addr = listening.local_addr().unwrap();
+
+        // The first request is handled, the second one waits for it.
+        let handled = (0..2).map(|_| {
+            let request = thread::spawn(move || get(addr));
+            thread::sleep(Duration::from_millis(50));
+            request
+        }).collect::<Vec<_>>();
+        assert_eq!(stats.depth(), 1);
+
+        let response = get(addr);
+        assert!(response.starts_with("HTTP/1.1 503 Service Unavailable"), "{}", response);
+        assert!(response.contains("Retry-After: 5"), "{}", response);
+        assert_eq!(stats.rejected(), 1);
+
+        for request in handled {
+            assert!(request.join().unwrap().ends_with("done"));
+        }
+        assert_eq!(stats.depth(), 0);
+    }
+
+    #[test]
+    fn test_max_wait() {
+        let queue = WorkQueue::new().with_max_wait(Some(Duration::from_millis(100)));
+        let stats = queue.stats();
+        let mut ferrum = Ferrum::new(|_: &mut Request| {
+            thread::sleep(Duration::from_millis(300));
+            Ok(Response::new().with_content("done", mime::TEXT_PLAIN))
+        });
+        ferrum.num_threads = 1;
+        ferrum.work_queue = queue;
+        let listening = ferrum.spawn("127.0.0.1:0").unwrap();
+        let addr = listening.local_addr().unwrap();
+
+        let first = thread::spawn(move || get(addr));
+        thread::sleep(Duration::from_millis(50));
+        let response = get(addr);
+        assert!(response.starts_with("HTTP/1.1 503 Service Unavailable"), "{}", response);
+        assert_eq!(stats.expired(), 1);
+        assert!(first.join().unwrap().ends_with("done"));
+    }
+}
@@ -0,0 +1,21 @@
+//! Random values for ids, nonces and tokens.
+//!
+//! The values are drawn from the random number generator of the operating
+//! system, which is suitable for cryptographic use.
+
+/// A random `u64`.
+pub fn random_u64() -> u64 {
+    let mut bytes = [0; 8];
+    fill(&mut bytes);
+    u64::from_be_bytes(bytes)
+}
+
+/// Fill `bytes` with random bytes.
+///
+/// # Panics
+///
+/// If the operating system fails to provide random bytes, as values it could
+/// not make unpredictable must not be handed out.
+pub fn fill(bytes: &mut [u8]) {
+    getrandom::getrandom(bytes).expect("The random number generator of the operating system failed");
+}
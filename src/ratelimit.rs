@@ -0,0 +1,579 @@
+//! Rate limiting of requests by client.
+//!
+//! `RateLimiter` is a `BeforeMiddleware` which counts requests against a quota
+//! per client key and rejects the requests exceeding it with
+//! `429 Too Many Requests`, along with `Retry-After` and `RateLimit-*` headers.
+//!
+//! Clients are keyed by their IP address by default, as given by
+//! `Request::client_ip`, or else by the `PeerCredentials` of Unix domain socket
+//! clients, see the `key_by*` methods for other choices. Requests without any
+//! key are not limited. The state of the quotas lives in a `RateLimitStore`,
+//! `MemoryStore` being the default.
+//!
+//! Keys taken from headers or query strings are chosen by the clients, which
+//! get a fresh quota with each new value: use them only behind a middleware
+//! rejecting unknown values, such as the API keys of the example below.
+//!
+//! `RateLimitHeaders` is an `AfterMiddleware` adding the `RateLimit-*` headers to
+//! the responses of allowed requests as well:
+//!
+//! ```rust
+//! use std::time::Duration;
+//! use ferrum::*;
+//! use ferrum::ratelimit::{Algorithm, RateLimiter};
+//!
+//! # fn hello(_: &mut Request) -> FerrumResult<Response> {
+//! #     Ok(Response::new().with_content("Hello world!", mime::TEXT_PLAIN))
+//! # }
+//! let limiter = RateLimiter::new(Algorithm::TokenBucket {
+//!     capacity: 10,
+//!     interval: Duration::from_secs(1),
+//! }).key_by_header("X-Api-Key");
+//!
+//! let mut chain = Chain::new(hello);
+//! chain.link(limiter.both());
+//! ```
+
+use std::collections::{BTreeMap, HashMap};
+use std::error::Error;
+use std::fmt;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use hyper::header::{ContentLength, RetryAfter};
+use url::form_urlencoded;
+
+use {Request, Response, FerrumResult, FerrumError, Headers, StatusCode};
+use middleware::{BeforeMiddleware, AfterMiddleware};
+use typemap::Key;
+
+/// The algorithm used to enforce a quota.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Algorithm {
+    /// A bucket holding up to `capacity` tokens, refilled with one token every
+    /// `interval`. Each request takes a token. Allows bursts of `capacity` requests.
+    TokenBucket {
+        capacity: u32,
+        interval: Duration,
+    },
+    /// At most `limit` requests during any `window`, approximated by weighting
+    /// the count of the previous fixed window.
+    SlidingWindow {
+        limit: u32,
+        window: Duration,
+    },
+}
+
+impl Algorithm {
+    /// The maximum number of requests allowed in a burst.
+    pub fn limit(&self) -> u32 {
+        match *self {
+            Algorithm::TokenBucket { capacity, .. } => capacity,
+            Algorithm::SlidingWindow { limit, .. } => limit,
+        }
+    }
+}
+
+/// The outcome of counting a request against its quota.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RateLimitStatus {
+    /// Whether the request is allowed.
+    pub allowed: bool,
+    /// The quota of the client.
+    pub limit: u32,
+    /// The number of requests the client can still make right away.
+    pub remaining: u32,
+    /// The time until the quota is fully restored.
+    pub reset: Duration,
+    /// The time until the next request would be allowed, for rejected requests.
+    pub retry_after: Option<Duration>,
+}
+
+impl RateLimitStatus {
+    /// Set the `RateLimit-Limit`, `RateLimit-Remaining` and `RateLimit-Reset` headers.
+    pub fn set_headers(&self, headers: &mut Headers) {
+        headers.set_raw("RateLimit-Limit", self.limit.to_string());
+        headers.set_raw("RateLimit-Remaining", self.remaining.to_string());
+        headers.set_raw("RateLimit-Reset", ceil_secs(self.reset).to_string());
+        if let Some(retry_after) = self.retry_after {
+            headers.set(RetryAfter::Delay(Duration::from_secs(ceil_secs(retry_after))));
+        }
+    }
+}
+
+impl Key for RateLimitStatus {
+    type Value = RateLimitStatus;
+}
+
+/// Storage for the state of the quotas, shared by all the request threads.
+///
+/// Implement this to keep the quotas in an external store shared between
+/// several processes.
+pub trait RateLimitStore: Send + Sync + 'static {
+    /// Count a request of the client identified by `key`, made at `now`.
+    fn acquire(&self, key: &str, algorithm: &Algorithm, now: Instant) -> RateLimitStatus;
+}
+
+enum Quota {
+    Bucket { tokens: f64, updated: Instant },
+    Window { start: Instant, current: u32, previous: u32 },
+}
+
+// How often the quotas fully restored are forgotten.
+const PURGE_INTERVAL: Duration = Duration::from_secs(10);
+
+/// A `RateLimitStore` keeping the quotas in process memory.
+///
+/// The quotas fully restored are forgotten periodically. Past the maximum
+/// number of clients, the least recently seen ones are forgotten as well.
+pub struct MemoryStore {
+    quotas: Mutex<Quotas>,
+    max_clients: usize,
+}
+
+#[derive(Default)]
+struct Quotas {
+    by_key: HashMap<String, (Quota, u64)>,
+    // The keys by last use, the least recently used first.
+    by_use: BTreeMap<u64, String>,
+    uses: u64,
+    purged: Option<Instant>,
+}
+
+impl Quotas {
+    // The quota of `key`, counted as the most recently used.
+    fn get_mut(&mut self, key: &str, max: usize, initial: Quota) -> &mut Quota {
+        self.uses += 1;
+        let uses = self.uses;
+        if let Some(&(_, used)) = self.by_key.get(key) {
+            self.by_use.remove(&used);
+        } else {
+            while self.by_key.len() >= max.max(1) {
+                let (_, oldest) = self.by_use.pop_first().expect("keys in use");
+                self.by_key.remove(&oldest);
+            }
+        }
+        self.by_use.insert(uses, key.to_string());
+        let entry = self.by_key.entry(key.to_string()).or_insert((initial, uses));
+        entry.1 = uses;
+        &mut entry.0
+    }
+
+    // Forget the clients whose quota is fully restored.
+    fn purge(&mut self, algorithm: &Algorithm, now: Instant) {
+        if self.purged.is_some_and(|purged| now.saturating_duration_since(purged) < PURGE_INTERVAL) {
+            return;
+        }
+        self.purged = Some(now);
+        let by_use = &mut self.by_use;
+        self.by_key.retain(|_, &mut (ref quota, used)| {
+            let restored = match (quota, *algorithm) {
+                (&Quota::Bucket { tokens, updated }, Algorithm::TokenBucket { capacity, interval }) => {
+                    tokens + ratio(now.saturating_duration_since(updated), interval) >= f64::from(capacity)
+                },
+                (&Quota::Window { start, .. }, Algorithm::SlidingWindow { window, .. }) => {
+                    now.saturating_duration_since(start) >= window * 2
+                },
+                _ => true
+            };
+            if restored {
+                by_use.remove(&used);
+            }
+            !restored
+        });
+    }
+}
+
+impl MemoryStore {
+    /// Create an empty `MemoryStore`, for up to 100,000 clients.
+    pub fn new() -> MemoryStore {
+        MemoryStore {
+            quotas: Mutex::new(Quotas::default()),
+            max_clients: 100_000,
+        }
+    }
+
+    /// Set the maximum number of clients whose quota is kept.
+    pub fn with_max_clients(mut self, max: usize) -> Self {
+        self.max_clients = max;
+        self
+    }
+}
+
+impl Default for MemoryStore {
+    fn default() -> MemoryStore {
+        MemoryStore::new()
+    }
+}
+
+impl RateLimitStore for MemoryStore {
+    fn acquire(&self, key: &str, algorithm: &Algorithm, now: Instant) -> RateLimitStatus {
+        let mut quotas = self.quotas.lock().unwrap();
+        quotas.purge(algorithm, now);
+
+        match *algorithm {
+            Algorithm::TokenBucket { capacity, interval } => {
+                let quota = quotas.get_mut(key, self.max_clients, Quota::Bucket { tokens: f64::from(capacity), updated: now });
+                if let Quota::Window { .. } = *quota {
+                    *quota = Quota::Bucket { tokens: f64::from(capacity), updated: now };
+                }
+                let tokens = match *quota {
+                    Quota::Bucket { ref mut tokens, ref mut updated } => {
+                        *tokens = (*tokens + ratio(now - *updated, interval)).min(f64::from(capacity));
+                        *updated = now;
+                        tokens
+                    },
+                    Quota::Window { .. } => unreachable!()
+                };
+
+                let allowed = *tokens >= 1.0;
+                if allowed {
+                    *tokens -= 1.0;
+                }
+                RateLimitStatus {
+                    allowed,
+                    limit: capacity,
+                    remaining: *tokens as u32,
+                    reset: scale(interval, f64::from(capacity) - *tokens),
+                    retry_after: if allowed { None } else { Some(scale(interval, 1.0 - *tokens)) },
+                }
+            },
+            Algorithm::SlidingWindow { limit, window } => {
+                let quota = quotas.get_mut(key, self.max_clients, Quota::Window { start: now, current: 0, previous: 0 });
+                if let Quota::Bucket { .. } = *quota {
+                    *quota = Quota::Window { start: now, current: 0, previous: 0 };
+                }
+                let (start, current, previous) = match *quota {
+                    Quota::Window { ref mut start, ref mut current, ref mut previous } => (start, current, previous),
+                    Quota::Bucket { .. } => unreachable!()
+                };
+
+                // Move the fixed windows forward.
+                let elapsed = now - *start;
+                if elapsed >= window * 2 {
+                    *start = now;
+                    *previous = 0;
+                    *current = 0;
+                } else if elapsed >= window {
+                    *start += window;
+                    *previous = *current;
+                    *current = 0;
+                }
+
+                let previous_weight = 1.0 - ratio(now - *start, window);
+                let count = f64::from(*previous) * previous_weight + f64::from(*current);
+                let allowed = count + 1.0 <= f64::from(limit);
+                if allowed {
+                    *current += 1;
+                }
+                let used = f64::from(*previous) * previous_weight + f64::from(*current);
+
+                let retry_after = if allowed {
+                    None
+                } else if *previous == 0 {
+                    Some(window - (now - *start))
+                } else {
+                    // Wait until enough of the previous window slid out.
+                    let needed = (count + 1.0 - f64::from(limit)) / f64::from(*previous);
+                    Some(scale(window, needed.min(1.0)))
+                };
+                RateLimitStatus {
+                    allowed,
+                    limit,
+                    remaining: (f64::from(limit) - used).max(0.0) as u32,
+                    reset: window * 2 - (now - *start),
+                    retry_after,
+                }
+            }
+        }
+    }
+}
+
+fn ratio(elapsed: Duration, interval: Duration) -> f64 {
+    secs(elapsed) / secs(interval)
+}
+
+fn scale(duration: Duration, factor: f64) -> Duration {
+    let secs = secs(duration) * factor.max(0.0);
+    Duration::new(secs as u64, ((secs - secs.floor()) * 1_000_000_000.0) as u32)
+}
+
+fn secs(duration: Duration) -> f64 {
+    duration.as_secs() as f64 + f64::from(duration.subsec_nanos()) / 1_000_000_000.0
+}
+
+fn ceil_secs(duration: Duration) -> u64 {
+    if duration.subsec_nanos() > 0 { duration.as_secs() + 1 } else { duration.as_secs() }
+}
+
+/// The error of a request rejected by a `RateLimiter`.
+#[derive(Debug)]
+pub struct RateLimitExceeded {
+    /// The key of the rejected client.
+    pub key: String,
+    /// The time until the next request would be allowed.
+    pub retry_after: Duration,
+}
+
+impl fmt::Display for RateLimitExceeded {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        write!(formatter, "Rate limit exceeded for {}, retry after {}s", self.key, ceil_secs(self.retry_after))
+    }
+}
+
+impl Error for RateLimitExceeded {}
+
+type KeyFn = Box<dyn Fn(&Request) -> Option<String> + Send + Sync>;
+
+/// A `BeforeMiddleware` enforcing a request quota per client.
+pub struct RateLimiter {
+    algorithm: Algorithm,
+    store: Box<dyn RateLimitStore>,
+    key: KeyFn,
+}
+
+impl RateLimiter {
+    /// Create a `RateLimiter` keeping quotas in a `MemoryStore`, keyed by the
+    /// IP address of the clients.
+    pub fn new(algorithm: Algorithm) -> RateLimiter {
+        RateLimiter {
+            algorithm,
+            store: Box::new(MemoryStore::new()),
+            key: Box::new(|_: &Request| None),
+        }
+    }
+
+    /// Keep the quotas in the given store.
+    pub fn with_store<S: RateLimitStore>(mut self, store: S) -> Self {
+        self.store = Box::new(store);
+        self
+    }
+
+    /// Key clients with a custom function.
+    ///
+    /// Requests for which the function returns `None` are keyed by their client
+    /// IP address, or else their peer credentials.
+    pub fn key_by<F>(mut self, key: F) -> Self
+        where F: Fn(&Request) -> Option<String> + Send + Sync + 'static
+    {
+        self.key = Box::new(key);
+        self
+    }
+
+    /// Key clients by the value of a request header, e.g. an API key.
+    ///
+    /// The client chooses the value, so changing it with each request evades
+    /// the limit: only use this behind a middleware rejecting unknown values,
+    /// or else key by the client IP address, the default.
+    pub fn key_by_header(self, name: &'static str) -> Self {
+        self.key_by(move |request: &Request| {
+            request.headers.get_raw(name)
+                .and_then(|values| values.one())
+                .map(|value| format!("{}:{}", name, String::from_utf8_lossy(value)))
+        })
+    }
+
+    /// Key clients by the value of a query string parameter, e.g. `api_key`.
+    ///
+    /// As with `key_by_header`, the client chooses the value: only use this
+    /// behind a middleware rejecting unknown values.
+    pub fn key_by_query(self, name: &'static str) -> Self {
+        self.key_by(move |request: &Request| {
+            let query = request.uri.query()?;
+            form_urlencoded::parse(query.as_bytes())
+                .find(|(key, _)| key == name)
+                .map(|(_, value)| format!("{}={}", name, value))
+        })
+    }
+
+    /// Key clients by a value stored in the request extensions by a previous
+    /// middleware, e.g. the authenticated user.
+    pub fn key_by_extension<K>(self) -> Self
+        where K: Key, K::Value: fmt::Display + Send + Sync
+    {
+        self.key_by(|request: &Request| {
+            request.extensions.get::<K>().map(|value| format!("extension:{}", value))
+        })
+    }
+
+    /// Get both this `RateLimiter` and `RateLimitHeaders`, to link them at once.
+    pub fn both(self) -> (RateLimiter, RateLimitHeaders) {
+        (self, RateLimitHeaders)
+    }
+
+    fn client_key(&self, request: &Request) -> Option<String> {
+        (self.key)(request)
+            .or_else(|| request.client_ip().map(|ip| ip.to_string()))
+            .or_else(|| request.peer_credentials.as_ref().map(|credentials| format!("uid:{}", credentials.uid)))
+    }
+}
+
+impl BeforeMiddleware for RateLimiter {
+    fn before(&self, request: &mut Request) -> FerrumResult<()> {
+        // Clients without a key would all share one quota.
+        let key = match self.client_key(request) {
+            Some(key) => key,
+            None => return Ok(())
+        };
+        let status = self.store.acquire(&key, &self.algorithm, Instant::now());
+        request.extensions.insert::<RateLimitStatus>(status);
+        if status.allowed {
+            return Ok(());
+        }
+
+        let body = "Too Many Requests";
+        let mut response = Response::new()
+            .with_status(StatusCode::TooManyRequests)
+            .with_header(ContentLength(body.len() as u64))
+            .with_body(body);
+        status.set_headers(&mut response.headers);
+        Err(FerrumError::new(
+            RateLimitExceeded { key, retry_after: status.retry_after.unwrap_or_default() },
+            Some(response)
+        ))
+    }
+}
+
+/// An `AfterMiddleware` adding the `RateLimit-*` headers of the status computed
+/// by a `RateLimiter` to the response.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RateLimitHeaders;
+
+impl AfterMiddleware for RateLimitHeaders {
+    fn after(&self, request: &mut Request, mut response: Response) -> FerrumResult<Response> {
+        if let Some(status) = request.extensions.get::<RateLimitStatus>() {
+            status.set_headers(&mut response.headers);
+        }
+        Ok(response)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::str::FromStr;
+    use hyper::Uri;
+    use request::PeerCredentials;
+
+    #[test]
+    fn test_token_bucket() {
+        let store = MemoryStore::new();
+        let algorithm = Algorithm::TokenBucket { capacity: 2, interval: Duration::from_secs(10) };
+        let now = Instant::now();
+
+        assert!(store.acquire("a", &algorithm, now).allowed);
+        let status = store.acquire("a", &algorithm, now);
+        assert!(status.allowed);
+        assert_eq!(status.remaining, 0);
+
+        let status = store.acquire("a", &algorithm, now);
+        assert!(!status.allowed);
+        assert_eq!(status.retry_after, Some(Duration::from_secs(10)));
+        assert!(store.acquire("b", &algorithm, now).allowed);
+
+        assert!(store.acquire("a", &algorithm, now + Duration::from_secs(10)).allowed);
+        assert!(!store.acquire("a", &algorithm, now + Duration::from_secs(10)).allowed);
+    }
+
+    #[test]
+    fn test_sliding_window() {
+        let store = MemoryStore::new();
+        let algorithm = Algorithm::SlidingWindow { limit: 2, window: Duration::from_secs(10) };
+        let now = Instant::now();
+
+        assert!(store.acquire("a", &algorithm, now).allowed);
+        assert!(store.acquire("a", &algorithm, now).allowed);
+        let status = store.acquire("a", &algorithm, now + Duration::from_secs(5));
+        assert!(!status.allowed);
+        assert_eq!(status.retry_after, Some(Duration::from_secs(5)));
+
+        // Half of the previous window still counts.
+        let later = now + Duration::from_secs(15);
+        assert!(store.acquire("a", &algorithm, later).allowed);
+        assert!(!store.acquire("a", &algorithm, later).allowed);
+
+        assert!(store.acquire("a", &algorithm, now + Duration::from_secs(30)).allowed);
+    }
+
+    #[test]
+    fn test_memory_store_bounds() {
+        let store = MemoryStore::new().with_max_clients(2);
+        let algorithm = Algorithm::TokenBucket { capacity: 1, interval: Duration::from_secs(60) };
+        let now = Instant::now();
+
+        assert!(store.acquire("a", &algorithm, now).allowed);
+        assert!(store.acquire("b", &algorithm, now).allowed);
+        assert!(!store.acquire("a", &algorithm, now).allowed);
+        // The least recently seen client is forgotten.
+        assert!(store.acquire("c", &algorithm, now).allowed);
+        assert_eq!(store.quotas.lock().unwrap().by_key.len(), 2);
+        assert!(!store.acquire("a", &algorithm, now).allowed);
+        assert!(store.acquire("b", &algorithm, now).allowed);
+
+        // The restored quotas are forgotten after a while.
+        store.acquire("d", &algorithm, now + Duration::from_secs(120));
+        let quotas = store.quotas.lock().unwrap();
+        assert_eq!(quotas.by_key.keys().collect::<Vec<_>>(), ["d"]);
+        assert_eq!(quotas.by_use.len(), 1);
+    }
+
+    #[test]
+    fn test_rate_limiter_rejection() {
+        let limiter = RateLimiter::new(Algorithm::TokenBucket { capacity: 1, interval: Duration::from_secs(60) });
+        let mut request = Request::stub();
+        assert!(limiter.before(&mut request).is_ok());
+
+        let error = limiter.before(&mut request).unwrap_err();
+        let response = error.response.unwrap();
+        assert_eq!(response.status, StatusCode::TooManyRequests);
+        assert_eq!(response.headers.get::<RetryAfter>(), Some(&RetryAfter::Delay(Duration::from_secs(60))));
+        assert_eq!(response.headers.get_raw("RateLimit-Limit").unwrap(), "1");
+        assert_eq!(response.headers.get_raw("RateLimit-Remaining").unwrap(), "0");
+    }
+
+    #[test]
+    fn test_rate_limiter_keys() {
+        let algorithm = Algorithm::TokenBucket { capacity: 1, interval: Duration::from_secs(60) };
+        let mut request = Request::stub();
+        request.headers.set_raw("X-Api-Key", "secret");
+        request.uri = Uri::from_str("http://www.rust-lang.org/?api_key=token").unwrap();
+
+        let key = |limiter: RateLimiter, request: &Request| limiter.client_key(request);
+        assert_eq!(key(RateLimiter::new(algorithm), &request).unwrap(), "127.0.0.1");
+        assert_eq!(key(RateLimiter::new(algorithm).key_by_header("X-Api-Key"), &request).unwrap(), "X-Api-Key:secret");
+        assert_eq!(key(RateLimiter::new(algorithm).key_by_query("api_key"), &request).unwrap(), "api_key=token");
+
+        struct User;
+        impl Key for User { type Value = String; }
+        let limiter = RateLimiter::new(algorithm).key_by_extension::<User>();
+        assert_eq!(limiter.client_key(&request).unwrap(), "127.0.0.1");
+        request.extensions.insert::<User>("alice".to_string());
+        assert_eq!(limiter.client_key(&request).unwrap(), "extension:alice");
+
+        // Unix domain socket clients have no address.
+        let limiter = RateLimiter::new(algorithm);
+        let mut request = Request::stub();
+        request.remote_addr = None;
+        request.peer_credentials = Some(PeerCredentials { uid: 1000, gid: 1000, pid: None });
+        assert_eq!(limiter.client_key(&request).unwrap(), "uid:1000");
+
+        // Clients without any key are not limited together.
+        request.peer_credentials = None;
+        assert!(limiter.before(&mut request).is_ok());
+        assert!(limiter.before(&mut request).is_ok());
+        assert!(request.extensions.get::<RateLimitStatus>().is_none());
+    }
+
+    #[test]
+    fn test_rate_limit_headers() {
+        let limiter = RateLimiter::new(Algorithm::TokenBucket { capacity: 5, interval: Duration::from_secs(1) });
+        let mut request = Request::stub();
+        limiter.before(&mut request).unwrap();
+
+        let response = RateLimitHeaders.after(&mut request, Response::new()).unwrap();
+        assert_eq!(response.headers.get_raw("RateLimit-Limit").unwrap(), "5");
+        assert_eq!(response.headers.get_raw("RateLimit-Remaining").unwrap(), "4");
+        assert_eq!(response.headers.get_raw("RateLimit-Reset").unwrap(), "1");
+        assert!(!response.headers.has::<RetryAfter>());
+    }
+}
@@ -0,0 +1,498 @@
+//! Trusted proxies handling of the `Forwarded` and `X-Forwarded-*` headers.
+//!
+//! When Ferrum runs behind reverse proxies, the `remote_addr` of a `Request` is the
+//! address of the nearest proxy. Proxies tell the original client address, scheme
+//! and host through the `Forwarded` header (RFC 7239) or the `X-Forwarded-For`,
+//! `X-Forwarded-Proto` and `X-Forwarded-Host` headers.
+//!
+//! Those headers can be set by anyone, so they are only believed when they were
+//! added by a trusted proxy. `TrustedProxies` is a `BeforeMiddleware` which walks
+//! the chain of forwarding hops from the nearest one, skipping the trusted
+//! proxies, and stores the first untrusted hop as a `ForwardedInfo` in the
+//! request extensions.
+//!
+//! Only the headers the proxies are configured to set are read, the
+//! `X-Forwarded-*` ones by default, see `TrustedProxies::with_header`. The
+//! others come from the client and are ignored, since proxies pass them along
+//! untouched.
+//!
+//! ```rust
+//! use ferrum::*;
+//! use ferrum::request::{ForwardedHeader, TrustedProxies};
+//!
+//! fn hello(request: &mut Request) -> FerrumResult<Response> {
+//!     let body = format!("Hello {:?}", request.client_ip());
+//!     Ok(Response::new().with_content(body, mime::TEXT_PLAIN))
+//! }
+//!
+//! let mut chain = Chain::new(hello);
+//! chain.link_before(TrustedProxies::new(&["127.0.0.1", "10.0.0.0/8"]).unwrap()
+//!     .with_header(ForwardedHeader::Forwarded));
+//! ```
+
+use std::error::Error;
+use std::fmt;
+use std::net::{IpAddr, Ipv6Addr, SocketAddr};
+use std::str::FromStr;
+
+use {Request, FerrumResult};
+use middleware::BeforeMiddleware;
+use typemap::Key;
+
+/// A block of IP addresses, e.g. `10.0.0.0/8` or `fd00::/8`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cidr {
+    address: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    /// Create a block from its first address and prefix length.
+    pub fn new(address: IpAddr, prefix: u8) -> Result<Cidr, CidrParseError> {
+        let max = if address.is_ipv4() { 32 } else { 128 };
+        if prefix > max {
+            return Err(CidrParseError(format!("{}/{}", address, prefix)));
+        }
+        Ok(Cidr { address, prefix })
+    }
+
+    /// Whether the block contains the given address.
+    ///
+    /// IPv4-mapped IPv6 addresses are matched against IPv4 blocks.
+    pub fn contains(&self, address: &IpAddr) -> bool {
+        match (self.address, canonical(*address)) {
+            (IpAddr::V4(network), IpAddr::V4(address)) => {
+                let mask = mask(self.prefix, 32) as u32;
+                u32::from(network) & mask == u32::from(address) & mask
+            },
+            (IpAddr::V6(network), IpAddr::V6(address)) => {
+                let mask = mask(self.prefix, 128);
+                u128::from(network) & mask == u128::from(address) & mask
+            },
+            _ => false
+        }
+    }
+}
+
+fn mask(prefix: u8, bits: u32) -> u128 {
+    if prefix == 0 {
+        0
+    } else {
+        (!0u128 << (128 - u32::from(prefix))) >> (128 - bits)
+    }
+}
+
+fn canonical(address: IpAddr) -> IpAddr {
+    match address {
+        IpAddr::V6(v6) => match v6.to_ipv4_mapped() {
+            Some(v4) => IpAddr::V4(v4),
+            None => IpAddr::V6(v6)
+        },
+        v4 => v4
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = CidrParseError;
+
+    fn from_str(s: &str) -> Result<Cidr, CidrParseError> {
+        let error = || CidrParseError(s.to_string());
+        let mut parts = s.trim().splitn(2, '/');
+        let address = parts.next()
+            .and_then(|address| address.parse::<IpAddr>().ok())
+            .ok_or_else(error)?;
+        let address = canonical(address);
+        let prefix = match parts.next() {
+            Some(prefix) => prefix.parse::<u8>().map_err(|_| error())?,
+            None => if address.is_ipv4() { 32 } else { 128 }
+        };
+        Cidr::new(address, prefix).map_err(|_| error())
+    }
+}
+
+impl fmt::Display for Cidr {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        write!(formatter, "{}/{}", self.address, self.prefix)
+    }
+}
+
+/// The error returned when parsing an invalid `Cidr`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CidrParseError(String);
+
+impl fmt::Display for CidrParseError {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        write!(formatter, "Invalid IP address block: {}", self.0)
+    }
+}
+
+impl Error for CidrParseError {}
+
+/// What the trusted proxies tell about the client of a `Request`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ForwardedInfo {
+    /// The IP address of the client, `None` if a proxy hid it.
+    pub client_ip: Option<IpAddr>,
+    /// The scheme the client used, e.g. `https`.
+    pub scheme: Option<String>,
+    /// The `Host` the client requested.
+    pub host: Option<String>,
+    /// The trusted proxies the request went through, nearest first.
+    pub proxies: Vec<IpAddr>,
+}
+
+impl Key for ForwardedInfo {
+    type Value = ForwardedInfo;
+}
+
+// A forwarding hop, as described by a proxy.
+#[derive(Debug, Default)]
+struct Hop {
+    node: Option<String>,
+    scheme: Option<String>,
+    host: Option<String>,
+}
+
+/// The forwarding headers set by the trusted proxies.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ForwardedHeader {
+    /// The `Forwarded` header of RFC 7239.
+    Forwarded,
+    /// The `X-Forwarded-For`, `X-Forwarded-Proto` and `X-Forwarded-Host`
+    /// headers.
+    XForwarded,
+}
+
+/// A `BeforeMiddleware` resolving the client of requests coming through trusted proxies.
+#[derive(Debug, Clone)]
+pub struct TrustedProxies {
+    networks: Vec<Cidr>,
+    header: ForwardedHeader,
+}
+
+impl TrustedProxies {
+    /// Trust the proxies within the given address blocks, e.g. `10.0.0.0/8`.
+    /// Single addresses are accepted as well.
+    pub fn new<S: AsRef<str>>(networks: &[S]) -> Result<TrustedProxies, CidrParseError> {
+        let networks = networks.iter()
+            .map(|network| network.as_ref().parse())
+            .collect::<Result<Vec<Cidr>, _>>()?;
+        Ok(TrustedProxies { networks, header: ForwardedHeader::XForwarded })
+    }
+
+    /// Set the forwarding headers the trusted proxies set, the other ones
+    /// being ignored. The default is `ForwardedHeader::XForwarded`.
+    pub fn with_header(mut self, header: ForwardedHeader) -> Self {
+        self.header = header;
+        self
+    }
+
+    /// Trust the proxies within the given address block as well.
+    pub fn trust(mut self, network: Cidr) -> Self {
+        self.networks.push(network);
+        self
+    }
+
+    /// Whether the given address belongs to a trusted proxy.
+    pub fn is_trusted(&self, address: &IpAddr) -> bool {
+        self.networks.iter().any(|network| network.contains(address))
+    }
+
+    /// Resolve the client of the given request.
+    pub fn resolve(&self, request: &Request) -> ForwardedInfo {
+        let mut info = ForwardedInfo {
+            client_ip: request.remote_addr.map(|addr| canonical(addr.ip())),
+            scheme: None,
+            host: None,
+            proxies: vec![],
+        };
+        match info.client_ip {
+            Some(ip) if self.is_trusted(&ip) => {},
+            _ => return info
+        }
+
+        let hops = match self.header {
+            ForwardedHeader::Forwarded => raw_values(request, "Forwarded")
+                .map(|values| parse_forwarded(&values))
+                .unwrap_or_default(),
+            ForwardedHeader::XForwarded => parse_x_forwarded(request)
+        };
+
+        for hop in hops.into_iter().rev() {
+            let proxy = info.client_ip.take().unwrap();
+            info.proxies.push(proxy);
+            info.client_ip = hop.node.as_ref().and_then(|node| parse_node(node));
+            info.scheme = hop.scheme.filter(|scheme| is_scheme(scheme));
+            info.host = hop.host.filter(|host| is_host(host));
+
+            match info.client_ip {
+                Some(ip) if self.is_trusted(&ip) => {},
+                _ => break
+            }
+        }
+        info
+    }
+}
+
+impl BeforeMiddleware for TrustedProxies {
+    fn before(&self, request: &mut Request) -> FerrumResult<()> {
+        let info = self.resolve(request);
+        request.extensions.insert::<ForwardedInfo>(info);
+        Ok(())
+    }
+}
+
+fn raw_values(request: &Request, name: &str) -> Option<String> {
+    let raw = request.headers.get_raw(name)?;
+    let values = raw.iter()
+        .map(|line| String::from_utf8_lossy(line).into_owned())
+        .collect::<Vec<_>>();
+    Some(values.join(","))
+}
+
+fn list(value: &str) -> Vec<String> {
+    value.split(',').map(|item| item.trim().to_string()).collect()
+}
+
+fn parse_x_forwarded(request: &Request) -> Vec<Hop> {
+    let nodes = match raw_values(request, "X-Forwarded-For") {
+        Some(value) => list(&value),
+        None => return vec![]
+    };
+    let schemes = raw_values(request, "X-Forwarded-Proto").map(|value| list(&value)).unwrap_or_default();
+    let hosts = raw_values(request, "X-Forwarded-Host").map(|value| list(&value)).unwrap_or_default();
+
+    // Proxies either append to the scheme and host lists, which then line up with
+    // the nodes, or overwrite them, in which case the last value is the one set
+    // by the nearest proxy.
+    let aligned = |values: &[String], index: usize| -> Option<String> {
+        let from_end = nodes.len() - index;
+        if values.len() >= from_end {
+            Some(values[values.len() - from_end].clone())
+        } else {
+            values.last().cloned()
+        }
+    };
+
+    nodes.iter().enumerate().map(|(index, node)| Hop {
+        node: Some(node.clone()),
+        scheme: aligned(&schemes, index),
+        host: aligned(&hosts, index),
+    }).collect()
+}
+
+fn parse_forwarded(value: &str) -> Vec<Hop> {
+    split_unquoted(value, ',').into_iter().map(|element| {
+        let mut hop = Hop::default();
+        for pair in split_unquoted(&element, ';') {
+            let mut parts = pair.splitn(2, '=');
+            let name = parts.next().unwrap_or("").trim().to_lowercase();
+            let value = unquote(parts.next().unwrap_or("").trim());
+            match &*name {
+                "for" => hop.node = Some(value),
+                "proto" => hop.scheme = Some(value.to_lowercase()),
+                "host" => hop.host = Some(value),
+                _ => {}
+            }
+        }
+        hop
+    }).collect()
+}
+
+fn split_unquoted(value: &str, separator: char) -> Vec<String> {
+    let mut items = vec![];
+    let mut item = String::new();
+    let mut quoted = false;
+    let mut escaped = false;
+    for c in value.chars() {
+        if escaped {
+            escaped = false;
+        } else if quoted && c == '\\' {
+            escaped = true;
+        } else if c == '"' {
+            quoted = !quoted;
+        } else if c == separator && !quoted {
+            items.push(item.trim().to_string());
+            item = String::new();
+            continue;
+        }
+        item.push(c);
+    }
+    items.push(item.trim().to_string());
+    items.into_iter().filter(|item| !item.is_empty()).collect()
+}
+
+fn unquote(value: &str) -> String {
+    if value.len() >= 2 && value.starts_with('"') && value.ends_with('"') {
+        let mut unquoted = String::new();
+        let mut escaped = false;
+        for c in value[1..value.len() - 1].chars() {
+            if !escaped && c == '\\' {
+                escaped = true;
+                continue;
+            }
+            escaped = false;
+            unquoted.push(c);
+        }
+        unquoted
+    } else {
+        value.to_string()
+    }
+}
+
+// Parse a node identifier: an IP address, optionally bracketed and with a port.
+// Obfuscated identifiers and "unknown" have no address.
+fn parse_node(node: &str) -> Option<IpAddr> {
+    if let Ok(ip) = node.parse::<IpAddr>() {
+        return Some(canonical(ip));
+    }
+    if let Ok(addr) = node.parse::<SocketAddr>() {
+        return Some(canonical(addr.ip()));
+    }
+    if node.starts_with('[') {
+        let end = node.find(']')?;
+        return node[1..end].parse::<Ipv6Addr>().ok().map(|ip| canonical(IpAddr::V6(ip)));
+    }
+    // IPv4 with an obfuscated port, e.g. "192.0.2.43:_hidden"
+    node.rsplit_once(':').and_then(|(ip, _)| ip.parse::<IpAddr>().ok()).map(canonical)
+}
+
+fn is_scheme(scheme: &str) -> bool {
+    let mut chars = scheme.chars();
+    match chars.next() {
+        Some(c) if c.is_ascii_alphabetic() => {},
+        _ => return false
+    }
+    chars.all(|c| c.is_ascii_alphanumeric() || c == '+' || c == '-' || c == '.')
+}
+
+pub(crate) fn is_host(host: &str) -> bool {
+    !host.is_empty() && host.chars().all(|c| {
+        c.is_ascii_alphanumeric() || "-._~:[]".contains(c)
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn request(remote: &str, headers: &[(&str, &str)]) -> Request {
+        let mut request = Request::stub();
+        request.remote_addr = Some(remote.parse().unwrap());
+        for &(name, value) in headers {
+            request.headers.append_raw(name.to_string(), value.as_bytes().to_vec());
+        }
+        request
+    }
+
+    fn proxies() -> TrustedProxies {
+        TrustedProxies::new(&["10.0.0.0/8", "::1"]).unwrap()
+    }
+
+    #[test]
+    fn test_cidr() {
+        let cidr = "192.168.0.0/16".parse::<Cidr>().unwrap();
+        assert!(cidr.contains(&"192.168.10.1".parse().unwrap()));
+        assert!(cidr.contains(&"::ffff:192.168.10.1".parse().unwrap()));
+        assert!(!cidr.contains(&"192.169.0.1".parse().unwrap()));
+
+        let cidr = "fd00::/8".parse::<Cidr>().unwrap();
+        assert!(cidr.contains(&"fd12::1".parse().unwrap()));
+        assert!(!cidr.contains(&"fe80::1".parse().unwrap()));
+
+        assert!("0.0.0.0/0".parse::<Cidr>().unwrap().contains(&"8.8.8.8".parse().unwrap()));
+        assert!("10.0.0.0/33".parse::<Cidr>().is_err());
+        assert!("localhost".parse::<Cidr>().is_err());
+    }
+
+    #[test]
+    fn test_untrusted_remote_is_client() {
+        let request = request("203.0.113.5:4000", &[("X-Forwarded-For", "198.51.100.1")]);
+        let info = proxies().resolve(&request);
+        assert_eq!(info.client_ip, Some("203.0.113.5".parse().unwrap()));
+        assert!(info.proxies.is_empty());
+    }
+
+    #[test]
+    fn test_x_forwarded_for() {
+        let request = request("10.0.0.2:4000", &[
+            ("X-Forwarded-For", "198.51.100.1, 203.0.113.9, 10.0.0.1"),
+            ("X-Forwarded-Proto", "https"),
+            ("X-Forwarded-Host", "example.com"),
+        ]);
+        let info = proxies().resolve(&request);
+        assert_eq!(info.client_ip, Some("203.0.113.9".parse().unwrap()));
+        assert_eq!(info.scheme, Some("https".to_string()));
+        assert_eq!(info.host, Some("example.com".to_string()));
+        assert_eq!(info.proxies, vec!["10.0.0.2".parse::<IpAddr>().unwrap(), "10.0.0.1".parse().unwrap()]);
+    }
+
+    #[test]
+    fn test_x_forwarded_proto_spoofing() {
+        // The client sent "https", the proxy appended the real scheme.
+        let request = request("10.0.0.2:4000", &[
+            ("X-Forwarded-For", "198.51.100.1"),
+            ("X-Forwarded-Proto", "https, http"),
+        ]);
+        assert_eq!(proxies().resolve(&request).scheme, Some("http".to_string()));
+    }
+
+    #[test]
+    fn test_forwarded() {
+        let request = request("[::1]:4000", &[
+            ("Forwarded", "for=198.51.100.1;proto=http"),
+            ("Forwarded", "for=\"[2001:db8:cafe::17]:4711\";proto=https;host=\"example.com:8443\", for=10.1.2.3"),
+            ("X-Forwarded-For", "192.0.2.1"),
+        ]);
+        let info = proxies().with_header(ForwardedHeader::Forwarded).resolve(&request);
+        assert_eq!(info.client_ip, Some("2001:db8:cafe::17".parse().unwrap()));
+        assert_eq!(info.scheme, Some("https".to_string()));
+        assert_eq!(info.host, Some("example.com:8443".to_string()));
+    }
+
+    #[test]
+    fn test_forwarded_obfuscated() {
+        let request = request("10.0.0.1:4000", &[("Forwarded", "for=_hidden, for=unknown;proto=https")]);
+        let info = proxies().with_header(ForwardedHeader::Forwarded).resolve(&request);
+        assert_eq!(info.client_ip, None);
+        assert_eq!(info.scheme, Some("https".to_string()));
+    }
+
+    #[test]
+    fn test_header_spoofing() {
+        // The proxy appends `X-Forwarded-For`, the client sent `Forwarded`.
+        let spoofed = request("10.0.0.2:4000", &[
+            ("Forwarded", "for=1.2.3.4;proto=https;host=evil"),
+            ("X-Forwarded-For", "198.51.100.1"),
+        ]);
+        let info = proxies().resolve(&spoofed);
+        assert_eq!(info.client_ip, Some("198.51.100.1".parse().unwrap()));
+        assert_eq!(info.scheme, None);
+        assert_eq!(info.host, None);
+
+        // Without `X-Forwarded-For`, the proxy itself is the client.
+        let spoofed = request("10.0.0.2:4000", &[("Forwarded", "for=1.2.3.4;proto=https;host=evil")]);
+        let info = proxies().resolve(&spoofed);
+        assert_eq!(info.client_ip, Some("10.0.0.2".parse().unwrap()));
+        assert_eq!(info.host, None);
+
+        // And the other way around.
+        let spoofed = request("10.0.0.2:4000", &[
+            ("X-Forwarded-For", "1.2.3.4"),
+            ("X-Forwarded-Host", "evil"),
+            ("Forwarded", "for=198.51.100.1"),
+        ]);
+        let info = proxies().with_header(ForwardedHeader::Forwarded).resolve(&spoofed);
+        assert_eq!(info.client_ip, Some("198.51.100.1".parse().unwrap()));
+        assert_eq!(info.host, None);
+    }
+
+    #[test]
+    fn test_client_ip() {
+        let mut request = request("10.0.0.1:4000", &[("X-Forwarded-For", "198.51.100.1")]);
+        assert_eq!(request.client_ip(), Some("10.0.0.1".parse().unwrap()));
+
+        proxies().before(&mut request).unwrap();
+        assert_eq!(request.client_ip(), Some("198.51.100.1".parse().unwrap()));
+    }
+}
@@ -1,10 +1,11 @@
 //! Ferrum's HTTP Request representation and associated methods.
 
 use std::mem;
-use std::net::SocketAddr;
+use std::net::{IpAddr, SocketAddr};
 use std::fmt::{self, Debug};
 
 use hyper::{Body, HttpVersion, Uri};
+use url::{Url, ParseError};
 
 use typemap::{TypeMap, TypeMapInner};
 use plugin::Extensible;
@@ -16,6 +17,12 @@ use {Plugin, Headers, Method};
 pub mod uri;
 pub use self::uri::*;
 
+pub mod forwarded;
+pub use self::forwarded::*;
+
+pub mod url;
+pub use self::url::Scheme;
+
 /// The `Request` given to all `Middleware`.
 ///
 /// Stores all the properties of the client's request plus
@@ -37,6 +44,19 @@ pub struct Request {
     /// may not have a socket address, such as Unix Sockets.
     pub remote_addr: Option<SocketAddr>,
 
+    /// The local address the request was received on, if the listener has one.
+    pub local_addr: Option<SocketAddr>,
+
+    /// The scheme of the listener the request was received on.
+    pub scheme: Scheme,
+
+    /// The credentials of the peer process, for requests received on a Unix
+    /// domain socket.
+    pub peer_credentials: Option<PeerCredentials>,
+
+    /// The tag of the endpoint the request was received on, see `Endpoint::with_tag`.
+    pub listener_tag: Option<String>,
+
     /// The request headers.
     pub headers: Headers,
 
@@ -56,11 +76,26 @@ impl Debug for Request {
         writeln!(f, "    method: {:?}", self.method)?;
         writeln!(f, "    version: {:?}", self.version)?;
         writeln!(f, "    remote_addr: {:?}", self.remote_addr)?;
+        writeln!(f, "    local_addr: {:?}", self.local_addr)?;
+        writeln!(f, "    scheme: {:?}", self.scheme)?;
+        writeln!(f, "    peer_credentials: {:?}", self.peer_credentials)?;
+        writeln!(f, "    listener_tag: {:?}", self.listener_tag)?;
         write!(f, "}}")?;
         Ok(())
     }
 }
 
+/// The credentials of the process on the other end of a Unix domain socket.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PeerCredentials {
+    /// The effective user id of the process.
+    pub uid: u32,
+    /// The effective group id of the process.
+    pub gid: u32,
+    /// The process id, when the platform provides it.
+    pub pid: Option<u32>,
+}
+
 impl Request {
     /// Create a request from an HyperRequest.
     ///
@@ -78,6 +113,10 @@ impl Request {
             method,
             version,
             remote_addr,
+            local_addr: None,
+            scheme: Scheme::Http,
+            peer_credentials: None,
+            listener_tag: None,
             headers,
             body: Some(body),
             extensions: TypeMap::custom(),
@@ -85,6 +124,44 @@ impl Request {
         }
     }
 
+    /// The IP address of the client.
+    ///
+    /// This is the address resolved by the `TrustedProxies` middleware when it is
+    /// linked, the address of the peer otherwise.
+    pub fn client_ip(&self) -> Option<IpAddr> {
+        match self.extensions.get::<ForwardedInfo>() {
+            Some(info) => info.client_ip,
+            None => self.remote_addr.map(|addr| addr.ip())
+        }
+    }
+
+    /// The absolute URL of the request.
+    ///
+    /// The scheme and host are taken, by order of preference, from the trusted
+    /// forwarding headers (see `TrustedProxies`), the request URI when it is in
+    /// absolute form, the `Host` header, and finally the listener the request
+    /// was received on. The path is the one received, including the prefixes
+    /// stripped by `mount::Mount`.
+    pub fn url(&self) -> Result<Url, ParseError> {
+        self::url::request_url(self)
+    }
+
+    /// Resolve a URL reference, e.g. `/login`, against the URL of the request.
+    ///
+    /// This is useful to build absolute `Location` headers:
+    ///
+    /// ```rust
+    /// use ferrum::*;
+    ///
+    /// fn handler(request: &mut Request) -> FerrumResult<Response> {
+    ///     let location = request.url_for("/login").map_err(|err| FerrumError::new(err, None))?;
+    ///     Ok(Response::new_redirect(location.to_string()))
+    /// }
+    /// ```
+    pub fn url_for(&self, reference: &str) -> Result<Url, ParseError> {
+        self.url()?.join(reference)
+    }
+
     pub fn take_body(&mut self) -> Body {
         let body = mem::replace(&mut self.body, None);
         body.unwrap_or_default()
@@ -104,6 +181,10 @@ impl Request {
             method: Method::Get,
             version: HttpVersion::Http11,
             remote_addr: Some("localhost:3000".to_socket_addrs().unwrap().next().unwrap()),
+            local_addr: None,
+            scheme: Scheme::Http,
+            peer_credentials: None,
+            listener_tag: None,
             headers: Headers::new(),
             body: None,
             extensions: TypeMap::custom(),
@@ -143,6 +224,10 @@ mod test {
         assert_eq!(request.method, Method::Get);
         assert_eq!(request.version, HttpVersion::default());
         assert_eq!(request.remote_addr, None);
+        assert_eq!(request.local_addr, None);
+        assert_eq!(request.scheme, Scheme::Http);
+        assert_eq!(request.peer_credentials, None);
+        assert_eq!(request.listener_tag, None);
         assert_eq!(request.headers, Headers::new());
     }
 
@@ -0,0 +1,144 @@
+//! Ferrum's reconstruction of the full URL of a request.
+
+use std::fmt;
+
+use url::{Url, ParseError};
+
+use Request;
+use mount::OriginalUri;
+use super::forwarded::{ForwardedInfo, is_host};
+
+/// The scheme of the listener a request was received on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Scheme {
+    /// Plain text HTTP.
+    #[default]
+    Http,
+    /// HTTP over TLS.
+    Https,
+}
+
+impl Scheme {
+    /// The scheme as it appears in URLs.
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            Scheme::Http => "http",
+            Scheme::Https => "https",
+        }
+    }
+
+    /// The port used when URLs of this scheme have none.
+    pub fn default_port(&self) -> u16 {
+        match *self {
+            Scheme::Http => 80,
+            Scheme::Https => 443,
+        }
+    }
+}
+
+impl fmt::Display for Scheme {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str(self.as_str())
+    }
+}
+
+// Build the absolute URL of the request, see `Request::url`.
+pub fn request_url(request: &Request) -> Result<Url, ParseError> {
+    let forwarded = request.extensions.get::<ForwardedInfo>();
+    // The URI as received, before a `Mount` stripped its prefix.
+    let uri = request.extensions.get::<OriginalUri>().unwrap_or(&request.uri);
+
+    let scheme = forwarded.and_then(|info| info.scheme.clone())
+        .or_else(|| uri.scheme().map(|scheme| scheme.to_string()))
+        .unwrap_or_else(|| request.scheme.as_str().to_string());
+
+    let host = forwarded.and_then(|info| info.host.clone())
+        .or_else(|| uri.authority().map(|authority| authority.to_string()))
+        .or_else(|| {
+            request.headers.get_raw("Host")
+                .and_then(|raw| raw.one())
+                .map(|host| String::from_utf8_lossy(host).trim().to_string())
+                .filter(|host| is_host(host))
+        })
+        .or_else(|| request.local_addr.map(|addr| addr.to_string()));
+
+    let host = match host {
+        Some(host) => host,
+        None => return Err(ParseError::EmptyHost)
+    };
+
+    let path = match uri.query() {
+        Some(query) => format!("{}?{}", uri.path(), query),
+        None => uri.path().to_string()
+    };
+
+    Url::parse(&format!("{}://{}{}", scheme, host, path))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::str::FromStr;
+    use hyper::Uri;
+    use hyper::header::Location;
+    use {Response, StatusCode};
+
+    fn request(uri: &str) -> Request {
+        let mut request = Request::stub();
+        request.uri = Uri::from_str(uri).unwrap();
+        request.local_addr = Some("127.0.0.1:3000".parse().unwrap());
+        request
+    }
+
+    #[test]
+    fn test_url_from_host_header() {
+        let mut request = request("/foo/bar?baz=1");
+        request.headers.set_raw("Host", "example.com:8080");
+        assert_eq!(request.url().unwrap().as_str(), "http://example.com:8080/foo/bar?baz=1");
+
+        request.scheme = Scheme::Https;
+        request.headers.set_raw("Host", "example.com:443");
+        assert_eq!(request.url().unwrap().as_str(), "https://example.com/foo/bar?baz=1");
+    }
+
+    #[test]
+    fn test_url_from_local_addr() {
+        let request = request("/");
+        assert_eq!(request.url().unwrap().as_str(), "http://127.0.0.1:3000/");
+
+        let mut request = Request::stub();
+        request.uri = Uri::from_str("/").unwrap();
+        assert_eq!(request.url(), Err(ParseError::EmptyHost));
+    }
+
+    #[test]
+    fn test_url_from_absolute_uri() {
+        let mut request = request("https://www.rust-lang.org/learn");
+        request.headers.set_raw("Host", "example.com");
+        assert_eq!(request.url().unwrap().as_str(), "https://www.rust-lang.org/learn");
+    }
+
+    #[test]
+    fn test_url_from_forwarded_info() {
+        let mut request = request("/foo");
+        request.headers.set_raw("Host", "internal:8080");
+        request.extensions.insert::<ForwardedInfo>(ForwardedInfo {
+            client_ip: None,
+            scheme: Some("https".to_string()),
+            host: Some("example.com".to_string()),
+            proxies: vec![],
+        });
+        assert_eq!(request.url().unwrap().as_str(), "https://example.com/foo");
+    }
+
+    #[test]
+    fn test_redirect_to_url_for() {
+        let mut request = request("/foo/bar");
+        request.headers.set_raw("Host", "example.com");
+        let location = request.url_for("../login?next=1").unwrap();
+        let response = Response::new_redirect(location.to_string());
+
+        assert_eq!(response.status, StatusCode::Found);
+        assert_eq!(&**response.headers.get::<Location>().unwrap(), "http://example.com/login?next=1");
+    }
+}
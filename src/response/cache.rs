@@ -0,0 +1,161 @@
+//! Ferrum's typed builder for the caching headers of a response.
+
+use std::time::{Duration, SystemTime};
+
+use hyper::header::{CacheControl, CacheDirective, Expires, HttpDate, Vary};
+use unicase::Ascii;
+
+use Headers;
+
+/// A builder for the `Cache-Control`, `Vary` and `Expires` headers of a `Response`.
+///
+/// ```rust
+/// use std::time::Duration;
+/// use ferrum::*;
+/// use ferrum::response::CachePolicy;
+///
+/// let response = Response::new()
+///     .with_content("Hello world!", mime::TEXT_PLAIN)
+///     .with_cache_policy(
+///         CachePolicy::new()
+///             .public()
+///             .max_age(Duration::from_secs(60))
+///             .vary("Accept-Encoding")
+///     );
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct CachePolicy {
+    directives: Vec<CacheDirective>,
+    vary: Vec<String>,
+    vary_any: bool,
+    expires: Option<SystemTime>,
+}
+
+impl CachePolicy {
+    /// Create an empty `CachePolicy`, which sets no headers.
+    pub fn new() -> CachePolicy {
+        CachePolicy::default()
+    }
+
+    /// Add an arbitrary `Cache-Control` directive.
+    pub fn directive(mut self, directive: CacheDirective) -> Self {
+        self.directives.push(directive);
+        self
+    }
+
+    /// Allow any cache to store the response.
+    pub fn public(self) -> Self {
+        self.directive(CacheDirective::Public)
+    }
+
+    /// Allow only the client's private cache to store the response.
+    pub fn private(self) -> Self {
+        self.directive(CacheDirective::Private)
+    }
+
+    /// Require caches to revalidate the response before each reuse.
+    pub fn no_cache(self) -> Self {
+        self.directive(CacheDirective::NoCache)
+    }
+
+    /// Forbid any cache to store the response.
+    pub fn no_store(self) -> Self {
+        self.directive(CacheDirective::NoStore)
+    }
+
+    /// Forbid intermediaries to transform the response body.
+    pub fn no_transform(self) -> Self {
+        self.directive(CacheDirective::NoTransform)
+    }
+
+    /// Forbid caches to serve the response once stale.
+    pub fn must_revalidate(self) -> Self {
+        self.directive(CacheDirective::MustRevalidate)
+    }
+
+    /// Forbid shared caches to serve the response once stale.
+    pub fn proxy_revalidate(self) -> Self {
+        self.directive(CacheDirective::ProxyRevalidate)
+    }
+
+    /// Set how long the response stays fresh.
+    pub fn max_age(self, age: Duration) -> Self {
+        self.directive(CacheDirective::MaxAge(seconds(age)))
+    }
+
+    /// Set how long the response stays fresh in shared caches.
+    pub fn s_max_age(self, age: Duration) -> Self {
+        self.directive(CacheDirective::SMaxAge(seconds(age)))
+    }
+
+    /// Add a request header the response varies on.
+    pub fn vary<S: Into<String>>(mut self, header: S) -> Self {
+        self.vary.push(header.into());
+        self
+    }
+
+    /// Mark the response as varying on something other than request headers.
+    pub fn vary_any(mut self) -> Self {
+        self.vary_any = true;
+        self
+    }
+
+    /// Set the date after which the response is considered stale.
+    pub fn expires(mut self, time: SystemTime) -> Self {
+        self.expires = Some(time);
+        self
+    }
+
+    /// Set the headers described by this policy, replacing existing ones.
+    pub fn apply(self, headers: &mut Headers) {
+        if !self.directives.is_empty() {
+            headers.set(CacheControl(self.directives));
+        }
+        if self.vary_any {
+            headers.set(Vary::Any);
+        } else if !self.vary.is_empty() {
+            headers.set(Vary::Items(self.vary.into_iter().map(Ascii::new).collect()));
+        }
+        if let Some(expires) = self.expires {
+            headers.set(Expires(HttpDate::from(expires)));
+        }
+    }
+}
+
+fn seconds(duration: Duration) -> u32 {
+    if duration.as_secs() > u64::from(u32::MAX) {
+        u32::MAX
+    } else {
+        duration.as_secs() as u32
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_apply_cache_policy() {
+        let mut headers = Headers::new();
+        CachePolicy::new()
+            .private()
+            .max_age(Duration::from_secs(120))
+            .vary("Accept-Encoding")
+            .vary("Cookie")
+            .apply(&mut headers);
+
+        assert_eq!(
+            headers.get::<CacheControl>(),
+            Some(&CacheControl(vec![CacheDirective::Private, CacheDirective::MaxAge(120)]))
+        );
+        assert_eq!(headers.get_raw("Vary").unwrap(), "Accept-Encoding, Cookie");
+        assert!(!headers.has::<Expires>());
+    }
+
+    #[test]
+    fn test_empty_cache_policy() {
+        let mut headers = Headers::new();
+        CachePolicy::new().apply(&mut headers);
+        assert_eq!(headers, Headers::new());
+    }
+}
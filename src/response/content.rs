@@ -5,7 +5,7 @@ use std::borrow::Cow;
 
 use hyper::Body;
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Content(pub Vec<u8>);
 
 impl Deref for Content {
@@ -0,0 +1,308 @@
+//! Ferrum's Server-Sent Events responses.
+
+use std::fmt;
+use std::str;
+use std::sync::Arc;
+use std::thread::{self, Thread};
+use std::time::{Duration, Instant};
+
+use futures::{executor, Async, Future, Sink, Stream};
+use futures::executor::Notify;
+use hyper::{self, Body, Chunk};
+use hyper::header::{CacheControl, CacheDirective, ContentType, Formatter, Header, Raw};
+use mime;
+
+use {Response, StatusCode};
+
+/// The default interval of the keep-alive comments of an `EventStream`.
+pub const DEFAULT_KEEP_ALIVE: Duration = Duration::from_secs(15);
+
+/// An event of an `EventStream`.
+///
+/// ```rust
+/// use std::time::Duration;
+/// use ferrum::response::Event;
+///
+/// let event = Event::new("{\"cpu\": 42}")
+///     .with_id("17")
+///     .with_event("load")
+///     .with_retry(Duration::from_secs(5));
+/// assert_eq!(event.to_string(), "id: 17\nevent: load\nretry: 5000\ndata: {\"cpu\": 42}\n\n");
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Event {
+    id: Option<String>,
+    event: Option<String>,
+    data: String,
+    retry: Option<Duration>,
+}
+
+impl Event {
+    /// Create an unnamed event carrying `data`, which may span several lines.
+    pub fn new<S: Into<String>>(data: S) -> Event {
+        Event {
+            id: None,
+            event: None,
+            data: data.into(),
+            retry: None,
+        }
+    }
+
+    /// Set the id of the event, sent back by reconnecting clients as the
+    /// `Last-Event-ID` header.
+    pub fn with_id<S: Into<String>>(mut self, id: S) -> Self {
+        self.id = Some(id.into());
+        self
+    }
+
+    /// Set the type of the event, dispatched by browsers to the listeners of
+    /// that type instead of `onmessage`.
+    pub fn with_event<S: Into<String>>(mut self, event: S) -> Self {
+        self.event = Some(event.into());
+        self
+    }
+
+    /// Set how long clients wait before reconnecting.
+    pub fn with_retry(mut self, retry: Duration) -> Self {
+        self.retry = Some(retry);
+        self
+    }
+}
+
+impl fmt::Display for Event {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        // A field value ends at the first line break.
+        let single_line = |value: &str| value.replace(['\r', '\n'], "");
+
+        if let Some(ref id) = self.id {
+            writeln!(formatter, "id: {}", single_line(id).replace('\0', ""))?;
+        }
+        if let Some(ref event) = self.event {
+            writeln!(formatter, "event: {}", single_line(event))?;
+        }
+        if let Some(retry) = self.retry {
+            writeln!(formatter, "retry: {}", retry.as_millis())?;
+        }
+        for line in self.data.replace("\r\n", "\n").replace('\r', "\n").split('\n') {
+            writeln!(formatter, "data: {}", line)?;
+        }
+        writeln!(formatter)
+    }
+}
+
+/// A stream of events sent as a `text/event-stream` response, see
+/// `Response::new_event_stream`.
+///
+/// The events are forwarded to the client from a dedicated thread, so any
+/// `Stream` works, for instance the receiver of a `futures::sync::mpsc`
+/// channel fed by other threads. The response ends with the stream, or when
+/// the stream fails.
+pub struct EventStream {
+    events: Box<dyn Stream<Item = Event, Error = ()> + Send>,
+    keep_alive: Option<Duration>,
+}
+
+impl EventStream {
+    /// Send the events of `events`, with a keep-alive comment after
+    /// `DEFAULT_KEEP_ALIVE` without events.
+    pub fn new<S>(events: S) -> EventStream
+        where S: Stream<Item = Event> + Send + 'static
+    {
+        EventStream {
+            events: Box::new(events.map_err(|_| ())),
+            keep_alive: Some(DEFAULT_KEEP_ALIVE),
+        }
+    }
+
+    /// Set the interval of the keep-alive comments, which stop proxies from
+    /// closing idle connections and let the server notice disconnected
+    /// clients. `None` disables them.
+    pub fn with_keep_alive(mut self, keep_alive: Option<Duration>) -> Self {
+        self.keep_alive = keep_alive;
+        self
+    }
+
+    // Forward the events to `sender` until the stream ends or the client goes away.
+    fn forward(self, mut sender: ::futures::sync::mpsc::Sender<Result<Chunk, hyper::Error>>) {
+        let notify = Arc::new(ThreadNotify(thread::current()));
+        let mut events = executor::spawn(self.events);
+        let mut deadline = self.keep_alive.map(|keep_alive| Instant::now() + keep_alive);
+
+        loop {
+            let chunk = match events.poll_stream_notify(&notify, 0) {
+                Ok(Async::Ready(Some(event))) => event.to_string(),
+                Ok(Async::Ready(None)) | Err(()) => return,
+                Ok(Async::NotReady) => match deadline {
+                    Some(instant) if Instant::now() >= instant => ": keep-alive\n\n".to_string(),
+                    Some(instant) => {
+                        thread::park_timeout(instant - Instant::now());
+                        continue;
+                    },
+                    None => {
+                        thread::park();
+                        continue;
+                    }
+                }
+            };
+
+            sender = match sender.send(Ok(chunk.into())).wait() {
+                Ok(sender) => sender,
+                Err(_) => return
+            };
+            deadline = self.keep_alive.map(|keep_alive| Instant::now() + keep_alive);
+        }
+    }
+}
+
+impl fmt::Debug for EventStream {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.debug_struct("EventStream")
+            .field("keep_alive", &self.keep_alive)
+            .finish()
+    }
+}
+
+// Wakes the forwarding thread when the stream has new events.
+struct ThreadNotify(Thread);
+
+impl Notify for ThreadNotify {
+    fn notify(&self, _: usize) {
+        self.0.unpark();
+    }
+}
+
+impl Response {
+    /// Construct a `text/event-stream` Response sending the events of `events`.
+    ///
+    /// ```rust,no_run
+    /// use std::thread;
+    /// use ferrum::*;
+    /// use ferrum::futures::sync::mpsc;
+    /// use ferrum::response::{Event, EventStream, LastEventId};
+    ///
+    /// let handler = |request: &mut Request| {
+    ///     // Resume after the last event received by a reconnecting client.
+    ///     let first = request.headers.get::<LastEventId>()
+    ///         .and_then(|id| id.0.parse::<u64>().ok())
+    ///         .map(|id| id + 1)
+    ///         .unwrap_or(0);
+    ///
+    ///     let (sender, events) = mpsc::unbounded();
+    ///     thread::spawn(move || {
+    ///         for id in first.. {
+    ///             let event = Event::new(format!("tick {}", id)).with_id(id.to_string());
+    ///             if sender.unbounded_send(event).is_err() {
+    ///                 break;
+    ///             }
+    ///             thread::sleep(std::time::Duration::from_secs(1));
+    ///         }
+    ///     });
+    ///     Ok(Response::new_event_stream(EventStream::new(events)))
+    /// };
+    /// Ferrum::new(handler).http("localhost:3000").unwrap();
+    /// ```
+    pub fn new_event_stream(events: EventStream) -> Response {
+        let (sender, body) = Body::pair();
+        let spawned = thread::Builder::new()
+            .name("ferrum-events".to_string())
+            .spawn(move || events.forward(sender));
+
+        let mut response = Response::new();
+        if spawned.is_err() {
+            response.status = StatusCode::ServiceUnavailable;
+            return response;
+        }
+        response.headers.set(ContentType(mime::TEXT_EVENT_STREAM));
+        response.headers.set(CacheControl(vec![CacheDirective::NoCache]));
+        response.body = Some(body);
+        response
+    }
+}
+
+/// The `Last-Event-ID` header, the id of the last event received by a client
+/// reconnecting to an event stream.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LastEventId(pub String);
+
+impl Header for LastEventId {
+    fn header_name() -> &'static str {
+        "Last-Event-ID"
+    }
+
+    fn parse_header(raw: &Raw) -> hyper::Result<LastEventId> {
+        raw.one()
+            .and_then(|line| str::from_utf8(line).ok())
+            .map(|id| LastEventId(id.to_string()))
+            .ok_or(hyper::Error::Header)
+    }
+
+    fn fmt_header(&self, formatter: &mut Formatter) -> fmt::Result {
+        formatter.fmt_line(&self.0)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::{Read, Write};
+    use std::net::TcpStream;
+
+    use futures::stream;
+    use futures::sync::mpsc;
+
+    use {Ferrum, Request};
+
+    #[test]
+    fn test_format_event() {
+        assert_eq!(Event::new("one\ntwo\r\nthree").to_string(), "data: one\ndata: two\ndata: three\n\n");
+        assert_eq!(Event::new("").with_event("a\nb").to_string(), "event: ab\ndata: \n\n");
+    }
+
+    #[test]
+    fn test_last_event_id() {
+        let mut request = Request::stub();
+        assert_eq!(request.headers.get::<LastEventId>(), None);
+        request.headers.set_raw("Last-Event-ID", "42");
+        assert_eq!(request.headers.get::<LastEventId>(), Some(&LastEventId("42".to_string())));
+    }
+
+    fn read_until(stream: &mut TcpStream, end: &str) -> String {
+        let mut received = Vec::new();
+        let mut buf = [0; 1024];
+        while !String::from_utf8_lossy(&received).contains(end) {
+            let len = stream.read(&mut buf).unwrap();
+            assert!(len > 0, "{}", String::from_utf8_lossy(&received));
+            received.extend_from_slice(&buf[..len]);
+        }
+        String::from_utf8(received).unwrap()
+    }
+
+    #[test]
+    fn test_event_stream() {
+        let (sender, events) = mpsc::unbounded();
+        let events = ::std::sync::Mutex::new(Some(events));
+        let listening = Ferrum::new(move |request: &mut Request| {
+            let last = request.headers.get::<LastEventId>().map(|id| id.0.clone()).unwrap_or_default();
+            let first = stream::iter_ok(vec![Event::new(format!("after {:?}", last))]);
+            let rest = events.lock().unwrap().take().unwrap();
+            let events = EventStream::new(first.chain(rest)).with_keep_alive(Some(Duration::from_millis(50)));
+            Ok(Response::new_event_stream(events))
+        }).spawn("127.0.0.1:0").unwrap();
+
+        let mut stream = TcpStream::connect(listening.local_addr().unwrap()).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        stream.write_all(b"GET /events HTTP/1.1\r\nHost: localhost\r\nLast-Event-ID: 7\r\n\r\n").unwrap();
+
+        let head = read_until(&mut stream, "data: after \"7\"\n\n");
+        assert!(head.starts_with("HTTP/1.1 200 OK"));
+        assert!(head.contains("Content-Type: text/event-stream"));
+        read_until(&mut stream, ": keep-alive\n\n");
+
+        sender.unbounded_send(Event::new("update").with_id("8")).unwrap();
+        read_until(&mut stream, "id: 8\ndata: update\n\n");
+
+        // The response ends with the stream.
+        drop(sender);
+        read_until(&mut stream, "0\r\n\r\n");
+    }
+}
@@ -8,14 +8,22 @@ use typemap::{TypeMap, TypeMapInner};
 use plugin::Extensible;
 use hyper::{Body, HttpVersion};
 use hyper::header::{ContentLength, ContentType, Location, Raw};
+use futures::{Future, Stream};
 
 use {Plugin, Header, Headers, StatusCode};
+use error::HyperResult;
 
 pub use hyper::Response as HyperResponse;
 
 pub mod content;
 pub use self::content::*;
 
+pub mod cache;
+pub use self::cache::*;
+
+pub mod events;
+pub use self::events::*;
+
 /// The response representation given to `Middleware`
 pub struct Response {
     /// The response status-code.
@@ -127,6 +135,44 @@ impl Response {
     pub fn set_mime(&mut self, mime: Mime) {
         self.headers.set(ContentType(mime));
     }
+
+    /// Set the caching headers described by `policy` and move the Response.
+    ///
+    /// Useful for the "builder-style" pattern.
+    #[inline]
+    pub fn with_cache_policy(mut self, policy: CachePolicy) -> Self {
+        self.set_cache_policy(policy);
+        self
+    }
+
+    /// Set the caching headers described by `policy`.
+    #[inline]
+    pub fn set_cache_policy(&mut self, policy: CachePolicy) {
+        policy.apply(&mut self.headers);
+    }
+
+    /// Whether the body is fully known up front, i.e. a `Content-Length` is set.
+    ///
+    /// Responses built with `set_content` are buffered, streamed bodies are not.
+    #[inline]
+    pub fn is_buffered(&self) -> bool {
+        self.headers.has::<ContentLength>()
+    }
+
+    /// Read the whole body into memory, putting an equivalent body back in place.
+    ///
+    /// Returns `None` when the response has no body. This blocks until the body
+    /// stream ends, so it should only be used on buffered responses.
+    pub fn buffer_content(&mut self) -> HyperResult<Option<Content>> {
+        let body = match self.body.take() {
+            Some(body) => body,
+            None => return Ok(None)
+        };
+        let chunk = body.concat2().wait()?;
+        let content = Content(chunk.to_vec());
+        self.body = Some(content.clone().into());
+        Ok(Some(content))
+    }
 }
 
 impl From<HyperResponse> for Response {
@@ -196,6 +242,20 @@ mod test {
         assert!(response.body.is_none());
     }
 
+    #[test]
+    fn test_buffer_content() {
+        let mut response = Response::new().with_content("Hello", mime::TEXT_PLAIN);
+        assert!(response.is_buffered());
+
+        let content = response.buffer_content().unwrap().unwrap();
+        assert_eq!(*content, b"Hello".to_vec());
+
+        let body = response.body.unwrap().concat2().wait().unwrap();
+        assert_eq!(&*body, b"Hello");
+
+        assert!(Response::new().buffer_content().unwrap().is_none());
+    }
+
     #[test]
     fn test_response_from_hyper_response() {
         let mut headers = Headers::new();
@@ -0,0 +1,75 @@
+//! A transport replaying the bytes read ahead of the protocol.
+
+use std::cmp;
+use std::io::{self, Read, Write};
+
+use futures::Poll;
+use tokio_io::{AsyncRead, AsyncWrite};
+
+/// A transport which first returns `prefix` to its readers, then reads from
+/// the underlying `io`.
+#[derive(Debug)]
+pub struct Rewind<S> {
+    prefix: Vec<u8>,
+    position: usize,
+    io: S,
+}
+
+impl<S> Rewind<S> {
+    pub fn new(io: S, prefix: Vec<u8>) -> Rewind<S> {
+        Rewind { prefix, position: 0, io }
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.io
+    }
+}
+
+impl<S: Read> Read for Rewind<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.position < self.prefix.len() {
+            let remaining = &self.prefix[self.position..];
+            let len = cmp::min(remaining.len(), buf.len());
+            buf[..len].copy_from_slice(&remaining[..len]);
+            self.position += len;
+            return Ok(len);
+        }
+        self.io.read(buf)
+    }
+}
+
+impl<S: Write> Write for Rewind<S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.io.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.io.flush()
+    }
+}
+
+impl<S: AsyncRead> AsyncRead for Rewind<S> {}
+
+impl<S: AsyncWrite> AsyncWrite for Rewind<S> {
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        self.io.shutdown()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn test_rewind() {
+        let mut rewind = Rewind::new(Cursor::new(b"world".to_vec()), b"hello ".to_vec());
+        let mut buf = [0; 4];
+        assert_eq!(rewind.read(&mut buf).unwrap(), 4);
+        assert_eq!(&buf, b"hell");
+
+        let mut rest = String::new();
+        rewind.read_to_string(&mut rest).unwrap();
+        assert_eq!(rest, "o world");
+    }
+}
@@ -0,0 +1,359 @@
+//! Hardened security headers for responses.
+//!
+//! `SecurityHeaders` is an `AfterMiddleware` setting the
+//! `Strict-Transport-Security`, `Content-Security-Policy`,
+//! `X-Content-Type-Options`, `X-Frame-Options`, `Referrer-Policy` and
+//! `Permissions-Policy` headers of responses, including error responses. The
+//! headers already set by the handler are kept, so a route can loosen the
+//! defaults.
+//!
+//! The `{nonce}` placeholders of the policy are replaced by the `CspNonce` of
+//! the request, a random value generated for each request by the `CspNonces`
+//! `BeforeMiddleware`, which templates put in the `nonce` attribute of their
+//! inline scripts and styles:
+//!
+//! ```rust
+//! use ferrum::*;
+//! use ferrum::security::{CspNonce, SecurityHeaders};
+//!
+//! fn page(request: &mut Request) -> FerrumResult<Response> {
+//!     let nonce = request.extensions.get::<CspNonce>().cloned().unwrap_or_default();
+//!     let html = format!("<script nonce=\"{}\">start()</script>", nonce);
+//!     Ok(Response::new().with_content(html, mime::TEXT_HTML))
+//! }
+//!
+//! let mut chain = Chain::new(page);
+//! chain.link(SecurityHeaders::new().with_referrer_policy(Some("no-referrer")).both());
+//! ```
+//!
+//! Without `CspNonces`, the sources of the policy including a placeholder are
+//! removed, leaving the inline scripts and styles blocked.
+//! `Strict-Transport-Security` is only sent over HTTPS, as browsers ignore
+//! it otherwise.
+
+use std::fmt;
+use std::time::Duration;
+
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+
+use {Request, Response, FerrumResult, FerrumError, Headers};
+use middleware::{BeforeMiddleware, AfterMiddleware};
+use random;
+use request::Scheme;
+use typemap::Key;
+
+/// The placeholder of the nonce in a `Content-Security-Policy`.
+pub const NONCE_PLACEHOLDER: &str = "{nonce}";
+
+/// The default `Content-Security-Policy`, allowing the resources of the same
+/// origin and the inline scripts and styles bearing the nonce.
+pub const DEFAULT_CSP: &str = "default-src 'self'; script-src 'self' 'nonce-{nonce}'; \
+    style-src 'self' 'nonce-{nonce}'; object-src 'none'; base-uri 'self'; frame-ancestors 'none'";
+
+/// The default `Permissions-Policy`, denying the powerful browser features.
+pub const DEFAULT_PERMISSIONS_POLICY: &str = "camera=(), microphone=(), geolocation=(), payment=(), usb=()";
+
+/// The CSP nonce of a request, in its extensions.
+pub struct CspNonce;
+
+impl Key for CspNonce {
+    type Value = String;
+}
+
+/// The `Strict-Transport-Security` policy.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Hsts {
+    /// How long browsers only use HTTPS for the host.
+    pub max_age: Duration,
+    /// Whether the policy covers the subdomains of the host.
+    pub include_subdomains: bool,
+    /// Whether the host asks to be preloaded in browsers.
+    pub preload: bool,
+}
+
+impl Default for Hsts {
+    /// Two years, including subdomains, without preload.
+    fn default() -> Hsts {
+        Hsts {
+            max_age: Duration::from_secs(2 * 365 * 24 * 60 * 60),
+            include_subdomains: true,
+            preload: false,
+        }
+    }
+}
+
+impl fmt::Display for Hsts {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        write!(formatter, "max-age={}", self.max_age.as_secs())?;
+        if self.include_subdomains {
+            formatter.write_str("; includeSubDomains")?;
+        }
+        if self.preload {
+            formatter.write_str("; preload")?;
+        }
+        Ok(())
+    }
+}
+
+/// The `X-Frame-Options` of responses.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FrameOptions {
+    /// The responses can't be framed.
+    Deny,
+    /// The responses can only be framed by pages of the same origin.
+    SameOrigin,
+}
+
+impl FrameOptions {
+    /// The value of the header.
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            FrameOptions::Deny => "DENY",
+            FrameOptions::SameOrigin => "SAMEORIGIN",
+        }
+    }
+}
+
+/// A `BeforeMiddleware` storing a random `CspNonce` in the extensions of each
+/// request.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CspNonces;
+
+impl BeforeMiddleware for CspNonces {
+    fn before(&self, request: &mut Request) -> FerrumResult<()> {
+        if !request.extensions.contains::<CspNonce>() {
+            let mut nonce = [0; 16];
+            random::fill(&mut nonce);
+            request.extensions.insert::<CspNonce>(BASE64.encode(nonce));
+        }
+        Ok(())
+    }
+}
+
+/// An `AfterMiddleware` setting the security headers of responses, see the
+/// `security` module.
+#[derive(Debug, Clone)]
+pub struct SecurityHeaders {
+    hsts: Option<Hsts>,
+    csp: Option<String>,
+    csp_report_only: bool,
+    content_type_options: bool,
+    frame_options: Option<FrameOptions>,
+    referrer_policy: Option<String>,
+    permissions_policy: Option<String>,
+}
+
+impl SecurityHeaders {
+    /// Create `SecurityHeaders` with the hardened defaults: the default
+    /// `Hsts`, `DEFAULT_CSP`, `nosniff`, `DENY`,
+    /// `strict-origin-when-cross-origin` and `DEFAULT_PERMISSIONS_POLICY`.
+    pub fn new() -> SecurityHeaders {
+        SecurityHeaders {
+            hsts: Some(Hsts::default()),
+            csp: Some(DEFAULT_CSP.to_string()),
+            csp_report_only: false,
+            content_type_options: true,
+            frame_options: Some(FrameOptions::Deny),
+            referrer_policy: Some("strict-origin-when-cross-origin".to_string()),
+            permissions_policy: Some(DEFAULT_PERMISSIONS_POLICY.to_string()),
+        }
+    }
+
+    /// Set the `Strict-Transport-Security` policy, `None` to send none.
+    pub fn with_hsts(mut self, hsts: Option<Hsts>) -> Self {
+        self.hsts = hsts;
+        self
+    }
+
+    /// Set the `Content-Security-Policy`, where `NONCE_PLACEHOLDER` stands for
+    /// the nonce of the request, `None` to send none.
+    pub fn with_csp(mut self, policy: Option<&str>) -> Self {
+        self.csp = policy.map(str::to_string);
+        self
+    }
+
+    /// Send the policy as `Content-Security-Policy-Report-Only`, to try it
+    /// out with the browsers reporting the violations instead of blocking
+    /// the resources. The default is `false`.
+    pub fn with_csp_report_only(mut self, report_only: bool) -> Self {
+        self.csp_report_only = report_only;
+        self
+    }
+
+    /// Send `X-Content-Type-Options: nosniff`, stopping browsers from
+    /// guessing the content type of responses. The default is `true`.
+    pub fn with_content_type_options(mut self, nosniff: bool) -> Self {
+        self.content_type_options = nosniff;
+        self
+    }
+
+    /// Set the `X-Frame-Options`, `None` to send none.
+    pub fn with_frame_options(mut self, options: Option<FrameOptions>) -> Self {
+        self.frame_options = options;
+        self
+    }
+
+    /// Set the `Referrer-Policy`, `None` to send none.
+    pub fn with_referrer_policy(mut self, policy: Option<&str>) -> Self {
+        self.referrer_policy = policy.map(str::to_string);
+        self
+    }
+
+    /// Set the `Permissions-Policy`, `None` to send none.
+    pub fn with_permissions_policy(mut self, policy: Option<&str>) -> Self {
+        self.permissions_policy = policy.map(str::to_string);
+        self
+    }
+
+    /// Get both `CspNonces` and these `SecurityHeaders`, to link them at once.
+    pub fn both(self) -> (CspNonces, SecurityHeaders) {
+        (CspNonces, self)
+    }
+
+    // The policy with the nonce of the request.
+    fn csp(&self, policy: &str, nonce: Option<&String>) -> String {
+        match nonce {
+            Some(nonce) => policy.replace(NONCE_PLACEHOLDER, nonce),
+            None => policy.split(';')
+                .map(|directive| {
+                    directive.split_whitespace()
+                        .filter(|source| !source.contains(NONCE_PLACEHOLDER))
+                        .collect::<Vec<_>>()
+                        .join(" ")
+                })
+                .collect::<Vec<_>>()
+                .join("; ")
+        }
+    }
+
+    fn set_headers(&self, request: &Request, headers: &mut Headers) {
+        let https = request.url()
+            .map(|url| url.scheme() == "https")
+            .unwrap_or(request.scheme == Scheme::Https);
+        let mut set = |name: &'static str, value: String| {
+            if headers.get_raw(name).is_none() {
+                headers.set_raw(name, value);
+            }
+        };
+
+        if let (Some(hsts), true) = (self.hsts, https) {
+            set("Strict-Transport-Security", hsts.to_string());
+        }
+        if let Some(ref policy) = self.csp {
+            let name = if self.csp_report_only {
+                "Content-Security-Policy-Report-Only"
+            } else {
+                "Content-Security-Policy"
+            };
+            set(name, self.csp(policy, request.extensions.get::<CspNonce>()));
+        }
+        if self.content_type_options {
+            set("X-Content-Type-Options", "nosniff".to_string());
+        }
+        if let Some(options) = self.frame_options {
+            set("X-Frame-Options", options.as_str().to_string());
+        }
+        if let Some(ref policy) = self.referrer_policy {
+            set("Referrer-Policy", policy.clone());
+        }
+        if let Some(ref policy) = self.permissions_policy {
+            set("Permissions-Policy", policy.clone());
+        }
+    }
+}
+
+impl Default for SecurityHeaders {
+    fn default() -> SecurityHeaders {
+        SecurityHeaders::new()
+    }
+}
+
+impl AfterMiddleware for SecurityHeaders {
+    fn after(&self, request: &mut Request, mut response: Response) -> FerrumResult<Response> {
+        self.set_headers(request, &mut response.headers);
+        Ok(response)
+    }
+
+    fn catch(&self, request: &mut Request, mut error: FerrumError) -> FerrumResult<Response> {
+        if let Some(ref mut response) = error.response {
+            self.set_headers(request, &mut response.headers);
+        }
+        Err(error)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use {Chain, Handler, StatusCode};
+
+    fn header(response: &Response, name: &str) -> Option<String> {
+        response.headers.get_raw(name)
+            .and_then(|raw| raw.one())
+            .map(|value| String::from_utf8(value.to_vec()).unwrap())
+    }
+
+    #[test]
+    fn test_default_headers() {
+        let mut chain = Chain::new(|request: &mut Request| {
+            let nonce = request.extensions.get::<CspNonce>().unwrap();
+            assert_eq!(BASE64.decode(nonce).unwrap().len(), 16);
+            Ok(Response::new().with_header(::hyper::header::ContentLength(0)))
+        });
+        chain.link(SecurityHeaders::new().both());
+
+        let mut request = Request::stub();
+        request.uri = "/".parse().unwrap();
+        request.headers.set_raw("Host", "example.com");
+        request.scheme = Scheme::Https;
+        let response = chain.handle(&mut request).unwrap();
+        let nonce = request.extensions.get::<CspNonce>().unwrap();
+
+        assert_eq!(header(&response, "Strict-Transport-Security").unwrap(), "max-age=63072000; includeSubDomains");
+        assert_eq!(header(&response, "Content-Security-Policy").unwrap(), DEFAULT_CSP.replace("{nonce}", nonce));
+        assert_eq!(header(&response, "X-Content-Type-Options").unwrap(), "nosniff");
+        assert_eq!(header(&response, "X-Frame-Options").unwrap(), "DENY");
+        assert_eq!(header(&response, "Referrer-Policy").unwrap(), "strict-origin-when-cross-origin");
+        assert_eq!(header(&response, "Permissions-Policy").unwrap(), DEFAULT_PERMISSIONS_POLICY);
+
+        // Each request gets its own nonce.
+        let mut other = Request::stub();
+        chain.handle(&mut other).unwrap();
+        assert_ne!(other.extensions.get::<CspNonce>(), Some(nonce));
+    }
+
+    #[test]
+    fn test_configured_headers() {
+        let headers = SecurityHeaders::new()
+            .with_csp(Some("script-src 'nonce-{nonce}'; img-src *"))
+            .with_csp_report_only(true)
+            .with_frame_options(Some(FrameOptions::SameOrigin))
+            .with_permissions_policy(None);
+        let mut chain = Chain::new(|_: &mut Request| {
+            let mut response = Response::new().with_header(::hyper::header::ContentLength(0));
+            response.headers.set_raw("X-Frame-Options", "DENY");
+            Ok(response)
+        });
+        chain.link_after(headers.clone());
+
+        // Without nonce nor HTTPS.
+        let response = chain.handle(&mut Request::stub()).unwrap();
+        assert_eq!(header(&response, "Content-Security-Policy-Report-Only").unwrap(), "script-src; img-src *");
+        assert_eq!(header(&response, "Content-Security-Policy"), None);
+        assert_eq!(header(&response, "Strict-Transport-Security"), None);
+        assert_eq!(header(&response, "X-Frame-Options").unwrap(), "DENY");
+        assert_eq!(header(&response, "Permissions-Policy"), None);
+
+        // The error responses get the headers too.
+        let mut chain = Chain::new(|_: &mut Request| {
+            Err(FerrumError::new(::std::io::Error::other("failed"), Some(Response::new().with_status(StatusCode::NotFound))))
+        });
+        chain.link_after(headers);
+        let error = chain.handle(&mut Request::stub()).unwrap_err();
+        let response = error.response.unwrap();
+        assert_eq!(header(&response, "X-Frame-Options").unwrap(), "SAMEORIGIN");
+        assert_eq!(header(&response, "X-Content-Type-Options").unwrap(), "nosniff");
+    }
+}
@@ -1,20 +1,33 @@
 use std::io;
+use std::net::SocketAddr;
 use std::sync::Arc;
 
 use hyper::server::{NewService, Service};
 use futures::{future, Future};
-use futures_cpupool::{CpuPool, CpuFuture};
+use futures_cpupool::CpuPool;
 
-use request::{Request, HyperRequest};
+use request::{Request, HyperRequest, Scheme, PeerCredentials};
 use response::HyperResponse;
 use error::HyperError;
 use middleware::Handler;
+use metrics::Metrics;
+use queue::WorkQueue;
+use websocket::{PendingUpgrade, UpgradeSlot};
+use StatusCode;
 
 pub struct InitialService<H>
     where H: Handler
 {
     pub handler: Arc<H>,
     pub thread_pool: Arc<CpuPool>,
+    pub work_queue: WorkQueue,
+    pub metrics: Option<Metrics>,
+    pub scheme: Scheme,
+    pub local_addr: Option<SocketAddr>,
+    pub remote_addr: Option<SocketAddr>,
+    pub peer_credentials: Option<PeerCredentials>,
+    pub listener_tag: Option<String>,
+    pub(crate) upgrade: Option<UpgradeSlot>,
 }
 
 impl<H> InitialService<H>
@@ -30,6 +43,14 @@ impl<H> InitialService<H>
         InitialService {
             handler: Arc::new(handler),
             thread_pool: Arc::new(thread_pool),
+            work_queue: WorkQueue::new(),
+            metrics: None,
+            scheme: Scheme::Http,
+            local_addr: None,
+            remote_addr: None,
+            peer_credentials: None,
+            listener_tag: None,
+            upgrade: None,
         }
     }
 }
@@ -41,6 +62,14 @@ impl<H> Clone for InitialService<H>
         InitialService {
             handler: self.handler.clone(),
             thread_pool: self.thread_pool.clone(),
+            work_queue: self.work_queue.clone(),
+            metrics: self.metrics.clone(),
+            scheme: self.scheme,
+            local_addr: self.local_addr,
+            remote_addr: self.remote_addr,
+            peer_credentials: self.peer_credentials,
+            listener_tag: self.listener_tag.clone(),
+            upgrade: self.upgrade.clone(),
         }
     }
 }
@@ -64,25 +93,54 @@ impl<H> Service for InitialService<H>
     type Request = HyperRequest;
     type Response = HyperResponse;
     type Error = HyperError;
-    type Future = CpuFuture<Self::Response, Self::Error>;
+    type Future = Box<dyn Future<Item = Self::Response, Error = Self::Error>>;
 
     fn call(&self, request: Self::Request) -> Self::Future {
+        let queue = self.work_queue.clone();
+        let queued = match queue.push() {
+            Some(queued) => queued,
+            None => return Box::new(future::ok(HyperResponse::from(queue.rejection())))
+        };
+
         let mut request = Request::new(request);
+        request.scheme = self.scheme;
+        request.local_addr = self.local_addr;
+        if request.remote_addr.is_none() {
+            request.remote_addr = self.remote_addr;
+        }
+        request.peer_credentials = self.peer_credentials;
+        request.listener_tag = self.listener_tag.clone();
         let handler = self.handler.clone();
+        let upgrade = self.upgrade.clone();
+        let metrics = self.metrics.clone();
 
-        self.thread_pool.spawn_fn(move || {
+        Box::new(self.thread_pool.spawn_fn(move || -> Box<dyn Future<Item = HyperResponse, Error = HyperError> + Send> {
+            if !queue.accepts(queued.leave()) {
+                return Box::new(future::ok(HyperResponse::from(queue.rejection())));
+            }
             let handle_result = match handler.handle(&mut request) {
                 Ok(response) => Box::new(future::ok(response)),
                 Err(err) => Box::new(future::err(err))
             };
             Box::new(handle_result
-                .and_then(move |response| {
+                .and_then(move |mut response| {
+                    // Hand an accepted WebSocket upgrade over to the connection,
+                    // unless a middleware answered something else than a 101.
+                    let pending = response.extensions.remove::<PendingUpgrade>();
+                    if let (Some(slot), Some(pending)) = (upgrade, pending) {
+                        if response.status == StatusCode::SwitchingProtocols {
+                            *slot.lock().unwrap() = pending.into_inner().unwrap();
+                        }
+                    }
                     future::ok(HyperResponse::from(response))
                 })
                 .or_else(move |error| {
+                    if let Some(metrics) = metrics {
+                        metrics.count_error();
+                    }
                     future::ok(HyperResponse::from(error))
                 })
             )
-        })
+        }))
     }
 }
@@ -0,0 +1,162 @@
+//! Systemd socket activation.
+//!
+//! With socket activation, systemd binds the sockets of a service and passes
+//! them to the process as inherited file descriptors, described by the
+//! `LISTEN_PID` and `LISTEN_FDS` environment variables. The sockets stay open
+//! while the service restarts, so no connection is refused in between:
+//!
+//! ```rust,no_run
+//! use ferrum::*;
+//! use ferrum::systemd;
+//!
+//! let handler = |_: &mut Request| Ok(Response::new().with_content("Hello world!", mime::TEXT_PLAIN));
+//! let mut listeners = systemd::listeners().unwrap();
+//! if listeners.is_empty() {
+//!     Ferrum::new(handler).http("localhost:3000").unwrap();
+//! } else {
+//!     Ferrum::new(handler).listen(listeners.remove(0)).unwrap();
+//! }
+//! ```
+
+use std::env;
+use std::io;
+use std::mem;
+use std::net::TcpListener;
+use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::os::unix::net::UnixListener;
+use std::process;
+
+use libc;
+
+use listener::Listener;
+
+/// The first file descriptor passed by systemd.
+pub const LISTEN_FDS_START: RawFd = 3;
+
+/// Take the listeners passed by systemd to this process.
+///
+/// The result is empty when the process was not socket activated. The
+/// environment variables are removed, so the listeners are only returned by
+/// the first call and are not inherited by child processes.
+///
+/// Removing environment variables races with other threads reading the
+/// environment, which is undefined behaviour on some platforms, glibc among
+/// them: call this at the start of `main`, before any thread is spawned.
+pub fn listeners() -> io::Result<Vec<Listener>> {
+    let count = activated_fds(
+        env::var("LISTEN_PID").ok(),
+        env::var("LISTEN_FDS").ok(),
+        process::id()
+    )?;
+    env::remove_var("LISTEN_PID");
+    env::remove_var("LISTEN_FDS");
+    env::remove_var("LISTEN_FDNAMES");
+
+    // Own all the file descriptors first, so they are closed on errors.
+    let fds = (LISTEN_FDS_START..LISTEN_FDS_START + count as RawFd)
+        .map(|fd| unsafe { OwnedFd::from_raw_fd(fd) })
+        .collect::<Vec<_>>();
+    fds.into_iter().map(listener_from_fd).collect()
+}
+
+// The number of file descriptors passed to the process `pid`.
+fn activated_fds(listen_pid: Option<String>, listen_fds: Option<String>, pid: u32) -> io::Result<usize> {
+    let (listen_pid, listen_fds) = match (listen_pid, listen_fds) {
+        (Some(listen_pid), Some(listen_fds)) => (listen_pid, listen_fds),
+        _ => return Ok(0)
+    };
+    let invalid = |name| io::Error::new(io::ErrorKind::InvalidData, format!("Invalid {}", name));
+
+    let listen_pid = listen_pid.trim().parse::<u32>().map_err(|_| invalid("LISTEN_PID"))?;
+    if listen_pid != pid {
+        // The variables were meant for a parent process.
+        return Ok(0);
+    }
+    listen_fds.trim().parse::<usize>().map_err(|_| invalid("LISTEN_FDS"))
+}
+
+// Get an integer option of the socket `fd`.
+fn socket_option(fd: RawFd, option: libc::c_int) -> io::Result<libc::c_int> {
+    let mut value: libc::c_int = 0;
+    let mut len = mem::size_of::<libc::c_int>() as libc::socklen_t;
+    let result = unsafe {
+        libc::getsockopt(
+            fd,
+            libc::SOL_SOCKET,
+            option,
+            &mut value as *mut libc::c_int as *mut libc::c_void,
+            &mut len
+        )
+    };
+    if result != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(value)
+}
+
+// Check that `fd` is a listening socket.
+fn listener_from_fd(fd: OwnedFd) -> io::Result<Listener> {
+    let raw = fd.as_raw_fd();
+    if unsafe { libc::fcntl(raw, libc::F_SETFD, libc::FD_CLOEXEC) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    if socket_option(raw, libc::SO_TYPE)? != libc::SOCK_STREAM {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput,
+            format!("File descriptor {} is not a stream socket", raw)));
+    }
+    if socket_option(raw, libc::SO_ACCEPTCONN)? == 0 {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput,
+            format!("File descriptor {} is not a listening socket", raw)));
+    }
+
+    let mut address: libc::sockaddr_storage = unsafe { mem::zeroed() };
+    let mut len = mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+    let address_ptr = &mut address as *mut libc::sockaddr_storage as *mut libc::sockaddr;
+    if unsafe { libc::getsockname(raw, address_ptr, &mut len) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    match address.ss_family as libc::c_int {
+        libc::AF_INET | libc::AF_INET6 => Ok(Listener::Tcp(TcpListener::from(fd))),
+        libc::AF_UNIX => Ok(Listener::Unix(UnixListener::from(fd))),
+        family => Err(io::Error::new(io::ErrorKind::InvalidInput,
+            format!("File descriptor {} has the unsupported address family {}", raw, family)))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::net::{TcpStream, UdpSocket};
+    use std::os::unix::io::AsFd;
+
+    fn some(value: &str) -> Option<String> {
+        Some(value.to_string())
+    }
+
+    #[test]
+    fn test_activated_fds() {
+        assert_eq!(activated_fds(None, None, 42).unwrap(), 0);
+        assert_eq!(activated_fds(some("42"), None, 42).unwrap(), 0);
+        assert_eq!(activated_fds(some("41"), some("2"), 42).unwrap(), 0);
+        assert_eq!(activated_fds(some("42"), some("2"), 42).unwrap(), 2);
+        assert!(activated_fds(some("42"), some("two"), 42).is_err());
+        assert!(activated_fds(some("pid"), some("2"), 42).is_err());
+    }
+
+    #[test]
+    fn test_listener_from_fd() {
+        let tcp = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = tcp.local_addr().unwrap();
+        let listener = listener_from_fd(tcp.as_fd().try_clone_to_owned().unwrap()).unwrap();
+        assert_eq!(listener.local_addr().unwrap(), Some(addr));
+
+        let udp = UdpSocket::bind("127.0.0.1:0").unwrap();
+        assert!(listener_from_fd(udp.as_fd().try_clone_to_owned().unwrap()).is_err());
+
+        let stream = TcpStream::connect(addr).unwrap();
+        let err = listener_from_fd(stream.as_fd().try_clone_to_owned().unwrap()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+}
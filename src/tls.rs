@@ -0,0 +1,572 @@
+//! TLS support for `Ferrum`, based on `rustls`.
+//!
+//! This module is available with the `tls` feature. A `TlsConfig` holds the
+//! certificates of the server, loaded from PEM files, and is passed to
+//! `Ferrum::https`:
+//!
+//! ```rust,no_run
+//! use ferrum::*;
+//! use ferrum::tls::TlsConfig;
+//!
+//! let config = TlsConfig::from_pem_files("cert.pem", "key.pem").unwrap()
+//!     .with_pem_files("api.example.com", "api-cert.pem", "api-key.pem").unwrap();
+//!
+//! Ferrum::new(|_: &mut Request| {
+//!     Ok(Response::new().with_content("Hello world!", mime::TEXT_PLAIN))
+//! }).https("localhost:3443", config).unwrap();
+//! ```
+//!
+//! Clients naming a host through SNI get the matching certificate, the others
+//! get the default certificate. `TlsConfig` is cheaply clonable and `reload`
+//! re-reads all the certificates from their files, which allows renewing them
+//! without restarting the server.
+
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
+
+use futures::{Async, Future, Poll};
+use rustls::{ServerConfig, ServerConnection};
+use rustls::crypto::ring::{default_provider, sign};
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::pki_types::pem::PemObject;
+use rustls::server::{ClientHello, ResolvesServerCert};
+use rustls::sign::CertifiedKey;
+use tokio_io::{AsyncRead, AsyncWrite};
+
+/// The errors which can happen while configuring TLS.
+#[derive(Debug)]
+pub enum TlsError {
+    /// A certificate or key file could not be read or parsed.
+    Pem(PathBuf, String),
+    /// A certificate file holds no certificate.
+    NoCertificate(PathBuf),
+    /// The configuration was rejected by `rustls`.
+    Rustls(rustls::Error),
+}
+
+impl fmt::Display for TlsError {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            TlsError::Pem(ref path, ref error) => write!(formatter, "Invalid PEM file {}: {}", path.display(), error),
+            TlsError::NoCertificate(ref path) => write!(formatter, "No certificate in {}", path.display()),
+            TlsError::Rustls(ref error) => write!(formatter, "TLS error: {}", error),
+        }
+    }
+}
+
+impl Error for TlsError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match *self {
+            TlsError::Rustls(ref error) => Some(error),
+            _ => None
+        }
+    }
+}
+
+impl From<rustls::Error> for TlsError {
+    fn from(error: rustls::Error) -> TlsError {
+        TlsError::Rustls(error)
+    }
+}
+
+impl From<TlsError> for io::Error {
+    fn from(error: TlsError) -> io::Error {
+        io::Error::new(io::ErrorKind::InvalidInput, error)
+    }
+}
+
+#[derive(Debug, Clone)]
+struct PemFiles {
+    cert: PathBuf,
+    key: PathBuf,
+}
+
+impl PemFiles {
+    fn load(&self) -> Result<Arc<CertifiedKey>, TlsError> {
+        let pem_error = |path: &Path, error: &dyn fmt::Display| TlsError::Pem(path.to_path_buf(), error.to_string());
+
+        let certs = CertificateDer::pem_file_iter(&self.cert)
+            .map_err(|err| pem_error(&self.cert, &err))?
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|err| pem_error(&self.cert, &err))?;
+        if certs.is_empty() {
+            return Err(TlsError::NoCertificate(self.cert.clone()));
+        }
+
+        let key = PrivateKeyDer::from_pem_file(&self.key)
+            .map_err(|err| pem_error(&self.key, &err))?;
+        let key = sign::any_supported_type(&key)?;
+        Ok(Arc::new(CertifiedKey::new(certs, key)))
+    }
+}
+
+// The files certificates are reloaded from.
+#[derive(Debug, Default)]
+struct CertificateFiles {
+    default: Option<PemFiles>,
+    by_name: Vec<(String, PemFiles)>,
+}
+
+// The certificates of the server, chosen by SNI.
+#[derive(Debug, Default)]
+struct Certificates {
+    files: Mutex<CertificateFiles>,
+    default: RwLock<Option<Arc<CertifiedKey>>>,
+    by_name: RwLock<HashMap<String, Arc<CertifiedKey>>>,
+}
+
+impl Certificates {
+    fn reload(&self) -> Result<(), TlsError> {
+        let files = self.files.lock().unwrap();
+        let default = match files.default {
+            Some(ref files) => Some(files.load()?),
+            None => None
+        };
+        let mut by_name = HashMap::new();
+        for (name, files) in &files.by_name {
+            by_name.insert(name.clone(), files.load()?);
+        }
+
+        // Only swap once everything loaded, so a bad file leaves the old certificates in place.
+        *self.default.write().unwrap() = default;
+        *self.by_name.write().unwrap() = by_name;
+        Ok(())
+    }
+}
+
+impl ResolvesServerCert for Certificates {
+    fn resolve(&self, client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        if let Some(name) = client_hello.server_name() {
+            let name = name.to_lowercase();
+            let by_name = self.by_name.read().unwrap();
+            if let Some(key) = by_name.get(&name) {
+                return Some(key.clone());
+            }
+            if let Some(dot) = name.find('.') {
+                if let Some(key) = by_name.get(&format!("*{}", &name[dot..])) {
+                    return Some(key.clone());
+                }
+            }
+        }
+        self.default.read().unwrap().clone()
+    }
+}
+
+/// The TLS configuration of a server.
+#[derive(Debug, Clone)]
+pub struct TlsConfig {
+    certificates: Arc<Certificates>,
+    alpn_protocols: Vec<Vec<u8>>,
+}
+
+impl TlsConfig {
+    /// Create a configuration with a default certificate chain and its private key.
+    pub fn from_pem_files<C, K>(cert: C, key: K) -> Result<TlsConfig, TlsError>
+        where C: AsRef<Path>, K: AsRef<Path>
+    {
+        let config = TlsConfig {
+            certificates: Arc::new(Certificates::default()),
+            alpn_protocols: vec![b"http/1.1".to_vec()],
+        };
+        config.certificates.files.lock().unwrap().default = Some(PemFiles {
+            cert: cert.as_ref().to_path_buf(),
+            key: key.as_ref().to_path_buf(),
+        });
+        config.reload()?;
+        Ok(config)
+    }
+
+    /// Add a certificate chain and its private key, used for clients requesting
+    /// `server_name` through SNI.
+    ///
+    /// The name can be a wildcard, such as `*.example.com`, matching a single label.
+    pub fn with_pem_files<C, K>(self, server_name: &str, cert: C, key: K) -> Result<TlsConfig, TlsError>
+        where C: AsRef<Path>, K: AsRef<Path>
+    {
+        let files = PemFiles {
+            cert: cert.as_ref().to_path_buf(),
+            key: key.as_ref().to_path_buf(),
+        };
+        let name = server_name.to_lowercase();
+        let key = files.load()?;
+        {
+            let by_name = &mut self.certificates.files.lock().unwrap().by_name;
+            by_name.retain(|(existing, _)| *existing != name);
+            by_name.push((name.clone(), files));
+        }
+        self.certificates.by_name.write().unwrap().insert(name, key);
+        Ok(self)
+    }
+
+    /// Set the protocols offered through ALPN, by order of preference.
+    ///
+    /// The default is `http/1.1`. Servers with HTTP/2 enabled offer `h2` first
+    /// when it is missing, and never offer it otherwise.
+    pub fn with_alpn_protocols(mut self, protocols: Vec<Vec<u8>>) -> Self {
+        self.alpn_protocols = protocols;
+        self
+    }
+
+    /// The protocols offered through ALPN.
+    pub fn alpn_protocols(&self) -> &[Vec<u8>] {
+        &self.alpn_protocols
+    }
+
+    /// Read all the certificates from their files again.
+    ///
+    /// New connections use the new certificates, established ones are unaffected.
+    /// If any file fails to load, the current certificates are kept.
+    pub fn reload(&self) -> Result<(), TlsError> {
+        self.certificates.reload()
+    }
+
+    /// Build the `rustls` configuration.
+    pub fn server_config(&self) -> Result<Arc<ServerConfig>, TlsError> {
+        let mut config = ServerConfig::builder_with_provider(Arc::new(default_provider()))
+            .with_safe_default_protocol_versions()?
+            .with_no_client_auth()
+            .with_cert_resolver(self.certificates.clone());
+        config.alpn_protocols = self.alpn_protocols.clone();
+        Ok(Arc::new(config))
+    }
+}
+
+/// A TLS stream over an underlying transport, such as a `TcpStream`.
+///
+/// The handshake is driven by the first reads and writes.
+#[derive(Debug)]
+pub struct TlsStream<S> {
+    io: S,
+    session: ServerConnection,
+}
+
+impl<S> TlsStream<S>
+    where S: Read + Write
+{
+    /// Start a server session over `io`.
+    pub fn new(io: S, config: Arc<ServerConfig>) -> io::Result<TlsStream<S>> {
+        let session = ServerConnection::new(config)
+            .map_err(io::Error::other)?;
+        Ok(TlsStream { io, session })
+    }
+
+    /// The TLS session.
+    pub fn session(&self) -> &ServerConnection {
+        &self.session
+    }
+
+    /// The underlying transport.
+    pub fn get_ref(&self) -> &S {
+        &self.io
+    }
+
+    // Write the pending TLS records to the transport.
+    fn flush_tls(&mut self) -> io::Result<()> {
+        while self.session.wants_write() {
+            if self.session.write_tls(&mut self.io)? == 0 {
+                return Err(io::ErrorKind::WriteZero.into());
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<S> Read for TlsStream<S>
+    where S: Read + Write
+{
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            match self.session.reader().read(buf) {
+                Ok(n) => return Ok(n),
+                Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => {},
+                Err(ref err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(0),
+                Err(err) => return Err(err)
+            }
+
+            // No plaintext yet, answer the peer (e.g. during the handshake) and
+            // wait for more records.
+            self.flush_tls()?;
+            if self.session.read_tls(&mut self.io)? == 0 {
+                return Ok(0);
+            }
+            if let Err(err) = self.session.process_new_packets() {
+                // Try to let the peer know what went wrong.
+                let _ = self.flush_tls();
+                return Err(io::Error::new(io::ErrorKind::InvalidData, err));
+            }
+        }
+    }
+}
+
+impl<S> Write for TlsStream<S>
+    where S: Read + Write
+{
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        loop {
+            let written = self.session.writer().write(buf)?;
+            match self.flush_tls() {
+                Ok(()) => {},
+                Err(ref err) if err.kind() == io::ErrorKind::WouldBlock && written > 0 => {},
+                Err(err) => return Err(err)
+            }
+            if written > 0 || buf.is_empty() {
+                return Ok(written);
+            }
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.session.writer().flush()?;
+        self.flush_tls()?;
+        self.io.flush()
+    }
+}
+
+impl<S> TlsStream<S>
+    where S: AsyncRead + AsyncWrite
+{
+    /// Complete the handshake, after which the negotiated parameters, such as
+    /// the ALPN protocol, are known.
+    pub fn handshake(self) -> Handshake<S> {
+        Handshake(Some(self))
+    }
+}
+
+impl<S> AsyncRead for TlsStream<S>
+    where S: AsyncRead + AsyncWrite
+{}
+
+impl<S> AsyncWrite for TlsStream<S>
+    where S: AsyncRead + AsyncWrite
+{
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        self.session.send_close_notify();
+        self.flush_tls()?;
+        self.io.shutdown()
+    }
+}
+
+/// A future completing the handshake of a `TlsStream`.
+#[derive(Debug)]
+pub struct Handshake<S>(Option<TlsStream<S>>);
+
+impl<S> Future for Handshake<S>
+    where S: AsyncRead + AsyncWrite
+{
+    type Item = TlsStream<S>;
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<TlsStream<S>, io::Error> {
+        {
+            let stream = self.0.as_mut().expect("Handshake polled after completion");
+            while stream.session.is_handshaking() {
+                try_nb!(stream.flush_tls());
+                if !stream.session.wants_read() {
+                    continue;
+                }
+                if try_nb!(stream.session.read_tls(&mut stream.io)) == 0 {
+                    return Err(io::ErrorKind::UnexpectedEof.into());
+                }
+                if let Err(err) = stream.session.process_new_packets() {
+                    let _ = stream.flush_tls();
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, err));
+                }
+            }
+            try_nb!(stream.flush_tls());
+        }
+        Ok(Async::Ready(self.0.take().expect("Handshake polled after completion")))
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+    use std::convert::TryFrom;
+    use std::env;
+    use std::fs;
+    use std::net::{TcpListener, TcpStream};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::thread;
+
+    use rcgen;
+    use rustls::{ClientConfig, ClientConnection, RootCertStore, StreamOwned};
+    use rustls::pki_types::ServerName;
+
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+    /// Generate a self-signed certificate for `names`, returning the paths of
+    /// the certificate and key files and the DER certificate.
+    pub fn self_signed(names: &[&str]) -> (PathBuf, PathBuf, CertificateDer<'static>) {
+        let names = names.iter().map(|name| name.to_string()).collect::<Vec<_>>();
+        let generated = rcgen::generate_simple_self_signed(names).unwrap();
+
+        let id = format!("ferrum-tls-{}-{}", ::std::process::id(), COUNTER.fetch_add(1, Ordering::SeqCst));
+        let cert = env::temp_dir().join(format!("{}-cert.pem", id));
+        let key = env::temp_dir().join(format!("{}-key.pem", id));
+        fs::write(&cert, generated.cert.pem()).unwrap();
+        fs::write(&key, generated.key_pair.serialize_pem()).unwrap();
+        (cert, key, generated.cert.der().clone())
+    }
+
+    /// Create a client configuration trusting the given certificates.
+    pub fn client_config(roots: &[&CertificateDer<'static>], alpn: &[&[u8]]) -> Arc<ClientConfig> {
+        let mut store = RootCertStore::empty();
+        for root in roots {
+            store.add((*root).clone()).unwrap();
+        }
+        let mut config = ClientConfig::builder_with_provider(Arc::new(default_provider()))
+            .with_safe_default_protocol_versions().unwrap()
+            .with_root_certificates(store)
+            .with_no_client_auth();
+        config.alpn_protocols = alpn.iter().map(|protocol| protocol.to_vec()).collect();
+        Arc::new(config)
+    }
+
+    // Serve a single connection echoing 5 bytes back, followed by the ALPN protocol.
+    fn echo_server(config: Arc<ServerConfig>) -> ::std::net::SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || -> io::Result<()> {
+            let (socket, _) = listener.accept()?;
+            let mut stream = TlsStream::new(socket, config)?;
+            let mut buf = [0; 5];
+            stream.read_exact(&mut buf)?;
+            let alpn = stream.session().alpn_protocol().map(|p| p.to_vec()).unwrap_or_default();
+            stream.write_all(&buf)?;
+            stream.write_all(&alpn)?;
+            stream.flush()
+        });
+        addr
+    }
+
+    fn exchange(addr: ::std::net::SocketAddr, name: &str, client: Arc<ClientConfig>) -> io::Result<Vec<u8>> {
+        let session = ClientConnection::new(client, ServerName::try_from(name.to_string()).unwrap()).unwrap();
+        let mut stream = StreamOwned::new(session, TcpStream::connect(addr)?);
+        stream.write_all(b"hello")?;
+        let mut response = vec![];
+        match stream.read_to_end(&mut response) {
+            Ok(_) => {},
+            Err(ref err) if err.kind() == io::ErrorKind::UnexpectedEof => {},
+            Err(err) => return Err(err)
+        }
+        Ok(response)
+    }
+
+    #[test]
+    fn test_tls_stream() {
+        let (cert, key, der) = self_signed(&["localhost"]);
+        let config = TlsConfig::from_pem_files(&cert, &key).unwrap();
+        let addr = echo_server(config.server_config().unwrap());
+
+        let response = exchange(addr, "localhost", client_config(&[&der], &[b"http/1.1"])).unwrap();
+        assert_eq!(response, b"hellohttp/1.1".to_vec());
+    }
+
+    #[test]
+    fn test_sni() {
+        let (cert, key, default_der) = self_signed(&["localhost"]);
+        let (api_cert, api_key, api_der) = self_signed(&["api.example.com"]);
+        let (wild_cert, wild_key, wild_der) = self_signed(&["*.example.org"]);
+        let config = TlsConfig::from_pem_files(&cert, &key).unwrap()
+            .with_pem_files("API.example.com", &api_cert, &api_key).unwrap()
+            .with_pem_files("*.example.org", &wild_cert, &wild_key).unwrap();
+
+        // Each client only trusts the certificate it expects.
+        let addr = echo_server(config.server_config().unwrap());
+        assert!(exchange(addr, "api.example.com", client_config(&[&api_der], &[])).is_ok());
+
+        let addr = echo_server(config.server_config().unwrap());
+        assert!(exchange(addr, "www.example.org", client_config(&[&wild_der], &[])).is_ok());
+
+        let addr = echo_server(config.server_config().unwrap());
+        assert!(exchange(addr, "localhost", client_config(&[&default_der], &[])).is_ok());
+
+        let addr = echo_server(config.server_config().unwrap());
+        assert!(exchange(addr, "localhost", client_config(&[&api_der], &[])).is_err());
+    }
+
+    #[test]
+    fn test_reload() {
+        let (cert, key, old_der) = self_signed(&["localhost"]);
+        let config = TlsConfig::from_pem_files(&cert, &key).unwrap();
+
+        let (new_cert, new_key, new_der) = self_signed(&["localhost"]);
+        fs::copy(&new_cert, &cert).unwrap();
+        fs::copy(&new_key, &key).unwrap();
+        config.reload().unwrap();
+
+        let addr = echo_server(config.server_config().unwrap());
+        assert!(exchange(addr, "localhost", client_config(&[&new_der], &[])).is_ok());
+        let addr = echo_server(config.server_config().unwrap());
+        assert!(exchange(addr, "localhost", client_config(&[&old_der], &[])).is_err());
+
+        // Setting the files of a name again replaces them.
+        let (other_cert, other_key, other_der) = self_signed(&["api.example.com"]);
+        let config = config.with_pem_files("api.example.com", &cert, &key).unwrap()
+            .with_pem_files("api.example.com", &other_cert, &other_key).unwrap();
+        config.reload().unwrap();
+        assert_eq!(config.certificates.files.lock().unwrap().by_name.len(), 1);
+        let addr = echo_server(config.server_config().unwrap());
+        assert!(exchange(addr, "api.example.com", client_config(&[&other_der], &[])).is_ok());
+
+        // A broken file leaves the current certificate in place.
+        fs::write(&cert, "garbage").unwrap();
+        assert!(config.reload().is_err());
+        let addr = echo_server(config.server_config().unwrap());
+        assert!(exchange(addr, "localhost", client_config(&[&new_der], &[])).is_ok());
+    }
+
+    #[test]
+    fn test_https() {
+        use {mime, Endpoint, Ferrum, Request, Response};
+
+        let (cert, key, der) = self_signed(&["localhost"]);
+        let config = TlsConfig::from_pem_files(&cert, &key).unwrap();
+        let listening = Ferrum::new(|request: &mut Request| {
+            Ok(Response::new().with_content(request.url().unwrap().to_string(), mime::TEXT_PLAIN))
+        }).spawn_endpoints(vec![Endpoint::https("127.0.0.1:0", config).unwrap()]).unwrap();
+
+        let session = ClientConnection::new(
+            client_config(&[&der], &[]),
+            ServerName::try_from("localhost").unwrap()
+        ).unwrap();
+        let mut stream = StreamOwned::new(session, TcpStream::connect(listening.local_addr().unwrap()).unwrap());
+        stream.write_all(b"GET /foo HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n").unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+
+        assert!(response.starts_with("HTTP/1.1 200 OK"));
+        assert!(response.ends_with("https://localhost/foo"));
+        listening.shutdown().unwrap();
+    }
+
+    #[test]
+    fn test_alpn_h2() {
+        use {mime, Endpoint, Ferrum, Request, Response};
+        use http2::{Http2Settings, PREFACE};
+
+        let (cert, key, der) = self_signed(&["localhost"]);
+        let config = TlsConfig::from_pem_files(&cert, &key).unwrap();
+        let mut ferrum = Ferrum::new(|_: &mut Request| Ok(Response::new().with_content("", mime::TEXT_PLAIN)));
+        ferrum.http2 = Some(Http2Settings::new());
+        let listening = ferrum.spawn_endpoints(vec![Endpoint::https("127.0.0.1:0", config).unwrap()]).unwrap();
+
+        let session = ClientConnection::new(
+            client_config(&[&der], &[b"h2", b"http/1.1"]),
+            ServerName::try_from("localhost").unwrap()
+        ).unwrap();
+        let mut stream = StreamOwned::new(session, TcpStream::connect(listening.local_addr().unwrap()).unwrap());
+        stream.write_all(PREFACE).unwrap();
+        stream.write_all(&[0, 0, 0, 4, 0, 0, 0, 0, 0]).unwrap();
+
+        // The server starts with its SETTINGS frame.
+        let mut frame_header = [0; 9];
+        stream.read_exact(&mut frame_header).unwrap();
+        assert_eq!(stream.conn.alpn_protocol(), Some(&b"h2"[..]));
+        assert_eq!(frame_header[3], 4);
+    }
+}
@@ -0,0 +1,576 @@
+//! Distributed tracing with the W3C Trace Context headers.
+//!
+//! `Tracing` is an `AroundMiddleware` continuing the trace of the
+//! `traceparent` and `tracestate` headers of requests, or starting a new one.
+//! It stores a server span in the extensions of each request, as the
+//! `CurrentSpan`. A `Chain` handling a traced request records a child span
+//! for each of its `BeforeMiddleware`, its `Handler` and each of its
+//! `AfterMiddleware`, so `Tracing` wraps the whole `Chain`:
+//!
+//! ```rust,no_run
+//! use ferrum::*;
+//! use ferrum::trace::{InMemoryExporter, Tracing};
+//!
+//! # fn hello(_: &mut Request) -> FerrumResult<Response> {
+//! #     Ok(Response::new().with_content("Hello world!", mime::TEXT_PLAIN))
+//! # }
+//! let mut chain = Chain::new(hello);
+//! chain.link_before(|_: &mut Request| Ok(()));
+//!
+//! let exporter = InMemoryExporter::new();
+//! let app = Tracing::new(exporter.clone()).around(Box::new(chain));
+//! Ferrum::new(app).http("localhost:3000").unwrap();
+//! ```
+//!
+//! The finished spans of sampled traces go to a `SpanExporter`, which sends
+//! them to the tracing backend. Handlers calling other services propagate the
+//! trace with `ActiveSpan::traceparent`.
+
+use std::fmt;
+use std::str;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+
+use hyper;
+use hyper::header::{Formatter, Header, Raw};
+
+use {Request, Response, FerrumResult, StatusCode};
+use metrics::Route;
+use random;
+use middleware::{AroundMiddleware, Handler};
+use typemap::Key;
+
+/// The id of a trace, 16 bytes shared by all its spans.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct TraceId(pub [u8; 16]);
+
+/// The id of a span, 8 bytes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct SpanId(pub [u8; 8]);
+
+impl TraceId {
+    /// Generate a random trace id.
+    pub fn random() -> TraceId {
+        let mut id = [0; 16];
+        id[..8].copy_from_slice(&random_id().to_be_bytes());
+        id[8..].copy_from_slice(&random_id().to_be_bytes());
+        TraceId(id)
+    }
+}
+
+impl SpanId {
+    /// Generate a random span id.
+    pub fn random() -> SpanId {
+        SpanId(random_id().to_be_bytes())
+    }
+}
+
+impl fmt::Display for TraceId {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        write_hex(formatter, &self.0)
+    }
+}
+
+impl fmt::Display for SpanId {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        write_hex(formatter, &self.0)
+    }
+}
+
+fn write_hex(formatter: &mut fmt::Formatter, bytes: &[u8]) -> fmt::Result {
+    for byte in bytes {
+        write!(formatter, "{:02x}", byte)?;
+    }
+    Ok(())
+}
+
+// Parse lowercase hex digits into `bytes`.
+fn parse_hex(hex: &str, bytes: &mut [u8]) -> Option<()> {
+    if hex.len() != bytes.len() * 2 || !hex.bytes().all(|c| c.is_ascii_digit() || (b'a'..=b'f').contains(&c)) {
+        return None;
+    }
+    for (i, byte) in bytes.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16).ok()?;
+    }
+    Some(())
+}
+
+// Parse a non-zero id.
+fn parse_id(hex: &str, bytes: &mut [u8]) -> Option<()> {
+    parse_hex(hex, bytes)?;
+    if bytes.iter().all(|&byte| byte == 0) {
+        return None;
+    }
+    Some(())
+}
+
+// A random non-zero id.
+fn random_id() -> u64 {
+    loop {
+        let id = random::random_u64();
+        if id != 0 {
+            return id;
+        }
+    }
+}
+
+/// The `traceparent` header, identifying the trace and the calling span of
+/// a request.
+///
+/// ```rust
+/// use ferrum::trace::TraceParent;
+///
+/// let parent: TraceParent = "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01".parse().unwrap();
+/// assert!(parent.sampled);
+/// assert_eq!(parent.to_string(), "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01");
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TraceParent {
+    /// The id of the trace.
+    pub trace_id: TraceId,
+    /// The id of the calling span.
+    pub parent_id: SpanId,
+    /// Whether the caller records the trace.
+    pub sampled: bool,
+}
+
+impl str::FromStr for TraceParent {
+    type Err = hyper::Error;
+
+    fn from_str(value: &str) -> hyper::Result<TraceParent> {
+        let value = value.trim();
+        let mut fields = value.split('-');
+        let (version, trace_id, parent_id, flags) = match (fields.next(), fields.next(), fields.next(), fields.next()) {
+            (Some(version), Some(trace_id), Some(parent_id), Some(flags)) => (version, trace_id, parent_id, flags),
+            _ => return Err(hyper::Error::Header)
+        };
+
+        let mut version_byte = [0];
+        let mut flag_byte = [0];
+        parse_hex(version, &mut version_byte).ok_or(hyper::Error::Header)?;
+        // The later versions may only append fields.
+        if version_byte[0] == 0xff || (version_byte[0] == 0 && fields.next().is_some()) {
+            return Err(hyper::Error::Header);
+        }
+
+        let mut parent = TraceParent {
+            trace_id: TraceId([0; 16]),
+            parent_id: SpanId([0; 8]),
+            sampled: false,
+        };
+        parse_id(trace_id, &mut parent.trace_id.0).ok_or(hyper::Error::Header)?;
+        parse_id(parent_id, &mut parent.parent_id.0).ok_or(hyper::Error::Header)?;
+        parse_hex(flags, &mut flag_byte).ok_or(hyper::Error::Header)?;
+        parent.sampled = flag_byte[0] & 1 == 1;
+        Ok(parent)
+    }
+}
+
+impl fmt::Display for TraceParent {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        write!(formatter, "00-{}-{}-{:02x}", self.trace_id, self.parent_id, self.sampled as u8)
+    }
+}
+
+impl Header for TraceParent {
+    fn header_name() -> &'static str {
+        "traceparent"
+    }
+
+    fn parse_header(raw: &Raw) -> hyper::Result<TraceParent> {
+        raw.one()
+            .and_then(|line| str::from_utf8(line).ok())
+            .ok_or(hyper::Error::Header)
+            .and_then(|value| value.parse())
+    }
+
+    fn fmt_header(&self, formatter: &mut Formatter) -> fmt::Result {
+        formatter.fmt_line(self)
+    }
+}
+
+/// The `tracestate` header, the vendor specific data of a trace, passed on
+/// unchanged. Its lines are joined into one list.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TraceState(pub String);
+
+impl Header for TraceState {
+    fn header_name() -> &'static str {
+        "tracestate"
+    }
+
+    fn parse_header(raw: &Raw) -> hyper::Result<TraceState> {
+        let mut members = Vec::new();
+        for line in raw.iter() {
+            let line = str::from_utf8(line).map_err(|_| hyper::Error::Header)?;
+            members.extend(line.split(',').map(str::trim).filter(|member| !member.is_empty()));
+        }
+        if members.is_empty() {
+            return Err(hyper::Error::Header);
+        }
+        Ok(TraceState(members.join(",")))
+    }
+
+    fn fmt_header(&self, formatter: &mut Formatter) -> fmt::Result {
+        formatter.fmt_line(&self.0)
+    }
+}
+
+/// The kind of a `Span`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SpanKind {
+    /// The handling of a request by the server.
+    Server,
+    /// A step of the handling of a request, e.g. a link of a `Chain`.
+    Internal,
+}
+
+/// A finished span, as given to the `SpanExporter`.
+#[derive(Debug, Clone)]
+pub struct Span {
+    /// The id of the trace of the span.
+    pub trace_id: TraceId,
+    /// The id of the span.
+    pub span_id: SpanId,
+    /// The id of the parent span, `None` for the root of a trace.
+    pub parent_id: Option<SpanId>,
+    /// The `tracestate` of the trace.
+    pub trace_state: Option<String>,
+    /// The name of the span.
+    pub name: String,
+    /// The kind of the span.
+    pub kind: SpanKind,
+    /// When the span started.
+    pub start: SystemTime,
+    /// How long the span lasted.
+    pub duration: Duration,
+    /// The attributes of the span, e.g. `http.status_code`.
+    pub attributes: Vec<(String, String)>,
+    /// Whether the span ended with an error.
+    pub error: bool,
+}
+
+/// A destination of the finished spans, e.g. a tracing backend.
+pub trait SpanExporter: Send + Sync + 'static {
+    /// Export a finished span. This is called from the request handling
+    /// threads, so slow exporters should queue the spans.
+    fn export(&self, span: Span);
+}
+
+impl<T> SpanExporter for Arc<T>
+    where T: SpanExporter
+{
+    fn export(&self, span: Span) {
+        (**self).export(span)
+    }
+}
+
+/// A `SpanExporter` keeping the spans in memory, e.g. for tests.
+///
+/// Clones share the same spans.
+#[derive(Debug, Clone, Default)]
+pub struct InMemoryExporter {
+    spans: Arc<Mutex<Vec<Span>>>,
+}
+
+impl InMemoryExporter {
+    /// Create an empty exporter.
+    pub fn new() -> InMemoryExporter {
+        InMemoryExporter::default()
+    }
+
+    /// The exported spans, in the order they finished.
+    pub fn spans(&self) -> Vec<Span> {
+        self.spans.lock().unwrap().clone()
+    }
+
+    /// Remove and return the exported spans.
+    pub fn take(&self) -> Vec<Span> {
+        ::std::mem::take(&mut *self.spans.lock().unwrap())
+    }
+}
+
+impl SpanExporter for InMemoryExporter {
+    fn export(&self, span: Span) {
+        self.spans.lock().unwrap().push(span);
+    }
+}
+
+/// The server span of a request being handled.
+#[derive(Debug)]
+pub struct ActiveSpan {
+    span: Span,
+    sampled: bool,
+    started: Instant,
+    children: Vec<Span>,
+}
+
+impl ActiveSpan {
+    /// The id of the trace of the request.
+    pub fn trace_id(&self) -> TraceId {
+        self.span.trace_id
+    }
+
+    /// The id of the server span.
+    pub fn span_id(&self) -> SpanId {
+        self.span.span_id
+    }
+
+    /// The id of the span of the caller, if it sent a `traceparent`.
+    pub fn parent_id(&self) -> Option<SpanId> {
+        self.span.parent_id
+    }
+
+    /// Whether the trace is recorded.
+    pub fn sampled(&self) -> bool {
+        self.sampled
+    }
+
+    /// The `tracestate` of the trace, to pass on to other services.
+    pub fn trace_state(&self) -> Option<TraceState> {
+        self.span.trace_state.clone().map(TraceState)
+    }
+
+    /// The `traceparent` of the requests sent while handling this request.
+    pub fn traceparent(&self) -> TraceParent {
+        TraceParent {
+            trace_id: self.span.trace_id,
+            parent_id: self.span.span_id,
+            sampled: self.sampled,
+        }
+    }
+
+    /// Set an attribute of the server span.
+    pub fn set_attribute<K: Into<String>, V: Into<String>>(&mut self, key: K, value: V) {
+        let key = key.into();
+        let value = value.into();
+        match self.span.attributes.iter_mut().find(|&&mut (ref k, _)| *k == key) {
+            Some(attribute) => attribute.1 = value,
+            None => self.span.attributes.push((key, value))
+        }
+    }
+
+    /// Record a finished child span of the server span.
+    pub fn record_child<S: Into<String>>(&mut self, name: S, start: SystemTime, duration: Duration, error: bool) {
+        self.children.push(Span {
+            trace_id: self.span.trace_id,
+            span_id: SpanId::random(),
+            parent_id: Some(self.span.span_id),
+            trace_state: self.span.trace_state.clone(),
+            name: name.into(),
+            kind: SpanKind::Internal,
+            start,
+            duration,
+            attributes: Vec::new(),
+            error,
+        });
+    }
+}
+
+/// The `ActiveSpan` of a request, in its extensions.
+pub struct CurrentSpan;
+
+impl Key for CurrentSpan {
+    type Value = ActiveSpan;
+}
+
+/// An `AroundMiddleware` tracing the requests, see the `trace` module.
+#[derive(Clone)]
+pub struct Tracing {
+    exporter: Arc<dyn SpanExporter>,
+}
+
+impl Tracing {
+    /// Trace the requests, exporting the spans to `exporter`.
+    pub fn new<E: SpanExporter>(exporter: E) -> Tracing {
+        Tracing {
+            exporter: Arc::new(exporter),
+        }
+    }
+
+    // Continue the trace of the request, or start a new one.
+    fn start(&self, request: &Request) -> ActiveSpan {
+        let parent = request.headers.get::<TraceParent>().cloned();
+        // The state of an invalid or missing parent is dropped with it.
+        let trace_state = parent.and_then(|_| request.headers.get::<TraceState>()).map(|state| state.0.clone());
+        let span = Span {
+            trace_id: parent.map(|parent| parent.trace_id).unwrap_or_else(TraceId::random),
+            span_id: SpanId::random(),
+            parent_id: parent.map(|parent| parent.parent_id),
+            trace_state,
+            name: request.method.to_string(),
+            kind: SpanKind::Server,
+            start: SystemTime::now(),
+            duration: Duration::from_secs(0),
+            attributes: vec![
+                ("http.method".to_string(), request.method.to_string()),
+                ("http.target".to_string(), request.uri.path().to_string()),
+            ],
+            error: false,
+        };
+        ActiveSpan {
+            span,
+            sampled: parent.map(|parent| parent.sampled).unwrap_or(true),
+            started: Instant::now(),
+            children: Vec::new(),
+        }
+    }
+
+    // Finish the span of the request and export the sampled spans.
+    fn finish(&self, request: &mut Request, result: &FerrumResult<Response>) {
+        let mut active = match request.extensions.remove::<CurrentSpan>() {
+            Some(active) => active,
+            None => return
+        };
+        let status = match *result {
+            Ok(ref response) => response.status,
+            Err(ref error) => error.response.as_ref().map(|response| response.status).unwrap_or(StatusCode::InternalServerError)
+        };
+        if let Some(route) = request.extensions.get::<Route>() {
+            active.span.name = format!("{} {}", request.method, route);
+            active.set_attribute("http.route", route.clone());
+        }
+        active.set_attribute("http.status_code", status.as_u16().to_string());
+        active.span.duration = active.started.elapsed();
+        active.span.error = result.is_err() || status.is_server_error();
+
+        if active.sampled {
+            for child in active.children {
+                self.exporter.export(child);
+            }
+            self.exporter.export(active.span);
+        }
+    }
+}
+
+impl AroundMiddleware for Tracing {
+    fn around(self, handler: Box<dyn Handler>) -> Box<dyn Handler> {
+        Box::new(TracingHandler {
+            tracing: self,
+            handler
+        })
+    }
+}
+
+struct TracingHandler {
+    tracing: Tracing,
+    handler: Box<dyn Handler>,
+}
+
+impl Handler for TracingHandler {
+    fn handle(&self, request: &mut Request) -> FerrumResult<Response> {
+        let active = self.tracing.start(request);
+        request.extensions.insert::<CurrentSpan>(active);
+        let result = self.handler.handle(request);
+        self.tracing.finish(request, &result);
+        result
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use {mime, Chain, FerrumError};
+
+    #[derive(Debug)]
+    struct Failure;
+
+    impl fmt::Display for Failure {
+        fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+            formatter.write_str("Failure")
+        }
+    }
+
+    impl ::std::error::Error for Failure {}
+
+    #[test]
+    fn test_parse_traceparent() {
+        let parent: TraceParent = "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-00".parse().unwrap();
+        assert_eq!(parent.trace_id.to_string(), "4bf92f3577b34da6a3ce929d0e0e4736");
+        assert_eq!(parent.parent_id.to_string(), "00f067aa0ba902b7");
+        assert!(!parent.sampled);
+
+        // Later versions may append fields.
+        assert!("01-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01-extra".parse::<TraceParent>().unwrap().sampled);
+
+        for invalid in &[
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01-extra",
+            "ff-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01",
+            "00-00000000000000000000000000000000-00f067aa0ba902b7-01",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-0000000000000000-01",
+            "00-4BF92F3577B34DA6A3CE929D0E0E4736-00f067aa0ba902b7-01",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7",
+        ] {
+            assert!(invalid.parse::<TraceParent>().is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn test_trace_state() {
+        let mut request = Request::stub();
+        request.headers.append_raw("tracestate", "congo=t61rcWkgMzE");
+        request.headers.append_raw("tracestate", "rojo=00f067aa0ba902b7, ");
+        assert_eq!(request.headers.get::<TraceState>(), Some(&TraceState("congo=t61rcWkgMzE,rojo=00f067aa0ba902b7".to_string())));
+    }
+
+    #[test]
+    fn test_continue_trace() {
+        let mut chain = Chain::new(|request: &mut Request| {
+            let span = request.extensions.get::<CurrentSpan>().unwrap();
+            let outgoing = span.traceparent().to_string();
+            Ok(Response::new().with_content(outgoing, mime::TEXT_PLAIN))
+        });
+        chain.link_before_named("check", |_: &mut Request| Ok(()));
+        chain.link_after_named("fail", |_: &mut Request, _: Response| -> FerrumResult<Response> {
+            Err(FerrumError::new(Failure, Some(Response::new().with_status(StatusCode::BadGateway))))
+        });
+
+        let exporter = InMemoryExporter::new();
+        let app = Tracing::new(exporter.clone()).around(Box::new(chain));
+        let mut request = Request::stub();
+        request.headers.set_raw("traceparent", "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01");
+        request.headers.set_raw("tracestate", "congo=t61rcWkgMzE");
+        assert!(app.handle(&mut request).is_err());
+
+        let spans = exporter.take();
+        let names = spans.iter().map(|span| span.name.as_str()).collect::<Vec<_>>();
+        assert_eq!(&names[..], &["before check", names[1], "after fail", "GET"]);
+        assert!(names[1].starts_with("handle ferrum::trace::test::"), "{}", names[1]);
+
+        let server = &spans[3];
+        assert_eq!(server.kind, SpanKind::Server);
+        assert_eq!(server.trace_id.to_string(), "4bf92f3577b34da6a3ce929d0e0e4736");
+        assert_eq!(server.parent_id.map(|id| id.to_string()), Some("00f067aa0ba902b7".to_string()));
+        assert_eq!(server.trace_state.as_deref(), Some("congo=t61rcWkgMzE"));
+        assert!(server.attributes.contains(&("http.status_code".to_string(), "502".to_string())));
+        assert!(server.error);
+        for child in &spans[..3] {
+            assert_eq!(child.trace_id, server.trace_id);
+            assert_eq!(child.parent_id, Some(server.span_id));
+        }
+        assert!(!spans[1].error);
+        assert!(spans[2].error);
+    }
+
+    #[test]
+    fn test_unsampled_trace() {
+        let exporter = InMemoryExporter::new();
+        let app = Tracing::new(exporter.clone()).around(Box::new(|request: &mut Request| {
+            let outgoing = request.extensions.get::<CurrentSpan>().unwrap().traceparent();
+            Ok(Response::new().with_header(outgoing))
+        }));
+        let mut request = Request::stub();
+        request.headers.set_raw("traceparent", "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-00");
+        let outgoing = app.handle(&mut request).unwrap().headers.get::<TraceParent>().cloned().unwrap();
+        assert_eq!(outgoing.trace_id.to_string(), "4bf92f3577b34da6a3ce929d0e0e4736");
+        assert!(!outgoing.sampled);
+        assert!(exporter.spans().is_empty());
+
+        // A new trace is sampled.
+        app.handle(&mut Request::stub()).unwrap();
+        let spans = exporter.spans();
+        assert_eq!(spans.len(), 1);
+        assert_eq!(spans[0].parent_id, None);
+    }
+}
@@ -0,0 +1,295 @@
+//! Unix domain sockets support for `Ferrum`.
+//!
+//! A `UnixSocket` describes the socket file a server listens on and is passed
+//! to `Ferrum::unix`:
+//!
+//! ```rust,no_run
+//! use ferrum::*;
+//! use ferrum::unix::UnixSocket;
+//!
+//! Ferrum::new(|request: &mut Request| {
+//!     let uid = request.peer_credentials.map(|credentials| credentials.uid);
+//!     Ok(Response::new().with_content(format!("Hello {:?}!", uid), mime::TEXT_PLAIN))
+//! }).unix(UnixSocket::new("/run/app.sock").with_mode(0o660)).unwrap();
+//! ```
+//!
+//! The credentials of the connected process are available on each request
+//! as `Request::peer_credentials`.
+
+use std::fs::{self, DirBuilder, Permissions};
+use std::io;
+use std::mem;
+use std::os::unix::fs::{DirBuilderExt, FileTypeExt, PermissionsExt};
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+
+use libc;
+
+use random;
+use request::PeerCredentials;
+
+/// The socket file of a Unix domain socket listener.
+#[derive(Debug, Clone)]
+pub struct UnixSocket {
+    path: PathBuf,
+    mode: Option<u32>,
+    remove_stale: bool,
+}
+
+impl UnixSocket {
+    /// Describe a socket at `path`.
+    ///
+    /// By default the socket gets the permissions of the process umask and a
+    /// stale socket file left at `path` by a previous server is removed.
+    pub fn new<P: AsRef<Path>>(path: P) -> UnixSocket {
+        UnixSocket {
+            path: path.as_ref().to_path_buf(),
+            mode: None,
+            remove_stale: true,
+        }
+    }
+
+    /// Set the permissions of the socket file, e.g. `0o660`.
+    ///
+    /// The socket is bound in a private directory next to `path` and only
+    /// linked at `path` once it has these permissions.
+    pub fn with_mode(mut self, mode: u32) -> Self {
+        self.mode = Some(mode);
+        self
+    }
+
+    /// Control whether a stale socket file is removed before binding.
+    ///
+    /// A socket file is stale when no process accepts connections on it
+    /// anymore. Files which are not sockets are never removed.
+    pub fn with_stale_removal(mut self, remove_stale: bool) -> Self {
+        self.remove_stale = remove_stale;
+        self
+    }
+
+    /// The path of the socket file.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Bind a listener on the socket file.
+    pub fn bind(&self) -> io::Result<UnixListener> {
+        if self.remove_stale {
+            self.remove_stale()?;
+        }
+        match self.mode {
+            Some(mode) => self.bind_with_mode(mode),
+            None => UnixListener::bind(&self.path)
+        }
+    }
+
+    // Bind the socket in a directory only accessible to the process, so that
+    // clients can't connect before the socket has its permissions.
+    fn bind_with_mode(&self, mode: u32) -> io::Result<UnixListener> {
+        let name = self.path.file_name().ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput,
+            format!("{} is not a socket file path", self.path.display())))?;
+        let dir = self.path.with_file_name(format!(".{:08x}", random::random_u64() as u32));
+        DirBuilder::new().mode(0o700).create(&dir)?;
+        let private = dir.join(name);
+
+        let bound = UnixListener::bind(&private).and_then(|listener| {
+            fs::set_permissions(&private, Permissions::from_mode(mode))?;
+            // Unlike a rename, linking never replaces an existing file.
+            fs::hard_link(&private, &self.path).map_err(|err| match err.kind() {
+                io::ErrorKind::AlreadyExists => io::Error::new(io::ErrorKind::AddrInUse, err),
+                _ => err
+            })?;
+            Ok(listener)
+        });
+        let _ = fs::remove_file(&private);
+        let _ = fs::remove_dir(&dir);
+        bound
+    }
+
+    fn remove_stale(&self) -> io::Result<()> {
+        let metadata = match fs::symlink_metadata(&self.path) {
+            Ok(metadata) => metadata,
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(err) => return Err(err)
+        };
+        if !metadata.file_type().is_socket() {
+            return Err(io::Error::new(io::ErrorKind::AlreadyExists,
+                format!("{} exists and is not a socket", self.path.display())));
+        }
+        match UnixStream::connect(&self.path) {
+            Ok(_) => Err(io::Error::new(io::ErrorKind::AddrInUse,
+                format!("{} is in use by another server", self.path.display()))),
+            Err(ref err) if err.kind() == io::ErrorKind::ConnectionRefused => fs::remove_file(&self.path),
+            Err(err) => Err(err)
+        }
+    }
+}
+
+impl<'a> From<&'a str> for UnixSocket {
+    fn from(path: &'a str) -> UnixSocket {
+        UnixSocket::new(path)
+    }
+}
+
+impl From<String> for UnixSocket {
+    fn from(path: String) -> UnixSocket {
+        UnixSocket::new(path)
+    }
+}
+
+impl<'a> From<&'a Path> for UnixSocket {
+    fn from(path: &'a Path) -> UnixSocket {
+        UnixSocket::new(path)
+    }
+}
+
+impl From<PathBuf> for UnixSocket {
+    fn from(path: PathBuf) -> UnixSocket {
+        UnixSocket { path, mode: None, remove_stale: true }
+    }
+}
+
+// Removes the socket file when the server stops.
+pub(crate) struct SocketFile(pub PathBuf);
+
+impl Drop for SocketFile {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.0);
+    }
+}
+
+/// Get the credentials of the process connected to a Unix domain socket.
+///
+/// The process id is only available on Linux and Android.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub fn peer_credentials<S: AsRawFd>(socket: &S) -> io::Result<PeerCredentials> {
+    let mut ucred: libc::ucred = unsafe { mem::zeroed() };
+    let mut len = mem::size_of::<libc::ucred>() as libc::socklen_t;
+    let result = unsafe {
+        libc::getsockopt(
+            socket.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_PEERCRED,
+            &mut ucred as *mut libc::ucred as *mut libc::c_void,
+            &mut len
+        )
+    };
+    if result != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(PeerCredentials {
+        uid: ucred.uid,
+        gid: ucred.gid,
+        pid: Some(ucred.pid as u32),
+    })
+}
+
+/// Get the credentials of the process connected to a Unix domain socket.
+///
+/// The process id is only available on Linux and Android.
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+pub fn peer_credentials<S: AsRawFd>(socket: &S) -> io::Result<PeerCredentials> {
+    let mut uid: libc::uid_t = unsafe { mem::zeroed() };
+    let mut gid: libc::gid_t = unsafe { mem::zeroed() };
+    if unsafe { libc::getpeereid(socket.as_raw_fd(), &mut uid, &mut gid) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(PeerCredentials { uid, gid, pid: None })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::env;
+    use std::io::{Read, Write};
+    use std::process;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use {mime, Endpoint, Ferrum, Request, Response};
+
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+    fn socket_path() -> PathBuf {
+        let id = COUNTER.fetch_add(1, Ordering::SeqCst);
+        env::temp_dir().join(format!("ferrum-unix-{}-{}.sock", process::id(), id))
+    }
+
+    #[test]
+    fn test_remove_stale_socket() {
+        let path = socket_path();
+        drop(UnixListener::bind(&path).unwrap());
+        assert!(path.exists());
+
+        assert!(UnixSocket::new(&path).with_stale_removal(false).bind().is_err());
+        let listener = UnixSocket::new(&path).bind().unwrap();
+
+        // A socket still accepting connections is left alone.
+        let err = UnixSocket::new(&path).bind().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::AddrInUse);
+        drop(listener);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_keep_regular_file() {
+        let path = socket_path();
+        fs::write(&path, "data").unwrap();
+
+        let err = UnixSocket::new(&path).bind().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::AlreadyExists);
+        assert_eq!(fs::read_to_string(&path).unwrap(), "data");
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_mode() {
+        let path = socket_path();
+        let listener = UnixSocket::new(&path).with_mode(0o600).bind().unwrap();
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        let _client = UnixStream::connect(&path).unwrap();
+        assert!(listener.accept().is_ok());
+
+        // An existing file is not replaced, and the private directory is removed.
+        let err = UnixSocket::new(&path).with_stale_removal(false).with_mode(0o600).bind().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::AddrInUse);
+        let name = path.file_name().unwrap().to_os_string();
+        let leftovers = fs::read_dir(path.parent().unwrap()).unwrap()
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| entry.path().join(&name).exists())
+            .count();
+        assert_eq!(leftovers, 0);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_peer_credentials() {
+        let (left, _right) = UnixStream::pair().unwrap();
+        let credentials = peer_credentials(&left).unwrap();
+        assert_eq!(credentials.uid, unsafe { libc::getuid() });
+        assert_eq!(credentials.gid, unsafe { libc::getgid() });
+        if cfg!(target_os = "linux") {
+            assert_eq!(credentials.pid, Some(process::id()));
+        }
+    }
+
+    #[test]
+    fn test_serve_unix() {
+        let path = socket_path();
+        let listening = Ferrum::new(|request: &mut Request| {
+            let credentials = request.peer_credentials.unwrap();
+            let content = format!("{} {}", credentials.uid, request.url().unwrap());
+            Ok(Response::new().with_content(content, mime::TEXT_PLAIN))
+        }).spawn_endpoints(vec![Endpoint::unix(path.clone()).unwrap()]).unwrap();
+
+        let mut stream = UnixStream::connect(&path).unwrap();
+        stream.write_all(b"GET /foo HTTP/1.1\r\nHost: app\r\nConnection: close\r\n\r\n").unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+
+        assert!(response.starts_with("HTTP/1.1 200 OK"));
+        assert!(response.ends_with(&format!("{} http://app/foo", unsafe { libc::getuid() })));
+        listening.shutdown().unwrap();
+    }
+}
@@ -0,0 +1,251 @@
+//! Dispatch of requests to handlers by host.
+//!
+//! `VirtualHosts` hands each request to the handler of its host, matched
+//! exactly, by a wildcard subdomain or by a regex:
+//!
+//! ```rust,no_run
+//! use ferrum::*;
+//! use ferrum::vhost::{HostCaptures, Regex, VirtualHosts};
+//!
+//! fn tenant(request: &mut Request) -> FerrumResult<Response> {
+//!     let captures = request.extensions.get::<HostCaptures>().unwrap();
+//!     let content = format!("Welcome to {}", captures.captures[0].as_ref().unwrap());
+//!     Ok(Response::new().with_content(content, mime::TEXT_PLAIN))
+//! }
+//! # fn site(_: &mut Request) -> FerrumResult<Response> { Ok(Response::new()) }
+//! # fn region(_: &mut Request) -> FerrumResult<Response> { Ok(Response::new()) }
+//! # fn not_found(_: &mut Request) -> FerrumResult<Response> { Ok(Response::new()) }
+//!
+//! let mut hosts = VirtualHosts::new();
+//! hosts.host("example.com", site)
+//!     .host("*.example.com", tenant)
+//!     .host_regex(Regex::new(r"api\.(?P<region>[a-z]+)\.example\.net").unwrap(), region)
+//!     .fallback(not_found);
+//! Ferrum::new(hosts).http("0.0.0.0:80").unwrap();
+//! ```
+//!
+//! The host is the one of `Request::url`, so the forwarded host is used
+//! behind trusted proxies. Exact hosts take precedence over wildcards, the
+//! longest wildcard over shorter ones, and wildcards over regexes, which are
+//! tried in the order they were added. The matched parts of the host are
+//! stored as the `HostCaptures` of the request.
+
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fmt;
+
+pub use regex::Regex;
+
+use {Request, Response, FerrumResult, FerrumError, StatusCode};
+use middleware::Handler;
+use typemap::Key;
+
+/// The parts of the host matched by `VirtualHosts`, in the extensions of the
+/// request.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct HostCaptures {
+    /// The host of the request, lowercase and without port.
+    pub host: String,
+    /// The subdomain matched by the `*` of a wildcard, or the groups of a
+    /// regex, `None` for the groups which didn't participate in the match.
+    pub captures: Vec<Option<String>>,
+    /// The named groups of a regex which matched.
+    pub named: BTreeMap<String, String>,
+}
+
+impl Key for HostCaptures {
+    type Value = HostCaptures;
+}
+
+/// The error of a request of a host without handler, answered with
+/// `404 Not Found`.
+#[derive(Debug)]
+pub struct UnknownHost(pub Option<String>);
+
+impl fmt::Display for UnknownHost {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        match self.0 {
+            Some(ref host) => write!(formatter, "No handler for the host {}", host),
+            None => formatter.write_str("No host in the request")
+        }
+    }
+}
+
+impl Error for UnknownHost {}
+
+enum Pattern {
+    Exact(String),
+    // The suffix of the subdomains, with its leading dot.
+    Wildcard(String),
+    Regex(Regex),
+}
+
+impl Pattern {
+    fn captures(&self, host: &str) -> Option<HostCaptures> {
+        let mut captures = HostCaptures { host: host.to_string(), ..HostCaptures::default() };
+        match *self {
+            Pattern::Exact(ref exact) if exact == host => {},
+            Pattern::Wildcard(ref suffix) if host.len() > suffix.len() && host.ends_with(suffix.as_str()) => {
+                captures.captures.push(Some(host[..host.len() - suffix.len()].to_string()));
+            },
+            Pattern::Regex(ref regex) => {
+                let matched = regex.captures(host)?;
+                captures.captures = matched.iter().skip(1)
+                    .map(|group| group.map(|group| group.as_str().to_string()))
+                    .collect();
+                for name in regex.capture_names().flatten() {
+                    if let Some(group) = matched.name(name) {
+                        captures.named.insert(name.to_string(), group.as_str().to_string());
+                    }
+                }
+            },
+            _ => return None
+        }
+        Some(captures)
+    }
+
+    // Exact hosts first, then the wildcards by decreasing length, then the regexes.
+    fn rank(&self) -> (u8, usize) {
+        match *self {
+            Pattern::Exact(_) => (0, 0),
+            Pattern::Wildcard(ref suffix) => (1, usize::MAX - suffix.len()),
+            Pattern::Regex(_) => (2, 0),
+        }
+    }
+}
+
+/// A `Handler` dispatching requests by host, see the `vhost` module.
+pub struct VirtualHosts {
+    hosts: Vec<(Pattern, Box<dyn Handler>)>,
+    fallback: Option<Box<dyn Handler>>,
+}
+
+impl VirtualHosts {
+    /// Create `VirtualHosts` without handlers.
+    pub fn new() -> VirtualHosts {
+        VirtualHosts {
+            hosts: Vec::new(),
+            fallback: None,
+        }
+    }
+
+    /// Handle the requests of `host`, e.g. `example.com`, or of its
+    /// subdomains for `*.example.com`. Hosts are case insensitive.
+    pub fn host<H: Handler>(&mut self, host: &str, handler: H) -> &mut VirtualHosts {
+        let host = normalize(host);
+        let pattern = if host.starts_with("*.") {
+            Pattern::Wildcard(host[1..].to_string())
+        } else {
+            Pattern::Exact(host)
+        };
+        self.add(pattern, handler)
+    }
+
+    /// Handle the requests of the hosts matched entirely by `regex`. The
+    /// hosts are lowercase and without port.
+    pub fn host_regex<H: Handler>(&mut self, regex: Regex, handler: H) -> &mut VirtualHosts {
+        // Anchoring a valid regex keeps it valid, along with its groups.
+        let anchored = Regex::new(&format!("^(?:{})$", regex.as_str())).unwrap_or(regex);
+        self.add(Pattern::Regex(anchored), handler)
+    }
+
+    /// Handle the requests matching no host. Without fallback, they are
+    /// answered with `404 Not Found`.
+    pub fn fallback<H: Handler>(&mut self, handler: H) -> &mut VirtualHosts {
+        self.fallback = Some(Box::new(handler));
+        self
+    }
+
+    fn add<H: Handler>(&mut self, pattern: Pattern, handler: H) -> &mut VirtualHosts {
+        let rank = pattern.rank();
+        let index = self.hosts.iter()
+            .position(|(other, _)| other.rank() > rank)
+            .unwrap_or(self.hosts.len());
+        self.hosts.insert(index, (pattern, Box::new(handler)));
+        self
+    }
+}
+
+impl Default for VirtualHosts {
+    fn default() -> VirtualHosts {
+        VirtualHosts::new()
+    }
+}
+
+fn normalize(host: &str) -> String {
+    host.trim_end_matches('.').to_lowercase()
+}
+
+impl Handler for VirtualHosts {
+    fn handle(&self, request: &mut Request) -> FerrumResult<Response> {
+        let host = request.url().ok().and_then(|url| url.host_str().map(normalize));
+        if let Some(ref host) = host {
+            for (pattern, handler) in &self.hosts {
+                if let Some(captures) = pattern.captures(host) {
+                    request.extensions.insert::<HostCaptures>(captures);
+                    return handler.handle(request);
+                }
+            }
+        }
+        match self.fallback {
+            Some(ref fallback) => fallback.handle(request),
+            None => Err(FerrumError::new(UnknownHost(host), Some(Response::new().with_status(StatusCode::NotFound))))
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use mime;
+
+    fn echo(name: &'static str) -> impl Handler {
+        move |request: &mut Request| {
+            let content = match request.extensions.get::<HostCaptures>() {
+                Some(captures) => format!("{} {:?} {:?}", name, captures.captures, captures.named),
+                None => name.to_string()
+            };
+            Ok(Response::new().with_content(content, mime::TEXT_PLAIN))
+        }
+    }
+
+    fn handle(hosts: &VirtualHosts, host: &str) -> FerrumResult<String> {
+        let mut request = Request::stub();
+        request.uri = "/".parse().unwrap();
+        request.headers.set_raw("Host", host.to_string());
+        let mut response = hosts.handle(&mut request)?;
+        Ok(String::from_utf8(response.buffer_content().unwrap().unwrap().0).unwrap())
+    }
+
+    #[test]
+    fn test_host_precedence() {
+        let mut hosts = VirtualHosts::new();
+        hosts.host_regex(Regex::new(r"(?P<name>[a-z]+)\.example\.com").unwrap(), echo("regex"))
+            .host("*.example.com", echo("wildcard"))
+            .host("*.eu.Example.com", echo("eu"))
+            .host("WWW.example.com", echo("www"));
+
+        assert_eq!(handle(&hosts, "www.example.com:8080").unwrap(), "www [] {}");
+        assert_eq!(handle(&hosts, "Shop.Example.com.").unwrap(), "wildcard [Some(\"shop\")] {}");
+        assert_eq!(handle(&hosts, "a.shop.eu.example.com").unwrap(), "eu [Some(\"a.shop\")] {}");
+
+        let error = handle(&hosts, "example.com").unwrap_err();
+        assert_eq!(error.response.unwrap().status, StatusCode::NotFound);
+        assert_eq!(error.error.to_string(), "No handler for the host example.com");
+    }
+
+    #[test]
+    fn test_regex_captures() {
+        let mut hosts = VirtualHosts::new();
+        hosts.host_regex(Regex::new(r"api(-(?P<version>v[0-9]+))?\.(?P<region>[a-z]+)\.example\.net").unwrap(), echo("api"))
+            .fallback(echo("fallback"));
+
+        assert_eq!(handle(&hosts, "api.eu.example.net").unwrap(),
+            "api [None, None, Some(\"eu\")] {\"region\": \"eu\"}");
+        assert_eq!(handle(&hosts, "api-v2.us.example.net").unwrap(),
+            "api [Some(\"-v2\"), Some(\"v2\"), Some(\"us\")] {\"region\": \"us\", \"version\": \"v2\"}");
+        // The regex must match the whole host.
+        assert_eq!(handle(&hosts, "old.api.eu.example.net").unwrap(), "fallback");
+    }
+}
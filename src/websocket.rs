@@ -0,0 +1,1011 @@
+//! WebSocket connections upgraded from HTTP/1.1 requests.
+//!
+//! A handler accepts the upgrade with a callback, which runs on its own
+//! thread, outside the request thread pool, and exchanges messages with the
+//! client through a `WebSocket`. Pings are answered and close frames are
+//! echoed by the server:
+//!
+//! ```rust,no_run
+//! use ferrum::*;
+//! use ferrum::websocket::{Message, WebSocket, WebSocketUpgrade};
+//!
+//! let echo = |request: &mut Request| {
+//!     let upgrade = WebSocketUpgrade::new(request)?;
+//!     Ok(upgrade.accept(|socket: WebSocket| {
+//!         for message in socket.incoming() {
+//!             if let Message::Text(text) = message {
+//!                 let _ = socket.send(Message::Text(text));
+//!             }
+//!         }
+//!     }))
+//! };
+//! Ferrum::new(echo).http("localhost:3000").unwrap();
+//! ```
+//!
+//! Few messages are queued in each direction: the server stops reading from
+//! the client while the callback is behind, and `WebSocket::send` blocks
+//! while the client is.
+
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, SyncSender, TryRecvError, TrySendError};
+use std::thread;
+use std::time::Duration;
+
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use futures::{Async, Future, Poll};
+use futures::task::AtomicTask;
+use hyper::{HttpVersion, Method, StatusCode};
+use hyper::header::Headers;
+use sha1_smol::Sha1;
+use tokio_core::reactor::{Handle, Timeout};
+use tokio_io::{AsyncRead, AsyncWrite};
+
+use typemap::Key;
+use {FerrumError, Request, Response};
+
+/// The only WebSocket protocol version supported, from RFC 6455.
+pub const VERSION: &str = "13";
+
+/// The default maximum size of a message, 16MiB.
+pub const DEFAULT_MAX_MESSAGE_SIZE: usize = 16 << 20;
+
+/// How long the closing handshake may take by default, 5 seconds.
+pub const DEFAULT_CLOSE_TIMEOUT: Duration = Duration::from_secs(5);
+
+// The number of messages queued in each direction.
+const QUEUE_LEN: usize = 16;
+
+// The size of the unsent frames over which the server stops reading.
+const MAX_WRITE_BUF: usize = 64 << 10;
+
+const GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+const OPCODE_CONTINUATION: u8 = 0x0;
+const OPCODE_TEXT: u8 = 0x1;
+const OPCODE_BINARY: u8 = 0x2;
+const OPCODE_CLOSE: u8 = 0x8;
+const OPCODE_PING: u8 = 0x9;
+const OPCODE_PONG: u8 = 0xA;
+
+const MAX_CONTROL_PAYLOAD: usize = 125;
+
+/// The value of the `Sec-WebSocket-Accept` header answering `key`.
+pub fn accept_key(key: &str) -> String {
+    let mut sha1 = Sha1::new();
+    sha1.update(key.as_bytes());
+    sha1.update(GUID.as_bytes());
+    BASE64.encode(sha1.digest().bytes())
+}
+
+/// Why a request can't be upgraded to a WebSocket connection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HandshakeError {
+    /// The request method is not `GET`.
+    Method,
+    /// The request is not an HTTP/1.1 request asking for a WebSocket upgrade.
+    NotUpgrade,
+    /// The `Sec-WebSocket-Version` is not 13, answered with
+    /// `426 Upgrade Required`.
+    UnsupportedVersion,
+    /// The `Sec-WebSocket-Key` is missing or is not a 16 bytes nonce.
+    InvalidKey,
+}
+
+impl fmt::Display for HandshakeError {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str(match *self {
+            HandshakeError::Method => "WebSocket handshake requires a GET request",
+            HandshakeError::NotUpgrade => "Request is not a WebSocket upgrade",
+            HandshakeError::UnsupportedVersion => "Unsupported WebSocket version",
+            HandshakeError::InvalidKey => "Invalid Sec-WebSocket-Key",
+        })
+    }
+}
+
+impl Error for HandshakeError {}
+
+impl From<HandshakeError> for FerrumError {
+    fn from(err: HandshakeError) -> FerrumError {
+        let mut response = Response::new();
+        match err {
+            HandshakeError::Method => response.status = StatusCode::MethodNotAllowed,
+            HandshakeError::UnsupportedVersion => {
+                response.status = StatusCode::UpgradeRequired;
+                response.headers.set_raw("Sec-WebSocket-Version", VERSION);
+            },
+            HandshakeError::NotUpgrade | HandshakeError::InvalidKey => response.status = StatusCode::BadRequest,
+        }
+        FerrumError::new(err, Some(response))
+    }
+}
+
+/// A request asking to be upgraded to a WebSocket connection.
+#[derive(Debug)]
+pub struct WebSocketUpgrade {
+    key: String,
+    protocols: Vec<String>,
+    protocol: Option<String>,
+    max_message_size: usize,
+    close_timeout: Duration,
+}
+
+impl WebSocketUpgrade {
+    /// Validate the opening handshake of `request`.
+    ///
+    /// The error converts to a `FerrumError` with the appropriate response.
+    pub fn new(request: &Request) -> Result<WebSocketUpgrade, HandshakeError> {
+        if request.method != Method::Get {
+            return Err(HandshakeError::Method);
+        }
+        if !WebSocketUpgrade::is_upgrade(request) {
+            return Err(HandshakeError::NotUpgrade);
+        }
+        if header_values(&request.headers, "Sec-WebSocket-Version") != [VERSION] {
+            return Err(HandshakeError::UnsupportedVersion);
+        }
+
+        let key = match header_values(&request.headers, "Sec-WebSocket-Key").as_slice() {
+            [key] if BASE64.decode(key).map(|nonce| nonce.len() == 16).unwrap_or(false) => key.clone(),
+            _ => return Err(HandshakeError::InvalidKey)
+        };
+
+        Ok(WebSocketUpgrade {
+            key,
+            protocols: header_values(&request.headers, "Sec-WebSocket-Protocol"),
+            protocol: None,
+            max_message_size: DEFAULT_MAX_MESSAGE_SIZE,
+            close_timeout: DEFAULT_CLOSE_TIMEOUT,
+        })
+    }
+
+    /// Whether `request` asks for a WebSocket upgrade, so a handler can serve
+    /// both WebSocket and plain requests.
+    pub fn is_upgrade(request: &Request) -> bool {
+        request.version == HttpVersion::Http11
+            && header_values(&request.headers, "Upgrade").iter().any(|value| value.eq_ignore_ascii_case("websocket"))
+            && header_values(&request.headers, "Connection").iter().any(|value| value.eq_ignore_ascii_case("upgrade"))
+    }
+
+    /// The subprotocols offered by the client, in its order of preference.
+    pub fn protocols(&self) -> &[String] {
+        &self.protocols
+    }
+
+    /// Select the subprotocol of the connection, which should be one of the
+    /// offered `protocols`.
+    pub fn with_protocol<S: Into<String>>(mut self, protocol: S) -> Self {
+        self.protocol = Some(protocol.into());
+        self
+    }
+
+    /// Close the connection with `1009 Message Too Big` when a message from
+    /// the client is larger than `size` bytes.
+    pub fn with_max_message_size(mut self, size: usize) -> Self {
+        self.max_message_size = size;
+        self
+    }
+
+    /// Close the connection when the closing handshake, started by either
+    /// side, is not completed within `timeout`.
+    pub fn with_close_timeout(mut self, timeout: Duration) -> Self {
+        self.close_timeout = timeout;
+        self
+    }
+
+    /// Accept the upgrade, returning the `101 Switching Protocols` response.
+    ///
+    /// Once the response is sent, `on_upgrade` is called on a new thread with
+    /// the socket of the connection, which is closed when the socket is dropped.
+    pub fn accept<F>(self, on_upgrade: F) -> Response
+        where F: FnOnce(WebSocket) + Send + 'static
+    {
+        let mut response = Response::new().with_status(StatusCode::SwitchingProtocols);
+        response.headers.set_raw("Upgrade", "websocket");
+        response.headers.set_raw("Connection", "Upgrade");
+        response.headers.set_raw("Sec-WebSocket-Accept", accept_key(&self.key));
+        if let Some(protocol) = self.protocol {
+            response.headers.set_raw("Sec-WebSocket-Protocol", protocol);
+        }
+        response.extensions.insert::<PendingUpgrade>(Mutex::new(Some(Upgrade {
+            on_upgrade: Box::new(on_upgrade),
+            max_message_size: self.max_message_size,
+            close_timeout: self.close_timeout,
+        })));
+        response
+    }
+}
+
+// The comma separated values of the header `name`.
+fn header_values(headers: &Headers, name: &str) -> Vec<String> {
+    headers.get_raw(name).map(|raw| {
+        raw.iter()
+            .filter_map(|line| ::std::str::from_utf8(line).ok())
+            .flat_map(|line| line.split(','))
+            .map(|value| value.trim().to_string())
+            .filter(|value| !value.is_empty())
+            .collect()
+    }).unwrap_or_default()
+}
+
+/// An accepted upgrade, taken from the response by the connection.
+pub(crate) struct Upgrade {
+    on_upgrade: Box<dyn FnOnce(WebSocket) + Send>,
+    max_message_size: usize,
+    close_timeout: Duration,
+}
+
+pub(crate) struct PendingUpgrade;
+
+impl Key for PendingUpgrade {
+    type Value = Mutex<Option<Upgrade>>;
+}
+
+/// Where the service hands the accepted upgrade of a response over to its
+/// connection.
+pub(crate) type UpgradeSlot = ::std::sync::Arc<Mutex<Option<Upgrade>>>;
+
+/// The status code and reason of a close frame.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CloseFrame {
+    pub code: u16,
+    pub reason: String,
+}
+
+impl CloseFrame {
+    /// The purpose of the connection was fulfilled.
+    pub const NORMAL: u16 = 1000;
+    /// The endpoint is going away.
+    pub const GOING_AWAY: u16 = 1001;
+    /// The peer violated the protocol.
+    pub const PROTOCOL_ERROR: u16 = 1002;
+    /// A text message is not valid UTF-8.
+    pub const INVALID_PAYLOAD: u16 = 1007;
+    /// A message is larger than the maximum message size.
+    pub const MESSAGE_TOO_BIG: u16 = 1009;
+
+    pub fn new<S: Into<String>>(code: u16, reason: S) -> CloseFrame {
+        CloseFrame { code, reason: reason.into() }
+    }
+}
+
+/// A WebSocket message.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Message {
+    Text(String),
+    Binary(Vec<u8>),
+    Ping(Vec<u8>),
+    Pong(Vec<u8>),
+    Close(Option<CloseFrame>),
+}
+
+impl Message {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        let (opcode, payload) = match *self {
+            Message::Text(ref text) => (OPCODE_TEXT, text.as_bytes().to_vec()),
+            Message::Binary(ref data) => (OPCODE_BINARY, data.clone()),
+            Message::Ping(ref data) => (OPCODE_PING, data.clone()),
+            Message::Pong(ref data) => (OPCODE_PONG, data.clone()),
+            Message::Close(None) => (OPCODE_CLOSE, Vec::new()),
+            Message::Close(Some(ref frame)) => {
+                let mut payload = frame.code.to_be_bytes().to_vec();
+                payload.extend_from_slice(frame.reason.as_bytes());
+                (OPCODE_CLOSE, payload)
+            }
+        };
+
+        // Server frames are final and unmasked.
+        buf.push(0x80 | opcode);
+        if payload.len() < 126 {
+            buf.push(payload.len() as u8);
+        } else if payload.len() <= 0xFFFF {
+            buf.push(126);
+            buf.extend_from_slice(&(payload.len() as u16).to_be_bytes());
+        } else {
+            buf.push(127);
+            buf.extend_from_slice(&(payload.len() as u64).to_be_bytes());
+        }
+        buf.extend_from_slice(&payload);
+    }
+
+    fn control_payload_len(&self) -> usize {
+        match *self {
+            Message::Ping(ref data) | Message::Pong(ref data) => data.len(),
+            Message::Close(Some(ref frame)) => 2 + frame.reason.len(),
+            _ => 0
+        }
+    }
+}
+
+/// An error sending or receiving a WebSocket message.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WebSocketError {
+    /// The connection is closed.
+    Closed,
+    /// No message was received before the timeout.
+    Timeout,
+    /// The payload of a ping, pong or close message is over 125 bytes.
+    ControlFrameTooLarge,
+}
+
+impl fmt::Display for WebSocketError {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str(match *self {
+            WebSocketError::Closed => "WebSocket connection closed",
+            WebSocketError::Timeout => "WebSocket receive timed out",
+            WebSocketError::ControlFrameTooLarge => "WebSocket control frame payload over 125 bytes",
+        })
+    }
+}
+
+impl Error for WebSocketError {}
+
+/// The blocking socket of an upgraded connection, given to the callback of
+/// `WebSocketUpgrade::accept`.
+///
+/// The connection is closed with `1000 Normal Closure` when the socket is
+/// dropped, unless it was already closed.
+pub struct WebSocket {
+    incoming: Receiver<Message>,
+    outgoing: Option<SyncSender<Message>>,
+    // The session of the connection, woken when a message is received or sent.
+    session: Arc<AtomicTask>,
+}
+
+impl WebSocket {
+    /// Wait for the next message from the client.
+    ///
+    /// The last message of a connection closed by the client is its
+    /// `Message::Close`, after which `WebSocketError::Closed` is returned.
+    pub fn recv(&self) -> Result<Message, WebSocketError> {
+        let message = self.incoming.recv().map_err(|_| WebSocketError::Closed)?;
+        self.session.notify();
+        Ok(message)
+    }
+
+    /// Wait at most `timeout` for the next message from the client.
+    pub fn recv_timeout(&self, timeout: Duration) -> Result<Message, WebSocketError> {
+        let message = self.incoming.recv_timeout(timeout).map_err(|err| match err {
+            RecvTimeoutError::Timeout => WebSocketError::Timeout,
+            RecvTimeoutError::Disconnected => WebSocketError::Closed,
+        })?;
+        self.session.notify();
+        Ok(message)
+    }
+
+    /// Send `message` to the client, waiting while the queue of messages not
+    /// yet sent is full.
+    pub fn send(&self, message: Message) -> Result<(), WebSocketError> {
+        if message.control_payload_len() > MAX_CONTROL_PAYLOAD {
+            return Err(WebSocketError::ControlFrameTooLarge);
+        }
+        let outgoing = self.outgoing.as_ref().ok_or(WebSocketError::Closed)?;
+        outgoing.send(message).map_err(|_| WebSocketError::Closed)?;
+        self.session.notify();
+        Ok(())
+    }
+
+    /// Send a text message to the client.
+    pub fn send_text<S: Into<String>>(&self, text: S) -> Result<(), WebSocketError> {
+        self.send(Message::Text(text.into()))
+    }
+
+    /// Send a binary message to the client.
+    pub fn send_binary<B: Into<Vec<u8>>>(&self, data: B) -> Result<(), WebSocketError> {
+        self.send(Message::Binary(data.into()))
+    }
+
+    /// Send a ping, answered by a `Message::Pong` from the client.
+    pub fn ping<B: Into<Vec<u8>>>(&self, data: B) -> Result<(), WebSocketError> {
+        self.send(Message::Ping(data.into()))
+    }
+
+    /// Start the closing handshake. Messages are still received until the
+    /// client echoes the close frame.
+    pub fn close<S: Into<String>>(&self, code: u16, reason: S) -> Result<(), WebSocketError> {
+        self.send(Message::Close(Some(CloseFrame::new(code, reason))))
+    }
+
+    /// Iterate over the messages from the client until the connection is
+    /// closed.
+    pub fn incoming(&self) -> Incoming<'_> {
+        Incoming { socket: self }
+    }
+}
+
+impl Drop for WebSocket {
+    fn drop(&mut self) {
+        // Let the session close the connection.
+        self.outgoing = None;
+        self.session.notify();
+    }
+}
+
+impl fmt::Debug for WebSocket {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("WebSocket")
+    }
+}
+
+/// An iterator over the messages received by a `WebSocket`.
+#[derive(Debug)]
+pub struct Incoming<'a> {
+    socket: &'a WebSocket,
+}
+
+impl<'a> Iterator for Incoming<'a> {
+    type Item = Message;
+
+    fn next(&mut self) -> Option<Message> {
+        self.socket.recv().ok()
+    }
+}
+
+/// Start the callback of `upgrade` on its own thread and return the future
+/// exchanging its messages over `io`.
+pub(crate) fn serve<S>(io: S, upgrade: Upgrade, handle: &Handle) -> Box<dyn Future<Item = (), Error = ()>>
+    where S: AsyncRead + AsyncWrite + 'static
+{
+    let (incoming_sender, incoming) = mpsc::sync_channel(QUEUE_LEN);
+    let (outgoing, outgoing_receiver) = mpsc::sync_channel(QUEUE_LEN);
+    let task = Arc::new(AtomicTask::new());
+    let socket = WebSocket { incoming, outgoing: Some(outgoing), session: task.clone() };
+
+    let on_upgrade = upgrade.on_upgrade;
+    let spawned = thread::Builder::new()
+        .name("ferrum-websocket".to_string())
+        .spawn(move || on_upgrade(socket));
+    if spawned.is_err() {
+        return Box::new(::futures::future::ok(()));
+    }
+
+    Box::new(Session {
+        io,
+        max_message_size: upgrade.max_message_size,
+        read_buf: Vec::new(),
+        write_buf: Vec::new(),
+        fragments: None,
+        incoming: Some(incoming_sender),
+        pending: None,
+        incoming_done: false,
+        outgoing: outgoing_receiver,
+        outgoing_done: false,
+        task,
+        close_sent: false,
+        close_received: false,
+        handle: handle.clone(),
+        close_timeout: upgrade.close_timeout,
+        closing: None,
+    })
+}
+
+struct Frame {
+    fin: bool,
+    opcode: u8,
+    payload: Vec<u8>,
+}
+
+// A text or binary message received in several frames.
+struct Fragments {
+    opcode: u8,
+    payload: Vec<u8>,
+}
+
+// The server side of a WebSocket connection, running on the event loop.
+struct Session<S> {
+    io: S,
+    max_message_size: usize,
+    read_buf: Vec<u8>,
+    write_buf: Vec<u8>,
+    fragments: Option<Fragments>,
+    incoming: Option<SyncSender<Message>>,
+    // A message waiting for room in the incoming queue.
+    pending: Option<Message>,
+    incoming_done: bool,
+    outgoing: Receiver<Message>,
+    outgoing_done: bool,
+    task: Arc<AtomicTask>,
+    close_sent: bool,
+    close_received: bool,
+    handle: Handle,
+    close_timeout: Duration,
+    // The deadline of the closing handshake, once started.
+    closing: Option<Timeout>,
+}
+
+impl<S> Session<S>
+    where S: AsyncRead + AsyncWrite
+{
+    fn queue(&mut self, message: Message) {
+        if self.close_sent {
+            return;
+        }
+        if let Message::Close(_) = message {
+            self.close_sent = true;
+        }
+        message.encode(&mut self.write_buf);
+    }
+
+    fn deliver(&mut self, message: Message) {
+        self.pending = Some(message);
+        self.poll_deliver();
+    }
+
+    // Hand the pending message over to the socket, returning whether the
+    // incoming queue had room for it.
+    fn poll_deliver(&mut self) -> bool {
+        if let (Some(message), Some(incoming)) = (self.pending.take(), self.incoming.as_ref()) {
+            if let Err(TrySendError::Full(message)) = incoming.try_send(message) {
+                // The socket may have received a message in between.
+                self.task.register();
+                if let Err(TrySendError::Full(message)) = incoming.try_send(message) {
+                    self.pending = Some(message);
+                    return false;
+                }
+            }
+        }
+        if self.incoming_done && self.pending.is_none() {
+            self.incoming = None;
+        }
+        true
+    }
+
+    // No more messages are delivered to the socket, besides the pending one.
+    fn end_incoming(&mut self) {
+        self.incoming_done = true;
+        self.poll_deliver();
+    }
+
+    // Close the connection after a protocol violation by the client.
+    fn fail(&mut self, code: u16) {
+        self.queue(Message::Close(Some(CloseFrame::new(code, ""))));
+        self.close_received = true;
+        self.end_incoming();
+        self.read_buf.clear();
+    }
+
+    // Queue the messages of the socket while the unsent frames are within
+    // bounds, returning whether some may be left.
+    fn poll_outgoing(&mut self) -> bool {
+        while !self.outgoing_done {
+            if self.write_buf.len() >= MAX_WRITE_BUF {
+                return true;
+            }
+            let message = match self.outgoing.try_recv() {
+                Err(TryRecvError::Empty) => {
+                    // The socket may have sent a message in between.
+                    self.task.register();
+                    self.outgoing.try_recv()
+                },
+                received => received
+            };
+            match message {
+                Ok(message) => self.queue(message),
+                Err(TryRecvError::Disconnected) => {
+                    // The socket was dropped.
+                    self.outgoing_done = true;
+                    self.queue(Message::Close(Some(CloseFrame::new(CloseFrame::NORMAL, ""))));
+                },
+                Err(TryRecvError::Empty) => break
+            }
+        }
+        false
+    }
+
+    fn poll_read(&mut self) -> io::Result<()> {
+        let mut buf = [0; 8192];
+        // Stop reading while the socket or the client is behind.
+        while !self.close_received && self.pending.is_none() && self.write_buf.len() < MAX_WRITE_BUF {
+            loop {
+                match self.parse_frame() {
+                    Ok(Some(frame)) => if let Err(code) = self.on_frame(frame) {
+                        self.fail(code);
+                        return Ok(());
+                    },
+                    Ok(None) => break,
+                    Err(code) => {
+                        self.fail(code);
+                        return Ok(());
+                    }
+                }
+                if self.close_received || self.pending.is_some() {
+                    return Ok(());
+                }
+            }
+
+            match self.io.read(&mut buf) {
+                Ok(0) => {
+                    // The client went away without a closing handshake.
+                    self.close_received = true;
+                    self.close_sent = true;
+                    self.end_incoming();
+                },
+                Ok(len) => self.read_buf.extend_from_slice(&buf[..len]),
+                Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => return Ok(()),
+                Err(err) => return Err(err)
+            }
+        }
+        Ok(())
+    }
+
+    // Parse the next complete frame of the read buffer.
+    fn parse_frame(&mut self) -> Result<Option<Frame>, u16> {
+        let buf = &self.read_buf;
+        if buf.len() < 2 {
+            return Ok(None);
+        }
+        let fin = buf[0] & 0x80 != 0;
+        let opcode = buf[0] & 0x0F;
+        // Client frames must be masked and no extension is negotiated.
+        if buf[0] & 0x70 != 0 || buf[1] & 0x80 == 0 {
+            return Err(CloseFrame::PROTOCOL_ERROR);
+        }
+
+        let (len, offset) = match buf[1] & 0x7F {
+            126 if buf.len() < 4 => return Ok(None),
+            126 => (u64::from(u16::from_be_bytes([buf[2], buf[3]])), 4),
+            127 if buf.len() < 10 => return Ok(None),
+            127 => {
+                let mut len = [0; 8];
+                len.copy_from_slice(&buf[2..10]);
+                (u64::from_be_bytes(len), 10)
+            },
+            len => (u64::from(len), 2)
+        };
+        if opcode >= OPCODE_CLOSE && (!fin || len > MAX_CONTROL_PAYLOAD as u64) {
+            return Err(CloseFrame::PROTOCOL_ERROR);
+        }
+        let buffered = self.fragments.as_ref().map(|fragments| fragments.payload.len()).unwrap_or(0);
+        if len + buffered as u64 > self.max_message_size as u64 {
+            return Err(CloseFrame::MESSAGE_TOO_BIG);
+        }
+
+        let start = offset + 4;
+        let end = start + len as usize;
+        if buf.len() < end {
+            return Ok(None);
+        }
+        let mask = &buf[offset..start];
+        let payload = buf[start..end].iter().enumerate().map(|(i, byte)| byte ^ mask[i % 4]).collect();
+        self.read_buf.drain(..end);
+        Ok(Some(Frame { fin, opcode, payload }))
+    }
+
+    fn on_frame(&mut self, frame: Frame) -> Result<(), u16> {
+        match frame.opcode {
+            OPCODE_CONTINUATION => {
+                let mut fragments = self.fragments.take().ok_or(CloseFrame::PROTOCOL_ERROR)?;
+                fragments.payload.extend_from_slice(&frame.payload);
+                if frame.fin {
+                    self.on_message(fragments.opcode, fragments.payload)?;
+                } else {
+                    self.fragments = Some(fragments);
+                }
+            },
+            OPCODE_TEXT | OPCODE_BINARY => {
+                if self.fragments.is_some() {
+                    return Err(CloseFrame::PROTOCOL_ERROR);
+                }
+                if frame.fin {
+                    self.on_message(frame.opcode, frame.payload)?;
+                } else {
+                    self.fragments = Some(Fragments { opcode: frame.opcode, payload: frame.payload });
+                }
+            },
+            OPCODE_CLOSE => {
+                let close = match frame.payload.len() {
+                    0 => None,
+                    1 => return Err(CloseFrame::PROTOCOL_ERROR),
+                    _ => {
+                        let code = u16::from_be_bytes([frame.payload[0], frame.payload[1]]);
+                        let reason = String::from_utf8(frame.payload[2..].to_vec())
+                            .map_err(|_| CloseFrame::INVALID_PAYLOAD)?;
+                        Some(CloseFrame { code, reason })
+                    }
+                };
+                // Echo the close frame, unless the server started the closing handshake.
+                self.queue(Message::Close(close.clone()));
+                self.close_received = true;
+                self.deliver(Message::Close(close));
+                self.end_incoming();
+            },
+            OPCODE_PING => {
+                self.queue(Message::Pong(frame.payload.clone()));
+                self.deliver(Message::Ping(frame.payload));
+            },
+            OPCODE_PONG => self.deliver(Message::Pong(frame.payload)),
+            _ => return Err(CloseFrame::PROTOCOL_ERROR)
+        }
+        Ok(())
+    }
+
+    fn on_message(&mut self, opcode: u8, payload: Vec<u8>) -> Result<(), u16> {
+        let message = if opcode == OPCODE_TEXT {
+            Message::Text(String::from_utf8(payload).map_err(|_| CloseFrame::INVALID_PAYLOAD)?)
+        } else {
+            Message::Binary(payload)
+        };
+        self.deliver(message);
+        Ok(())
+    }
+
+    fn poll_write(&mut self) -> Poll<(), io::Error> {
+        while !self.write_buf.is_empty() {
+            let len = try_nb!(self.io.write(&self.write_buf));
+            if len == 0 {
+                return Err(io::ErrorKind::WriteZero.into());
+            }
+            self.write_buf.drain(..len);
+        }
+        try_nb!(self.io.flush());
+        Ok(Async::Ready(()))
+    }
+}
+
+impl<S> Future for Session<S>
+    where S: AsyncRead + AsyncWrite
+{
+    type Item = ();
+    type Error = ();
+
+    fn poll(&mut self) -> Poll<(), ()> {
+        loop {
+            let more = self.poll_outgoing();
+            if self.poll_deliver() {
+                self.poll_read().map_err(|_| ())?;
+            }
+            if self.poll_write().map_err(|_| ())?.is_not_ready() || !more {
+                break;
+            }
+        }
+
+        // Close the connection once both sides sent their close frame.
+        if self.close_sent && self.close_received && self.write_buf.is_empty() {
+            return self.io.shutdown().map_err(|_| ());
+        }
+        // Or once the closing handshake took too long.
+        if self.close_sent || self.close_received {
+            if self.closing.is_none() {
+                self.closing = Some(Timeout::new(self.close_timeout, &self.handle).map_err(|_| ())?);
+            }
+            if let Some(ref mut closing) = self.closing {
+                return closing.poll().map_err(|_| ());
+            }
+        }
+        Ok(Async::NotReady)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::{Read, Write};
+    use std::net::{SocketAddr, TcpStream};
+
+    use {mime, Ferrum, Listening};
+
+    #[test]
+    fn test_accept_key() {
+        assert_eq!(accept_key("dGhlIHNhbXBsZSBub25jZQ=="), "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=");
+    }
+
+    #[test]
+    fn test_handshake_errors() {
+        let mut request = Request::stub();
+        request.headers.set_raw("Upgrade", "websocket");
+        request.headers.set_raw("Connection", "keep-alive, Upgrade");
+        request.headers.set_raw("Sec-WebSocket-Key", "dGhlIHNhbXBsZSBub25jZQ==");
+        request.headers.set_raw("Sec-WebSocket-Version", "8");
+        assert_eq!(WebSocketUpgrade::new(&request).unwrap_err(), HandshakeError::UnsupportedVersion);
+
+        let response = FerrumError::from(HandshakeError::UnsupportedVersion).response.unwrap();
+        assert_eq!(response.status, StatusCode::UpgradeRequired);
+        assert_eq!(response.headers.get_raw("Sec-WebSocket-Version").unwrap(), "13");
+
+        request.headers.set_raw("Sec-WebSocket-Version", "13");
+        request.headers.set_raw("Sec-WebSocket-Protocol", "chat, superchat");
+        let upgrade = WebSocketUpgrade::new(&request).unwrap();
+        assert_eq!(upgrade.protocols(), ["chat", "superchat"]);
+
+        request.headers.set_raw("Sec-WebSocket-Key", "c2hvcnQ=");
+        assert_eq!(WebSocketUpgrade::new(&request).unwrap_err(), HandshakeError::InvalidKey);
+
+        request.headers.remove_raw("Upgrade");
+        assert_eq!(WebSocketUpgrade::new(&request).unwrap_err(), HandshakeError::NotUpgrade);
+    }
+
+    fn spawn_echo(max_message_size: usize) -> Listening {
+         Ferrum::new(move |request: &mut Request| {
+            if !WebSocketUpgrade::is_upgrade(request) {
+                return Ok(Response::new().with_content("plain", mime::TEXT_PLAIN));
+            }
+            let upgrade = WebSocketUpgrade::new(request)?.with_max_message_size(max_message_size);
+            Ok(upgrade.accept(|socket: WebSocket| {
+                for message in socket.incoming() {
+                    if let Message::Text(text) = message {
+                        socket.send_text(text).unwrap();
+                    }
+                }
+            }))
+        }).spawn("127.0.0.1:0").unwrap()
+    }
+
+    fn connect(addr: SocketAddr) -> TcpStream {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        stream.write_all(b"GET /chat HTTP/1.1\r\nHost: localhost\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+            Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\n\r\n").unwrap();
+
+        let mut head = Vec::new();
+        let mut byte = [0];
+        while !head.ends_with(b"\r\n\r\n") {
+            stream.read_exact(&mut byte).unwrap();
+            head.push(byte[0]);
+        }
+        let head = String::from_utf8(head).unwrap();
+        assert!(head.starts_with("HTTP/1.1 101 Switching Protocols"), "{}", head);
+        assert!(head.contains("Sec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo="), "{}", head);
+        stream
+    }
+
+    fn send_frame(stream: &mut TcpStream, opcode: u8, payload: &[u8]) {
+        let mask = [0x12, 0x34, 0x56, 0x78];
+        let mut frame = vec![0x80 | opcode];
+        if payload.len() < 126 {
+            frame.push(0x80 | payload.len() as u8);
+        } else {
+            frame.push(0x80 | 127);
+            frame.extend_from_slice(&(payload.len() as u64).to_be_bytes());
+        }
+        frame.extend_from_slice(&mask);
+        frame.extend(payload.iter().enumerate().map(|(i, byte)| byte ^ mask[i % 4]));
+        stream.write_all(&frame).unwrap();
+    }
+
+    fn read_frame(stream: &mut TcpStream) -> (u8, Vec<u8>) {
+        let mut head = [0; 2];
+        stream.read_exact(&mut head).unwrap();
+        assert_eq!(head[1] & 0x80, 0, "server frames are not masked");
+        let len = match head[1] {
+            126 => {
+                let mut len = [0; 2];
+                stream.read_exact(&mut len).unwrap();
+                u16::from_be_bytes(len) as usize
+            },
+            len => len as usize
+        };
+        let mut payload = vec![0; len];
+        stream.read_exact(&mut payload).unwrap();
+        (head[0] & 0x0F, payload)
+    }
+
+    #[test]
+    fn test_websocket_echo() {
+        let listening = spawn_echo(DEFAULT_MAX_MESSAGE_SIZE);
+        let addr = listening.local_addr().unwrap();
+        let mut stream = connect(addr);
+
+        send_frame(&mut stream, OPCODE_TEXT, b"hello");
+        assert_eq!(read_frame(&mut stream), (OPCODE_TEXT, b"hello".to_vec()));
+
+        send_frame(&mut stream, OPCODE_PING, b"ping");
+        assert_eq!(read_frame(&mut stream), (OPCODE_PONG, b"ping".to_vec()));
+
+        // A message in two frames.
+        stream.write_all(&[OPCODE_TEXT, 0x83, 0, 0, 0, 0, b'a', b'b', b'c']).unwrap();
+        send_frame(&mut stream, OPCODE_CONTINUATION, b"def");
+        assert_eq!(read_frame(&mut stream), (OPCODE_TEXT, b"abcdef".to_vec()));
+
+        send_frame(&mut stream, OPCODE_CLOSE, &[0x03, 0xE8]);
+        assert_eq!(read_frame(&mut stream), (OPCODE_CLOSE, vec![0x03, 0xE8]));
+        let mut rest = Vec::new();
+        stream.read_to_end(&mut rest).unwrap();
+        assert!(rest.is_empty());
+
+        // Plain requests are still served.
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n").unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        assert!(response.ends_with("plain"));
+    }
+
+    #[test]
+    fn test_websocket_max_message_size() {
+        let listening = spawn_echo(1024);
+        let addr = listening.local_addr().unwrap();
+        let mut stream = connect(addr);
+
+        send_frame(&mut stream, OPCODE_TEXT, &[b'a'; 1024]);
+        assert_eq!(read_frame(&mut stream).1.len(), 1024);
+
+        send_frame(&mut stream, OPCODE_TEXT, &[b'a'; 1025]);
+        let (opcode, payload) = read_frame(&mut stream);
+        assert_eq!(opcode, OPCODE_CLOSE);
+        assert_eq!(u16::from_be_bytes([payload[0], payload[1]]), CloseFrame::MESSAGE_TOO_BIG);
+    }
+
+    #[test]
+    fn test_websocket_backpressure() {
+        let listening = Ferrum::new(|request: &mut Request| {
+            Ok(WebSocketUpgrade::new(request)?.accept(|socket: WebSocket| {
+                // The client gets ahead of the callback.
+                thread::sleep(Duration::from_millis(100));
+                let texts: Vec<_> = socket.incoming().take(64).map(|message| match message {
+                    Message::Text(text) => text,
+                    _ => String::new()
+                }).collect();
+                socket.send_text(texts.join(",")).unwrap();
+            }))
+        }).spawn("127.0.0.1:0").unwrap();
+        let mut stream = connect(listening.local_addr().unwrap());
+
+        let texts: Vec<_> = (0..64).map(|i| i.to_string()).collect();
+        for text in &texts {
+            send_frame(&mut stream, OPCODE_TEXT, text.as_bytes());
+        }
+        assert_eq!(read_frame(&mut stream), (OPCODE_TEXT, texts.join(",").into_bytes()));
+    }
+
+    #[test]
+    fn test_websocket_close_timeout() {
+        let listening = Ferrum::new(|request: &mut Request| {
+            let upgrade = WebSocketUpgrade::new(request)?.with_close_timeout(Duration::from_millis(100));
+            Ok(upgrade.accept(|socket: WebSocket| {
+                socket.close(CloseFrame::NORMAL, "bye").unwrap();
+                while socket.recv().is_ok() {}
+            }))
+        }).spawn("127.0.0.1:0").unwrap();
+        let mut stream = connect(listening.local_addr().unwrap());
+
+        let (opcode, payload) = read_frame(&mut stream);
+        assert_eq!(opcode, OPCODE_CLOSE);
+        assert_eq!(&payload[2..], b"bye");
+        // The client never echoes the close frame.
+        let mut rest = Vec::new();
+        stream.read_to_end(&mut rest).unwrap();
+        assert!(rest.is_empty());
+    }
+
+    #[test]
+    fn test_websocket_rejected_after_accept() {
+        use std::sync::Arc;
+        use std::sync::atomic::{AtomicBool, Ordering};
+        use std::thread;
+        use {AfterMiddleware, Chain, FerrumResult};
+
+        struct Forbid;
+
+        impl AfterMiddleware for Forbid {
+            fn after(&self, _: &mut Request, response: Response) -> FerrumResult<Response> {
+                Ok(response.with_status(StatusCode::Forbidden))
+            }
+        }
+
+        let served = Arc::new(AtomicBool::new(false));
+        let flag = served.clone();
+        let mut chain = Chain::new(move |request: &mut Request| {
+            let flag = flag.clone();
+            Ok(WebSocketUpgrade::new(request)?.accept(move |_: WebSocket| flag.store(true, Ordering::SeqCst)))
+        });
+        chain.link_after(Forbid);
+        let listening = Ferrum::new(chain).spawn("127.0.0.1:0").unwrap();
+
+        let mut stream = TcpStream::connect(listening.local_addr().unwrap()).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        stream.write_all(b"GET /chat HTTP/1.1\r\nHost: localhost\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+            Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\n\r\n").unwrap();
+        let mut response = [0; 1024];
+        let len = stream.read(&mut response).unwrap();
+        let response = String::from_utf8_lossy(&response[..len]);
+        assert!(response.starts_with("HTTP/1.1 403 Forbidden"), "{}", response);
+
+        // The connection is not switched over once it ends.
+        drop(stream);
+        thread::sleep(Duration::from_millis(100));
+        assert!(!served.load(Ordering::SeqCst));
+    }
+}